@@ -1,6 +1,10 @@
 use std::{env, path::PathBuf, process::Command};
 
 fn main() {
+    // the in-process host needs neither the enclave bindings nor the sgx runtime
+    if env::var_os("CARGO_FEATURE_SGX").is_none() {
+        return;
+    }
     let sgx_sdk_dir =
         PathBuf::from(env::var("SGX_SDK").unwrap_or_else(|_| "/opt/sgxsdk".to_string()));
     let sgx_mode = env::var("SGX_MODE").unwrap_or_else(|_| "HW".to_string());
@@ -1,14 +1,18 @@
 #[macro_use]
 extern crate tracing;
+#[cfg(feature = "sgx")]
 extern crate sgx_types;
+#[cfg(feature = "sgx")]
 extern crate sgx_urts;
+#[cfg(feature = "sgx")]
 use sgx_types::{sgx_attributes_t, sgx_launch_token_t, sgx_misc_attribute_t, SgxResult};
+#[cfg(feature = "sgx")]
 use sgx_urts::SgxEnclave;
 use rocksdb::{Options, DB};
 use merkle_tree::storage::{ReadInterface, WriteInterface, NodeId, MerkleNode};
 use std::fs;
-use std::path::Path;
-use vfs_common::{digest::Digest, MERKLE_PATH};
+use std::path::{Path, PathBuf};
+use vfs_common::{digest::Digest, page_size, DEFAULT_PAGE_SIZE};
 use serde::{Serialize, Deserialize};
 use anyhow::{Context, Result, Error};
 use tracing_subscriber::EnvFilter;
@@ -16,10 +20,14 @@ use howlong::ProcessDuration;
 
 
 pub mod ocall;
+pub mod sim;
+pub mod vbf_file;
 
+#[cfg(feature = "sgx")]
 static ENCLAVE_FILE: &'static str =
     "/home/comp/hxwang/sgx_vsqlite/target/release/libvsqlite_enclave.signed.so";
 
+#[cfg(feature = "sgx")]
 pub fn init_enclave() -> SgxResult<SgxEnclave> {
     let mut launch_token: sgx_launch_token_t = [0; 1024];
     let mut launch_token_updated: i32 = 0;
@@ -53,11 +61,25 @@ pub fn init_tracing_subscriber(directives: &str) -> Result<()> {
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Parameter {
     root_id: Option<NodeId>,
+    // trees built before the page size was recorded all use 4096
+    #[serde(default = "default_page_size")]
+    page_size: usize,
+    // digest of the vbf the enclave computed along with the root
+    #[serde(default)]
+    vbf_digest: Option<Digest>,
+}
+
+fn default_page_size() -> usize {
+    DEFAULT_PAGE_SIZE
 }
 
 impl Parameter {
-    fn new(root_id: Option<NodeId>) -> Self {
-        Self { root_id }
+    fn new(root_id: Option<NodeId>, page_size: usize) -> Self {
+        Self { root_id, page_size, vbf_digest: None }
+    }
+
+    pub fn get_page_size(&self) -> usize {
+        self.page_size
     }
 
     pub fn get_height(&self) -> u32 {
@@ -70,9 +92,14 @@ impl Parameter {
     pub fn get_root_id(&self) -> Option<NodeId> {
         self.root_id
     }
+
+    pub fn get_vbf_digest(&self) -> Option<Digest> {
+        self.vbf_digest
+    }
 }
 
 pub struct MerkleDB {
+    path: PathBuf,
     param: Parameter,
     merkle_db: DB,
 }
@@ -87,6 +114,7 @@ impl MerkleDB {
         let mut opts = rocksdb::Options::default();
         opts.create_if_missing(true);
         Ok(Self {
+            path: path.to_path_buf(),
             param,
             merkle_db: DB::open(&opts, path.join("merkle.db"))?,
         })
@@ -94,6 +122,7 @@ impl MerkleDB {
 
     fn open(path: &Path) -> Result<Self> {
         Ok(Self {
+            path: path.to_path_buf(),
             param: serde_json::from_str::<Parameter>(&fs::read_to_string(
                 path.join("param.json"),
             )?)?,
@@ -104,6 +133,7 @@ impl MerkleDB {
     pub fn open_read_only(path: &Path) -> Result<Self> {
         let opts = Options::default();
         Ok(Self {
+            path: path.to_path_buf(),
             param: serde_json::from_str::<Parameter>(&fs::read_to_string(
                 path.join("param.json"),
             )?)?,
@@ -116,7 +146,7 @@ impl MerkleDB {
             Self::open(path)
         } else {
             info!("attention! merkle db create is called, path is {:?}", path);
-            Self::create(path, Parameter::new(None))
+            Self::create(path, Parameter::new(None, page_size()))
         }
     }
 
@@ -128,12 +158,24 @@ impl MerkleDB {
         self.param.get_root_id()
     }
 
+    pub fn get_page_size(&self) -> usize {
+        self.param.get_page_size()
+    }
+
+    pub fn get_vbf_digest(&self) -> Option<Digest> {
+        self.param.get_vbf_digest()
+    }
+
+    // written to param.json by the next `update_param`, along with the root
+    pub fn set_vbf_digest(&mut self, vbf_digest: Digest) {
+        self.param.vbf_digest = Some(vbf_digest);
+    }
+
     pub fn update_param(&mut self, new_root_id: Option<NodeId>) -> Result<()> {
-        let path = Path::new(MERKLE_PATH);
-        let param = Parameter::new(new_root_id);
+        self.param.root_id = new_root_id;
         fs::write(
-            path.join("param.json"),
-            serde_json::to_string_pretty(&param)?,
+            self.path.join("param.json"),
+            serde_json::to_string_pretty(&self.param)?,
         )?;
         Ok(())
     }
@@ -160,6 +202,11 @@ impl WriteInterface for MerkleDB {
         self.merkle_db.put(addr.as_bytes(), bytes)?;
         Ok(())
     }
+
+    fn remove_node(&mut self, addr: &Digest) -> Result<()> {
+        self.merkle_db.delete(addr.as_bytes())?;
+        Ok(())
+    }
 }
 
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -1,5 +1,5 @@
 use anyhow::{Result, bail};
-use vfs_common::{TMP_FILE_PATH, MAIN_PATH, MERKLE_PATH, page::PageId, digest::{Digest, Digestible}, PAGE_SIZE};
+use vfs_common::{TMP_FILE_PATH, MAIN_PATH, MERKLE_PATH, SEALED_STATE_PATH, COUNTER_UUID_PATH, VBF_PATH, page::PageId, digest::{Digest, Digestible}, page_size, set_page_size, vbf::VersionBloomFilter};
 use rand::Rng;
 use std::io::{ErrorKind};
 use std::{
@@ -10,7 +10,7 @@ use std::{
     time::{Duration, Instant},
 };
 use time;
-use crate::{MerkleDB, NodeId};
+use crate::{MerkleDB, NodeId, vbf_file};
 use merkle_tree::{read::ReadContext, write::WriteContext, proof::Proof, storage::{ReadInterface, WriteInterface, MerkleNode}};
 use std::ptr::copy_nonoverlapping;
 
@@ -122,7 +122,7 @@ pub unsafe extern "C" fn ocall_file_read(
     0 // ffi::SQLITE_OK
 }
 
-fn file_update_open(path: &str) -> Result<File> {
+fn file_update_open<P: AsRef<Path>>(path: P) -> Result<File> {
     Ok(fs::OpenOptions::new()
         .read(true)
         .write(true)
@@ -292,7 +292,7 @@ pub unsafe extern "C" fn ocall_get_read_proof_with_len(
     ptr: *const u8, 
     len: usize, 
     proof_ptr: *mut u8, 
-    _predicated_p_len: usize,
+    predicated_p_len: usize,
     real_p_len: *mut usize,
 ) -> i32 {
     let bytes: Vec<u8> = slice::from_raw_parts(ptr, len).to_vec();
@@ -315,6 +315,9 @@ pub unsafe extern "C" fn ocall_get_read_proof_with_len(
     println!("proof size: {} byte", real_proof_len);
 
     *real_p_len = real_proof_len;
+    if real_proof_len > predicated_p_len {
+        return 2;
+    }
     copy_nonoverlapping(p_bytes.as_ptr(), proof_ptr, real_proof_len);
     0
 }
@@ -349,7 +352,7 @@ pub unsafe extern "C" fn ocall_get_nodes_with_len(
     ptr: *const u8, 
     len: usize, 
     nodes_ptr: *mut u8, 
-    _predicated_len: usize,
+    predicated_len: usize,
     real_len: *mut usize,
 ) -> i32 {
     let bytes: Vec<u8> = slice::from_raw_parts(ptr, len).to_vec();
@@ -372,6 +375,9 @@ pub unsafe extern "C" fn ocall_get_nodes_with_len(
 
     let real_nodes_len = bytes.len();
     *real_len = real_nodes_len;
+    if real_nodes_len > predicated_len {
+        return 2;
+    }
     copy_nonoverlapping(bytes.as_ptr(), nodes_ptr, real_nodes_len);
 
     0
@@ -382,15 +388,20 @@ pub unsafe extern "C" fn ocall_read_pages_with_len(
     ptr: *const u8, 
     len: usize, 
     pages_ptr: *mut u8, 
-    _predicated_p_len: usize,
+    predicated_p_len: usize,
     real_p_len: *mut usize,
 ) -> i32 {
     let bytes: Vec<u8> = slice::from_raw_parts(ptr, len).to_vec();
-    let p_ids: Vec<PageId> =
-        postcard::from_bytes::<Vec<PageId>>(&bytes).unwrap();
+    let p_ids = match postcard::from_bytes::<Vec<PageId>>(&bytes) {
+        Ok(p_ids) => p_ids,
+        Err(e) => {
+            println!("failed to cast bytes to page ids, reason: {:?}", e);
+            return 1;
+        }
+    };
     let mut pages_info = Vec::<(PageId, Vec<u8>)>::new();
     for p_id in p_ids {
-        let page = match read_page(p_id) {
+        let page = match read_page(Path::new(MAIN_PATH), p_id) {
             Ok(p) => p,
             Err(e) => {
                 println!("failed to read page {:?}", p_id);
@@ -410,14 +421,18 @@ pub unsafe extern "C" fn ocall_read_pages_with_len(
 
     let real_pages_len = p_bytes.len();
     *real_p_len = real_pages_len;
+    if real_pages_len > predicated_p_len {
+        // the enclave sized the buffer for another page size
+        return 2;
+    }
     copy_nonoverlapping(p_bytes.as_ptr(), pages_ptr, real_pages_len);
     0
 }
 
-fn read_page(p_id: PageId) -> Result<Vec<u8>> {
-    let ofset = p_id.get_id() as u64 * PAGE_SIZE as u64;
+pub(crate) fn read_page(path: &Path, p_id: PageId) -> Result<Vec<u8>> {
+    let ofset = p_id.get_id() as u64 * page_size() as u64;
 
-    let mut file = match std::fs::File::open(&MAIN_PATH) {
+    let mut file = match std::fs::File::open(path) {
         Ok(f) => f,
         Err(_) => {
             println!("cannot open");
@@ -439,7 +454,7 @@ fn read_page(p_id: PageId) -> Result<Vec<u8>> {
         }
     }
 
-    let mut bytes = vec![0 as u8; PAGE_SIZE];
+    let mut bytes = vec![0 as u8; page_size()];
     
     if let Err(err) = std::io::Read::read_exact(&mut file, &mut bytes) {
         let kind = err.kind();
@@ -464,7 +479,7 @@ pub unsafe extern "C" fn ocall_write_pages(
         postcard::from_bytes::<Vec<(PageId, Vec<u8>)>>(&bytes).unwrap();
 
     for (p_id, bytes) in modif {
-        match write_page(p_id, bytes) {
+        match write_page(Path::new(MAIN_PATH), p_id, &bytes) {
             Ok(_) => {},
             Err(_) => {return 1;},
         }
@@ -473,10 +488,10 @@ pub unsafe extern "C" fn ocall_write_pages(
     0
 }
 
-fn write_page(p_id: PageId, bytes: Vec<u8>) -> Result<()> {
-    let ofst = p_id.get_id() as u64 * PAGE_SIZE as u64;
+pub(crate) fn write_page(path: &Path, p_id: PageId, bytes: &[u8]) -> Result<()> {
+    let ofst = p_id.get_id() as u64 * page_size() as u64;
 
-    let mut file = match file_update_open(&MAIN_PATH) {
+    let mut file = match file_update_open(path) {
         Ok(f) => f,
         Err(_) => {
             trace!("cannot open");
@@ -498,7 +513,7 @@ fn write_page(p_id: PageId, bytes: Vec<u8>) -> Result<()> {
         }
     }
 
-    if let Err(_err) = file.write_all(&bytes) {
+    if let Err(_err) = file.write_all(bytes) {
         bail!("io error write"); 
     }
 
@@ -558,7 +573,9 @@ pub unsafe extern "C" fn ocall_get_merkle_root(ptr: *mut u8, len: usize) -> i32
     } else {
         Digest::default()
     };
-    let root_info = (root_id, dig);
+    // pages are served with the same page size that is reported to the enclave
+    set_page_size(merkle_db.get_page_size());
+    let root_info = (root_id, dig, merkle_db.get_page_size() as u32, merkle_db.get_vbf_digest());
 
     let bytes = match postcard::to_allocvec(&root_info) {
         Ok(buf) => buf,
@@ -567,47 +584,210 @@ pub unsafe extern "C" fn ocall_get_merkle_root(ptr: *mut u8, len: usize) -> i32
             return 1;
         }
     };
-    copy_nonoverlapping(bytes.as_ptr(), ptr, len);
+    if bytes.len() > len {
+        println!("root info buffer too small");
+        return 1;
+    }
+    copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
 
     0
 }
 
 
+#[no_mangle]
+pub unsafe extern "C" fn ocall_get_vbf_with_len(
+    ptr: *mut u8,
+    predicated_len: usize,
+    real_len: *mut usize,
+) -> i32 {
+    // the file is kept in the layout the vsqlite server reads, the enclave gets it with postcard
+    let bytes = match vbf_file::load(Path::new(VBF_PATH)).and_then(|vbf| Ok(postcard::to_allocvec(&vbf)?)) {
+        Ok(b) => b,
+        Err(e) => {
+            println!("failed to read vbf, reason: {:?}", e);
+            return 1;
+        }
+    };
+    *real_len = bytes.len();
+    if bytes.len() > predicated_len {
+        // the enclave retries with a buffer of `real_len`
+        return 2;
+    }
+    copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+    0
+}
 
+// the enclave checked the vbf against the published digest and advanced it with the root
 #[no_mangle]
-pub unsafe extern "C" fn ocall_update_merkle_db(
-    ptr: *const u8, 
+pub unsafe extern "C" fn ocall_update_vbf(
+    ptr: *const u8,
     len: usize,
 ) -> i32 {
-    let timer = howlong::ProcessCPUTimer::new();
     let bytes: Vec<u8> = slice::from_raw_parts(ptr, len).to_vec();
-    let modif =
-        postcard::from_bytes::<Vec<(PageId, Digest)>>(&bytes).unwrap();
+    let vbf = match postcard::from_bytes::<VersionBloomFilter>(&bytes) {
+        Ok(vbf) => vbf,
+        Err(e) => {
+            println!("failed to cast bytes to vbf, reason: {:?}", e);
+            return 1;
+        }
+    };
     let mut merkle_db = MerkleDB::create_new(Path::new(MERKLE_PATH)).expect("failed to open or create merkle db");
-    let root_id = merkle_db.get_root_id();
-    let mut ctx = WriteContext::new(&merkle_db, root_id);
-    for (p_id, dig) in modif {
-        ctx.update(dig, p_id)
-            .expect("Failed to update merkle tree");
+    if let Err(e) = save_vbf(&mut merkle_db, Path::new(VBF_PATH), &vbf) {
+        println!("failed to write vbf, reason: {:?}", e);
+        return 1;
     }
-    let changes = ctx.changes();
-    let new_root_id = changes.root_id;
-    for (addr, node) in changes.nodes {
-        merkle_db.write_node(&addr, &node).unwrap();
+    merkle_db.close();
+    0
+}
+
+// the enclave sealed its trusted state, a rename keeps a crash from leaving half of it
+#[no_mangle]
+pub unsafe extern "C" fn ocall_save_sealed_state(
+    ptr: *const u8,
+    len: usize,
+) -> i32 {
+    let bytes = slice::from_raw_parts(ptr, len);
+    let path = Path::new(SEALED_STATE_PATH);
+    let tmp = path.with_extension("tmp");
+    if let Err(e) = fs::write(&tmp, bytes).and_then(|_| fs::rename(&tmp, path)) {
+        println!("failed to write sealed state, reason: {:?}", e);
+        return 1;
+    }
+    0
+}
+
+// `real_len` is 0 if the enclave has not sealed a state yet
+#[no_mangle]
+pub unsafe extern "C" fn ocall_load_sealed_state_with_len(
+    ptr: *mut u8,
+    predicated_len: usize,
+    real_len: *mut usize,
+) -> i32 {
+    let bytes = match fs::read(SEALED_STATE_PATH) {
+        Ok(b) => b,
+        Err(e) if e.kind() == ErrorKind::NotFound => Vec::new(),
+        Err(e) => {
+            println!("failed to read sealed state, reason: {:?}", e);
+            return 1;
+        }
+    };
+    *real_len = bytes.len();
+    if bytes.len() > predicated_len {
+        // the enclave retries with a buffer of `real_len`
+        return 2;
     }
-    merkle_db.update_param(new_root_id).unwrap();
+    copy_nonoverlapping(bytes.as_ptr(), ptr, bytes.len());
+    0
+}
+
+// the enclave created the counter of its sealed state, it is saved before any state is sealed
+#[no_mangle]
+pub unsafe extern "C" fn ocall_save_counter_uuid(
+    ptr: *const u8,
+    len: usize,
+) -> i32 {
+    let bytes = slice::from_raw_parts(ptr, len);
+    let path = Path::new(COUNTER_UUID_PATH);
+    let tmp = path.with_extension("tmp");
+    if let Err(e) = fs::write(&tmp, bytes).and_then(|_| fs::rename(&tmp, path)) {
+        println!("failed to write counter uuid, reason: {:?}", e);
+        return 1;
+    }
+    0
+}
+
+// `found` is 0 if the enclave has not created a counter yet
+#[no_mangle]
+pub unsafe extern "C" fn ocall_load_counter_uuid(
+    ptr: *mut u8,
+    len: usize,
+    found: *mut i32,
+) -> i32 {
+    let bytes = match fs::read(COUNTER_UUID_PATH) {
+        Ok(b) => b,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            *found = 0;
+            return 0;
+        }
+        Err(e) => {
+            println!("failed to read counter uuid, reason: {:?}", e);
+            return 1;
+        }
+    };
+    if bytes.len() != len {
+        println!("malformed counter uuid of {} bytes", bytes.len());
+        return 1;
+    }
+    copy_nonoverlapping(bytes.as_ptr(), ptr, len);
+    *found = 1;
+    0
+}
 
-    // for dbg only
-    // println!("dbg: real new root id: {:?}", new_root_id.unwrap());
-    let new_root_hash = merkle_db.get_node(&new_root_id.unwrap().to_digest()).unwrap().unwrap().get_hash();
-    // println!("dbg: real new root hash: {:?}", new_root_hash);
+// the digest of `vbf` goes to param.json along with the current root
+pub(crate) fn save_vbf(merkle_db: &mut MerkleDB, path: &Path, vbf: &VersionBloomFilter) -> Result<()> {
+    vbf_file::save(path, vbf)?;
+    merkle_db.set_vbf_digest(vbf.to_digest());
+    let root_id = merkle_db.get_root_id();
+    merkle_db.update_param(root_id)
+}
 
+#[no_mangle]
+pub unsafe extern "C" fn ocall_update_merkle_db(
+    ptr: *const u8, 
+    len: usize,
+    root_ptr: *mut u8,
+    root_len: usize,
+) -> i32 {
+    let timer = howlong::ProcessCPUTimer::new();
+    let bytes: Vec<u8> = slice::from_raw_parts(ptr, len).to_vec();
+    let (modif, page_cnt) =
+        postcard::from_bytes::<(Vec<(PageId, Digest)>, Option<u32>)>(&bytes).unwrap();
+    let mut merkle_db = MerkleDB::create_new(Path::new(MERKLE_PATH)).expect("failed to open or create merkle db");
+    let new_root_id = apply_modif(&mut merkle_db, &modif, page_cnt).expect("Failed to update merkle tree");
+    let new_root_hash = match new_root_id {
+        Some(r_id) => merkle_db.get_node(&r_id.to_digest()).unwrap().expect("Cannot find root").get_hash(),
+        None => Digest::default(),
+    };
     merkle_db.close();
 
+    // the enclave only endorses the batch if this is the root it computed
+    let bytes = match postcard::to_allocvec(&(new_root_id, new_root_hash)) {
+        Ok(buf) => buf,
+        Err(e) => {
+            println!("failed to cast root to bytes, reason: {:?}", e);
+            return 1;
+        }
+    };
+    if bytes.len() > root_len {
+        println!("root buffer too small");
+        return 1;
+    }
+    copy_nonoverlapping(bytes.as_ptr(), root_ptr, bytes.len());
+
     let time = timer.elapsed().real.as_micros() / 1000;
     println!("merkle db maintain time: {} ms", time);
 
     0
 }
 
-
+// the page hashes the enclave verified, then the truncation if the main file shrank after them
+pub(crate) fn apply_modif(merkle_db: &mut MerkleDB, modif: &[(PageId, Digest)], page_cnt: Option<u32>) -> Result<Option<NodeId>> {
+    let root_id = merkle_db.get_root_id();
+    let mut ctx = WriteContext::new(&*merkle_db, root_id);
+    for (p_id, dig) in modif {
+        ctx.update(*dig, *p_id)?;
+    }
+    if let Some(n) = page_cnt {
+        ctx.truncate(n)?;
+    }
+    let changes = ctx.changes();
+    let new_root_id = changes.root_id;
+    for (addr, node) in changes.nodes {
+        merkle_db.write_node(&addr, &node)?;
+    }
+    for addr in changes.removed {
+        merkle_db.remove_node(&addr)?;
+    }
+    merkle_db.update_param(new_root_id)?;
+    Ok(new_root_id)
+}
@@ -0,0 +1,627 @@
+// an in-process host for the update verifier, serving the same MerkleDB and main file the
+// ocalls do, so the whole verification path runs without an enclave
+use crate::{
+    ocall::{apply_modif, read_page, save_vbf, write_page},
+    vbf_file, MerkleDB,
+};
+use anyhow::{bail, Context, Result};
+use merkle_tree::{
+    proof::Proof,
+    read::ReadContext,
+    storage::{MerkleNode, NodeId, ReadInterface},
+};
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+use update_verifier::{Root, RootInfo, SealingBackend, TrustedState, UntrustedHost};
+use vfs_common::{
+    digest::{Digest, Digestible},
+    page::PageId,
+    vbf::VersionBloomFilter,
+};
+
+pub struct SimHost {
+    merkle_db: MerkleDB,
+    main_path: PathBuf,
+    vbf_path: PathBuf,
+}
+
+impl SimHost {
+    // the tree is created at `merkle_path` if it does not exist, the vbf is kept next to it
+    pub fn open(merkle_path: &Path, main_path: &Path) -> Result<Self> {
+        if let Some(dir) = main_path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(Self {
+            merkle_db: MerkleDB::create_new(merkle_path)?,
+            main_path: main_path.to_path_buf(),
+            vbf_path: merkle_path.join("vbf.bin"),
+        })
+    }
+
+    pub fn close(self) {
+        self.merkle_db.close();
+    }
+}
+
+impl UntrustedHost for SimHost {
+    fn get_root(&self) -> Result<RootInfo> {
+        let root_id = self.merkle_db.get_root_id();
+        let dig = match root_id {
+            Some(r_id) => self
+                .merkle_db
+                .get_node(&r_id.to_digest())?
+                .context("Cannot find root")?
+                .get_hash(),
+            None => Digest::default(),
+        };
+        Ok((
+            root_id,
+            dig,
+            self.merkle_db.get_page_size() as u32,
+            self.merkle_db.get_vbf_digest(),
+        ))
+    }
+
+    fn get_node(&self, id: NodeId) -> Result<Option<MerkleNode>> {
+        self.merkle_db.get_node(&id.to_digest())
+    }
+
+    fn get_nodes(&self, ids: &[NodeId]) -> Result<Vec<(NodeId, Option<MerkleNode>)>> {
+        ids.iter()
+            .map(|id| Ok((*id, self.merkle_db.get_node(&id.to_digest())?)))
+            .collect()
+    }
+
+    fn get_read_proof(&self, p_ids: &[PageId], _root_id: NodeId) -> Result<Proof> {
+        let mut ctx = ReadContext::new(&self.merkle_db, self.merkle_db.get_root_id())?;
+        for p_id in p_ids {
+            ctx.query(*p_id)?;
+        }
+        Ok(ctx.into_proof())
+    }
+
+    fn read_pages(&self, p_ids: &[PageId]) -> Result<Vec<(PageId, Vec<u8>)>> {
+        p_ids
+            .iter()
+            .map(|p_id| Ok((*p_id, read_page(&self.main_path, *p_id)?)))
+            .collect()
+    }
+
+    fn write_pages(&mut self, pages: &[(PageId, Vec<u8>)]) -> Result<()> {
+        for (p_id, bytes) in pages {
+            write_page(&self.main_path, *p_id, bytes)?;
+        }
+        Ok(())
+    }
+
+    fn update_merkle_db(
+        &mut self,
+        modif: &[(PageId, Digest)],
+        page_cnt: Option<u32>,
+    ) -> Result<Root> {
+        apply_modif(&mut self.merkle_db, modif, page_cnt)?;
+        let (root_id, root_hash, _, _) = self.get_root()?;
+        Ok((root_id, root_hash))
+    }
+
+    fn get_vbf(&self) -> Result<VersionBloomFilter> {
+        vbf_file::load(&self.vbf_path)
+    }
+
+    fn update_vbf(&mut self, vbf: &VersionBloomFilter) -> Result<()> {
+        save_vbf(&mut self.merkle_db, &self.vbf_path, vbf)
+    }
+}
+
+// a stand-in for sealed storage and the monotonic counter of the enclave: the counter file plays
+// the counter and is bumped before the state is written, so only the state written last loads.
+// Nothing stops the host from editing either file, it only models the protocol
+pub struct FileSealing {
+    path: PathBuf,
+    counter_path: PathBuf,
+}
+
+impl FileSealing {
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            counter_path: path.with_extension("counter"),
+        }
+    }
+}
+
+fn read_opt(path: &Path) -> Result<Option<Vec<u8>>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(bytes)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("failed to read {:?}", path)),
+    }
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, bytes)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+impl SealingBackend for FileSealing {
+    fn seal(&mut self, state: &TrustedState) -> Result<()> {
+        write_atomic(&self.counter_path, &state.version.to_le_bytes())?;
+        let bytes = match postcard::to_allocvec(state) {
+            Ok(buf) => buf,
+            Err(e) => bail!("failed to cast trusted state to bytes, reason: {:?}", e),
+        };
+        write_atomic(&self.path, &bytes)
+    }
+
+    fn unseal(&mut self) -> Result<Option<TrustedState>> {
+        let counter = match read_opt(&self.counter_path)? {
+            Some(bytes) => match <[u8; 8]>::try_from(bytes.as_slice()) {
+                Ok(b) => Some(u64::from_le_bytes(b)),
+                Err(_) => bail!("malformed counter {:?}", self.counter_path),
+            },
+            None => None,
+        };
+        let state = match (read_opt(&self.path)?, counter) {
+            (None, None) => return Ok(None),
+            (None, Some(_)) => bail!("the counter is set but the sealed state is gone"),
+            (Some(_), None) => bail!("the sealed state has no counter"),
+            (Some(bytes), Some(counter)) => match postcard::from_bytes::<TrustedState>(&bytes) {
+                Ok(state) if state.version == counter => state,
+                Ok(state) => bail!(
+                    "the sealed state is stale, sealed at version {} while the counter is at {}",
+                    state.version,
+                    counter
+                ),
+                Err(e) => bail!("failed to cast bytes to trusted state, reason: {:?}", e),
+            },
+        };
+        Ok(Some(state))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hashbrown::HashMap;
+    use update_verifier::{verify_then_update, CachePage, TrustedRoot};
+    use vfs_common::page_size;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sim_host_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn open_host(dir: &Path) -> SimHost {
+        SimHost::open(&dir.join("merkle_db"), &dir.join("test.db")).unwrap()
+    }
+
+    fn full_page(fill: u8) -> CachePage {
+        CachePage::new(0, page_size(), vec![fill; page_size()])
+    }
+
+    fn update<H: UntrustedHost>(
+        host: &mut H,
+        writes: Vec<(u32, CachePage)>,
+        read: Vec<(u32, CachePage)>,
+        truncated: Option<u32>,
+        opt_level: u8,
+    ) -> Result<Root> {
+        let (mut read_map, mut write_map) = (page_map(read), page_map(writes));
+        verify_then_update(
+            host,
+            &mut TrustedRoot::load(NoSealing).unwrap(),
+            &mut read_map,
+            &mut write_map,
+            truncated,
+            opt_level,
+            7,
+        )
+    }
+
+    fn page_map(pages: Vec<(u32, CachePage)>) -> HashMap<PageId, CachePage> {
+        pages.into_iter().map(|(i, p)| (PageId(i), p)).collect()
+    }
+
+    // trusts whatever root the host starts from
+    struct NoSealing;
+
+    impl SealingBackend for NoSealing {
+        fn seal(&mut self, _: &TrustedState) -> Result<()> {
+            Ok(())
+        }
+        fn unseal(&mut self) -> Result<Option<TrustedState>> {
+            Ok(None)
+        }
+    }
+
+    fn load_trusted(dir: &Path) -> Result<TrustedRoot<FileSealing>> {
+        TrustedRoot::load(FileSealing::new(&dir.join("trusted.sealed")))
+    }
+
+    fn endorsed(trusted: &TrustedRoot<FileSealing>) -> Option<(Root, u64)> {
+        trusted.state().map(|s| (s.root, s.version))
+    }
+
+    fn batch(
+        host: &mut SimHost,
+        trusted: &mut TrustedRoot<FileSealing>,
+        writes: Vec<(u32, CachePage)>,
+    ) -> Result<Root> {
+        let mut write_map = page_map(writes);
+        verify_then_update(
+            host,
+            trusted,
+            &mut HashMap::new(),
+            &mut write_map,
+            None,
+            0,
+            7,
+        )
+    }
+
+    fn copy_dir(from: &Path, to: &Path) {
+        fs::create_dir_all(to).unwrap();
+        for entry in fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let path = to.join(entry.file_name());
+            if entry.file_type().unwrap().is_dir() {
+                copy_dir(&entry.path(), &path);
+            } else {
+                fs::copy(entry.path(), &path).unwrap();
+            }
+        }
+    }
+
+    // a host that reports success without touching its tree
+    struct KeepTree(SimHost);
+
+    impl UntrustedHost for KeepTree {
+        fn get_root(&self) -> Result<RootInfo> {
+            self.0.get_root()
+        }
+        fn get_node(&self, id: NodeId) -> Result<Option<MerkleNode>> {
+            self.0.get_node(id)
+        }
+        fn get_nodes(&self, ids: &[NodeId]) -> Result<Vec<(NodeId, Option<MerkleNode>)>> {
+            self.0.get_nodes(ids)
+        }
+        fn get_read_proof(&self, p_ids: &[PageId], root_id: NodeId) -> Result<Proof> {
+            self.0.get_read_proof(p_ids, root_id)
+        }
+        fn read_pages(&self, p_ids: &[PageId]) -> Result<Vec<(PageId, Vec<u8>)>> {
+            self.0.read_pages(p_ids)
+        }
+        fn write_pages(&mut self, pages: &[(PageId, Vec<u8>)]) -> Result<()> {
+            self.0.write_pages(pages)
+        }
+        fn update_merkle_db(&mut self, _: &[(PageId, Digest)], _: Option<u32>) -> Result<Root> {
+            Ok(host_root(&self.0))
+        }
+        fn get_vbf(&self) -> Result<VersionBloomFilter> {
+            self.0.get_vbf()
+        }
+        fn update_vbf(&mut self, vbf: &VersionBloomFilter) -> Result<()> {
+            self.0.update_vbf(vbf)
+        }
+    }
+
+    // a host that reports its root but serves the nodes of an older tree
+    struct StaleNodes {
+        host: SimHost,
+        old: SimHost,
+    }
+
+    impl UntrustedHost for StaleNodes {
+        fn get_root(&self) -> Result<RootInfo> {
+            self.host.get_root()
+        }
+        fn get_node(&self, id: NodeId) -> Result<Option<MerkleNode>> {
+            self.old.get_node(id)
+        }
+        fn get_nodes(&self, ids: &[NodeId]) -> Result<Vec<(NodeId, Option<MerkleNode>)>> {
+            self.old.get_nodes(ids)
+        }
+        fn get_read_proof(&self, p_ids: &[PageId], root_id: NodeId) -> Result<Proof> {
+            self.host.get_read_proof(p_ids, root_id)
+        }
+        fn read_pages(&self, p_ids: &[PageId]) -> Result<Vec<(PageId, Vec<u8>)>> {
+            self.host.read_pages(p_ids)
+        }
+        fn write_pages(&mut self, pages: &[(PageId, Vec<u8>)]) -> Result<()> {
+            self.host.write_pages(pages)
+        }
+        fn update_merkle_db(
+            &mut self,
+            modif: &[(PageId, Digest)],
+            page_cnt: Option<u32>,
+        ) -> Result<Root> {
+            self.host.update_merkle_db(modif, page_cnt)
+        }
+        fn get_vbf(&self) -> Result<VersionBloomFilter> {
+            self.host.get_vbf()
+        }
+        fn update_vbf(&mut self, vbf: &VersionBloomFilter) -> Result<()> {
+            self.host.update_vbf(vbf)
+        }
+    }
+
+    fn host_root(host: &SimHost) -> Root {
+        let (root_id, root_hash, _, _) = host.get_root().unwrap();
+        (root_id, root_hash)
+    }
+
+    #[test]
+    fn test_new_root_matches_host() {
+        let dir = test_dir("new_root");
+        let mut host = open_host(&dir);
+        let writes = (0..5).map(|i| (i, full_page(i as u8))).collect();
+        let root = update(&mut host, writes, vec![], None, 0).unwrap();
+        assert_eq!(root, host_root(&host));
+
+        let read = vec![(3, full_page(3))];
+        let writes = vec![(1, full_page(9)), (5, full_page(5)), (6, full_page(6))];
+        let root = update(&mut host, writes, read, None, 0).unwrap();
+        assert_eq!(root, host_root(&host));
+        assert_eq!(
+            read_page(&dir.join("test.db"), PageId(5)).unwrap(),
+            vec![5; page_size()]
+        );
+        assert_eq!(host.get_vbf().unwrap().version(), 2);
+        host.close();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn hex(digest: Digest) -> String {
+        digest.0.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // the same filter built by the vsqlite writer, see `test_vbf_matches_sgx` there
+    #[test]
+    fn test_vbf_file_matches_vsqlite() {
+        let mut vbf = VersionBloomFilter::with_seed(16, 2, 7);
+        assert_eq!(vbf.insert_batch(&[PageId(4), PageId(9)]), 1);
+        assert_eq!(vbf.counter(), 2);
+        assert_eq!(
+            hex(vbf.to_digest()),
+            "c6f9e04dc08075c2ff60a9cbc6a331a91b310726100a68fb1d9af5915ab0327d"
+        );
+        let bytes = vbf_file::encode(&vbf).unwrap();
+        assert_eq!(
+            hex(bytes.to_digest()),
+            "c8224f9cdb59fb4b88f788069ace3a82c8348749fcb4f88cf7c1fdabf5937d71"
+        );
+        let decoded = vbf_file::decode(&bytes).unwrap();
+        assert_eq!(decoded.to_digest(), vbf.to_digest());
+        assert_eq!(decoded.version(), 1);
+        // a truncated file
+        assert!(vbf_file::decode(&bytes[..bytes.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn test_truncate_matches_host() {
+        let dir = test_dir("truncate");
+        let mut host = open_host(&dir);
+        let writes = (0..9).map(|i| (i, full_page(i as u8))).collect();
+        update(&mut host, writes, vec![], None, 0).unwrap();
+
+        let root = update(&mut host, vec![(1, full_page(11))], vec![], Some(3), 0).unwrap();
+        assert_eq!(root, host_root(&host));
+        assert_eq!(root.0, Some(NodeId::new(2, 0)));
+        host.close();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_partial_write_in_batch() {
+        let (dir_a, dir_b) = (test_dir("batch_a"), test_dir("batch_b"));
+        let (mut host_a, mut host_b) = (open_host(&dir_a), open_host(&dir_b));
+        for host in [&mut host_a, &mut host_b] {
+            let writes = (0..4).map(|i| (i, full_page(i as u8))).collect();
+            update(host, writes, vec![], None, 0).unwrap();
+        }
+
+        // the batch path completes the page from the host before hashing it
+        let mut page = vec![2; page_size()];
+        page[100..200].fill(8);
+        let partial = CachePage::new(100, 100, page.clone());
+        let batch_root = update(&mut host_a, vec![(2, partial)], vec![], None, 2).unwrap();
+        let full = CachePage::new(0, page_size(), page.clone());
+        let base_root = update(&mut host_b, vec![(2, full)], vec![], None, 0).unwrap();
+        assert_eq!(batch_root, base_root);
+        assert_eq!(read_page(&dir_a.join("test.db"), PageId(2)).unwrap(), page);
+        host_a.close();
+        host_b.close();
+        fs::remove_dir_all(&dir_a).unwrap();
+        fs::remove_dir_all(&dir_b).unwrap();
+    }
+
+    #[test]
+    fn test_reject_tampered_page() {
+        let dir = test_dir("tampered");
+        let mut host = open_host(&dir);
+        let writes = (0..4).map(|i| (i, full_page(i as u8))).collect();
+        update(&mut host, writes, vec![], None, 0).unwrap();
+        let root = host_root(&host);
+
+        // a page the host served with other bytes than the tree commits to
+        let read = vec![(2, full_page(42))];
+        assert!(update(&mut host, vec![(0, full_page(1))], read, None, 0).is_err());
+        assert_eq!(root, host_root(&host));
+        host.close();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reject_stale_nodes() {
+        for opt_level in [0, 2] {
+            let (dir, old) = (test_dir("stale"), test_dir("stale_old"));
+            let mut host = open_host(&dir);
+            let writes = (0..4).map(|i| (i, full_page(i as u8))).collect();
+            update(&mut host, writes, vec![], None, 0).unwrap();
+            host.close();
+            copy_dir(&dir, &old);
+
+            let mut host = open_host(&dir);
+            update(&mut host, vec![(0, full_page(9))], vec![], None, 0).unwrap();
+            let root = host_root(&host);
+
+            // the siblings of page 2 from before page 0 changed, consistent among themselves
+            let mut host = StaleNodes {
+                host,
+                old: open_host(&old),
+            };
+            let writes = vec![(2, full_page(7))];
+            assert!(update(&mut host, writes, vec![], None, opt_level).is_err());
+            assert_eq!(root, host_root(&host.host));
+            host.host.close();
+            host.old.close();
+            fs::remove_dir_all(&dir).unwrap();
+            fs::remove_dir_all(&old).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_chain_from_endorsed_root() {
+        let (dir_a, dir_b) = (test_dir("chain_a"), test_dir("chain_b"));
+        let (mut host_a, mut host_b) = (open_host(&dir_a), open_host(&dir_b));
+        let writes = (0..4).map(|i| (i, full_page(i as u8 + 5))).collect();
+        update(&mut host_b, writes, vec![], None, 0).unwrap();
+
+        let mut trusted = load_trusted(&dir_a).unwrap();
+        for fill in 0..2 {
+            let mut writes = page_map((0..4).map(|i| (i, full_page(i as u8 + fill))).collect());
+            let res = verify_then_update(
+                &mut host_a,
+                &mut trusted,
+                &mut HashMap::new(),
+                &mut writes,
+                None,
+                0,
+                7,
+            );
+            assert_eq!(res.unwrap(), host_root(&host_a));
+            assert_eq!(
+                endorsed(&trusted),
+                Some((host_root(&host_a), fill as u64 + 1))
+            );
+        }
+
+        // the same batch on a host that did not start from the endorsed root
+        let mut writes = page_map(vec![(1, full_page(9))]);
+        let res = verify_then_update(
+            &mut host_b,
+            &mut trusted,
+            &mut HashMap::new(),
+            &mut writes,
+            None,
+            0,
+            7,
+        );
+        assert!(res.is_err());
+        assert_eq!(endorsed(&trusted), Some((host_root(&host_a), 2)));
+
+        // the vbf published with the root is endorsed along with it
+        let (root_id, root_hash, _, vbf) = host_a.get_root().unwrap();
+        assert!(vbf.is_some());
+        assert!(trusted.check((root_id, root_hash), vbf).is_ok());
+        assert!(trusted.check((root_id, root_hash), None).is_err());
+        host_a.close();
+        host_b.close();
+        fs::remove_dir_all(&dir_a).unwrap();
+        fs::remove_dir_all(&dir_b).unwrap();
+    }
+
+    #[test]
+    fn test_reject_unapplied_update() {
+        let dir = test_dir("unapplied");
+        let mut host = open_host(&dir);
+        let writes = (0..4).map(|i| (i, full_page(i as u8))).collect();
+        update(&mut host, writes, vec![], None, 0).unwrap();
+
+        let mut host = KeepTree(host);
+        let mut trusted = load_trusted(&dir).unwrap();
+        let (root_id, root_hash, _, vbf) = host.0.get_root().unwrap();
+        trusted.endorse((root_id, root_hash), vbf).unwrap();
+        let mut writes = page_map(vec![(2, full_page(9))]);
+        let res = verify_then_update(
+            &mut host,
+            &mut trusted,
+            &mut HashMap::new(),
+            &mut writes,
+            None,
+            0,
+            7,
+        );
+        assert!(res.is_err());
+        assert_eq!(endorsed(&trusted), Some((host_root(&host.0), 1)));
+        host.0.close();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reject_rollback() {
+        let (dir, old) = (test_dir("rollback"), test_dir("rollback_old"));
+        let mut host = open_host(&dir);
+        let mut trusted = load_trusted(&dir).unwrap();
+        let writes = (0..4).map(|i| (i, full_page(i as u8))).collect();
+        batch(&mut host, &mut trusted, writes).unwrap();
+        host.close();
+        copy_dir(&dir, &old);
+
+        let mut host = open_host(&dir);
+        let writes = vec![(1, full_page(9))];
+        batch(&mut host, &mut trusted, writes).unwrap();
+        let root = host_root(&host);
+        host.close();
+        drop(trusted);
+
+        // the host restarts from the database of the first batch, the sealed state still holds
+        // the second
+        fs::remove_dir_all(dir.join("merkle_db")).unwrap();
+        copy_dir(&old.join("merkle_db"), &dir.join("merkle_db"));
+        fs::copy(old.join("test.db"), dir.join("test.db")).unwrap();
+        let mut host = open_host(&dir);
+        let mut trusted = load_trusted(&dir).unwrap();
+        assert_eq!(endorsed(&trusted), Some((root, 2)));
+        let writes = vec![(2, full_page(9))];
+        let res = batch(&mut host, &mut trusted, writes);
+        assert!(res.is_err());
+        host.close();
+
+        // the sealed state of the first batch does not load either
+        fs::copy(old.join("trusted.sealed"), dir.join("trusted.sealed")).unwrap();
+        assert!(load_trusted(&dir).is_err());
+
+        // nor does a missing one once a state was sealed
+        fs::remove_file(dir.join("trusted.sealed")).unwrap();
+        assert!(load_trusted(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&old).unwrap();
+    }
+
+    #[test]
+    fn test_reject_missing_state() {
+        let dir = test_dir("missing_state");
+        let mut host = open_host(&dir);
+        let sealing = || FileSealing::new(&dir.join("trusted.sealed"));
+        let mut trusted = TrustedRoot::open(sealing(), &host).unwrap();
+        let writes = (0..4).map(|i| (i, full_page(i as u8))).collect();
+        batch(&mut host, &mut trusted, writes).unwrap();
+        assert!(TrustedRoot::open(sealing(), &host).is_ok());
+
+        // a host dropping both the sealed state and the counter looks like a first start, but
+        // still has the tree and the vbf it published
+        fs::remove_file(dir.join("trusted.sealed")).unwrap();
+        fs::remove_file(dir.join("trusted.counter")).unwrap();
+        assert!(load_trusted(&dir).unwrap().state().is_none());
+        assert!(TrustedRoot::open(sealing(), &host).is_err());
+        host.close();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,75 @@
+// vbf.bin as the vsqlite server reads it, so the filter it serves with FETCH_VBF is the one
+// the enclave advanced and published the digest of
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fs,
+    path::Path,
+};
+use vfs_common::vbf::VersionBloomFilter;
+
+// bincode layout of `VersionBloomFilter` in v2fs-vsqlite; the log of slot changes and the
+// page versions are only kept by its writer, the enclave's filter leaves them empty
+#[derive(Debug, Serialize, Deserialize)]
+struct VsqliteVbf {
+    seed: u64,
+    map_size: u64,
+    hash_num: u32,
+    vmap: VersionMap,
+    counter: u64,
+    log: VecDeque<(u64, u32)>,
+    log_from: u64,
+    versions: BTreeMap<u32, u32>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct VersionMap {
+    map: Vec<u32>,
+}
+
+impl From<&VersionBloomFilter> for VsqliteVbf {
+    fn from(vbf: &VersionBloomFilter) -> Self {
+        Self {
+            seed: vbf.seed(),
+            map_size: vbf.map_size(),
+            hash_num: vbf.hash_num(),
+            vmap: VersionMap {
+                map: vbf.slots().to_vec(),
+            },
+            counter: vbf.counter(),
+            log: VecDeque::new(),
+            // clients behind the counter get the whole filter
+            log_from: vbf.counter(),
+            versions: BTreeMap::new(),
+        }
+    }
+}
+
+pub fn encode(vbf: &VersionBloomFilter) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(&VsqliteVbf::from(vbf))?)
+}
+
+pub fn decode(bytes: &[u8]) -> Result<VersionBloomFilter> {
+    let vbf: VsqliteVbf = bincode::deserialize(bytes).context("invalid vbf")?;
+    VersionBloomFilter::from_parts(
+        vbf.seed,
+        vbf.map_size,
+        vbf.hash_num,
+        vbf.vmap.map,
+        vbf.counter,
+    )
+    .context("vbf slots do not match its size")
+}
+
+pub fn load(path: &Path) -> Result<VersionBloomFilter> {
+    let bytes = fs::read(path).with_context(|| format!("failed to read vbf {:?}", path))?;
+    decode(&bytes).with_context(|| format!("invalid vbf {:?}", path))
+}
+
+// written aside and renamed, the server never serves a partial filter
+pub fn save(path: &Path, vbf: &VersionBloomFilter) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, encode(vbf)?).with_context(|| format!("failed to write vbf {:?}", tmp))?;
+    fs::rename(&tmp, path).with_context(|| format!("failed to replace vbf {:?}", path))
+}
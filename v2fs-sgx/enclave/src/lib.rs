@@ -16,19 +16,35 @@ use std::string::String;
 use std::slice;
 use std::str;
 use sgx_types::sgx_status_t;
-use vfs_common::{MAIN_PATH, SGX_VFS};
-use crate::{verify::verify_then_update};
+use vfs_common::{set_page_size, MAIN_PATH, SGX_VFS};
+use crate::{verify::{get_origin_root, verify_then_update}};
 use rusqlite::{Connection, OpenFlags};
 use alloc::vec::Vec;
 
 pub mod vfs;
 pub mod verify;
+pub mod sealing;
 
 #[no_mangle]
 pub extern "C" fn ecall_exec(stmt_ptr: *const u8, len: usize) -> sgx_status_t {
     let bytes = unsafe { slice::from_raw_parts(stmt_ptr, len) };
-    let stmts =
-        postcard::from_bytes::<Vec<String>>(&bytes).expect("failed to cast bytes to Vec<String>");
+    let stmts = match postcard::from_bytes::<Vec<String>>(&bytes) {
+        Ok(stmts) => stmts,
+        Err(e) => {
+            println!("failed to cast bytes to Vec<String>, reason: {:?}", e);
+            return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+        }
+    };
+
+    // a wrong page size only yields pages that fail verification against the root
+    match get_origin_root() {
+        Ok((_, _, page_size, _)) => {
+            if !set_page_size(page_size as usize) {
+                return sgx_status_t::SGX_ERROR_INVALID_PARAMETER;
+            }
+        }
+        Err(_) => return sgx_status_t::SGX_ERROR_UNEXPECTED,
+    }
 
     if exec_stmt_in_batch(&stmts) == 0 {
         sgx_status_t::SGX_SUCCESS
@@ -47,26 +63,40 @@ fn exec_stmt(stmt: &str) -> u32 {
             SGX_VFS,
     ).unwrap();
     conn.execute(stmt,[],).unwrap();
-    verify_then_update().unwrap();
+    if let Err(e) = verify_then_update() {
+        println!("verification failed: {:?}", e);
+        return 1;
+    }
     0
 }
 
+// pages come from the host, so a refused batch is reported to the caller instead of aborting
 fn exec_stmt_in_batch(stmts: &Vec<String>) -> u32 {
+    if let Err(e) = run_batch(stmts) {
+        println!("failed to execute the batch: {:?}", e);
+        return 1;
+    }
+    if let Err(e) = verify_then_update() {
+        println!("verification failed: {:?}", e);
+        return 1;
+    }
+    0
+}
+
+fn run_batch(stmts: &Vec<String>) -> rusqlite::Result<()> {
     let mut conn = Connection::open_with_flags_and_vfs(
         MAIN_PATH,
         OpenFlags::SQLITE_OPEN_READ_WRITE
             | OpenFlags::SQLITE_OPEN_CREATE
             | OpenFlags::SQLITE_OPEN_NO_MUTEX,
             SGX_VFS,
-    ).unwrap();
+    )?;
 
-    let tx = conn.transaction().unwrap();
+    let tx = conn.transaction()?;
     for stmt in stmts {
-        tx.execute(&stmt, []).unwrap();
+        tx.execute(&stmt, [])?;
     }
-    tx.commit().unwrap();
-    verify_then_update().unwrap();
-    0
+    tx.commit()
 }
 
 
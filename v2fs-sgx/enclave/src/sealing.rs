@@ -0,0 +1,298 @@
+use anyhow::Result;
+use sgx_tseal::SgxSealedData;
+use sgx_types::{sgx_sealed_data_t, sgx_status_t};
+use update_verifier::{SealingBackend, TrustedState};
+use alloc::vec::Vec;
+
+// upper bound of a sealed copy the enclave reads back: sealed data header, the counter uuid and
+// the encoded state with its counter value take a few hundred bytes
+const MAX_SEALED_LEN: usize = 1 << 16;
+
+// sgx_mc_uuid_t, a 3-byte counter id followed by a 13-byte nonce; the pse api was dropped from
+// sgx_types along with sdk 2.8, so it is declared here and needs a platform that still has it
+#[cfg(feature = "pse")]
+type McUuid = [u8; 16];
+
+#[cfg(feature = "pse")]
+extern "C" {
+    fn sgx_create_pse_session() -> sgx_status_t;
+
+    fn sgx_close_pse_session() -> sgx_status_t;
+
+    fn sgx_create_monotonic_counter(
+        counter_uuid: *mut McUuid,
+        counter_value: *mut u32,
+    ) -> sgx_status_t;
+
+    fn sgx_increment_monotonic_counter(
+        counter_uuid: *const McUuid,
+        counter_value: *mut u32,
+    ) -> sgx_status_t;
+
+    fn sgx_read_monotonic_counter(
+        counter_uuid: *const McUuid,
+        counter_value: *mut u32,
+    ) -> sgx_status_t;
+
+    fn ocall_save_counter_uuid(
+        retval: *mut i32,
+        ptr: *const u8,
+        len: usize,
+    ) -> sgx_status_t;
+
+    fn ocall_load_counter_uuid(
+        retval: *mut i32,
+        ptr: *mut u8,
+        len: usize,
+        found: *mut i32,
+    ) -> sgx_status_t;
+}
+
+extern "C" {
+    fn ocall_save_sealed_state(
+        retval: *mut i32,
+        ptr: *const u8,
+        len: usize,
+    ) -> sgx_status_t;
+
+    fn ocall_load_sealed_state_with_len(
+        retval: *mut i32,
+        ptr: *mut u8,
+        predicated_len: usize,
+        real_len: *mut usize,
+    ) -> sgx_status_t;
+}
+
+// with the `pse` feature, seals the trusted state along with the value of a monotonic counter
+// bumped on every seal, an older sealed copy the host hands back carries an older value and is
+// refused. The uuid of the counter is also saved on its own, so once a state was sealed a missing
+// copy is refused too. Without it the state is only sealed, which keeps it from being forged but
+// not from being replaced by an older sealed copy
+pub struct EnclaveSealing {
+    // created on the first seal, its uuid is the additional text of every sealed copy
+    #[cfg(feature = "pse")]
+    counter: Option<McUuid>,
+}
+
+impl EnclaveSealing {
+    #[cfg(feature = "pse")]
+    pub fn new() -> Self {
+        Self { counter: None }
+    }
+
+    #[cfg(not(feature = "pse"))]
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+// runs `f` in a pse session, the counters are only reachable through one
+#[cfg(feature = "pse")]
+fn with_pse_session<T>(f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let sgx_ret = unsafe { sgx_create_pse_session() };
+    if sgx_ret != sgx_status_t::SGX_SUCCESS {
+        bail!("failed to create pse session: {:?}", sgx_ret);
+    }
+    let res = f();
+    unsafe { sgx_close_pse_session() };
+    res
+}
+
+#[cfg(feature = "pse")]
+impl SealingBackend for EnclaveSealing {
+    fn seal(&mut self, state: &TrustedState) -> Result<()> {
+        // the counter moves first, a crash before the sealed copy is saved leaves no copy that
+        // unseals rather than one that can be rolled back to
+        let mut value = 0;
+        let uuid = with_pse_session(|| {
+            let (uuid, sgx_ret) = match self.counter {
+                Some(uuid) => (uuid, unsafe {
+                    sgx_increment_monotonic_counter(&uuid, &mut value as *mut u32)
+                }),
+                None => {
+                    let mut uuid = McUuid::default();
+                    let sgx_ret = unsafe {
+                        sgx_create_monotonic_counter(&mut uuid, &mut value as *mut u32)
+                    };
+                    (uuid, sgx_ret)
+                }
+            };
+            if sgx_ret != sgx_status_t::SGX_SUCCESS {
+                bail!("failed to bump monotonic counter: {:?}", sgx_ret);
+            }
+            Ok(uuid)
+        })?;
+        if self.counter.is_none() {
+            save_counter_uuid(&uuid)?;
+        }
+        self.counter = Some(uuid);
+
+        let bytes = match postcard::to_allocvec(&(state, value)) {
+            Ok(buf) => buf,
+            Err(e) => bail!("failed to cast trusted state to bytes, reason: {:?}", e),
+        };
+        save_sealed_state(&uuid, &bytes)
+    }
+
+    fn unseal(&mut self) -> Result<Option<TrustedState>> {
+        let saved_uuid = load_counter_uuid()?;
+        let buf = load_sealed_state()?;
+        match (buf.is_empty(), saved_uuid) {
+            (true, None) => return Ok(None),
+            (true, Some(_)) => bail!("the counter is set but the sealed state is gone"),
+            (false, None) => bail!("the sealed state has no counter"),
+            (false, Some(_)) => {}
+        }
+        let (additional, decrypted) = unseal_bytes(buf)?;
+        let mut uuid = McUuid::default();
+        if additional.len() != uuid.len() {
+            bail!("the sealed state does not name a monotonic counter");
+        }
+        uuid.copy_from_slice(&additional);
+        if Some(uuid) != saved_uuid {
+            bail!("the sealed state names another monotonic counter");
+        }
+        let (state, sealed_value) = match postcard::from_bytes::<(TrustedState, u32)>(&decrypted) {
+            Ok(s) => s,
+            Err(e) => bail!("failed to cast bytes to trusted state, reason: {:?}", e),
+        };
+
+        let mut value = 0;
+        with_pse_session(|| {
+            let sgx_ret = unsafe { sgx_read_monotonic_counter(&uuid, &mut value as *mut u32) };
+            if sgx_ret != sgx_status_t::SGX_SUCCESS {
+                bail!("failed to read monotonic counter: {:?}", sgx_ret);
+            }
+            Ok(())
+        })?;
+        if value != sealed_value {
+            bail!(
+                "the sealed state is stale, sealed at counter {} while the counter is at {}",
+                sealed_value,
+                value
+            );
+        }
+        self.counter = Some(uuid);
+        Ok(Some(state))
+    }
+}
+
+#[cfg(not(feature = "pse"))]
+impl SealingBackend for EnclaveSealing {
+    fn seal(&mut self, state: &TrustedState) -> Result<()> {
+        let bytes = match postcard::to_allocvec(state) {
+            Ok(buf) => buf,
+            Err(e) => bail!("failed to cast trusted state to bytes, reason: {:?}", e),
+        };
+        save_sealed_state(&[], &bytes)
+    }
+
+    fn unseal(&mut self) -> Result<Option<TrustedState>> {
+        let buf = load_sealed_state()?;
+        if buf.is_empty() {
+            return Ok(None);
+        }
+        let (_, decrypted) = unseal_bytes(buf)?;
+        match postcard::from_bytes::<TrustedState>(&decrypted) {
+            Ok(state) => Ok(Some(state)),
+            Err(e) => bail!("failed to cast bytes to trusted state, reason: {:?}", e),
+        }
+    }
+}
+
+// `bytes` sealed to the enclave with `additional` as plain authenticated text, kept by the host
+fn save_sealed_state(additional: &[u8], bytes: &[u8]) -> Result<()> {
+    let sealed = match SgxSealedData::<[u8]>::seal_data(additional, bytes) {
+        Ok(sealed) => sealed,
+        Err(e) => bail!("failed to seal trusted state: {:?}", e),
+    };
+    let len = SgxSealedData::<[u8]>::calc_raw_sealed_data_size(additional.len() as u32, bytes.len() as u32);
+    let mut buf = vec![0_u8; len as usize];
+    let raw = unsafe { sealed.to_raw_sealed_data_t(buf.as_mut_ptr() as *mut sgx_sealed_data_t, len) };
+    if raw.is_none() {
+        bail!("failed to cast sealed trusted state to bytes");
+    }
+
+    let mut retval: i32 = 0;
+    let sgx_ret = unsafe {
+        ocall_save_sealed_state(&mut retval as *mut _, buf.as_ptr(), buf.len())
+    };
+    if sgx_ret != sgx_status_t::SGX_SUCCESS || retval != 0 {
+        bail!("sgx_err happened in ocall_save_sealed_state");
+    }
+    Ok(())
+}
+
+// (additional text, decrypted text) of a sealed copy from the host
+fn unseal_bytes(mut buf: Vec<u8>) -> Result<(Vec<u8>, Vec<u8>)> {
+    let sealed = unsafe {
+        SgxSealedData::<[u8]>::from_raw_sealed_data_t(buf.as_mut_ptr() as *mut sgx_sealed_data_t, buf.len() as u32)
+    };
+    let sealed = match sealed {
+        Some(sealed) => sealed,
+        None => bail!("the host sent a malformed sealed state"),
+    };
+    let unsealed = match sealed.unseal_data() {
+        Ok(unsealed) => unsealed,
+        Err(e) => bail!("failed to unseal trusted state: {:?}", e),
+    };
+    Ok((unsealed.get_additional_txt().to_vec(), unsealed.get_decrypt_txt().to_vec()))
+}
+
+#[cfg(feature = "pse")]
+fn save_counter_uuid(uuid: &McUuid) -> Result<()> {
+    let mut retval: i32 = 0;
+    let sgx_ret = unsafe {
+        ocall_save_counter_uuid(&mut retval as *mut _, uuid.as_ptr(), uuid.len())
+    };
+    if sgx_ret != sgx_status_t::SGX_SUCCESS || retval != 0 {
+        bail!("sgx_err happened in ocall_save_counter_uuid");
+    }
+    Ok(())
+}
+
+// the uuid of the counter the host keeps, none if no state was ever sealed
+#[cfg(feature = "pse")]
+fn load_counter_uuid() -> Result<Option<McUuid>> {
+    let mut retval: i32 = 0;
+    let mut found: i32 = 0;
+    let mut uuid = McUuid::default();
+    let sgx_ret = unsafe {
+        ocall_load_counter_uuid(&mut retval as *mut _, uuid.as_mut_ptr(), uuid.len(), &mut found as *mut _)
+    };
+    if sgx_ret != sgx_status_t::SGX_SUCCESS || retval != 0 {
+        bail!("sgx_err happened in ocall_load_counter_uuid");
+    }
+    Ok(if found != 0 { Some(uuid) } else { None })
+}
+
+// the sealed copy the host keeps, empty if it has none
+fn load_sealed_state() -> Result<Vec<u8>> {
+    let mut predicated_len = 1024;
+    loop {
+        let mut retval = 0;
+        let mut real_len = 0;
+        let mut buf = vec![0_u8; predicated_len];
+        let sgx_ret = unsafe {
+            ocall_load_sealed_state_with_len(&mut retval as *mut _, buf.as_mut_ptr(), predicated_len, &mut real_len as *mut usize)
+        };
+        if sgx_ret != sgx_status_t::SGX_SUCCESS {
+            bail!("sgx_err happened in ocall_load_sealed_state_with_len: {:?}", sgx_ret);
+        }
+        if retval == 2 && real_len > predicated_len {
+            if real_len > MAX_SEALED_LEN {
+                bail!("the host sent a sealed state of {} bytes", real_len);
+            }
+            predicated_len = real_len;
+            continue;
+        }
+        if real_len > predicated_len {
+            bail!("the host claims {} bytes of sealed state in a buffer of {}", real_len, predicated_len);
+        }
+        if retval != 0 {
+            bail!("the host failed to read the sealed state");
+        }
+        buf.truncate(real_len);
+        return Ok(buf);
+    }
+}
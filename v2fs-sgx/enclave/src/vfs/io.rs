@@ -1,7 +1,7 @@
 use std::ffi::{c_int, c_void};
 use std::string::String;
 use super::server_vfs::{ServerFileState, CachePage};
-use vfs_common::{MAIN_PATH, PAGE_SIZE, page::{PageId}, UPDATE_OPT_LEVEL};
+use vfs_common::{MAIN_PATH, page_size, page::{PageId}, UPDATE_OPT_LEVEL};
 use anyhow::{Context, Result};
 use libsqlite3_sys as ffi;
 use sgx_types::sgx_status_t;
@@ -134,7 +134,7 @@ fn collect_page_bytes_base(
         let mut bytes = read_page(f_name_buf, p_id);
         if write_map.get(p_id).is_none() {
             if read_map.get(p_id).is_none() {
-                let cache_p = CachePage::new(0, PAGE_SIZE, bytes.clone());
+                let cache_p = CachePage::new(0, page_size(), bytes.clone());
                 read_map.insert(*p_id, cache_p);
             }
         }
@@ -152,7 +152,7 @@ fn collect_page_bytes_batch(
     let mut res = Vec::<u8>::new();
     for p_id in p_ids {
         if let Some(w_cache_p) = write_map.get(p_id) {
-            if w_cache_p.get_len() == PAGE_SIZE {
+            if w_cache_p.get_len() == page_size() {
                 res.append(&mut w_cache_p.copy_bytes());
             } else {
                 let offset = w_cache_p.get_offset();
@@ -179,7 +179,7 @@ fn collect_page_bytes_batch(
                     res.append(&mut buf);
                 } else {
                     let bytes = read_page(f_name_buf, p_id);
-                    let new_cache_p = CachePage::new(0, PAGE_SIZE, bytes.clone());
+                    let new_cache_p = CachePage::new(0, page_size(), bytes.clone());
                     read_map.insert(*p_id, new_cache_p);
                     let mut cursor = Cursor::new(bytes);
                     match cursor.seek(SeekFrom::Start(offset as u64)) {
@@ -204,7 +204,7 @@ fn collect_page_bytes_batch(
             res.append(&mut cache_p.copy_bytes());
         } else {
             let mut bytes = read_page(f_name_buf, p_id);
-            let cache_p = CachePage::new(0, PAGE_SIZE, bytes.clone());
+            let cache_p = CachePage::new(0, page_size(), bytes.clone());
             read_map.insert(*p_id, cache_p);
             res.append(&mut bytes);
         }
@@ -215,12 +215,12 @@ fn collect_page_bytes_batch(
 
 
 fn read_page(f_name_buf: &[u8], p_id: &PageId) -> Vec<u8> {
-    let mut bytes = vec![0 as u8; PAGE_SIZE];
+    let mut bytes = vec![0 as u8; page_size()];
     let mut retval: i32 = 0;
-    let ofset = p_id.get_id() as u64 * PAGE_SIZE as u64;
+    let ofset = p_id.get_id() as u64 * page_size() as u64;
     let sgx_ret =
         unsafe {
-            ocall_file_read(&mut retval as *mut _, f_name_buf.as_ptr(), f_name_buf.len(), ofset, bytes.as_mut_ptr(), PAGE_SIZE)
+            ocall_file_read(&mut retval as *mut _, f_name_buf.as_ptr(), f_name_buf.len(), ofset, bytes.as_mut_ptr(), page_size())
         };
     if retval != 0 && retval != 522 { // ffi::SQLITE_IOERR_SHORT_READ
         println!("vfs_err happened, err code: {}", retval);
@@ -286,7 +286,7 @@ fn do_write_base(
     input_data: &[u8],
 ) -> c_int {
     let mut retval: i32 = 0;
-    let buf_len: usize = PAGE_SIZE * p_ids.len();
+    let buf_len: usize = page_size() * p_ids.len();
     let mut buf = vec![0_u8; buf_len];
     let sgx_ret = 
         unsafe {
@@ -316,8 +316,8 @@ fn do_write_base(
     }
     let mut buf = cursor.into_inner();
     for p_id in p_ids {
-        let bytes = buf.split_off(PAGE_SIZE);
-        let cache_p = CachePage::new(0, PAGE_SIZE, buf);
+        let bytes = buf.split_off(page_size());
+        let cache_p = CachePage::new(0, page_size(), buf);
         buf = bytes;
         write_map.insert(p_id, cache_p);
     }
@@ -355,7 +355,7 @@ fn do_write_batch(
             cache_p.to_bytes()
         } else {
             meta_data.insert(*p_id, (0, 0));
-            vec![0_u8; PAGE_SIZE]
+            vec![0_u8; page_size()]
         };
         total_bytes.append(&mut bytes);
     }
@@ -379,9 +379,9 @@ fn do_write_batch(
     let mut ofst = (i_ofst - start_point) as usize;
     let mut total_len = i_amt;
     for p_id in &p_ids {
-        let bytes = buf.split_off(PAGE_SIZE);
-        let len = if total_len + ofst > PAGE_SIZE {
-            PAGE_SIZE - ofst
+        let bytes = buf.split_off(page_size());
+        let len = if total_len + ofst > page_size() {
+            page_size() - ofst
         } else {
             total_len
         };
@@ -415,10 +415,11 @@ fn do_write_batch(
 }
 
 fn compute_page_ids(ofst: u64, len: u64) -> (u64, Vec<PageId>) {
-    let start_page = ofst / PAGE_SIZE as u64;
-    let start_point = start_page * PAGE_SIZE as u64;
-    // let end_page = (ofst + len) / PAGE_SIZE as u64; 
-    let end_page = (ofst + len - 1) / PAGE_SIZE as u64; // ATTENTION PLEASE
+    let page_size = page_size() as u64;
+    let start_page = ofst / page_size;
+    let start_point = start_page * page_size;
+    // let end_page = (ofst + len) / page_size; 
+    let end_page = (ofst + len - 1) / page_size; // ATTENTION PLEASE
     let mut res = Vec::new();
     for i in start_page..(end_page + 1) {
         res.push(PageId(i as u32));
@@ -445,6 +446,19 @@ pub unsafe extern "C" fn s_truncate(
     if sgx_ret != sgx_status_t::SGX_SUCCESS {
         println!("sgx_err happened");
     }
+    if retval == 0 && f_name.eq(MAIN_PATH) {
+        // pages past the new end are not written back, the tree is cut at verification
+        let file_state = (p_file as *mut ServerFileState)
+            .as_mut()
+            .expect("null pointer");
+        let page_cnt = ((size as u64 + page_size() as u64 - 1) / page_size() as u64) as u32;
+        file_state.write_map.retain(|p_id, _| p_id.get_id() < page_cnt);
+        let truncated = match *file_state.truncated {
+            Some(n) if n < page_cnt => n,
+            _ => page_cnt,
+        };
+        *file_state.truncated = Some(truncated);
+    }
     retval
 }
 
@@ -11,7 +11,8 @@ use std::{
 };
 use alloc::{boxed::Box, vec::Vec};
 use sgx_types::sgx_status_t;
-use vfs_common::{page::PageId, MAX_PATH_LENGTH, TMP_FILE_PATH, MERKLE_PATH, SGX_VFS, digest::{Digest, Digestible}};
+use vfs_common::{page::PageId, MAX_PATH_LENGTH, TMP_FILE_PATH, MERKLE_PATH, SGX_VFS};
+pub use update_verifier::CachePage;
 
 use super::io;
 
@@ -25,54 +26,13 @@ extern "C" {
     fn ocall_fill_rand_bytes(retval: *mut i32, dest: *mut i8, len: usize) -> sgx_status_t;
 }
 
-#[derive(Debug)]
-pub struct CachePage {
-    offset: usize,
-    len: usize,
-    bytes: Vec<u8>,
-}
-
-impl CachePage {
-    pub fn new(offset: usize, len: usize, bytes: Vec<u8>) -> Self {
-        Self {
-            offset,
-            len,
-            bytes,
-        }
-    }
-
-    pub fn get_bytes(&self) -> &Vec<u8> {
-        &self.bytes
-    }
-    
-    pub fn copy_bytes(&self) -> Vec<u8> {
-        self.bytes.clone()
-    }
-
-    pub fn to_bytes(self) -> Vec<u8> {
-        self.bytes
-    }
-
-    pub fn get_offset(&self) -> usize {
-        self.offset
-    }
-
-    pub fn get_len(&self) -> usize {
-        self.len
-    }
-}
-
-impl Digestible for CachePage {
-    fn to_digest(&self) -> Digest {
-        self.bytes.to_digest()
-    }
-}
-
 #[derive(Debug)]
 pub struct ServerVfs {
     merkle_db_path: String,
     pub read_map: HashMap<PageId, CachePage>,
     pub write_map: HashMap<PageId, CachePage>,
+    // pages left in the main file when the transaction shrank it
+    pub truncated: Option<u32>,
 }
 
 impl ServerVfs {
@@ -85,6 +45,7 @@ impl ServerVfs {
             merkle_db_path,
             read_map,
             write_map,
+            truncated: None,
         }
     }
 
@@ -234,6 +195,7 @@ pub(crate) struct ServerFileState<'a> {
     pub(crate) merkle_db_path: String,
     pub(crate) read_map: &'a mut HashMap<PageId, CachePage>,
     pub(crate) write_map: &'a mut HashMap<PageId, CachePage>,
+    pub(crate) truncated: &'a mut Option<u32>,
 }
 
 
@@ -286,6 +248,7 @@ pub unsafe extern "C" fn s_open(
     file_state.file = Some(s_file);
     file_state.read_map = &mut s_vfs.read_map;
     file_state.write_map = &mut s_vfs.write_map;
+    file_state.truncated = &mut s_vfs.truncated;
 
     ffi::SQLITE_OK
 }
@@ -85,6 +85,7 @@ pub trait ReadInterface {
 
 pub trait WriteInterface {
     fn write_node(&mut self, addr: &Digest, node: &MerkleNode) -> Result<()>;
+    fn remove_node(&mut self, addr: &Digest) -> Result<()>;
 }
 
 pub trait MerkleNodeLoader {
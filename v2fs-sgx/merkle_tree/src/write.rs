@@ -3,7 +3,7 @@ use crate::{
     storage::{MerkleNode, MerkleNodeLoader, NodeId},
 };
 use anyhow::Result;
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use vfs_common::{
     digest::{Digest, Digestible},
     page::PageId,
@@ -12,6 +12,8 @@ use vfs_common::{
 pub struct Apply {
     pub root_id: Option<NodeId>,
     pub nodes: HashMap<Digest, MerkleNode>,
+    // nodes of pages cut off by a truncation, to be deleted from the store
+    pub removed: HashSet<Digest>,
 }
 
 impl Apply {
@@ -36,6 +38,7 @@ impl<'a, L: MerkleNodeLoader> WriteContext<'a, L> {
             apply: Apply {
                 root_id,
                 nodes: HashMap::new(),
+                removed: HashSet::new(),
             },
         }
     }
@@ -58,14 +61,90 @@ impl<'a, L: MerkleNodeLoader> WriteContext<'a, L> {
     }
 
     fn get_node(&self, id: &NodeId) -> Result<Option<MerkleNode>> {
-        Ok(match self.apply.nodes.get(&id.to_digest()) {
+        let addr = id.to_digest();
+        if self.apply.removed.contains(&addr) {
+            return Ok(None);
+        }
+        Ok(match self.apply.nodes.get(&addr) {
             Some(n) => Some(n.clone()),
             None => self.node_loader.load_node(id)?,
         })
     }
 
     fn write_node(&mut self, id: NodeId, n: MerkleNode) {
-        self.apply.nodes.insert(id.to_digest(), n);
+        let addr = id.to_digest();
+        self.apply.removed.remove(&addr);
+        self.apply.nodes.insert(addr, n);
+    }
+
+    fn remove_node(&mut self, id: NodeId) {
+        let addr = id.to_digest();
+        self.apply.nodes.remove(&addr);
+        self.apply.removed.insert(addr);
+    }
+
+    // number of pages in the tree, the last leaf is found by following the right spine
+    pub fn page_cnt(&self) -> Result<u32> {
+        let mut id = match self.apply.root_id {
+            Some(id) => id,
+            None => return Ok(0),
+        };
+        while !id.is_leaf() {
+            let (l_id, r_id) = id.get_children()?;
+            id = if self.get_node(&r_id)?.is_some() { r_id } else { l_id };
+        }
+        Ok(id.get_width() + 1)
+    }
+
+    // drop the leaves of pages from `page_cnt` on, rebuild the path of the new last page and
+    // lower the root to its height
+    pub fn truncate(&mut self, page_cnt: u32) -> Result<()> {
+        let old_cnt = self.page_cnt()?;
+        if page_cnt >= old_cnt {
+            return Ok(());
+        }
+        let height = self.get_height();
+        let last = page_cnt.checked_sub(1);
+        let new_height = match last {
+            Some(l) => find_height(PageId(l)),
+            None => 0,
+        };
+        for h in 0..=height {
+            // nodes above the new root go as well as those covering only cut off pages
+            let first = match last {
+                Some(l) if h <= new_height => (l >> h) + 1,
+                _ => 0,
+            };
+            for w in first..=((old_cnt - 1) >> h) {
+                self.remove_node(NodeId::new(h, w));
+            }
+        }
+        let last = match last {
+            Some(l) => l,
+            None => {
+                self.apply.root_id = None;
+                return Ok(());
+            }
+        };
+
+        let mut cur_id = NodeId::from_page_id(PageId(last));
+        let mut cur_hash = match self.get_node(&cur_id)? {
+            Some(n) => n.get_hash(),
+            None => bail!("Cannot find the last leaf"),
+        };
+        while cur_id.get_height() < new_height {
+            // the right sibling of a node on this path lies past the last page
+            cur_hash = if cur_id.is_even() {
+                nonleaf_hash(Some(cur_hash), None)
+            } else {
+                let sib_hash = self.get_node(&cur_id.get_sib_id())?.map(|n| n.get_hash());
+                nonleaf_hash(sib_hash, Some(cur_hash))
+            };
+            cur_id = cur_id.get_parent_id();
+            self.write_node(cur_id, MerkleNode::new(cur_hash));
+        }
+        self.set_root_id(cur_id);
+        Ok(())
     }
 
     pub fn update(&mut self, p_hash: Digest, p_id: PageId) -> Result<()> {
@@ -141,3 +220,107 @@ fn find_height(p_id: PageId) -> u32 {
     }
     height
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::ReadInterface;
+
+    // a store holding the nodes of every applied change
+    #[derive(Default)]
+    struct MemStore {
+        nodes: HashMap<Digest, MerkleNode>,
+        root_id: Option<NodeId>,
+    }
+
+    impl ReadInterface for MemStore {
+        fn get_node(&self, addr: &Digest) -> Result<Option<MerkleNode>> {
+            Ok(self.nodes.get(addr).cloned())
+        }
+    }
+
+    impl MemStore {
+        fn write(&mut self, f: impl FnOnce(&mut WriteContext<MemStore>)) {
+            let mut ctx = WriteContext::new(&*self, self.root_id);
+            f(&mut ctx);
+            let changes = ctx.changes();
+            for addr in changes.removed {
+                self.nodes.remove(&addr);
+            }
+            self.nodes.extend(changes.nodes);
+            self.root_id = changes.root_id;
+        }
+
+        fn page_cnt(&self) -> u32 {
+            WriteContext::new(self, self.root_id).page_cnt().unwrap()
+        }
+
+        // hash of every node, to compare with a tree built from scratch
+        fn hashes(&self) -> HashMap<Digest, Digest> {
+            self.nodes.iter().map(|(a, n)| (*a, n.get_hash())).collect()
+        }
+    }
+
+    fn page_hash(p_id: u32) -> Digest {
+        [p_id as u8; 8][..].to_digest()
+    }
+
+    fn write_pages(store: &mut MemStore, pages: core::ops::Range<u32>) {
+        store.write(|ctx| {
+            for p in pages {
+                ctx.update(page_hash(p), PageId(p)).unwrap();
+            }
+        });
+    }
+
+    fn tree(page_cnt: u32) -> MemStore {
+        let mut store = MemStore::default();
+        write_pages(&mut store, 0..page_cnt);
+        store
+    }
+
+    #[test]
+    fn test_truncate_to_zero() {
+        let mut store = tree(5);
+        assert_eq!(store.page_cnt(), 5);
+        store.write(|ctx| ctx.truncate(0).unwrap());
+        assert_eq!(store.root_id, None);
+        assert!(store.nodes.is_empty());
+        assert_eq!(store.page_cnt(), 0);
+    }
+
+    #[test]
+    fn test_truncate_across_levels() {
+        // 9 pages take 4 levels above the leaves, 4 pages only 2
+        let mut store = tree(9);
+        assert_eq!(store.root_id, Some(NodeId::new(4, 0)));
+        store.write(|ctx| ctx.truncate(4).unwrap());
+        let expected = tree(4);
+        assert_eq!(store.root_id, Some(NodeId::new(2, 0)));
+        assert_eq!(store.root_id, expected.root_id);
+        assert_eq!(store.hashes(), expected.hashes());
+        assert_eq!(store.page_cnt(), 4);
+
+        // a cut that keeps an odd last page leaves its right sibling out
+        let mut store = tree(9);
+        store.write(|ctx| ctx.truncate(5).unwrap());
+        assert_eq!(store.hashes(), tree(5).hashes());
+        assert_eq!(store.page_cnt(), 5);
+
+        // growing back is a no-op for truncate
+        store.write(|ctx| ctx.truncate(7).unwrap());
+        assert_eq!(store.page_cnt(), 5);
+    }
+
+    #[test]
+    fn test_truncate_then_regrow() {
+        let mut store = tree(9);
+        store.write(|ctx| ctx.truncate(3).unwrap());
+        assert_eq!(store.page_cnt(), 3);
+        write_pages(&mut store, 3..11);
+        let expected = tree(11);
+        assert_eq!(store.root_id, expected.root_id);
+        assert_eq!(store.hashes(), expected.hashes());
+        assert_eq!(store.page_cnt(), 11);
+    }
+}
@@ -0,0 +1,44 @@
+use alloc::vec::Vec;
+use anyhow::Result;
+use merkle_tree::{
+    proof::Proof,
+    storage::{MerkleNode, NodeId},
+};
+use vfs_common::{digest::Digest, page::PageId, vbf::VersionBloomFilter};
+
+// (root id, root hash, page size, vbf digest) recorded by the host
+pub type RootInfo = (Option<NodeId>, Digest, u32, Option<Digest>);
+
+// (root id, root hash) of a version of the tree
+pub type Root = (Option<NodeId>, Digest);
+
+// what the verifier needs from the untrusted side, the enclave forwards every call as an ocall;
+// nothing returned here is trusted before it is checked against the root
+pub trait UntrustedHost {
+    fn get_root(&self) -> Result<RootInfo>;
+
+    fn get_node(&self, id: NodeId) -> Result<Option<MerkleNode>>;
+
+    // none for the nodes missing from the host's tree
+    fn get_nodes(&self, ids: &[NodeId]) -> Result<Vec<(NodeId, Option<MerkleNode>)>>;
+
+    // proof of the pages under `root_id`, the root the enclave got from `get_root`
+    fn get_read_proof(&self, p_ids: &[PageId], root_id: NodeId) -> Result<Proof>;
+
+    fn read_pages(&self, p_ids: &[PageId]) -> Result<Vec<(PageId, Vec<u8>)>>;
+
+    fn write_pages(&mut self, pages: &[(PageId, Vec<u8>)]) -> Result<()>;
+
+    // the host applies the same page hashes and truncation to its tree and reports the root it
+    // ends up with
+    fn update_merkle_db(
+        &mut self,
+        modif: &[(PageId, Digest)],
+        page_cnt: Option<u32>,
+    ) -> Result<Root>;
+
+    fn get_vbf(&self) -> Result<VersionBloomFilter>;
+
+    // the host keeps the filter for the next update and publishes its digest with the root
+    fn update_vbf(&mut self, vbf: &VersionBloomFilter) -> Result<()>;
+}
@@ -0,0 +1,17 @@
+#![no_std]
+
+#[macro_use]
+extern crate alloc;
+
+#[macro_use]
+extern crate anyhow;
+
+pub mod host;
+pub mod page;
+pub mod trusted;
+pub mod verify;
+
+pub use host::{Root, RootInfo, UntrustedHost};
+pub use page::CachePage;
+pub use trusted::{SealingBackend, TrustedRoot, TrustedState};
+pub use verify::verify_then_update;
@@ -0,0 +1,42 @@
+use alloc::vec::Vec;
+use vfs_common::digest::{Digest, Digestible};
+
+// a page buffered by the server vfs, `offset` and `len` span the bytes written to it
+#[derive(Debug)]
+pub struct CachePage {
+    offset: usize,
+    len: usize,
+    bytes: Vec<u8>,
+}
+
+impl CachePage {
+    pub fn new(offset: usize, len: usize, bytes: Vec<u8>) -> Self {
+        Self { offset, len, bytes }
+    }
+
+    pub fn get_bytes(&self) -> &Vec<u8> {
+        &self.bytes
+    }
+
+    pub fn copy_bytes(&self) -> Vec<u8> {
+        self.bytes.clone()
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn get_offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn get_len(&self) -> usize {
+        self.len
+    }
+}
+
+impl Digestible for CachePage {
+    fn to_digest(&self) -> Digest {
+        self.bytes.to_digest()
+    }
+}
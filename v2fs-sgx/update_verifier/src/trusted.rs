@@ -0,0 +1,88 @@
+use crate::host::{Root, UntrustedHost};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use vfs_common::digest::Digest;
+
+// the root the last endorsed batch ended at, the digest of the vbf published with it and the
+// number of batches endorsed up to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TrustedState {
+    pub root: Root,
+    pub vbf: Option<Digest>,
+    pub version: u64,
+}
+
+// keeps the trusted state across restarts of the updater; a backend only unseals the state it
+// sealed last, so the host cannot hand back an older copy to roll the database back
+pub trait SealingBackend {
+    fn seal(&mut self, state: &TrustedState) -> Result<()>;
+
+    // none if no state was sealed yet
+    fn unseal(&mut self) -> Result<Option<TrustedState>>;
+}
+
+pub struct TrustedRoot<S: SealingBackend> {
+    backend: S,
+    state: Option<TrustedState>,
+}
+
+impl<S: SealingBackend> TrustedRoot<S> {
+    // before the first endorsed batch the root of the host is taken as it is
+    pub fn load(mut backend: S) -> Result<Self> {
+        let state = backend.unseal()?;
+        Ok(Self { backend, state })
+    }
+
+    // as `load`, but with no sealed state the host must be as new as the backend: a tree or a
+    // published vbf means a batch was endorsed once and the host dropped its sealed state
+    pub fn open<H: UntrustedHost>(backend: S, host: &H) -> Result<Self> {
+        let trusted = Self::load(backend)?;
+        if trusted.state.is_none() {
+            let (root_id, _, _, vbf) = host.get_root()?;
+            if root_id.is_some() || vbf.is_some() {
+                bail!("the host has a tree but no sealed state, refusing to trust its root");
+            }
+        }
+        Ok(trusted)
+    }
+
+    pub fn state(&self) -> Option<&TrustedState> {
+        self.state.as_ref()
+    }
+
+    pub fn check(&self, root: Root, vbf: Option<Digest>) -> Result<()> {
+        if let Some(s) = &self.state {
+            if s.root != root {
+                bail!(
+                    "the host reports root {:?}, the last endorsed one is {:?} at version {}",
+                    root,
+                    s.root,
+                    s.version
+                );
+            }
+            // an older filter would hand clients versions their caches are already past
+            if s.vbf != vbf {
+                bail!(
+                    "the host reports vbf {:?}, the last endorsed one is {:?} at version {}",
+                    vbf,
+                    s.vbf,
+                    s.version
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // sealed before it replaces the state in memory, a batch whose state failed to seal is not
+    // endorsed
+    pub fn endorse(&mut self, root: Root, vbf: Option<Digest>) -> Result<TrustedState> {
+        let state = TrustedState {
+            root,
+            vbf,
+            version: self.state.map_or(0, |s| s.version) + 1,
+        };
+        self.backend.seal(&state)?;
+        self.state = Some(state);
+        Ok(state)
+    }
+}
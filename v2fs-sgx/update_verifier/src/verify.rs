@@ -0,0 +1,460 @@
+use crate::{
+    host::{Root, UntrustedHost},
+    page::CachePage,
+    trusted::{SealingBackend, TrustedRoot},
+};
+use alloc::{collections::vec_deque::VecDeque, vec::Vec};
+use anyhow::Result;
+use hashbrown::{HashMap, HashSet};
+use merkle_tree::{
+    hash::{leaf_hash, nonleaf_hash},
+    storage::NodeId,
+};
+use vfs_common::{
+    digest::{Digest, Digestible},
+    page::PageId,
+    page_size,
+    vbf::{VersionBloomFilter, VBF_HASH_NUM, VBF_MAP_SIZE},
+};
+
+// hashes of the nodes the host sent, keyed by their position in the tree
+type NodeHashes = HashMap<NodeId, Digest>;
+
+// the page count left by a truncation and the path of the new last page in the old tree
+type Shrink = Option<(u32, NodeHashes)>;
+
+/// Verify the pages read and written by a transaction against the root of the host, write the
+/// pages back through the host and return the new root. `opt_level` is one of the
+/// `UPDATE_OPT_LEVEL`s, `vbf_seed` keys the vbf if the host has not published one yet.
+///
+/// `trusted` holds the root the last endorsed batch ended at, the host must start from it. At
+/// level 0 the host must also end at the root computed here, which is then sealed as the next
+/// trusted version.
+pub fn verify_then_update<H: UntrustedHost, S: SealingBackend>(
+    host: &mut H,
+    trusted: &mut TrustedRoot<S>,
+    read_map: &mut HashMap<PageId, CachePage>,
+    write_map: &mut HashMap<PageId, CachePage>,
+    truncated: Option<u32>,
+    opt_level: u8,
+    vbf_seed: u64,
+) -> Result<Root> {
+    let res = verify_batch(
+        host, trusted, read_map, write_map, truncated, opt_level, vbf_seed,
+    );
+    // a refused batch must not leave its pages for the next one
+    read_map.clear();
+    write_map.clear();
+    res
+}
+
+fn verify_batch<H: UntrustedHost, S: SealingBackend>(
+    host: &mut H,
+    trusted: &mut TrustedRoot<S>,
+    read_map: &mut HashMap<PageId, CachePage>,
+    write_map: &mut HashMap<PageId, CachePage>,
+    truncated: Option<u32>,
+    opt_level: u8,
+    vbf_seed: u64,
+) -> Result<Root> {
+    let (root_id, root_hash, _, vbf_digest) = host.get_root()?;
+    trusted.check((root_id, root_hash), vbf_digest)?;
+    let mut vbf = load_vbf(host, vbf_digest, vbf_seed)?;
+    let written: Vec<PageId> = write_map.keys().copied().collect();
+
+    if !read_map.is_empty() {
+        if let Some(r_id) = root_id {
+            verify_read_map(host, read_map, root_hash, r_id)?;
+        }
+    }
+
+    // the path of the new last page in the old tree, the shrunk root is built from it
+    let shrink = match (truncated, root_id) {
+        (Some(n), Some(r_id)) if n > 0 => {
+            verify_spine(host, PageId(n - 1), r_id, root_hash)?.map(|spine| (n, spine))
+        }
+        _ => None,
+    };
+
+    let new_root = if written.is_empty() && shrink.is_none() {
+        (root_id, root_hash)
+    } else if opt_level == 2 {
+        verify_write_map_batch(host, read_map, write_map, (root_id, root_hash), &shrink)?
+    } else {
+        verify_write_map_base(host, write_map, (root_id, root_hash), &shrink, opt_level)?
+    };
+
+    let mut new_vbf_digest = vbf_digest;
+    if !written.is_empty() {
+        vbf.insert_batch(&written);
+        host.update_vbf(&vbf)?;
+        new_vbf_digest = Some(vbf.to_digest());
+    }
+
+    // the other levels leave the host's tree behind, there is no version to chain from; a batch
+    // that left the root and the vbf as they were needs no new version unless none was endorsed
+    // yet
+    let changed = new_root != (root_id, root_hash) || new_vbf_digest != vbf_digest;
+    if opt_level == 0 && (trusted.state().is_none() || changed) {
+        trusted.endorse(new_root, new_vbf_digest)?;
+    }
+    Ok(new_root)
+}
+
+// the host's vbf, checked against the digest published with the root; a new one if none
+// was published yet
+fn load_vbf<H: UntrustedHost>(
+    host: &H,
+    published: Option<Digest>,
+    seed: u64,
+) -> Result<VersionBloomFilter> {
+    let published = match published {
+        Some(d) => d,
+        None => {
+            return Ok(VersionBloomFilter::with_seed(
+                VBF_MAP_SIZE,
+                VBF_HASH_NUM,
+                seed,
+            ))
+        }
+    };
+    let vbf = host.get_vbf()?;
+    if vbf.to_digest() != published {
+        bail!("vbf from the host does not match the published digest");
+    }
+    Ok(vbf)
+}
+
+fn verify_read_map<H: UntrustedHost>(
+    host: &H,
+    read_map: &HashMap<PageId, CachePage>,
+    old_root_hash: Digest,
+    old_r_id: NodeId,
+) -> Result<()> {
+    let mut p_ids_to_verify = Vec::with_capacity(read_map.len());
+    let mut p_hashes = Vec::with_capacity(read_map.len());
+    for (p_id, cache_p) in read_map {
+        p_ids_to_verify.push(*p_id);
+        p_hashes.push((*p_id, cache_p.to_digest()));
+    }
+
+    let proof = host.get_read_proof(&p_ids_to_verify, old_r_id)?;
+    let computed_root_hash = proof.root_hash()?;
+    if computed_root_hash != old_root_hash {
+        bail!("verification failed, the re-constructed root hash not matched");
+    }
+
+    for (p_id, dig) in p_hashes {
+        let leaf_hash = leaf_hash(&p_id, &dig);
+        proof.verify_val(leaf_hash, p_id, old_r_id.get_height())?;
+    }
+
+    Ok(())
+}
+
+fn verify_write_map_batch<H: UntrustedHost>(
+    host: &mut H,
+    read_map: &mut HashMap<PageId, CachePage>,
+    write_map: &mut HashMap<PageId, CachePage>,
+    old_root: Root,
+    shrink: &Shrink,
+) -> Result<Root> {
+    let mut bg_complete_pages = HashMap::<PageId, Vec<u8>>::new();
+    let mut p_ids_need_read = vec![];
+
+    for (p_id, w_cache_p) in write_map.iter() {
+        if w_cache_p.get_len() != page_size() {
+            if let Some(r_cache_p) = read_map.remove(p_id) {
+                bg_complete_pages.insert(*p_id, r_cache_p.to_bytes());
+            } else {
+                p_ids_need_read.push(*p_id);
+            }
+        }
+    }
+
+    if !p_ids_need_read.is_empty() {
+        for (p_id, bytes) in host.read_pages(&p_ids_need_read)? {
+            bg_complete_pages.insert(p_id, bytes);
+        }
+    }
+
+    let mut pages_to_write = vec![];
+    let mut modif_hashes = Vec::new();
+    for (p_id, w_cache_p) in write_map.drain() {
+        let len = w_cache_p.get_len();
+        if len != page_size() {
+            // only the written span of a partial page is taken from the write map
+            let mut bytes = match bg_complete_pages.remove(&p_id) {
+                Some(b) => b,
+                None => bail!("the host did not send page {:?}", p_id),
+            };
+            let ofst = w_cache_p.get_offset();
+            if bytes.len() < ofst + len {
+                bytes.resize(ofst + len, 0);
+            }
+            bytes[ofst..(ofst + len)].copy_from_slice(&w_cache_p.get_bytes()[ofst..(ofst + len)]);
+            modif_hashes.push((p_id, bytes.to_digest()));
+            pages_to_write.push((p_id, bytes));
+        } else {
+            modif_hashes.push((p_id, w_cache_p.get_bytes().to_digest()));
+            pages_to_write.push((p_id, w_cache_p.to_bytes()));
+        }
+    }
+    host.write_pages(&pages_to_write)?;
+
+    modif_hashes.sort_by(|(p1, _), (p2, _)| p1.cmp(p2));
+    let mut proof = NodeHashes::new();
+    let mut height = 0;
+    if let (Some(r_id), root_hash) = old_root {
+        proof = verify_read_batch(host, &modif_hashes, r_id, root_hash)?;
+        height = r_id.get_height();
+    }
+    cal_new_root(&modif_hashes, &mut proof, height, shrink)
+}
+
+fn verify_write_map_base<H: UntrustedHost>(
+    host: &mut H,
+    write_map: &mut HashMap<PageId, CachePage>,
+    old_root: Root,
+    shrink: &Shrink,
+    opt_level: u8,
+) -> Result<Root> {
+    let mut modif_hashes = Vec::new();
+    for (p_id, cache_p) in write_map.drain() {
+        modif_hashes.push((p_id, cache_p.to_digest()));
+    }
+    modif_hashes.sort_by(|(p1, _), (p2, _)| p1.cmp(p2));
+    // the new root is only worth comparing with the host's if the old paths lead to the
+    // endorsed root
+    let mut proof = NodeHashes::new();
+    let mut height = 0;
+    if let (Some(r_id), root_hash) = old_root {
+        proof = verify_read_base(host, &modif_hashes, r_id, root_hash)?;
+        height = r_id.get_height();
+    }
+
+    let new_root = cal_new_root(&modif_hashes, &mut proof, height, shrink)?;
+
+    if opt_level == 0 {
+        let host_root = host.update_merkle_db(&modif_hashes, shrink.as_ref().map(|(n, _)| *n))?;
+        if host_root != new_root {
+            bail!(
+                "the host updated its tree to {:?}, the enclave computed {:?}",
+                host_root,
+                new_root
+            );
+        }
+    }
+    Ok(new_root)
+}
+
+// fetch the leaves and siblings on the paths of all modified pages with one call
+fn verify_read_batch<H: UntrustedHost>(
+    host: &H,
+    modif: &[(PageId, Digest)],
+    r_id: NodeId,
+    root_hash: Digest,
+) -> Result<NodeHashes> {
+    let p_ids: Vec<PageId> = modif.iter().map(|(p_id, _)| *p_id).collect();
+    let ids_to_read = path_ids(&p_ids, r_id);
+    let mut nodes = NodeHashes::new();
+    if !ids_to_read.is_empty() {
+        for (n_id, node) in host.get_nodes(&ids_to_read)? {
+            if let Some(n) = node {
+                nodes.insert(n_id, n.get_hash());
+            }
+        }
+    }
+    verify_paths(&nodes, &p_ids, r_id, root_hash)
+}
+
+// fetch the leaves and siblings on the paths of the modified pages node by node
+fn verify_read_base<H: UntrustedHost>(
+    host: &H,
+    modif: &[(PageId, Digest)],
+    r_id: NodeId,
+    root_hash: Digest,
+) -> Result<NodeHashes> {
+    let p_ids: Vec<PageId> = modif.iter().map(|(p_id, _)| *p_id).collect();
+    let mut nodes = NodeHashes::new();
+    for n_id in path_ids(&p_ids, r_id) {
+        if let Some(n) = host.get_node(n_id)? {
+            nodes.insert(n_id, n.get_hash());
+        }
+    }
+    verify_paths(&nodes, &p_ids, r_id, root_hash)
+}
+
+// pages under the old root, those past it are not in the old tree
+fn in_tree(p_id: PageId, r_id: NodeId) -> bool {
+    p_id.get_id().checked_shr(r_id.get_height()).unwrap_or(0) == r_id.get_width()
+}
+
+// the leaves of the pages under the old root and the siblings along their paths
+fn path_ids(p_ids: &[PageId], r_id: NodeId) -> Vec<NodeId> {
+    let mut ids = HashSet::<NodeId>::new();
+    for p_id in p_ids.iter().filter(|p_id| in_tree(**p_id, r_id)) {
+        let mut cur_id = NodeId::from_page_id(*p_id);
+        ids.insert(cur_id);
+        while cur_id.get_height() < r_id.get_height() {
+            ids.insert(cur_id.get_sib_id());
+            cur_id = cur_id.get_parent_id();
+        }
+    }
+    ids.into_iter().collect()
+}
+
+// rebuild the old root from the leaves of `p_ids` and the siblings of their paths as the host
+// sent them, a node it left out is empty; the result must be `root_hash`, so every node used
+// is the one the endorsed tree has. Returns the siblings and the old root, the nodes the new
+// root is computed from, nodes past the old root are empty
+fn verify_paths(
+    nodes: &NodeHashes,
+    p_ids: &[PageId],
+    r_id: NodeId,
+    root_hash: Digest,
+) -> Result<NodeHashes> {
+    let mut siblings = NodeHashes::new();
+    let mut level: HashMap<NodeId, Option<Digest>> = p_ids
+        .iter()
+        .filter(|p_id| in_tree(**p_id, r_id))
+        .map(|p_id| {
+            let id = NodeId::from_page_id(*p_id);
+            (id, nodes.get(&id).copied())
+        })
+        .collect();
+    if !level.is_empty() {
+        for _ in 0..r_id.get_height() {
+            let mut next = HashMap::new();
+            for id in level.keys() {
+                let parent_id = id.get_parent_id();
+                if next.contains_key(&parent_id) {
+                    continue;
+                }
+                let (l_id, rc_id) = parent_id.get_children()?;
+                let mut child_hash = |id: NodeId| match level.get(&id) {
+                    Some(hash) => *hash,
+                    None => {
+                        let hash = nodes.get(&id).copied();
+                        if let Some(h) = hash {
+                            siblings.insert(id, h);
+                        }
+                        hash
+                    }
+                };
+                let (l_hash, r_hash) = (child_hash(l_id), child_hash(rc_id));
+                // pages fill the tree from the left, and one child hashes the same on both sides
+                if l_hash.is_none() && r_hash.is_some() {
+                    bail!("the host sent node {:?} without its left sibling", rc_id);
+                }
+                let hash = l_hash.map(|_| nonleaf_hash(l_hash, r_hash));
+                next.insert(parent_id, hash);
+            }
+            level = next;
+        }
+        if level.get(&r_id) != Some(&Some(root_hash)) {
+            bail!("verification failed, the paths from the host do not lead to the old root");
+        }
+    }
+    siblings.insert(r_id, root_hash);
+    Ok(siblings)
+}
+
+// calculate the new root hash and id
+fn cal_new_root(
+    modif: &[(PageId, Digest)],
+    proof: &mut NodeHashes,
+    height: u32,
+    shrink: &Shrink,
+) -> Result<Root> {
+    let mut queue = VecDeque::<NodeId>::new();
+    let mut max_pid = PageId(0);
+    for (p_id, dig) in modif {
+        if *p_id > max_pid {
+            max_pid = *p_id;
+        }
+        queue.push_back(NodeId::from_page_id(*p_id));
+        proof.insert(NodeId::from_page_id(*p_id), leaf_hash(p_id, dig));
+    }
+    let target_height = max_pid.find_height().max(height);
+
+    // a tree of a single page has its leaf as the root
+    let mut root_id = NodeId::from_page_id(PageId(0));
+    let mut root_hash = proof.get(&root_id).copied().unwrap_or_default();
+
+    while let Some(cur_id) = queue.pop_front() {
+        if cur_id.get_height() >= target_height {
+            break;
+        }
+        let cur_hash = proof.get(&cur_id).copied();
+        let sib_hash = proof.get(&cur_id.get_sib_id()).copied();
+        root_hash = if cur_id.is_even() {
+            nonleaf_hash(cur_hash, sib_hash)
+        } else {
+            nonleaf_hash(sib_hash, cur_hash)
+        };
+        root_id = cur_id.get_parent_id();
+        queue.push_back(root_id);
+        proof.insert(root_id, root_hash);
+    }
+
+    if let Some((page_cnt, spine)) = shrink {
+        // the root lowers to the path of the new last page, nodes right of it are gone and
+        // those left of it were recomputed above or are unchanged since the old tree
+        let last = PageId(page_cnt - 1);
+        let node_hash = |id: &NodeId| proof.get(id).or_else(|| spine.get(id)).copied();
+        root_id = NodeId::from_page_id(last);
+        root_hash = match node_hash(&root_id) {
+            Some(h) => h,
+            None => bail!("the last page is missing from the proof"),
+        };
+        while root_id.get_height() < last.find_height() {
+            root_hash = if root_id.is_even() {
+                nonleaf_hash(Some(root_hash), None)
+            } else {
+                nonleaf_hash(node_hash(&root_id.get_sib_id()), Some(root_hash))
+            };
+            root_id = root_id.get_parent_id();
+        }
+    }
+
+    Ok((Some(root_id), root_hash))
+}
+
+// hashes of the last page left by a truncation and of its siblings in the old tree, checked
+// against the old root; none if the tree does not reach past that page
+fn verify_spine<H: UntrustedHost>(
+    host: &H,
+    last: PageId,
+    r_id: NodeId,
+    root_hash: Digest,
+) -> Result<Option<NodeHashes>> {
+    if !in_tree(last, r_id) {
+        return Ok(None);
+    }
+    let ids_to_read = path_ids(&[last], r_id);
+    let mut nodes = NodeHashes::new();
+    for (n_id, node) in host.get_nodes(&ids_to_read)? {
+        if let Some(n) = node {
+            nodes.insert(n_id, n.get_hash());
+        }
+    }
+    let mut spine = verify_paths(&nodes, &[last], r_id, root_hash)?;
+
+    // a node right of the path is the only sign of pages past the last one
+    let mut cur_id = NodeId::from_page_id(last);
+    let mut reaches_past = false;
+    while cur_id.get_height() < r_id.get_height() {
+        reaches_past |= cur_id.is_even() && spine.contains_key(&cur_id.get_sib_id());
+        cur_id = cur_id.get_parent_id();
+    }
+    if !reaches_past {
+        return Ok(None);
+    }
+    let leaf_id = NodeId::from_page_id(last);
+    match nodes.get(&leaf_id) {
+        Some(h) => spine.insert(leaf_id, *h),
+        None => bail!("the last page {:?} is not in the tree", last),
+    };
+    Ok(Some(spine))
+}
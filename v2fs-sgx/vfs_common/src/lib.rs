@@ -4,12 +4,40 @@ extern crate alloc;
 
 pub mod digest;
 pub mod page;
+pub mod vbf;
+
+use core::sync::atomic::{AtomicUsize, Ordering};
 
 pub const MAX_PATH_LENGTH: usize = 512;
-pub const PAGE_SIZE: usize = 4096;
+pub const DEFAULT_PAGE_SIZE: usize = 4096;
+
+// page size of the database, recorded in the tree parameters when the ADS is built
+static CUR_PAGE_SIZE: AtomicUsize = AtomicUsize::new(DEFAULT_PAGE_SIZE);
+
+pub fn page_size() -> usize {
+    CUR_PAGE_SIZE.load(Ordering::Relaxed)
+}
+
+// returns false and keeps the current value if `page_size` is not a valid sqlite page size
+pub fn set_page_size(page_size: usize) -> bool {
+    if !(512..=65536).contains(&page_size) || !page_size.is_power_of_two() {
+        return false;
+    }
+    CUR_PAGE_SIZE.store(page_size, Ordering::Relaxed);
+    true
+}
 
 pub const MERKLE_PATH: &str = "./db/merkle_db/merkle_test";
 
+// the version bloom filter of the main database, next to the merkle tree it is published with
+pub const VBF_PATH: &str = "./db/merkle_db/merkle_test/vbf.bin";
+
+// the trusted state sealed by the enclave, only the copy sealed last unseals
+pub const SEALED_STATE_PATH: &str = "./db/merkle_db/merkle_test/trusted_state.sealed";
+
+// the uuid of the monotonic counter of the sealed state, kept apart so a missing state is noticed
+pub const COUNTER_UUID_PATH: &str = "./db/merkle_db/merkle_test/trusted_state.counter";
+
 pub const MAIN_PATH: &str = "./db/sqlite_db/test.db";
 
 pub const TMP_FILE_PATH: &str = "./db/tmp_file";
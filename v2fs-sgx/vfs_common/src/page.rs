@@ -1,5 +1,5 @@
 use alloc::boxed::Box;
-use crate::{digest::{Digest, Digestible}, PAGE_SIZE};
+use crate::digest::{Digest, Digestible};
 use serde::{Deserialize, Serialize};
 use alloc::vec::Vec;
 
@@ -32,11 +32,11 @@ impl Digestible for PageId {
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Page {
-    bytes: Box<[u8; PAGE_SIZE]>,
+    bytes: Box<[u8]>,
 }
 
 impl Page {
-    pub fn new(bytes: Box<[u8; PAGE_SIZE]>) -> Self {
+    pub fn new(bytes: Box<[u8]>) -> Self {
         Self { bytes }
     }
 
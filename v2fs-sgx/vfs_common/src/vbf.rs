@@ -0,0 +1,125 @@
+use crate::digest::{blake2, Digest, Digestible};
+use crate::page::PageId;
+use alloc::vec::Vec;
+use serde::{Deserialize, Serialize};
+
+pub const VBF_MAP_SIZE: usize = 10000;
+pub const VBF_HASH_NUM: u32 = 4;
+
+// version of every page hashed into `hash_num` slots, kept by the host and advanced by the
+// enclave, which publishes its digest with the root; slots, counter and digest follow the
+// `VersionBloomFilter` of v2fs-vsqlite, whose server hands this filter to the clients
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VersionBloomFilter {
+    // key of the slot hashes
+    seed: u64,
+    map_size: u64,
+    hash_num: u32,
+    vmap: Vec<u32>,
+    // bumped by every page inserted
+    counter: u64,
+}
+
+impl VersionBloomFilter {
+    pub fn with_seed(map_size: usize, hash_num: u32, seed: u64) -> Self {
+        Self {
+            seed,
+            map_size: map_size as u64,
+            hash_num,
+            vmap: alloc::vec![0; map_size],
+            counter: 0,
+        }
+    }
+
+    // a filter read back from its parts, `None` if `vmap` does not have `map_size` slots
+    pub fn from_parts(
+        seed: u64,
+        map_size: u64,
+        hash_num: u32,
+        vmap: Vec<u32>,
+        counter: u64,
+    ) -> Option<Self> {
+        if vmap.len() as u64 != map_size || map_size == 0 {
+            return None;
+        }
+        Some(Self {
+            seed,
+            map_size,
+            hash_num,
+            vmap,
+            counter,
+        })
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn map_size(&self) -> u64 {
+        self.map_size
+    }
+
+    pub fn hash_num(&self) -> u32 {
+        self.hash_num
+    }
+
+    pub fn slots(&self) -> &[u32] {
+        &self.vmap
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.counter
+    }
+
+    // version of the last batch written
+    pub fn version(&self) -> u32 {
+        self.vmap.iter().max().copied().unwrap_or(0)
+    }
+
+    // mark the pages of one write batch with the next version; as in the vsqlite filter a slot
+    // never goes back to an older version
+    pub fn insert_batch(&mut self, p_ids: &[PageId]) -> u32 {
+        let version = self.version() + 1;
+        for p_id in p_ids {
+            self.counter += 1;
+            let (h1, h2) = self.hash_kernel(*p_id);
+            for i in 0..self.hash_num {
+                let idx = self.get_idx(h1, h2, i as u64);
+                self.vmap[idx] = self.vmap[idx].max(version);
+            }
+        }
+        version
+    }
+
+    fn hash_kernel(&self, p_id: PageId) -> (u64, u64) {
+        let hash = blake2b_simd::Params::new()
+            .hash_length(16)
+            .key(&self.seed.to_le_bytes())
+            .hash(&p_id.get_id().to_le_bytes());
+        let (h1, h2) = hash.as_bytes().split_at(8);
+        let mut b1 = [0u8; 8];
+        let mut b2 = [0u8; 8];
+        b1.copy_from_slice(h1);
+        b2.copy_from_slice(h2);
+        (u64::from_le_bytes(b1), u64::from_le_bytes(b2))
+    }
+
+    // g(x) = h1(x) + ih2(x)
+    fn get_idx(&self, h1: u64, h2: u64, hash_id: u64) -> usize {
+        (h1.wrapping_add(hash_id.wrapping_mul(h2)) % self.map_size) as usize
+    }
+}
+
+impl Digestible for VersionBloomFilter {
+    fn to_digest(&self) -> Digest {
+        let mut state = blake2().to_state();
+        state.update(&self.seed.to_le_bytes());
+        state.update(&self.map_size.to_le_bytes());
+        state.update(&self.hash_num.to_le_bytes());
+        state.update(&self.counter.to_le_bytes());
+        for v in &self.vmap {
+            state.update(&v.to_le_bytes());
+        }
+        Digest::from(state.finalize())
+    }
+}
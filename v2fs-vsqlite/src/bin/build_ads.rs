@@ -1,8 +1,35 @@
-use anyhow::Result;
-use v2fs_vsqlite::{utils::init_tracing_subscriber, vfs::io::build_merkle_tree};
+use anyhow::{bail, Result};
+use std::path::Path;
+use structopt::StructOpt;
+use v2fs_vsqlite::{
+    utils::init_tracing_subscriber,
+    vfs::{
+        attached_main_path, attached_merkle_path, io::build_merkle_tree, io::build_merkle_tree_at,
+        is_valid_db_name,
+    },
+};
+
+#[derive(StructOpt, Debug)]
+struct Opt {
+    // build the tree of the attachable database ./db/sqlite_db/<name>.db instead of the main one
+    #[structopt(short, long)]
+    name: Option<String>,
+}
 
 fn main() -> Result<()> {
     init_tracing_subscriber("trace")?;
-    build_merkle_tree()?;
+    let opts = Opt::from_args();
+    match opts.name {
+        Some(name) => {
+            if !is_valid_db_name(&name) {
+                bail!("invalid database name {}", name);
+            }
+            build_merkle_tree_at(
+                Path::new(&attached_main_path(&name)),
+                Path::new(&attached_merkle_path(&name)),
+            )?;
+        }
+        None => build_merkle_tree()?,
+    }
     Ok(())
 }
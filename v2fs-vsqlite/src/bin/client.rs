@@ -2,15 +2,23 @@
 extern crate tracing;
 
 use anyhow::{bail, Result};
-use std::collections::{HashMap, VecDeque};
+use std::collections::HashMap;
 use std::net::TcpStream;
 use structopt::StructOpt;
 use v2fs_vsqlite::digest::Digest;
-use v2fs_vsqlite::query::{query, update_user_bf};
+use v2fs_vsqlite::eviction::Eviction;
+use v2fs_vsqlite::query::{
+    attach_user_dbs, query, submit_writes, update_user_bf, update_user_root, warm_up,
+};
 use v2fs_vsqlite::script::load_query_wkld;
 use v2fs_vsqlite::simple_vcache::SVCache;
-use v2fs_vsqlite::utils::{cal_cap, default_connect, register_vfs};
+use v2fs_vsqlite::stats::{write_stats, QueryStats};
+use v2fs_vsqlite::trace::{start_recording, stop_recording, Trace};
+use v2fs_vsqlite::utils::{cal_budget, default_connect, get_page_size, get_root, register_vfs};
 use v2fs_vsqlite::utils::{init_tracing_subscriber, ResInfo, Time};
+use v2fs_vsqlite::vbf::{MAX_FILL_RATIO, MAX_FP_RATE};
+use v2fs_vsqlite::verified_cache::{compression::Compression, VerifiedCache};
+use v2fs_vsqlite::vfs::{page_size, set_page_size, GLOBAL_TS};
 use v2fs_vsqlite::{cache::Cache, version_cache::VCache};
 use v2fs_vsqlite::{PageId, Type};
 
@@ -31,6 +39,38 @@ struct Opt {
 
     #[structopt(short, long, default_value = "5")]
     hash_num: u32,
+
+    // verify every page on arrival instead of one batch proof after the query
+    #[structopt(short, long)]
+    stream_verify: bool,
+
+    // verified database attached to every query under its own name, can be repeated
+    #[structopt(short, long)]
+    attach: Vec<String>,
+
+    // cache eviction policy: lru, lfu, arc, 2q or cost
+    #[structopt(short, long, default_value = "lru")]
+    eviction: Eviction,
+
+    // compression of cached pages: none, lz4 or zstd
+    #[structopt(long, default_value = "none")]
+    compression: Compression,
+
+    // write the pages each query reads to this trace file
+    #[structopt(long)]
+    record: Option<String>,
+
+    // fetch and verify the pages of this trace before the first query
+    #[structopt(long)]
+    warmup: Option<String>,
+
+    // write per-query cache and protocol stats to this file, csv if it ends with .csv
+    #[structopt(long)]
+    stats: Option<String>,
+
+    // submit the statements of this file as one write before the first query
+    #[structopt(long)]
+    write: Option<String>,
 }
 
 pub fn main() -> Result<()> {
@@ -41,9 +81,16 @@ pub fn main() -> Result<()> {
     let map_size = opts.map_size;
     let hash_num = opts.hash_num;
     let workload_path = opts.workload_path;
-    let cache_cap = cal_cap(cache_size_in_mb, opt_level);
+    // the page size is published with the root
+    set_page_size(get_page_size()?)?;
+    let cache_budget = cal_budget(cache_size_in_mb);
+
+    if opts.stream_verify && opt_level != 0 {
+        bail!("Streaming verification only supports opt_level 0");
+    }
 
     let tp = match opt_level {
+        0 if opts.stream_verify => Type::Stream,
         0 => Type::None,
         1 => Type::Intra,
         2 => Type::Both,
@@ -52,23 +99,64 @@ pub fn main() -> Result<()> {
         _ => bail!("Invalid opt_level"),
     };
 
-    exp(cache_cap, tp, workload_path, map_size, hash_num)?;
+    if let Some(path) = &opts.write {
+        submit_from(path)?;
+    }
+
+    let mut trace = match opts.record {
+        Some(_) => Some(Trace::new(
+            get_root()?,
+            unsafe { GLOBAL_TS },
+            opt_level,
+            page_size(),
+        )),
+        None => None,
+    };
+
+    exp(
+        cache_budget,
+        tp,
+        workload_path,
+        map_size,
+        hash_num,
+        &opts.attach,
+        opts.eviction,
+        opts.compression,
+        opts.warmup.as_deref(),
+        trace.as_mut(),
+        opts.stats.as_deref(),
+    )?;
+
+    if let (Some(path), Some(trace)) = (opts.record, trace) {
+        trace.save(&path)?;
+        info!(
+            "trace of {} queries written to {}",
+            trace.queries.len(),
+            path
+        );
+    }
 
     Ok(())
 }
 
 #[allow(clippy::too_many_arguments)]
 pub fn exp(
-    cache_cap: usize,
+    cache_budget: usize,
     tp: Type,
     workload_path: String,
     map_size: usize,
     hash_num: u32,
+    attach: &[String],
+    eviction: Eviction,
+    compression: Compression,
+    warmup: Option<&str>,
+    trace: Option<&mut Trace>,
+    stats_path: Option<&str>,
 ) -> Result<()> {
     let queries = load_query_wkld(&workload_path)?;
-    let mut cache = Cache::new(cache_cap);
-    let mut vcache = VCache::new(cache_cap);
-    let mut svcache = SVCache::new(cache_cap);
+    let mut cache = Cache::with_options(cache_budget, eviction, compression);
+    let mut vcache = VCache::with_options(cache_budget, eviction, compression);
+    let mut svcache = SVCache::with_options(cache_budget, eviction, compression);
     let mut stream = default_connect()?;
     let mut map = HashMap::new();
 
@@ -82,6 +170,11 @@ pub fn exp(
         map_size,
         hash_num,
     )?;
+    attach_user_dbs(attach)?;
+
+    if let Some(path) = warmup {
+        warm_up_from(path, tp, &mut stream)?;
+    }
 
     exec_wkld(
         &mut cache,
@@ -91,11 +184,58 @@ pub fn exp(
         &mut stream,
         &mut map,
         queries,
+        trace,
+        stats_path,
     )?;
 
     Ok(())
 }
 
+// the server returns the transition of its tree, the new root is only accepted if it is the
+// one verified for the writes
+fn submit_from(path: &str) -> Result<()> {
+    let stmts = load_query_wkld(path)?;
+    let timer = howlong::ProcessCPUTimer::new();
+    let new_root = submit_writes(&stmts)?;
+    let time = Time::from(timer.elapsed());
+    match new_root {
+        Some((hash, _)) if hash == get_root()? => {
+            info!(
+                "{} statements written in {}ms",
+                stmts.len(),
+                time.real / 1000
+            );
+            Ok(())
+        }
+        _ => bail!("the published root is not the one verified for the writes"),
+    }
+}
+
+// fetch the pages of a recorded trace into the cache, verified by a single proof
+fn warm_up_from(path: &str, tp: Type, stream: &mut TcpStream) -> Result<()> {
+    let trace = Trace::load(path)?;
+    if trace.page_size != page_size() {
+        bail!(
+            "trace {} was recorded with page size {}, the database uses {}",
+            path,
+            trace.page_size,
+            page_size()
+        );
+    }
+    if trace.root != get_root()?.to_string() {
+        warn!("the database changed since trace {} was recorded", path);
+    }
+    if matches!(tp, Type::BothBloom | Type::SimpleBloom) {
+        update_user_bf()?;
+    }
+    *stream = TcpStream::connect("127.0.0.1:7878")?;
+    let timer = howlong::ProcessCPUTimer::new();
+    let cnt = warm_up(&trace.pages(), tp, stream)?;
+    let time = Time::from(timer.elapsed());
+    info!("warmed up {} pages in {}ms", cnt, time.real / 1000);
+    Ok(())
+}
+
 #[allow(clippy::too_many_arguments)]
 fn exec_wkld(
     cache: &mut Cache,
@@ -105,8 +245,10 @@ fn exec_wkld(
     stream: &mut TcpStream,
     map: &mut HashMap<PageId, Digest>,
     queries: Vec<String>,
+    mut trace: Option<&mut Trace>,
+    stats_path: Option<&str>,
 ) -> Result<()> {
-    let mut res_infos = VecDeque::<ResInfo>::new();
+    let mut res_infos = Vec::<ResInfo>::new();
     for (i, sql) in queries.iter().enumerate() {
         info!("Processing query: {}...", i);
         map.clear();
@@ -126,6 +268,9 @@ fn exec_wkld(
                 svcache.unconfirm();
                 update_user_bf()?;
             }
+            Type::Stream => {
+                update_user_root()?;
+            }
         }
 
         // if opt_level == 4 {
@@ -140,11 +285,17 @@ fn exec_wkld(
         //     cache.clear();
         // }
         *stream = TcpStream::connect("127.0.0.1:7878")?;
+        if trace.is_some() {
+            start_recording();
+        }
         let timer = howlong::ProcessCPUTimer::new();
         let res_info = query(sql, tp, stream)?;
         let time = Time::from(timer.elapsed());
+        if let Some(trace) = trace.as_deref_mut() {
+            trace.queries.push(stop_recording());
+        }
         info!("query time: {}ms", time.real / 1000);
-        res_infos.push_back(res_info);
+        res_infos.push(res_info);
     }
 
     let size = res_infos.len();
@@ -154,14 +305,16 @@ fn exec_wkld(
     let mut total_v_t = 0;
     let mut total_p_s = 0;
     let mut c_size = 0;
+    let mut total = QueryStats::default();
 
-    for res_info in res_infos {
+    for res_info in &res_infos {
         total_q_t += res_info.query_t.real;
         total_v_t += res_info.verify_t.real;
         total_p_s += res_info.proof_s;
         if c_size < res_info.cache_size {
             c_size = res_info.cache_size;
         }
+        total += &res_info.stats;
     }
     let mut q_t_in_s = total_q_t as f64 / 1000000.0;
     q_t_in_s /= size as f64;
@@ -171,10 +324,28 @@ fn exec_wkld(
     let mut p_s_in_kb = total_p_s as f64 / 1024.0;
     p_s_in_kb /= size as f64;
 
+    let c_s_in_mb = c_size as f64 / (1024.0 * 1024.0);
+
     info!(
-        "average q_t: {}s, v_t: {}s, total_t: {}s, p_s: {}KB",
-        q_t_in_s, v_t_in_s, total_t_in_s, p_s_in_kb
+        "average q_t: {}s, v_t: {}s, total_t: {}s, p_s: {}KB, peak cache: {}MB",
+        q_t_in_s, v_t_in_s, total_t_in_s, p_s_in_kb, c_s_in_mb
     );
+    info!("total stats: {:?}", total);
+    if matches!(tp, Type::BothBloom | Type::SimpleBloom) {
+        let fp_rate = total.vbf_false_positive_rate();
+        info!("vbf false positive rate: {:.4}", fp_rate);
+        if fp_rate > MAX_FP_RATE {
+            warn!(
+                "the vbf flags more than {} of unchanged pages, the writer grows it once it is \
+                 {} full or when started with a larger -m",
+                MAX_FP_RATE, MAX_FILL_RATIO
+            );
+        }
+    }
+    if let Some(path) = stats_path {
+        write_stats(path, &res_infos, &total)?;
+        info!("stats of {} queries written to {}", size, path);
+    }
 
     Ok(())
 }
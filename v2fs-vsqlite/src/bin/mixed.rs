@@ -0,0 +1,386 @@
+#[macro_use]
+extern crate tracing;
+
+use anyhow::{bail, Context, Result};
+use rand::{distributions::Distribution, rngs::StdRng, SeedableRng};
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::net::TcpStream;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::{Duration, Instant};
+use structopt::StructOpt;
+use v2fs_vsqlite::cache::Cache;
+use v2fs_vsqlite::eviction::Eviction;
+use v2fs_vsqlite::query::{query, submit_writes, update_user_bf, update_user_root};
+use v2fs_vsqlite::script::load_query_wkld;
+use v2fs_vsqlite::simple_vcache::SVCache;
+use v2fs_vsqlite::stats::QueryStats;
+use v2fs_vsqlite::utils::{
+    cal_budget, default_connect, get_page_size, get_root, init_tracing_subscriber, register_vfs,
+    Time,
+};
+use v2fs_vsqlite::verified_cache::{compression::Compression, VerifiedCache};
+use v2fs_vsqlite::version_cache::VCache;
+use v2fs_vsqlite::vfs::set_page_size;
+use v2fs_vsqlite::Type;
+use zipf::ZipfDistribution;
+
+// a failed query is retried this often if the root moved while it ran
+const MAX_RETRIES: usize = 3;
+
+#[derive(StructOpt, Debug)]
+struct Opt {
+    #[structopt(short, long, default_value = "500")]
+    cache_size_in_mb: usize,
+
+    // 0: no opt, 1: intra-cache, 2: inter-cache, 3: inter+vbf
+    #[structopt(short, long, default_value = "2")]
+    opt_level: u8,
+
+    #[structopt(short, long, default_value = "./query/test_wkld.txt")]
+    workload_path: String,
+
+    #[structopt(short, long, default_value = "10000")]
+    map_size: usize,
+
+    #[structopt(short, long, default_value = "5")]
+    hash_num: u32,
+
+    #[structopt(short, long, default_value = "lru")]
+    eviction: Eviction,
+
+    // query streams, each one but the first runs in its own process
+    #[structopt(long, default_value = "1")]
+    clients: usize,
+
+    // set on the processes spawned for the other query streams
+    #[structopt(long)]
+    client_id: Option<usize>,
+
+    #[structopt(long, default_value = "60")]
+    duration_secs: u64,
+
+    #[structopt(long, default_value = "10")]
+    window_secs: u64,
+
+    // pause between two update batches, 0 runs queries only
+    #[structopt(long, default_value = "1000")]
+    update_interval_ms: u64,
+
+    // statements submitted in one write
+    #[structopt(short, long, default_value = "10")]
+    batch_size: usize,
+
+    // updates run `update <table> set <column> = <column> + 1 where <key> = k`, with k in
+    // 1..=rows drawn from a zipf distribution
+    #[structopt(long, default_value = "lineitem")]
+    table: String,
+
+    #[structopt(long, default_value = "l_orderkey")]
+    key: String,
+
+    #[structopt(long, default_value = "l_quantity")]
+    column: String,
+
+    #[structopt(long, default_value = "1000")]
+    rows: usize,
+
+    #[structopt(long, default_value = "1.0")]
+    zipf: f64,
+
+    // windows are written to <out>_client<i>.csv and <out>_updates.csv
+    #[structopt(long, default_value = "./mixed")]
+    out: String,
+}
+
+// what one query stream saw in a time window
+#[derive(Debug, Default, Serialize)]
+struct ClientWindow {
+    window: u64,
+    queries: usize,
+    // queries that failed to verify as the root moved while they ran, and were retried
+    stale_queries: usize,
+    // roots published since the previous query, summed over the queries
+    root_changes: usize,
+    query_us: u64,
+    pages_requested: usize,
+    valid_hits: usize,
+    vbf_hits: usize,
+    confirms: usize,
+    // cached pages found stale on CONFIRM
+    confirm_failures: usize,
+    confirm_failure_rate: f64,
+    // share of unchanged cached pages the vbf still flagged
+    vbf_false_positive_rate: f64,
+    fetched: usize,
+}
+
+impl ClientWindow {
+    fn add(&mut self, stats: &QueryStats, query_us: u64) {
+        self.queries += 1;
+        self.query_us += query_us;
+        self.pages_requested += stats.pages_requested;
+        self.valid_hits += stats.valid_hits;
+        self.vbf_hits += stats.vbf_hits;
+        self.confirms += stats.confirms;
+        self.confirm_failures += stats.refetched;
+        self.fetched += stats.fetched;
+    }
+
+    fn finish(&mut self) {
+        if self.confirms > 0 {
+            self.confirm_failure_rate = self.confirm_failures as f64 / self.confirms as f64;
+        }
+        // the same rate the client reports per query
+        let stats = QueryStats {
+            vbf_hits: self.vbf_hits,
+            confirms: self.confirms,
+            refetched: self.confirm_failures,
+            ..Default::default()
+        };
+        self.vbf_false_positive_rate = stats.vbf_false_positive_rate();
+    }
+}
+
+// what the update stream did in a time window
+#[derive(Debug, Default, Serialize)]
+struct UpdateWindow {
+    window: u64,
+    batches: usize,
+    statements: usize,
+    failed: usize,
+    write_us: u64,
+}
+
+fn main() -> Result<()> {
+    init_tracing_subscriber("info")?;
+    let opts = Opt::from_args();
+    if opts.window_secs == 0 {
+        bail!("window must be at least one second");
+    }
+    set_page_size(get_page_size()?)?;
+
+    if let Some(id) = opts.client_id {
+        return run_client(&opts, id);
+    }
+
+    let mut children = Vec::new();
+    for id in 1..opts.clients {
+        children.push(spawn_client(id)?);
+    }
+    let start = Instant::now();
+    let updater = if opts.update_interval_ms > 0 {
+        let (stmts, interval) = (update_stream(&opts)?, opts.update_interval_ms);
+        let (duration, window) = (opts.duration_secs, opts.window_secs);
+        Some(thread::spawn(move || {
+            run_updates(stmts, interval, start, duration, window)
+        }))
+    } else {
+        None
+    };
+    let res = run_client(&opts, 0);
+
+    if let Some(updater) = updater {
+        match updater.join() {
+            Ok(windows) => write_windows(&format!("{}_updates.csv", opts.out), &windows?)?,
+            Err(_) => bail!("update stream panicked"),
+        }
+    }
+    for mut child in children {
+        let status = child.wait()?;
+        if !status.success() {
+            warn!("query stream exited with {}", status);
+        }
+    }
+    res
+}
+
+// another query stream, the user vfs is registered once per process
+fn spawn_client(id: usize) -> Result<Child> {
+    let child = Command::new(std::env::current_exe()?)
+        .args(std::env::args().skip(1))
+        .args(["--client-id", &id.to_string()])
+        .spawn()
+        .context("failed to spawn query stream")?;
+    Ok(child)
+}
+
+// an endless stream of batches, keys follow a zipf distribution so a few rows get most updates
+fn update_stream(opts: &Opt) -> Result<impl Iterator<Item = Vec<String>>> {
+    let dist = match ZipfDistribution::new(opts.rows, opts.zipf) {
+        Ok(dist) => dist,
+        Err(()) => bail!("invalid zipf distribution over {} rows", opts.rows),
+    };
+    let stmt = format!(
+        "UPDATE {} SET {} = {} + 1 WHERE {} = ",
+        opts.table, opts.column, opts.column, opts.key
+    );
+    let batch_size = opts.batch_size.max(1);
+    let mut rng = StdRng::from_entropy();
+    Ok(std::iter::repeat_with(move || {
+        (0..batch_size)
+            .map(|_| format!("{}{}", stmt, dist.sample(&mut rng)))
+            .collect()
+    }))
+}
+
+fn run_updates(
+    mut stmts: impl Iterator<Item = Vec<String>>,
+    interval_ms: u64,
+    start: Instant,
+    duration_secs: u64,
+    window_secs: u64,
+) -> Result<Vec<UpdateWindow>> {
+    let mut windows = BTreeMap::<u64, UpdateWindow>::new();
+    while start.elapsed().as_secs() < duration_secs {
+        let batch = stmts.next().context("update stream ended")?;
+        let timer = howlong::ProcessCPUTimer::new();
+        let res = submit_writes(&batch);
+        let time = Time::from(timer.elapsed());
+        let window = start.elapsed().as_secs() / window_secs;
+        let w = windows.entry(window).or_default();
+        w.batches += 1;
+        w.statements += batch.len();
+        w.write_us += time.real;
+        if let Err(e) = res {
+            warn!("update batch failed: {:?}", e);
+            w.failed += 1;
+        }
+        thread::sleep(Duration::from_millis(interval_ms));
+    }
+    Ok(into_rows(windows, |w, i| w.window = i))
+}
+
+fn run_client(opts: &Opt, id: usize) -> Result<()> {
+    let tp = match opts.opt_level {
+        0 => Type::None,
+        1 => Type::Intra,
+        2 => Type::Both,
+        3 => Type::SimpleBloom,
+        _ => bail!("Invalid opt_level"),
+    };
+    let queries = load_query_wkld(&opts.workload_path)?;
+    if queries.is_empty() {
+        bail!("no queries in {}", opts.workload_path);
+    }
+    let cache_budget = cal_budget(opts.cache_size_in_mb);
+    let compression = Compression::None;
+    let mut cache = Cache::with_options(cache_budget, opts.eviction, compression);
+    let mut vcache = VCache::with_options(cache_budget, opts.eviction, compression);
+    let mut svcache = SVCache::with_options(cache_budget, opts.eviction, compression);
+    let mut stream = default_connect()?;
+    let mut map = HashMap::new();
+    register_vfs(
+        tp,
+        &mut cache,
+        &mut vcache,
+        &mut svcache,
+        &mut stream,
+        &mut map,
+        opts.map_size,
+        opts.hash_num,
+    )?;
+
+    let start = Instant::now();
+    let mut windows = BTreeMap::<u64, ClientWindow>::new();
+    let mut root = get_root()?;
+    for sql in queries.iter().cycle() {
+        if start.elapsed().as_secs() >= opts.duration_secs {
+            break;
+        }
+        let mut retries = 0;
+        let (stats, query_us) = loop {
+            map.clear();
+            match tp {
+                Type::None => {}
+                Type::Intra => cache.clear(),
+                Type::Both => cache.unconfirm(),
+                Type::BothBloom => {
+                    vcache.unconfirm();
+                    update_user_bf()?;
+                }
+                Type::SimpleBloom => {
+                    svcache.unconfirm();
+                    update_user_bf()?;
+                }
+                Type::Stream => update_user_root()?,
+            }
+            let before = get_root()?;
+            if before != root {
+                windows
+                    .entry(window_of(start, opts))
+                    .or_default()
+                    .root_changes += 1;
+                root = before;
+            }
+            stream = TcpStream::connect("127.0.0.1:7878")?;
+            let timer = howlong::ProcessCPUTimer::new();
+            let res = query(sql, tp, &mut stream);
+            let time = Time::from(timer.elapsed());
+            match res {
+                Ok(res_info) => break (res_info.stats, time.real),
+                Err(e) if retries < MAX_RETRIES && get_root()? != before => {
+                    // pages cached by the failed query were never verified
+                    debug!("query raced with an update, retrying: {:?}", e);
+                    cache.clear();
+                    vcache.clear();
+                    svcache.clear();
+                    windows
+                        .entry(window_of(start, opts))
+                        .or_default()
+                        .stale_queries += 1;
+                    retries += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        };
+        windows
+            .entry(window_of(start, opts))
+            .or_default()
+            .add(&stats, query_us);
+    }
+
+    let rows = into_rows(windows, |w, i| {
+        w.window = i;
+        w.finish();
+    });
+    for w in &rows {
+        info!(
+            "client {} window {}: {} queries, {} stale, confirm failure rate {:.4}, \
+             vbf false positive rate {:.4}",
+            id,
+            w.window,
+            w.queries,
+            w.stale_queries,
+            w.confirm_failure_rate,
+            w.vbf_false_positive_rate
+        );
+    }
+    write_windows(&format!("{}_client{}.csv", opts.out, id), &rows)
+}
+
+fn window_of(start: Instant, opts: &Opt) -> u64 {
+    start.elapsed().as_secs() / opts.window_secs
+}
+
+fn into_rows<W>(windows: BTreeMap<u64, W>, mut set: impl FnMut(&mut W, u64)) -> Vec<W> {
+    windows
+        .into_iter()
+        .map(|(i, mut w)| {
+            set(&mut w, i);
+            w
+        })
+        .collect()
+}
+
+fn write_windows<W: Serialize>(path: &str, rows: &[W]) -> Result<()> {
+    let mut w =
+        csv::Writer::from_path(path).with_context(|| format!("failed to create {}", path))?;
+    for row in rows {
+        w.serialize(row)?;
+    }
+    w.flush()?;
+    info!("{} windows written to {}", rows.len(), path);
+    Ok(())
+}
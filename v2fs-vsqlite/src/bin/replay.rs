@@ -0,0 +1,77 @@
+#[macro_use]
+extern crate tracing;
+
+use anyhow::{bail, Result};
+use structopt::StructOpt;
+use v2fs_vsqlite::{
+    eviction::Eviction,
+    trace::Trace,
+    utils::{cal_budget, init_tracing_subscriber},
+};
+
+#[derive(StructOpt, Debug)]
+struct Opt {
+    // trace written by `client --record`
+    trace_path: String,
+
+    // cache sizes to simulate, can be repeated
+    #[structopt(short, long)]
+    cache_size_in_mb: Vec<usize>,
+
+    // eviction policies to simulate, can be repeated, all of them by default
+    #[structopt(short, long)]
+    eviction: Vec<Eviction>,
+
+    // 0: no opt, 1: intra-cache, 2: inter-cache, 3: inter+vbf; the recorded level by default
+    #[structopt(short, long)]
+    opt_level: Option<u8>,
+}
+
+fn main() -> Result<()> {
+    init_tracing_subscriber("info")?;
+    let opts = Opt::from_args();
+    let trace = Trace::load(&opts.trace_path)?;
+    let opt_level = opts.opt_level.unwrap_or(trace.opt_level);
+    if opt_level > 3 {
+        bail!("Invalid opt_level");
+    }
+    let sizes = match opts.cache_size_in_mb.is_empty() {
+        true => vec![500],
+        false => opts.cache_size_in_mb,
+    };
+    let policies = match opts.eviction.is_empty() {
+        true => vec![
+            Eviction::Lru,
+            Eviction::Lfu,
+            Eviction::Arc,
+            Eviction::TwoQ,
+            Eviction::Cost,
+        ],
+        false => opts.eviction,
+    };
+
+    info!(
+        "replaying {} queries, {} distinct pages, recorded at version {} with opt_level {}",
+        trace.queries.len(),
+        trace.pages().len(),
+        trace.version,
+        trace.opt_level
+    );
+    println!("cache_mb,eviction,hits,misses,confirms,hit_ratio,peak_bytes");
+    for size in sizes {
+        for eviction in &policies {
+            let stats = trace.simulate(opt_level, cal_budget(size), *eviction);
+            println!(
+                "{},{:?},{},{},{},{:.4},{}",
+                size,
+                eviction,
+                stats.hits,
+                stats.misses,
+                stats.confirms,
+                stats.hit_ratio(),
+                stats.peak
+            );
+        }
+    }
+    Ok(())
+}
@@ -1,109 +1,293 @@
 #[macro_use]
 extern crate tracing;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Serialize;
 use std::{
-    collections::HashSet,
+    collections::{BTreeMap, HashSet},
     fs::File,
     io::{ErrorKind, Read, Seek, SeekFrom, Write},
     net::{TcpListener, TcpStream},
-    path::Path,
+    path::{Path, PathBuf},
+    sync::Mutex,
     thread,
+    time::Duration,
 };
+use structopt::StructOpt;
 use v2fs_vsqlite::{
     digest::{Digest, Digestible},
-    merkle_cb_tree::{read::ReadContext, NodeId, ReadInterface},
-    utils::init_tracing_subscriber,
+    merkle_cb_tree::{proof::Proof, read::ReadContext, NodeId, ReadInterface},
+    query::{recover_db, update_db_batch},
+    replica::{serve_follower, sync_from},
+    utils::{get_page_size, init_tracing_subscriber, register_server_vfs},
+    vbf::VersionBloomFilter,
     vfs::{
-        BOTH_CACHE, CONFIRM, END, MAIN_PATH, MERKLE_PATH, NO_CACHE, NO_FLAG, PAGE_SIZE, QUERY,
-        YES_FLAG,
+        attached_main_path, attached_merkle_path, is_valid_db_name, page_size, read_msg,
+        server_vfs::lock_snapshot_shared, set_page_size, ATTACH, BOTH_CACHE, CONFIRM, END,
+        FETCH_VBF, MAIN_DB, MAIN_PATH, MERKLE_PATH, MSG_BUF_LEN, NO_CACHE, NO_FLAG, QUERY,
+        REPLICATE, SELECT_DB, STREAM_VERIFY, SUBMIT_WRITE, VBF_PATH, YES_FLAG,
     },
     MerkleDB, PageId,
 };
 
-fn handle_sender(mut stream: TcpStream) -> Result<()> {
-    let merkle_db = MerkleDB::open_read_only(Path::new(MERKLE_PATH))?;
-    let root_id = merkle_db.get_root_id();
-    let ctx = ReadContext::new(&merkle_db, root_id)?;
+#[derive(StructOpt, Debug)]
+struct Opt {
+    // slots of the vbf created for user writes, an existing one is grown to this size
+    #[structopt(short, long, default_value = "10000")]
+    map_size: usize,
 
-    let mut buf = [0; PAGE_SIZE as usize];
+    #[structopt(short, long, default_value = "5")]
+    hash_num: u32,
+
+    #[structopt(long, default_value = "127.0.0.1:7878")]
+    addr: String,
+
+    // address of a primary to replicate, the server then serves read-only
+    #[structopt(long)]
+    follow: Option<String>,
+
+    // merkle dir holding the root published for the primary, each pulled version must match it
+    #[structopt(long)]
+    trusted: Option<PathBuf>,
+
+    #[structopt(long, default_value = "1000")]
+    sync_interval_ms: u64,
+}
+
+// the server vfs tracks the pages of one write at a time
+static WRITE_LOCK: Mutex<()> = Mutex::new(());
+
+// a database served in one session with the pages it has sent
+struct ServedDb {
+    main_path: String,
+    merkle_db: MerkleDB,
+    root_id: Option<NodeId>,
+    pids: HashSet<PageId>,
+}
+
+impl ServedDb {
+    fn open(main_path: String, merkle_path: &str) -> Result<Self> {
+        let merkle_db = MerkleDB::open_read_only(Path::new(merkle_path))?;
+        let root_id = merkle_db.get_root_id();
+        Ok(Self {
+            main_path,
+            merkle_db,
+            root_id,
+            pids: HashSet::new(),
+        })
+    }
+
+    fn open_attached(name: &str) -> Result<Self> {
+        if !is_valid_db_name(name) {
+            bail!("invalid database name {}", name);
+        }
+        let db = Self::open(attached_main_path(name), &attached_merkle_path(name))?;
+        if db.merkle_db.get_page_size() != page_size() {
+            bail!("page size of {} differs from the main database", name);
+        }
+        Ok(db)
+    }
+}
+
+// what the server did for one connection, logged when it ends
+#[derive(Debug, Default, Serialize)]
+struct SessionStats {
+    pages_sent: usize,
+    confirms: usize,
+    matched_at_leaf: usize,
+    matched_at_ancestor: usize,
+    mismatched: usize,
+    proof_bytes: usize,
+}
+
+// databases of one connection keyed by the index the user assigns, the main one is MAIN_DB
+struct Session {
+    dbs: BTreeMap<u32, ServedDb>,
+    cur: u32,
+    stats: SessionStats,
+}
+
+impl Session {
+    fn new() -> Result<Self> {
+        let main = ServedDb::open(MAIN_PATH.to_string(), MERKLE_PATH)?;
+        set_page_size(main.merkle_db.get_page_size())?;
+        Ok(Self {
+            dbs: BTreeMap::from([(MAIN_DB as u32, main)]),
+            cur: MAIN_DB as u32,
+            stats: SessionStats::default(),
+        })
+    }
+
+    fn cur(&mut self) -> &mut ServedDb {
+        self.dbs
+            .get_mut(&self.cur)
+            .expect("current database not attached")
+    }
+
+    // handle ATTACH and SELECT_DB, return false for any other flag
+    fn handle_db_msg(&mut self, stream: &mut TcpStream, flag: u32, p_id: PageId) -> Result<bool> {
+        if flag == ATTACH {
+            stream.write_all(&YES_FLAG.to_le_bytes())?;
+            let mut len_buf = [0; 8];
+            stream.read_exact(&mut len_buf)?;
+            let len = u64::from_le_bytes(len_buf) as usize;
+            if len > MSG_BUF_LEN {
+                bail!("database name too long");
+            }
+            let mut name = vec![0; len];
+            stream.read_exact(&mut name)?;
+            let name = String::from_utf8(name)?;
+            let res = if p_id.get_id() == MAIN_DB as u32 {
+                Err(anyhow!("index of the main database cannot be reused"))
+            } else {
+                ServedDb::open_attached(&name)
+            };
+            match res {
+                Ok(db) => {
+                    debug!("attach {} as database {}", name, p_id);
+                    self.dbs.insert(p_id.get_id(), db);
+                    stream.write_all(&YES_FLAG.to_le_bytes())?;
+                }
+                Err(e) => {
+                    warn!("failed to attach {}: {:?}", name, e);
+                    stream.write_all(&NO_FLAG.to_le_bytes())?;
+                }
+            }
+            Ok(true)
+        } else if flag == SELECT_DB {
+            if !self.dbs.contains_key(&p_id.get_id()) {
+                bail!("database {} is not attached", p_id);
+            }
+            self.cur = p_id.get_id();
+            stream.write_all(&YES_FLAG.to_le_bytes())?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    // one proof per database, each against the root of its own tree
+    fn send_proof(&mut self, stream: &mut TcpStream) -> Result<()> {
+        let mut bundle = Vec::<(u32, Proof)>::new();
+        for (idx, db) in &self.dbs {
+            let mut ctx = ReadContext::new(&db.merkle_db, db.root_id)?;
+            for p_id in &db.pids {
+                ctx.query(*p_id)?;
+            }
+            bundle.push((*idx, ctx.into_proof()));
+        }
+        let bytes = bincode::serialize(&bundle)?;
+        let bytes_len = bytes.len();
+        self.stats.proof_bytes += bytes_len;
+        let _w_amt = stream.write(&bytes_len.to_le_bytes())?;
+        let mut buf = [0; MSG_BUF_LEN];
+        let _bytes_read = stream.read(&mut buf)?;
+        let flag = bincode::deserialize::<u32>(&buf)?;
+        if flag == YES_FLAG {
+            stream.write_all(&bytes)?;
+        } else {
+            bail!("invalid signal for proof transmission");
+        }
+        Ok(())
+    }
+}
+
+fn handle_sender(mut stream: TcpStream, follower: bool) -> Result<()> {
+    let mut buf = [0; MSG_BUF_LEN];
     let _bytes_read = stream.read(&mut buf)?;
     let flag = bincode::deserialize::<u32>(&buf)?;
+    if flag == SUBMIT_WRITE {
+        if follower {
+            stream.write_all(&NO_FLAG.to_le_bytes())?;
+            bail!("a follower does not accept writes");
+        }
+        // takes the snapshot lock for writing itself
+        return apply_writes(&mut stream);
+    }
+
+    // pages and proofs of one query come from the same checkpoint
+    let _snapshot = lock_snapshot_shared()?;
+    if flag == REPLICATE {
+        return serve_follower(&mut stream);
+    }
+    let mut session = Session::new()?;
     if flag == NO_CACHE {
         let _w_amt = stream.write(&YES_FLAG.to_le_bytes())?;
         // hand_shake finished
-        handle_no_cache(&mut stream, ctx)?;
+        handle_no_cache(&mut stream, &mut session)?;
     } else if flag == BOTH_CACHE {
         let _w_amt = stream.write(&YES_FLAG.to_le_bytes())?;
         // hand_shake finished
-        handle_both_cache(&mut stream, ctx, &merkle_db)?;
+        handle_both_cache(&mut stream, &mut session)?;
+    } else if flag == STREAM_VERIFY {
+        let _w_amt = stream.write(&YES_FLAG.to_le_bytes())?;
+        // hand_shake finished
+        handle_stream_verify(&mut stream, &mut session)?;
+    } else if flag == FETCH_VBF {
+        send_vbf(&mut stream)?;
+        return Ok(());
     }
+    info!("session stats: {}", serde_json::to_string(&session.stats)?);
 
     Ok(())
 }
 
-fn handle_both_cache(
-    stream: &mut TcpStream,
-    mut ctx: ReadContext<MerkleDB>,
-    merkle_db: &MerkleDB,
-) -> Result<()> {
-    let mut pids = HashSet::new();
-
+fn handle_both_cache(stream: &mut TcpStream, session: &mut Session) -> Result<()> {
     loop {
-        let mut buff = [0; PAGE_SIZE as usize];
+        let mut buff = [0; MSG_BUF_LEN];
         let _bytes_read = stream.read(&mut buff)?;
         let (flag, p_id, digs) = bincode::deserialize::<(u32, PageId, Vec<Digest>)>(&buff)?;
         if flag == END {
             debug!("end flag received");
             // query finished, generate proof
-            for p_id in pids {
-                ctx.query(p_id)?;
-            }
-            let p = ctx.into_proof();
-            let bytes = bincode::serialize(&p)?;
-            let bytes_len = bytes.len();
-            let _w_amt = stream.write(&bytes_len.to_le_bytes())?;
-            let mut buf = [0; PAGE_SIZE as usize];
-            let _bytes_read = stream.read(&mut buf)?;
-            let flag = bincode::deserialize::<u32>(&buf)?;
-            if flag == YES_FLAG {
-                let _w_amt = stream.write(&bytes)?;
-            } else {
-                bail!("invalid signal for proof transmission");
-            }
+            session.send_proof(stream)?;
             break;
         } else if flag == CONFIRM {
             debug!("confirm flag received, the id is {}", p_id);
-            let (match_flag, pos) = confirm(p_id, &digs, merkle_db)?;
+            let (match_flag, pos) = confirm(p_id, &digs, &session.cur().merkle_db)?;
+            let stats = &mut session.stats;
+            stats.confirms += 1;
+            match (match_flag, pos.0) {
+                (true, 0) => stats.matched_at_leaf += 1,
+                (true, _) => stats.matched_at_ancestor += 1,
+                // the current page is sent instead
+                (false, _) => {
+                    stats.mismatched += 1;
+                    stats.pages_sent += 1;
+                }
+            }
+            let db = session.cur();
 
             if match_flag {
                 debug!("match, return YES_FLAG, then (h, w)");
 
                 let _w_amt = stream.write(&YES_FLAG.to_le_bytes())?;
-                let mut buff = [0; PAGE_SIZE as usize];
+                let mut buff = [0; MSG_BUF_LEN];
                 let _bytes_read = stream.read(&mut buff)?;
                 let _receipt = bincode::deserialize::<u32>(&buff)?;
 
                 let transfer_data = pos;
                 let bytes = bincode::serialize(&transfer_data).expect("failed to serialize");
                 let _w_amt = stream.write(&bytes)?;
-                pids.insert(p_id);
+                db.pids.insert(p_id);
             } else {
                 debug!("not match, return NO_FLAG, then bytes");
                 let _w_amt = stream.write(&NO_FLAG.to_le_bytes())?;
-                let mut buff = [0; PAGE_SIZE as usize];
+                let mut buff = [0; MSG_BUF_LEN];
                 let _bytes_read = stream.read(&mut buff)?;
                 let _receipt = bincode::deserialize::<u32>(&buff)?;
-                let bytes = query_page(p_id);
-                pids.insert(p_id);
-                let _w_amt = stream.write(&bytes)?;
+                let bytes = query_page(&db.main_path, p_id);
+                db.pids.insert(p_id);
+                stream.write_all(&bytes)?;
             }
         } else if flag == QUERY {
             debug!("query flag received, the id is {}", p_id);
-            pids.insert(p_id);
-            let p_cont = query_page(p_id);
-            let _w_amt = stream.write(&p_cont)?;
-        } else {
+            session.stats.pages_sent += 1;
+            let db = session.cur();
+            db.pids.insert(p_id);
+            let p_cont = query_page(&db.main_path, p_id);
+            stream.write_all(&p_cont)?;
+        } else if !session.handle_db_msg(stream, flag, p_id)? {
             debug!("invalid signal received: {}, {}, {:?}", flag, p_id, digs);
             bail!("Invalid signal.");
         }
@@ -132,40 +316,27 @@ fn confirm(p_id: PageId, digs: &Vec<Digest>, merkle_db: &MerkleDB) -> Result<(bo
     Ok((flag, pos))
 }
 
-fn handle_no_cache(stream: &mut TcpStream, mut ctx: ReadContext<MerkleDB>) -> Result<()> {
+fn handle_no_cache(stream: &mut TcpStream, session: &mut Session) -> Result<()> {
     debug!("handle no cache");
-    let mut pids = HashSet::<PageId>::new();
     loop {
-        let mut buff = [0; PAGE_SIZE as usize];
+        let mut buff = [0; MSG_BUF_LEN];
         let _bytes_read = stream.read(&mut buff)?;
         let (flag, p_id, digs) = bincode::deserialize::<(u32, PageId, Vec<Digest>)>(&buff)?;
         debug!("received flag: {}, p_id: {}, digs: {:?}", flag, p_id, digs);
         if flag == END {
             debug!("query finished, generate proof");
-            for p_id in pids {
-                ctx.query(p_id)?;
-            }
-            let p = ctx.into_proof();
-            let bytes = bincode::serialize(&p)?;
-            let bytes_len = bytes.len();
-            let _w_amt = stream.write(&bytes_len.to_le_bytes())?;
-            let mut buf = [0; PAGE_SIZE as usize];
-            let _bytes_read = stream.read(&mut buf)?;
-            let flag = bincode::deserialize::<u32>(&buf)?;
-            if flag == YES_FLAG {
-                let _w_amt = stream.write(&bytes)?;
-            } else {
-                bail!("invalid signal for proof transmission");
-            }
+            session.send_proof(stream)?;
             break;
         } else if flag == QUERY {
             debug!("query page {}...", p_id);
             // query page
-            pids.insert(p_id);
-            let p_cont = query_page(p_id);
-            let _w_amt = stream.write(&p_cont)?;
+            session.stats.pages_sent += 1;
+            let db = session.cur();
+            db.pids.insert(p_id);
+            let p_cont = query_page(&db.main_path, p_id);
+            stream.write_all(&p_cont)?;
             debug!("page bytes has been sent to user");
-        } else {
+        } else if !session.handle_db_msg(stream, flag, p_id)? {
             bail!("Invalid signal");
         }
     }
@@ -173,10 +344,96 @@ fn handle_no_cache(stream: &mut TcpStream, mut ctx: ReadContext<MerkleDB>) -> Re
     Ok(())
 }
 
-fn query_page(p_id: PageId) -> [u8; PAGE_SIZE as usize] {
-    let mut file = File::open(Path::new(MAIN_PATH)).expect("failed to open file");
-    let ofst = p_id.get_id() as u64 * PAGE_SIZE as u64;
-    let mut buf: [u8; PAGE_SIZE as usize] = [0; PAGE_SIZE as usize];
+// every page is sent along with its own proof, no proof is generated at the end
+fn handle_stream_verify(stream: &mut TcpStream, session: &mut Session) -> Result<()> {
+    debug!("handle stream verify");
+    loop {
+        let mut buff = [0; MSG_BUF_LEN];
+        let _bytes_read = stream.read(&mut buff)?;
+        let (flag, p_id, _digs) = bincode::deserialize::<(u32, PageId, Vec<Digest>)>(&buff)?;
+        if flag == END {
+            debug!("query finished");
+            break;
+        } else if flag == QUERY {
+            debug!("query page {} with proof...", p_id);
+            session.stats.pages_sent += 1;
+            let db = session.cur();
+            let mut ctx = ReadContext::new(&db.merkle_db, db.root_id)?;
+            ctx.query(p_id)?;
+            let p = ctx.into_proof();
+            let p_cont = query_page(&db.main_path, p_id);
+            let bytes = bincode::serialize(&(p_cont, p))?;
+            // length prefix and payload in one write to avoid an extra round of delayed ack
+            let mut msg = (bytes.len() as u64).to_le_bytes().to_vec();
+            msg.extend(bytes);
+            stream.write_all(&msg)?;
+        } else if !session.handle_db_msg(stream, flag, p_id)? {
+            bail!("Invalid signal");
+        }
+    }
+
+    Ok(())
+}
+
+// the vbf saved with the merkle tree, by the writer or by the host of the v2fs-sgx enclave,
+// read under the same snapshot as the pages; the user sends the seed and counter of its copy
+// and gets the slots changed since then
+fn send_vbf(stream: &mut TcpStream) -> Result<()> {
+    let vbf = match VersionBloomFilter::load(Path::new(VBF_PATH)) {
+        Ok(vbf) => vbf,
+        Err(e) => {
+            warn!("failed to load vbf: {:?}", e);
+            stream.write_all(&NO_FLAG.to_le_bytes())?;
+            return Ok(());
+        }
+    };
+    stream.write_all(&YES_FLAG.to_le_bytes())?;
+    let mut buf = [0; 16];
+    stream.read_exact(&mut buf)?;
+    let (seed, counter) = bincode::deserialize::<(u64, u64)>(&buf)?;
+    let bytes = bincode::serialize(&vbf.update_since(seed, counter))?;
+    let mut msg = (bytes.len() as u64).to_le_bytes().to_vec();
+    msg.extend(bytes);
+    stream.write_all(&msg)?;
+    Ok(())
+}
+
+// statements of one user applied in a single transaction through the server vfs, the user gets
+// the transition of the tree and checks it against the root it trusts
+fn apply_writes(stream: &mut TcpStream) -> Result<()> {
+    stream.write_all(&YES_FLAG.to_le_bytes())?;
+    let buf = read_msg(stream)?;
+    let stmts = bincode::deserialize::<Vec<String>>(&buf)?;
+
+    let res = {
+        let _writer = WRITE_LOCK.lock().expect("write lock poisoned");
+        update_db_batch(&stmts)
+    };
+    let res = match res {
+        Ok(t) => {
+            info!(
+                "{} statements applied, {} pages written",
+                stmts.len(),
+                t.iter().map(|t| t.modif.len()).sum::<usize>()
+            );
+            Ok(t)
+        }
+        Err(e) => {
+            warn!("failed to apply writes: {:?}", e);
+            Err(format!("{:#}", e))
+        }
+    };
+    let bytes = bincode::serialize(&res)?;
+    let mut msg = (bytes.len() as u64).to_le_bytes().to_vec();
+    msg.extend(bytes);
+    stream.write_all(&msg)?;
+    Ok(())
+}
+
+fn query_page(main_path: &str, p_id: PageId) -> Vec<u8> {
+    let mut file = File::open(Path::new(main_path)).expect("failed to open file");
+    let ofst = p_id.get_id() as u64 * page_size() as u64;
+    let mut buf = vec![0; page_size() as usize];
     match file.seek(SeekFrom::Start(ofst)) {
         Ok(o) => {
             if o != ofst {
@@ -198,11 +455,45 @@ fn query_page(p_id: PageId) -> [u8; PAGE_SIZE as usize] {
     buf
 }
 
+// pull every new version the primary publishes, a failed pull leaves the last verified one served
+fn follow(primary: String, trusted: PathBuf, interval: Duration) {
+    loop {
+        thread::sleep(interval);
+        match sync_from(&primary, &trusted) {
+            Ok(Some(pages)) => info!("replica moved to the trusted root, {} pages pulled", pages),
+            Ok(None) => {}
+            Err(e) => warn!(
+                "replication failed, serving the last verified version: {:?}",
+                e
+            ),
+        }
+    }
+}
+
 fn main() -> Result<()> {
     init_tracing_subscriber("info")?;
-    // Enable port 7878 binding
-    let receiver_listener =
-        TcpListener::bind("127.0.0.1:7878").expect("Failed and bind with the sender");
+    let opts = Opt::from_args();
+    let follower = opts.follow.is_some();
+    if let Some(primary) = opts.follow.clone() {
+        let trusted = opts
+            .trusted
+            .clone()
+            .context("a follower needs the trusted root of the primary")?;
+        let interval = Duration::from_millis(opts.sync_interval_ms);
+        // nothing is served before the first verified version
+        while let Err(e) = sync_from(&primary, &trusted) {
+            warn!("initial replication failed: {:?}", e);
+            thread::sleep(interval);
+        }
+        set_page_size(get_page_size()?)?;
+        thread::spawn(move || follow(primary, trusted, interval));
+    } else {
+        // users submit writes that go through the server vfs like those of the local writer
+        set_page_size(get_page_size()?)?;
+        register_server_vfs(opts.map_size, opts.hash_num)?;
+        recover_db()?;
+    }
+    let receiver_listener = TcpListener::bind(&opts.addr).expect("Failed and bind with the sender");
     // Getting a handle of the underlying thread.
     // listen to incoming connections messages and bind them to a sever socket address.
     for stream in receiver_listener.incoming() {
@@ -210,7 +501,7 @@ fn main() -> Result<()> {
         // let the receiver connect with the sender
         let _handle = thread::spawn(move || {
             //receiver failed to read from the stream
-            handle_sender(stream).unwrap_or_else(|error| eprintln!("{:?}", error))
+            handle_sender(stream, follower).unwrap_or_else(|error| eprintln!("{:?}", error))
         });
     }
     Ok(())
@@ -0,0 +1,95 @@
+#[macro_use]
+extern crate tracing;
+
+use anyhow::{bail, Context, Result};
+use serde::Serialize;
+use std::fs;
+use structopt::StructOpt;
+use v2fs_vsqlite::{
+    query::{recover_db, update_db_batch},
+    script::load_query_wkld,
+    utils::{get_page_size, init_tracing_subscriber, register_server_vfs, Time},
+    vfs::{set_page_size, GLOBAL_TS},
+};
+
+#[derive(StructOpt, Debug)]
+struct Opt {
+    // statements separated by `;`, like a query workload
+    #[structopt(short, long, default_value = "./query/update_wkld.txt")]
+    workload_path: String,
+
+    // statements applied in one transaction, each batch gets a new version
+    #[structopt(short, long, default_value = "1")]
+    batch_size: usize,
+
+    #[structopt(short, long, default_value = "10000")]
+    map_size: usize,
+
+    #[structopt(short, long, default_value = "5")]
+    hash_num: u32,
+
+    // write per-batch timings to this file as json
+    #[structopt(long)]
+    stats: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct BatchInfo {
+    version: u32,
+    stmts: usize,
+    pages: usize,
+    time: Time,
+}
+
+fn main() -> Result<()> {
+    init_tracing_subscriber("info")?;
+    let opts = Opt::from_args();
+    if opts.batch_size == 0 {
+        bail!("batch size must be positive");
+    }
+    let stmts = load_query_wkld(&opts.workload_path)
+        .with_context(|| format!("failed to read {}", opts.workload_path))?;
+
+    set_page_size(get_page_size()?)?;
+    register_server_vfs(opts.map_size, opts.hash_num)?;
+    recover_db()?;
+
+    let mut infos = Vec::new();
+    for (i, batch) in stmts.chunks(opts.batch_size).enumerate() {
+        let timer = howlong::ProcessCPUTimer::new();
+        let transitions = update_db_batch(batch)?;
+        let time = Time::from(timer.elapsed());
+        let info = BatchInfo {
+            version: unsafe { GLOBAL_TS },
+            stmts: batch.len(),
+            pages: transitions.iter().map(|t| t.modif.len()).sum(),
+            time,
+        };
+        info!(
+            "batch {}: {} statements, {} pages at version {} in {}ms",
+            i,
+            info.stmts,
+            info.pages,
+            info.version,
+            info.time.real / 1000
+        );
+        infos.push(info);
+    }
+
+    let total_t: u64 = infos.iter().map(|info| info.time.real).sum();
+    let batch_num = infos.len().max(1) as f64;
+    info!(
+        "{} statements in {} batches, average per batch: {}ms, per statement: {}ms",
+        stmts.len(),
+        infos.len(),
+        total_t as f64 / (batch_num * 1000.0),
+        total_t as f64 / (stmts.len().max(1) as f64 * 1000.0)
+    );
+    if let Some(path) = opts.stats {
+        fs::write(&path, serde_json::to_vec_pretty(&infos)?)
+            .with_context(|| format!("failed to write stats {:?}", path))?;
+        info!("stats of {} batches written to {}", infos.len(), path);
+    }
+
+    Ok(())
+}
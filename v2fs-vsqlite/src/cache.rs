@@ -5,11 +5,16 @@ pub mod non_leaf;
 use self::{hash::merge_hash, leaf::CacheLeafNode, non_leaf::CacheNonLeafNode};
 use crate::{
     digest::{Digest, Digestible},
+    eviction::Eviction,
     merkle_cb_tree::NodeId,
-    vfs::PAGE_SIZE,
+    vbf::VersionBloomFilter,
+    verified_cache::{
+        compression::{Compression, PageBytes},
+        store::{NodeStore, TreeNode},
+        VerifiedCache,
+    },
     PageId,
 };
-use lru::LruCache;
 
 #[derive(Clone)]
 pub(crate) enum CacheNode {
@@ -17,25 +22,52 @@ pub(crate) enum CacheNode {
     NonLeaf(CacheNonLeafNode),
 }
 
-impl CacheNode {
-    pub(crate) fn is_valid(&self) -> bool {
+impl TreeNode for CacheNode {
+    fn get_id(&self) -> NodeId {
+        match self {
+            CacheNode::Leaf(l) => l.get_id(),
+            CacheNode::NonLeaf(n) => n.get_id(),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
         match self {
             CacheNode::Leaf(l) => l.is_valid(),
             CacheNode::NonLeaf(n) => n.is_valid(),
         }
     }
 
-    pub(crate) fn validate(&mut self) {
+    fn validate(&mut self) {
         match self {
             CacheNode::Leaf(l) => l.validate(),
             CacheNode::NonLeaf(n) => n.validate(),
         }
     }
 
-    pub(crate) fn get_id(&self) -> NodeId {
+    fn unconfirm(&mut self) {
         match self {
-            CacheNode::Leaf(l) => l.get_id(),
-            CacheNode::NonLeaf(n) => n.get_id(),
+            CacheNode::Leaf(l) => l.unconfirm(),
+            CacheNode::NonLeaf(n) => n.unconfirm(),
+        }
+    }
+
+    fn get_bytes(&self) -> Option<Box<[u8]>> {
+        match self {
+            CacheNode::Leaf(l) => l.get_bytes(),
+            CacheNode::NonLeaf(_) => None,
+        }
+    }
+
+    fn merge(left: &Self, right: &Self) -> Self {
+        let id = left.get_id().get_parent_id();
+        let hash = merge_hash(&left.to_digest(), &right.to_digest());
+        CacheNode::NonLeaf(CacheNonLeafNode::new(id, hash))
+    }
+
+    fn heap_size(&self) -> usize {
+        match self {
+            CacheNode::Leaf(l) => l.heap_size(),
+            CacheNode::NonLeaf(_) => 0,
         }
     }
 }
@@ -49,146 +81,59 @@ impl Digestible for CacheNode {
     }
 }
 
+// pages confirmed by the server, used by the intra- and inter-query levels
 #[derive(Debug)]
 pub struct Cache {
-    lru: LruCache<Digest, CacheNode>,
+    store: NodeStore<CacheNode>,
+    compression: Compression,
 }
 
 impl Cache {
-    pub fn new(cap: usize) -> Self {
-        Self {
-            lru: LruCache::<Digest, CacheNode>::new(cap),
-        }
+    // `budget` in bytes
+    pub fn new(budget: usize) -> Self {
+        Self::with_eviction(budget, Eviction::default())
     }
 
-    pub(crate) fn get_node(&mut self, key: &Digest) -> Option<&CacheNode> {
-        self.lru.get(key)
-    }
-
-    pub(crate) fn get_node_mut(&mut self, key: &Digest) -> Option<&mut CacheNode> {
-        self.lru.get_mut(key)
-    }
-
-    // push node into cache, if some node is popped out, remove all its parents
-    pub(crate) fn push_node(&mut self, id: NodeId, node: CacheNode) {
-        let hash = id.to_digest();
-        if let Some((_, n)) = self.lru.push(hash, node) {
-            let mut cur_id = n.get_id();
-            let mut affected_pare_hashes = Vec::<Digest>::new();
-            while let Some(p) = self.find_parent(cur_id) {
-                match p {
-                    CacheNode::Leaf(_) => {
-                        panic!("impossible to be a leaf")
-                    }
-                    CacheNode::NonLeaf(n) => {
-                        affected_pare_hashes.push(n.get_id().to_digest());
-                        cur_id = n.get_id();
-                    }
-                }
-            }
-            for dig in affected_pare_hashes {
-                self.lru.pop(&dig);
-            }
+    pub fn with_eviction(budget: usize, eviction: Eviction) -> Self {
+        Self::with_options(budget, eviction, Compression::default())
+    }
+
+    // cached pages are compressed, the budget counts their compressed size
+    pub fn with_options(budget: usize, eviction: Eviction, compression: Compression) -> Self {
+        Self {
+            store: NodeStore::new(budget, eviction),
+            compression,
         }
     }
+}
 
-    pub fn clear(&mut self) {
-        self.lru.clear();
+impl VerifiedCache for Cache {
+    fn lookup(&mut self, p_id: PageId) -> Option<(Box<[u8]>, bool)> {
+        self.store.lookup(NodeId::from_page_id(p_id))
     }
 
-    // change `is_valid` for all nodes to "false"
-    pub fn unconfirm(&mut self) {
-        for (_, cache_n) in self.lru.iter_mut() {
-            match cache_n {
-                CacheNode::Leaf(l) => {
-                    l.unconfirm();
-                }
-                CacheNode::NonLeaf(n) => n.unconfirm(),
-            }
-        }
+    fn confirm_path(&mut self, p_id: PageId) -> Vec<Digest> {
+        self.store.confirm_path(NodeId::from_page_id(p_id))
     }
 
-    // change `is_valid` for all covered nodes of a sub-root to "true"
-    pub(crate) fn confirm(&mut self, root_id: NodeId) {
-        let mut covered_ids = Vec::new();
-        let h = root_id.get_height();
-        let w = root_id.get_width();
-        for i in 0..h + 1 {
-            let a = 2_i32.pow(h - i) as u32;
-            for j in (w * a)..((w + 1) * a) {
-                covered_ids.push(NodeId::new(i, j as u32));
-            }
-        }
-        for id in covered_ids {
-            if let Some(n) = self.get_node_mut(&id.to_digest()) {
-                n.validate();
-            } else {
-                warn!("Cannot find a node during cache confirming");
-            }
-        }
+    fn confirm(&mut self, root_id: NodeId) {
+        self.store.confirm(root_id);
     }
 
-    pub fn cache_size_and_height(&self) -> (u32, u32) {
-        let mut height = 0;
-        let mut size = 0;
-        for (_, n) in self.lru.iter() {
-            match n {
-                CacheNode::Leaf(_) => {
-                    size += 4100;
-                }
-                CacheNode::NonLeaf(n) => {
-                    size += 40;
-                    let id = n.get_id();
-                    let h = id.get_height();
-                    if height < h {
-                        height = h;
-                    }
-                }
-            }
-        }
+    fn insert(&mut self, p_id: PageId, bytes: Box<[u8]>, _vbf: &VersionBloomFilter) {
+        let leaf = CacheLeafNode::new(p_id, PageBytes::new(bytes, self.compression));
+        self.store.insert(CacheNode::Leaf(leaf));
+    }
 
-        (size, height)
+    fn unconfirm(&mut self) {
+        self.store.unconfirm();
     }
 
-    pub(crate) fn find_parent(&mut self, n_id: NodeId) -> Option<&CacheNode> {
-        let height = n_id.get_height() + 1;
-        let width = n_id.get_width() / 2;
-        let parent_id = NodeId::new(height, width);
-        self.get_node(&parent_id.to_digest())
+    fn clear(&mut self) {
+        self.store.clear();
     }
 
-    pub(crate) fn find_sib(&mut self, id: NodeId) -> Option<&CacheNode> {
-        let sib_key = id.get_sib_id().to_digest();
-        if let Some(node) = self.get_node(&sib_key) {
-            if node.is_valid() {
-                return Some(node);
-            }
-        }
-        None
-    }
-
-    pub(crate) fn has_sib(&self, id: NodeId) -> bool {
-        let sib_key = id.get_sib_id().to_digest();
-        self.lru.contains(&sib_key)
-    }
-
-    pub(crate) fn insert(&mut self, p_id: PageId, bytes: Box<[u8; PAGE_SIZE as usize]>) {
-        let new_n = CacheLeafNode::new(p_id, bytes);
-        let mut cur_id = NodeId::from_page_id(p_id);
-        let mut cur_hash = new_n.to_digest();
-        self.push_node(cur_id, CacheNode::Leaf(new_n));
-
-        while let Some(sib) = self.find_sib(cur_id) {
-            let parent_id = cur_id.get_parent_id();
-            let parent_hash = if cur_id.is_even() {
-                merge_hash(&cur_hash, &sib.to_digest())
-            } else {
-                merge_hash(&sib.to_digest(), &cur_hash)
-            };
-            let parent = CacheNonLeafNode::new(parent_id, parent_hash);
-            cur_hash = parent_hash;
-            cur_id = parent_id;
-            self.push_node(parent_id, CacheNode::NonLeaf(parent));
-        }
+    fn cache_size_and_height(&self) -> (usize, u32) {
+        self.store.cache_size_and_height()
     }
 }
@@ -1,14 +1,15 @@
 use super::{hash::leaf_hash, *};
+use crate::verified_cache::compression::PageBytes;
 
 #[derive(Clone)]
 pub(crate) struct CacheLeafNode {
     id: NodeId,
-    bytes: Box<[u8; PAGE_SIZE as usize]>,
+    bytes: PageBytes,
     is_valid: bool,
 }
 
 impl CacheLeafNode {
-    pub(crate) fn new(p_id: PageId, bytes: Box<[u8; PAGE_SIZE as usize]>) -> Self {
+    pub(crate) fn new(p_id: PageId, bytes: PageBytes) -> Self {
         Self {
             id: NodeId::new(0, p_id.get_id()),
             bytes,
@@ -20,8 +21,12 @@ impl CacheLeafNode {
         self.id
     }
 
-    pub(crate) fn get_bytes(&self) -> Box<[u8; PAGE_SIZE as usize]> {
-        self.bytes.clone()
+    pub(crate) fn get_bytes(&self) -> Option<Box<[u8]>> {
+        self.bytes.get()
+    }
+
+    pub(crate) fn heap_size(&self) -> usize {
+        self.bytes.heap_size()
     }
 
     pub(crate) fn is_valid(&self) -> bool {
@@ -39,6 +44,6 @@ impl CacheLeafNode {
 
 impl Digestible for CacheLeafNode {
     fn to_digest(&self) -> Digest {
-        leaf_hash(self.id.get_width(), &self.bytes.to_digest())
+        leaf_hash(self.id.get_width(), &self.bytes.digest())
     }
 }
@@ -0,0 +1,163 @@
+pub mod arc;
+pub mod cost;
+pub mod lfu;
+pub mod lru;
+pub mod two_q;
+
+use self::{arc::Arc, cost::CostAware, lfu::Lfu, lru::Lru, two_q::TwoQ};
+use crate::merkle_cb_tree::NodeId;
+use anyhow::{bail, Error};
+use std::{fmt, str::FromStr};
+
+// decides which merkle node leaves a verified cache, nodes are tracked by id only and the
+// cache asks for victims until the incoming node fits its byte budget
+pub trait EvictionPolicy: fmt::Debug {
+    // a cached node was read
+    fn access(&mut self, id: &NodeId);
+    // start tracking a node that was just cached
+    fn insert(&mut self, id: NodeId);
+    // a node left the cache without being chosen as victim
+    fn remove(&mut self, id: &NodeId);
+    // pick and forget a node to make room for `incoming`, which is not tracked yet
+    fn evict(&mut self, incoming: &NodeId) -> Option<NodeId>;
+    fn clear(&mut self);
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Eviction {
+    #[default]
+    Lru,
+    Lfu,
+    Arc,
+    TwoQ,
+    // keeps nodes whose eviction would drop high merkle subtrees
+    Cost,
+}
+
+impl Eviction {
+    pub fn build(self) -> Box<dyn EvictionPolicy> {
+        match self {
+            Eviction::Lru => Box::new(Lru::default()),
+            Eviction::Lfu => Box::new(Lfu::default()),
+            Eviction::Arc => Box::new(Arc::default()),
+            Eviction::TwoQ => Box::new(TwoQ::default()),
+            Eviction::Cost => Box::new(CostAware::default()),
+        }
+    }
+}
+
+impl FromStr for Eviction {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "lru" => Eviction::Lru,
+            "lfu" => Eviction::Lfu,
+            "arc" => Eviction::Arc,
+            "2q" => Eviction::TwoQ,
+            "cost" => Eviction::Cost,
+            _ => bail!(
+                "unknown eviction policy {}, expect lru, lfu, arc, 2q or cost",
+                s
+            ),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Eviction, EvictionPolicy};
+    use crate::merkle_cb_tree::NodeId;
+
+    fn leaf(w: u32) -> NodeId {
+        NodeId::new(0, w)
+    }
+
+    // cache `id` in a cache holding at most `cap` nodes, return the victim
+    fn admit(
+        p: &mut dyn EvictionPolicy,
+        len: &mut usize,
+        cap: usize,
+        id: NodeId,
+    ) -> Option<NodeId> {
+        let victim = if *len >= cap { p.evict(&id) } else { None };
+        if victim.is_none() {
+            *len += 1;
+        }
+        p.insert(id);
+        victim
+    }
+
+    #[test]
+    fn test_eviction_parse() {
+        assert_eq!("2Q".parse::<Eviction>().unwrap(), Eviction::TwoQ);
+        assert!("mru".parse::<Eviction>().is_err());
+    }
+
+    #[test]
+    fn test_lru() {
+        let (mut p, mut len) = (Eviction::Lru.build(), 0);
+        assert_eq!(admit(&mut *p, &mut len, 2, leaf(0)), None);
+        assert_eq!(admit(&mut *p, &mut len, 2, leaf(1)), None);
+        p.access(&leaf(0));
+        assert_eq!(admit(&mut *p, &mut len, 2, leaf(2)), Some(leaf(1)));
+    }
+
+    #[test]
+    fn test_lfu() {
+        let (mut p, mut len) = (Eviction::Lfu.build(), 0);
+        admit(&mut *p, &mut len, 2, leaf(0));
+        admit(&mut *p, &mut len, 2, leaf(1));
+        p.access(&leaf(1));
+        p.access(&leaf(1));
+        p.access(&leaf(0));
+        assert_eq!(admit(&mut *p, &mut len, 2, leaf(2)), Some(leaf(0)));
+        p.access(&leaf(2));
+        p.access(&leaf(2));
+        // ties go to the least recently used
+        assert_eq!(admit(&mut *p, &mut len, 2, leaf(3)), Some(leaf(1)));
+    }
+
+    #[test]
+    fn test_arc() {
+        let (mut p, mut len) = (Eviction::Arc.build(), 0);
+        admit(&mut *p, &mut len, 2, leaf(0));
+        admit(&mut *p, &mut len, 2, leaf(1));
+        p.access(&leaf(0));
+        // 1 was seen once, 0 twice
+        assert_eq!(admit(&mut *p, &mut len, 2, leaf(2)), Some(leaf(1)));
+        // 1 comes back from the ghost list as frequent, which grows the recent side
+        assert_eq!(admit(&mut *p, &mut len, 2, leaf(1)), Some(leaf(2)));
+        assert_eq!(admit(&mut *p, &mut len, 2, leaf(3)), Some(leaf(0)));
+        p.remove(&leaf(3));
+        assert_eq!(p.evict(&leaf(4)), Some(leaf(1)));
+        assert_eq!(p.evict(&leaf(4)), None);
+    }
+
+    #[test]
+    fn test_two_q() {
+        let (mut p, mut len) = (Eviction::TwoQ.build(), 0);
+        for w in 0..4 {
+            assert_eq!(admit(&mut *p, &mut len, 4, leaf(w)), None);
+        }
+        // first-in leaves first however often it is read
+        p.access(&leaf(0));
+        assert_eq!(admit(&mut *p, &mut len, 4, leaf(4)), Some(leaf(0)));
+        // a ghost hit is promoted to the main queue
+        assert_eq!(admit(&mut *p, &mut len, 4, leaf(0)), Some(leaf(1)));
+        assert_eq!(admit(&mut *p, &mut len, 4, leaf(5)), Some(leaf(2)));
+        assert_eq!(admit(&mut *p, &mut len, 4, leaf(6)), Some(leaf(3)));
+    }
+
+    #[test]
+    fn test_cost_aware() {
+        let (mut p, mut len) = (Eviction::Cost.build(), 0);
+        admit(&mut *p, &mut len, 4, leaf(0));
+        admit(&mut *p, &mut len, 4, leaf(1));
+        admit(&mut *p, &mut len, 4, NodeId::new(1, 0));
+        admit(&mut *p, &mut len, 4, leaf(2));
+        // the oldest leaf would take its cached parent along, the newer one would not
+        assert_eq!(admit(&mut *p, &mut len, 4, leaf(3)), Some(leaf(2)));
+        assert_eq!(admit(&mut *p, &mut len, 4, leaf(4)), Some(leaf(3)));
+    }
+}
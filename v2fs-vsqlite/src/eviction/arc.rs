@@ -0,0 +1,98 @@
+use super::EvictionPolicy;
+use crate::merkle_cb_tree::NodeId;
+use lru::LruCache;
+
+// adaptive replacement cache (Megiddo and Modha), balancing recency and frequency with
+// ghost lists of recently evicted ids; the cache size `c` of the paper is the number of
+// cached nodes, which varies under a byte budget
+#[derive(Debug)]
+pub struct Arc {
+    // target size of `t1`
+    p: usize,
+    // seen once
+    t1: LruCache<NodeId, ()>,
+    // seen at least twice
+    t2: LruCache<NodeId, ()>,
+    // ghosts evicted from `t1` and `t2`
+    b1: LruCache<NodeId, ()>,
+    b2: LruCache<NodeId, ()>,
+}
+
+impl Default for Arc {
+    fn default() -> Self {
+        Self {
+            p: 0,
+            t1: LruCache::unbounded(),
+            t2: LruCache::unbounded(),
+            b1: LruCache::unbounded(),
+            b2: LruCache::unbounded(),
+        }
+    }
+}
+
+impl Arc {
+    // |t1| + |b1| <= c and |t1| + |t2| + |b1| + |b2| <= 2c, only once the incoming node is
+    // cached since `c` shrinks while evicting for it
+    fn trim_ghosts(&mut self) {
+        let c = self.t1.len() + self.t2.len();
+        while self.t1.len() + self.b1.len() > c && self.b1.pop_lru().is_some() {}
+        while c + self.b1.len() + self.b2.len() > 2 * c && self.b2.pop_lru().is_some() {}
+        self.p = self.p.min(c);
+    }
+}
+
+impl EvictionPolicy for Arc {
+    fn access(&mut self, id: &NodeId) {
+        if self.t1.pop(id).is_some() {
+            self.t2.put(*id, ());
+        } else {
+            self.t2.get(id);
+        }
+    }
+
+    fn insert(&mut self, id: NodeId) {
+        let (b1_len, b2_len) = (self.b1.len(), self.b2.len());
+        if self.b1.pop(&id).is_some() {
+            let c = self.t1.len() + self.t2.len() + 1;
+            self.p = c.min(self.p + (b2_len / b1_len).max(1));
+            self.t2.put(id, ());
+        } else if self.b2.pop(&id).is_some() {
+            self.p = self.p.saturating_sub((b1_len / b2_len).max(1));
+            self.t2.put(id, ());
+        } else {
+            self.t1.put(id, ());
+        }
+        self.trim_ghosts();
+    }
+
+    fn remove(&mut self, id: &NodeId) {
+        if self.t1.pop(id).is_none() {
+            self.t2.pop(id);
+        }
+    }
+
+    fn evict(&mut self, incoming: &NodeId) -> Option<NodeId> {
+        let t1_len = self.t1.len();
+        let in_b2 = self.b2.contains(incoming);
+        let victim = if t1_len > 0 && (t1_len > self.p || (in_b2 && t1_len == self.p))
+            || self.t2.is_empty()
+        {
+            let (id, _) = self.t1.pop_lru()?;
+            self.b1.put(id, ());
+            id
+        } else {
+            let (id, _) = self.t2.pop_lru()?;
+            self.b2.put(id, ());
+            id
+        };
+        Some(victim)
+    }
+
+    fn clear(&mut self) {
+        self.p = 0;
+        self.t1.clear();
+        self.t2.clear();
+        self.b1.clear();
+        self.b2.clear();
+    }
+}
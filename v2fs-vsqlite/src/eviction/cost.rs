@@ -0,0 +1,64 @@
+use super::EvictionPolicy;
+use crate::merkle_cb_tree::NodeId;
+use lru::LruCache;
+
+// least recent nodes considered for each eviction
+const WINDOW: usize = 8;
+
+// evicting a node also drops its cached ancestors, so among the least recent nodes the
+// victim is the one whose loss reaches the lowest height, which keeps high sub-roots that
+// confirm many pages at once
+#[derive(Debug)]
+pub struct CostAware {
+    order: LruCache<NodeId, ()>,
+}
+
+impl Default for CostAware {
+    fn default() -> Self {
+        Self {
+            order: LruCache::unbounded(),
+        }
+    }
+}
+
+impl CostAware {
+    // height of the highest node lost when `id` is evicted
+    fn loss(&self, id: NodeId) -> u32 {
+        let mut top = id;
+        while self.order.contains(&top.get_parent_id()) {
+            top = top.get_parent_id();
+        }
+        top.get_height()
+    }
+}
+
+impl EvictionPolicy for CostAware {
+    fn access(&mut self, id: &NodeId) {
+        self.order.get(id);
+    }
+
+    fn insert(&mut self, id: NodeId) {
+        self.order.put(id, ());
+    }
+
+    fn remove(&mut self, id: &NodeId) {
+        self.order.pop(id);
+    }
+
+    fn evict(&mut self, _incoming: &NodeId) -> Option<NodeId> {
+        // min_by_key keeps the first, i.e. least recent, of equal losses
+        let victim = self
+            .order
+            .iter()
+            .rev()
+            .take(WINDOW)
+            .map(|(k, _)| *k)
+            .min_by_key(|k| self.loss(*k))?;
+        self.order.pop(&victim);
+        Some(victim)
+    }
+
+    fn clear(&mut self) {
+        self.order.clear();
+    }
+}
@@ -0,0 +1,53 @@
+use super::EvictionPolicy;
+use crate::merkle_cb_tree::NodeId;
+use std::collections::{BTreeMap, HashMap};
+
+// least frequently used, ties broken by the least recent access
+#[derive(Debug, Default)]
+pub struct Lfu {
+    tick: u64,
+    // id -> (frequency, last access)
+    entries: HashMap<NodeId, (u64, u64)>,
+    order: BTreeMap<(u64, u64), NodeId>,
+}
+
+impl Lfu {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
+
+impl EvictionPolicy for Lfu {
+    fn access(&mut self, id: &NodeId) {
+        let tick = self.next_tick();
+        if let Some(key) = self.entries.get_mut(id) {
+            self.order.remove(key);
+            *key = (key.0 + 1, tick);
+            self.order.insert(*key, *id);
+        }
+    }
+
+    fn insert(&mut self, id: NodeId) {
+        let key = (1, self.next_tick());
+        self.entries.insert(id, key);
+        self.order.insert(key, id);
+    }
+
+    fn remove(&mut self, id: &NodeId) {
+        if let Some(key) = self.entries.remove(id) {
+            self.order.remove(&key);
+        }
+    }
+
+    fn evict(&mut self, _incoming: &NodeId) -> Option<NodeId> {
+        let (_, victim) = self.order.pop_first()?;
+        self.entries.remove(&victim);
+        Some(victim)
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
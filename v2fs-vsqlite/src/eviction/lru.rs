@@ -0,0 +1,38 @@
+use super::EvictionPolicy;
+use crate::merkle_cb_tree::NodeId;
+use lru::LruCache;
+
+#[derive(Debug)]
+pub struct Lru {
+    order: LruCache<NodeId, ()>,
+}
+
+impl Default for Lru {
+    fn default() -> Self {
+        Self {
+            order: LruCache::unbounded(),
+        }
+    }
+}
+
+impl EvictionPolicy for Lru {
+    fn access(&mut self, id: &NodeId) {
+        self.order.get(id);
+    }
+
+    fn insert(&mut self, id: NodeId) {
+        self.order.put(id, ());
+    }
+
+    fn remove(&mut self, id: &NodeId) {
+        self.order.pop(id);
+    }
+
+    fn evict(&mut self, _incoming: &NodeId) -> Option<NodeId> {
+        self.order.pop_lru().map(|(k, _)| k)
+    }
+
+    fn clear(&mut self) {
+        self.order.clear();
+    }
+}
@@ -0,0 +1,64 @@
+use super::EvictionPolicy;
+use crate::merkle_cb_tree::NodeId;
+use lru::LruCache;
+
+// full 2Q (Johnson and Shasha): first accesses wait in a fifo of a quarter of the cached
+// nodes, ids evicted from it are remembered for half as many and promoted to the main lru
+// when they come back
+#[derive(Debug)]
+pub struct TwoQ {
+    // read order is never updated, so it stays a fifo
+    a1_in: LruCache<NodeId, ()>,
+    a1_out: LruCache<NodeId, ()>,
+    am: LruCache<NodeId, ()>,
+}
+
+impl Default for TwoQ {
+    fn default() -> Self {
+        Self {
+            a1_in: LruCache::unbounded(),
+            a1_out: LruCache::unbounded(),
+            am: LruCache::unbounded(),
+        }
+    }
+}
+
+impl EvictionPolicy for TwoQ {
+    fn access(&mut self, id: &NodeId) {
+        self.am.get(id);
+    }
+
+    fn insert(&mut self, id: NodeId) {
+        if self.a1_out.pop(&id).is_some() {
+            self.am.put(id, ());
+        } else {
+            self.a1_in.put(id, ());
+        }
+    }
+
+    fn remove(&mut self, id: &NodeId) {
+        if self.a1_in.pop(id).is_none() {
+            self.am.pop(id);
+        }
+    }
+
+    fn evict(&mut self, _incoming: &NodeId) -> Option<NodeId> {
+        let cached = self.a1_in.len() + self.am.len();
+        if self.a1_in.len() > (cached / 4).max(1) || self.am.is_empty() {
+            let (id, _) = self.a1_in.pop_lru()?;
+            self.a1_out.put(id, ());
+            if self.a1_out.len() > (cached / 2).max(1) {
+                self.a1_out.pop_lru();
+            }
+            Some(id)
+        } else {
+            self.am.pop_lru().map(|(k, _)| k)
+        }
+    }
+
+    fn clear(&mut self) {
+        self.a1_in.clear();
+        self.a1_out.clear();
+        self.am.clear();
+    }
+}
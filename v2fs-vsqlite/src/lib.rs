@@ -5,17 +5,22 @@ extern crate lru;
 
 pub mod cache;
 pub mod digest;
+pub mod eviction;
 pub mod merkle_cb_tree;
 pub mod query;
+pub mod replica;
 pub mod script;
 pub mod simple_vcache;
+pub mod stats;
+pub mod trace;
 pub mod utils;
 pub mod vbf;
+pub mod verified_cache;
 pub mod verify;
 pub mod version_cache;
 pub mod vfs;
 
-use crate::merkle_cb_tree::NodeId;
+use crate::merkle_cb_tree::{transition::Transition, NodeId};
 use anyhow::{Context, Result};
 use cache::Cache;
 use digest::{Digest, Digestible};
@@ -26,10 +31,13 @@ use simple_vcache::SVCache;
 use std::collections::HashMap;
 use std::fs::{self, File};
 use std::net::TcpStream;
-use std::path::Path;
-use vbf::VersionBloomFilter;
+use std::path::{Path, PathBuf};
+use vbf::{VbfUpdate, VersionBloomFilter};
+use verified_cache::VerifiedCache;
 use version_cache::VCache;
-use vfs::{OpenAccess, OpenOptions, MERKLE_PATH};
+use vfs::{
+    error::Corrupt, page_size, shm::Shm, OpenAccess, OpenOptions, DEFAULT_PAGE_SIZE, MAIN_DB,
+};
 
 #[derive(
     Debug,
@@ -66,20 +74,27 @@ impl Digestible for PageId {
 #[derive(Debug)]
 pub struct ServerVfs {
     merkle_db_path: String,
-    pub map: HashMap<PageId, Digest>,
+    // images of the main database pages written by the open transaction, hashed and applied to
+    // the tree once it commits
+    pub dirty: HashMap<PageId, Box<[u8]>>,
+    // pages left in the main database when the open transaction shrank it
+    pub truncated: Option<u32>,
     pub vbf: VersionBloomFilter,
+    // tree transitions of the commits since the last `update_merkle_db`
+    pub committed: Vec<Transition>,
+    // wal-index of every database opened in wal mode, keyed by path
+    pub shm: HashMap<String, Shm>,
 }
 
 impl ServerVfs {
-    pub fn new(
-        merkle_db_path: String,
-        map: HashMap<PageId, Digest>,
-        vbf: VersionBloomFilter,
-    ) -> Self {
+    pub fn new(merkle_db_path: String, vbf: VersionBloomFilter) -> Self {
         Self {
             merkle_db_path,
-            map,
+            dirty: HashMap::new(),
+            truncated: None,
             vbf,
+            committed: Vec::new(),
+            shm: HashMap::new(),
         }
     }
 
@@ -123,6 +138,25 @@ pub enum Type {
     Both,
     BothBloom,
     SimpleBloom,
+    Stream,
+}
+
+// a remote database attached to every user connection, verified against its own root
+#[derive(Debug)]
+pub struct AttachedDb {
+    pub name: String,
+    pub map: HashMap<PageId, Digest>,
+    pub root: (Digest, u32),
+}
+
+impl AttachedDb {
+    pub fn new(name: String) -> Self {
+        Self {
+            name,
+            map: HashMap::new(),
+            root: (Digest::zero(), 0),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -134,6 +168,12 @@ pub struct UserVfs<'a, 'b> {
     stream: &'b mut TcpStream,
     pub map: &'a mut HashMap<PageId, Digest>,
     pub vbf: VersionBloomFilter,
+    // trusted (root hash, height) used by streaming verification
+    pub root: (Digest, u32),
+    pub stream_proof_s: usize,
+    pub attached: Vec<AttachedDb>,
+    // database the server currently serves within the query session
+    cur_db: usize,
 }
 
 impl<'a, 'b> UserVfs<'a, 'b> {
@@ -154,22 +194,94 @@ impl<'a, 'b> UserVfs<'a, 'b> {
             stream,
             map,
             vbf,
+            root: (Digest::zero(), 0),
+            stream_proof_s: 0,
+            attached: Vec::new(),
+            cur_db: MAIN_DB,
         }
     }
 
     pub fn set_vbf(&mut self, vbf: VersionBloomFilter) {
+        // nodes of the vcache keep the slots of their pages, which another seed moves
+        if !self.vbf.same_hashing(&vbf) {
+            debug!("switching to a vbf with {} slots", vbf.map_size());
+            self.vcache.clear();
+        }
         self.vbf = vbf;
     }
+
+    // the updated vbf is only used if it matches the digest published with the root
+    pub fn update_vbf(&mut self, update: VbfUpdate, published: Digest) -> Result<()> {
+        let vbf = match update {
+            VbfUpdate::Full(vbf) => vbf,
+            delta => {
+                let mut vbf = self.vbf.clone();
+                vbf.apply(delta)?;
+                vbf
+            }
+        };
+        let digest = vbf.to_digest();
+        if digest != published {
+            return Err(Corrupt(format!(
+                "vbf digest {} does not match the published {}",
+                digest, published
+            ))
+            .into());
+        }
+        self.set_vbf(vbf);
+        Ok(())
+    }
+
+    pub fn set_root(&mut self, root_hash: Digest, height: u32) {
+        self.root = (root_hash, height);
+        self.stream_proof_s = 0;
+    }
+
+    // cache the user reads through, none for levels without one
+    pub fn verified_cache(&mut self) -> Option<&mut dyn VerifiedCache> {
+        match self.tp {
+            Type::None | Type::Stream => None,
+            Type::Intra | Type::Both => Some(&mut *self.cache),
+            Type::BothBloom => Some(&mut *self.vcache),
+            Type::SimpleBloom => Some(&mut *self.svcache),
+        }
+    }
+
+    // every query opens a new session which starts on the main database
+    pub fn new_session(&mut self) {
+        self.cur_db = MAIN_DB;
+        for db in self.attached.iter_mut() {
+            db.map.clear();
+        }
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Parameter {
     root_id: Option<NodeId>,
+    // trees built before the page size was recorded all use 4096
+    #[serde(default = "default_page_size")]
+    page_size: u32,
+    // digest of the vbf that matches the root, users check the filter they fetch against it
+    #[serde(default)]
+    vbf_digest: Option<Digest>,
+}
+
+fn default_page_size() -> u32 {
+    DEFAULT_PAGE_SIZE
 }
 
 impl Parameter {
-    fn new(root_id: Option<NodeId>) -> Self {
-        Self { root_id }
+    fn new(root_id: Option<NodeId>, page_size: u32) -> Self {
+        Self {
+            root_id,
+            page_size,
+            vbf_digest: None,
+        }
+    }
+
+    pub fn get_page_size(&self) -> u32 {
+        self.page_size
     }
 
     pub fn get_height(&self) -> u32 {
@@ -182,9 +294,14 @@ impl Parameter {
     pub fn get_root_id(&self) -> Option<NodeId> {
         self.root_id
     }
+
+    pub fn get_vbf_digest(&self) -> Option<Digest> {
+        self.vbf_digest
+    }
 }
 
 pub struct MerkleDB {
+    path: PathBuf,
     param: Parameter,
     merkle_db: DB,
 }
@@ -199,6 +316,7 @@ impl MerkleDB {
         let mut opts = rocksdb::Options::default();
         opts.create_if_missing(true);
         Ok(Self {
+            path: path.to_path_buf(),
             param,
             merkle_db: DB::open(&opts, path.join("merkle.db"))?,
         })
@@ -206,6 +324,7 @@ impl MerkleDB {
 
     fn open(path: &Path) -> Result<Self> {
         Ok(Self {
+            path: path.to_path_buf(),
             param: serde_json::from_str::<Parameter>(&fs::read_to_string(
                 path.join("param.json"),
             )?)?,
@@ -216,6 +335,7 @@ impl MerkleDB {
     pub fn open_read_only(path: &Path) -> Result<Self> {
         let opts = Options::default();
         Ok(Self {
+            path: path.to_path_buf(),
             param: serde_json::from_str::<Parameter>(&fs::read_to_string(
                 path.join("param.json"),
             )?)?,
@@ -228,7 +348,7 @@ impl MerkleDB {
             Self::open(path)
         } else {
             info!("attention! merkle db create is called, path is {:?}", path);
-            Self::create(path, Parameter::new(None))
+            Self::create(path, Parameter::new(None, page_size()))
         }
     }
 
@@ -240,12 +360,28 @@ impl MerkleDB {
         self.param.get_root_id()
     }
 
+    pub fn get_page_size(&self) -> u32 {
+        self.param.get_page_size()
+    }
+
+    pub(crate) fn set_page_size(&mut self, page_size: u32) {
+        self.param.page_size = page_size;
+    }
+
+    pub fn get_vbf_digest(&self) -> Option<Digest> {
+        self.param.get_vbf_digest()
+    }
+
+    // written to param.json by the next `update_param`, along with the root
+    pub(crate) fn set_vbf_digest(&mut self, vbf_digest: Digest) {
+        self.param.vbf_digest = Some(vbf_digest);
+    }
+
     fn update_param(&mut self, new_root_id: Option<NodeId>) -> Result<()> {
-        let path = Path::new(MERKLE_PATH);
-        let param = Parameter::new(new_root_id);
+        self.param.root_id = new_root_id;
         fs::write(
-            path.join("param.json"),
-            serde_json::to_string_pretty(&param)?,
+            self.path.join("param.json"),
+            serde_json::to_string_pretty(&self.param)?,
         )?;
         Ok(())
     }
@@ -272,4 +408,9 @@ impl WriteInterface for MerkleDB {
         self.merkle_db.put(addr.as_bytes(), bytes)?;
         Ok(())
     }
+
+    fn remove_node(&mut self, addr: &Digest) -> Result<()> {
+        self.merkle_db.delete(addr.as_bytes())?;
+        Ok(())
+    }
 }
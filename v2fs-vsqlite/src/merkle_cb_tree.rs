@@ -10,9 +10,11 @@ use self::hash::id_hash;
 pub mod hash;
 pub mod proof;
 pub mod read;
+pub mod transition;
 pub mod write;
 
-#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Serialize, Deserialize)]
+// ordered by height, then width
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq, Ord, PartialOrd, Serialize, Deserialize)]
 pub struct NodeId(u32, u32);
 
 impl NodeId {
@@ -80,6 +82,7 @@ pub trait ReadInterface {
 
 pub trait WriteInterface {
     fn write_node(&mut self, addr: &Digest, node: &MerkleNode) -> Result<()>;
+    fn remove_node(&mut self, addr: &Digest) -> Result<()>;
 }
 
 pub trait MerkleNodeLoader {
@@ -1,5 +1,6 @@
 use super::{
     read::ReadContext,
+    transition::WriteProof,
     write::{Apply, WriteContext},
     MerkleNode, MerkleNodeLoader, NodeId,
 };
@@ -44,6 +45,9 @@ impl TestTree {
     fn apply(&mut self, apply: Apply) {
         self.root_id = apply.root_id;
         self.nodes.extend(apply.nodes.into_iter());
+        for addr in &apply.removed {
+            self.nodes.remove(addr);
+        }
     }
 }
 
@@ -336,6 +340,108 @@ fn test_read() -> Result<()> {
     Ok(())
 }
 
+fn root_of(tree: &TestTree) -> Option<(Digest, u32)> {
+    let id = tree.root_id?;
+    let n = tree.load_node(&id).unwrap()?;
+    Some((n.get_hash(), id.get_height()))
+}
+
+#[test]
+fn test_transition() -> Result<()> {
+    let mut merkle_tree = build_tree();
+    let old = root_of(&merkle_tree);
+    // pages 1 and 7 are updated, 20 grows the tree by one level
+    let modif = vec![
+        (PageId(1), "new_page1".to_digest()),
+        (PageId(7), "new_page7".to_digest()),
+        (PageId(20), "new_page20".to_digest()),
+    ];
+    let proof = WriteProof::new(&merkle_tree, merkle_tree.root_id, modif.iter().map(|m| m.0))?;
+    let mut ctx = WriteContext::new(&merkle_tree, merkle_tree.root_id);
+    for (p_id, dig) in &modif {
+        ctx.update(*dig, *p_id)?;
+    }
+    merkle_tree.apply(ctx.changes());
+    assert_eq!(proof.verify(old, &modif, None)?, root_of(&merkle_tree));
+
+    // another page than the one proven
+    let mut forged = modif.clone();
+    forged[0].0 = PageId(2);
+    assert_ne!(
+        proof.verify(old, &forged, None).ok().flatten(),
+        root_of(&merkle_tree)
+    );
+    // a proof of another tree
+    assert!(proof.verify(root_of(&merkle_tree), &modif, None).is_err());
+
+    // the first pages of a new database
+    let tree = TestTree::new();
+    let modif = vec![
+        (PageId(0), "page0".to_digest()),
+        (PageId(1), "page1".to_digest()),
+    ];
+    let proof = WriteProof::new(&tree, None, modif.iter().map(|m| m.0))?;
+    let mut ctx = WriteContext::new(&tree, None);
+    for (p_id, dig) in &modif {
+        ctx.update(*dig, *p_id)?;
+    }
+    let mut tree = tree.clone();
+    tree.apply(ctx.changes());
+    assert_eq!(proof.verify(None, &modif, None)?, root_of(&tree));
+    Ok(())
+}
+
+fn tree_of(pages: u32) -> TestTree {
+    let mut tree = TestTree::new();
+    let mut ctx = WriteContext::new(&tree, None);
+    for i in 0..pages {
+        ctx.update(format!("old_page{}", i).to_digest(), PageId(i))
+            .unwrap();
+    }
+    tree.apply(ctx.changes());
+    tree
+}
+
+#[test]
+fn test_truncate() -> Result<()> {
+    for page_cnt in [8, 5, 4, 2, 1] {
+        let mut tree = build_tree();
+        let mut ctx = WriteContext::new(&tree, tree.root_id);
+        assert_eq!(ctx.page_cnt()?, 9);
+        ctx.truncate(page_cnt)?;
+        assert_eq!(ctx.page_cnt()?, page_cnt);
+        tree.apply(ctx.changes());
+        let expected = tree_of(page_cnt);
+        assert_eq!(tree.root_id, expected.root_id);
+        assert_eq!(root_of(&tree), root_of(&expected));
+        assert_eq!(tree.nodes.len(), expected.nodes.len());
+    }
+
+    let mut tree = build_tree();
+    let mut ctx = WriteContext::new(&tree, tree.root_id);
+    ctx.truncate(0)?;
+    tree.apply(ctx.changes());
+    assert_eq!(tree.root_id, None);
+    assert!(tree.nodes.is_empty());
+
+    // pages written before the file shrank, the proof covers the path of the new last page
+    let mut tree = build_tree();
+    let old = root_of(&tree);
+    let modif = vec![(PageId(1), "new_page1".to_digest())];
+    let p_ids = modif.iter().map(|m| m.0).chain([PageId(4)]);
+    let proof = WriteProof::new(&tree, tree.root_id, p_ids)?;
+    let mut ctx = WriteContext::new(&tree, tree.root_id);
+    ctx.update(modif[0].1, modif[0].0)?;
+    ctx.truncate(5)?;
+    tree.apply(ctx.changes());
+    assert_eq!(proof.verify(old, &modif, Some(5))?, root_of(&tree));
+    assert_ne!(
+        proof.verify(old, &modif, Some(6)).ok().flatten(),
+        root_of(&tree)
+    );
+    Ok(())
+}
+
 #[test]
 fn test_sha256() {
     let a = [1, 2, 3, 4, 5];
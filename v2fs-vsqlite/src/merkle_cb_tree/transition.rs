@@ -0,0 +1,177 @@
+use super::{
+    hash::{leaf_hash, nonleaf_hash},
+    write::find_height,
+    MerkleNodeLoader, NodeId,
+};
+use crate::{digest::Digest, PageId};
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+// what one batch of writes did to the tree, sent to the user who submitted it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Transition {
+    pub old_root: Option<(Digest, u32)>,
+    pub new_root: Option<(Digest, u32)>,
+    pub modif: Vec<(PageId, Digest)>,
+    // pages left when the batch shrank the file
+    pub page_cnt: Option<u32>,
+    pub proof: WriteProof,
+}
+
+impl Transition {
+    // the new root, if it follows from the trusted old one by writing exactly `modif` and
+    // truncating to `page_cnt`
+    pub fn verify(&self, trusted: Option<(Digest, u32)>) -> Result<Option<(Digest, u32)>> {
+        if self.old_root != trusted {
+            bail!("the writes were applied to another root");
+        }
+        let new_root = self.proof.verify(trusted, &self.modif, self.page_cnt)?;
+        if new_root != self.new_root {
+            bail!("the new root does not follow from the written pages");
+        }
+        Ok(new_root)
+    }
+}
+
+// old hashes of the written leaves and of the siblings along their paths, enough to recompute
+// the root before and after a batch of writes; a truncation adds the path of the new last page
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WriteProof {
+    nodes: BTreeMap<NodeId, Digest>,
+}
+
+impl WriteProof {
+    // read from the old tree, before the writes are applied
+    pub fn new(
+        node_loader: &impl MerkleNodeLoader,
+        root_id: Option<NodeId>,
+        p_ids: impl IntoIterator<Item = PageId>,
+    ) -> Result<Self> {
+        let mut nodes = BTreeMap::new();
+        let height = match root_id {
+            Some(id) => id.get_height(),
+            None => return Ok(Self { nodes }),
+        };
+        for p_id in p_ids {
+            let mut cur_id = NodeId::from_page_id(p_id);
+            if !in_tree(cur_id, height) {
+                continue;
+            }
+            let mut ids = vec![cur_id];
+            while cur_id.get_height() < height {
+                ids.push(cur_id.get_sib_id());
+                cur_id = cur_id.get_parent_id();
+            }
+            for id in ids {
+                if nodes.contains_key(&id) {
+                    continue;
+                }
+                if let Some(n) = node_loader.load_node(&id)? {
+                    nodes.insert(id, n.get_hash());
+                }
+            }
+        }
+        Ok(Self { nodes })
+    }
+
+    // (root hash, height) after writing `modif` to the tree with root `old` and cutting it to
+    // `page_cnt` pages, an error if the proof does not lead to `old`
+    pub fn verify(
+        &self,
+        old: Option<(Digest, u32)>,
+        modif: &[(PageId, Digest)],
+        page_cnt: Option<u32>,
+    ) -> Result<Option<(Digest, u32)>> {
+        if modif.is_empty() && page_cnt.is_none() {
+            return Ok(old);
+        }
+        let last = page_cnt.map(|n| n.checked_sub(1).map(|l| NodeId::from_page_id(PageId(l))));
+        let old_height = old.map(|(_, h)| h);
+        // only nodes of the old tree are taken from the proof, the old root covers the rest
+        let mut siblings: HashMap<NodeId, Digest> = self
+            .nodes
+            .iter()
+            .filter(|(id, _)| old_height.is_some_and(|h| in_tree(**id, h) && id.get_height() < h))
+            .map(|(id, dig)| (*id, *dig))
+            .collect();
+        if let Some((hash, height)) = old {
+            siblings.insert(NodeId::new(height, 0), hash);
+            let old_leaves = modif
+                .iter()
+                .map(|(p_id, _)| NodeId::from_page_id(*p_id))
+                .chain(last.flatten())
+                .filter(|id| in_tree(*id, height))
+                .map(|id| (id, siblings.get(&id).copied()))
+                .collect::<BTreeMap<_, _>>();
+            if !old_leaves.is_empty() && fold(old_leaves, &siblings, height) != Some(hash) {
+                bail!("write proof does not match the old root");
+            }
+        }
+
+        let mut new_leaves = modif
+            .iter()
+            .map(|(p_id, dig)| (NodeId::from_page_id(*p_id), Some(leaf_hash(p_id, dig))))
+            .collect::<BTreeMap<_, _>>();
+        let height = match last {
+            None => modif
+                .iter()
+                .map(|(p_id, _)| find_height(*p_id))
+                .chain(old_height)
+                .max()
+                .unwrap_or(0),
+            Some(None) => return Ok(None),
+            Some(Some(last)) => {
+                // nodes starting past the last page are gone, the root rises from its path
+                let end = last.get_width() as u64;
+                new_leaves.retain(|id, _| id.get_width() as u64 <= end);
+                siblings.retain(|id, _| (id.get_width() as u64) << id.get_height() <= end);
+                let old_last = siblings.get(&last).copied();
+                new_leaves.entry(last).or_insert(old_last);
+                find_height(PageId(last.get_width()))
+            }
+        };
+        Ok(fold(new_leaves, &siblings, height).map(|hash| (hash, height)))
+    }
+}
+
+// whether the node lies under the root of a tree of this height
+fn in_tree(id: NodeId, height: u32) -> bool {
+    id.get_height() <= height && (id.get_width() as u64) < 1 << (height - id.get_height())
+}
+
+// hash of the root at `height` from the nodes of one level, siblings that are not on a path
+// come from `siblings` and are absent otherwise
+fn fold(
+    mut level: BTreeMap<NodeId, Option<Digest>>,
+    siblings: &HashMap<NodeId, Digest>,
+    height: u32,
+) -> Option<Digest> {
+    for h in 0..height {
+        let mut parents = BTreeMap::new();
+        for id in level.keys() {
+            let parent_id = id.get_parent_id();
+            if parents.contains_key(&parent_id) {
+                continue;
+            }
+            let child = |w: u32| {
+                let c_id = NodeId::new(h, w);
+                match level.get(&c_id) {
+                    Some(dig) => *dig,
+                    None => siblings.get(&c_id).copied(),
+                }
+            };
+            let (l_hash, r_hash) = (
+                child(parent_id.get_width() * 2),
+                child(parent_id.get_width() * 2 + 1),
+            );
+            let hash = match (l_hash, r_hash) {
+                (None, None) => None,
+                _ => Some(nonleaf_hash(l_hash, r_hash)),
+            };
+            parents.insert(parent_id, hash);
+        }
+        level = parents;
+    }
+    level.get(&NodeId::new(height, 0)).copied().flatten()
+}
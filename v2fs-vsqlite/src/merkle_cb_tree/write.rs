@@ -6,12 +6,14 @@ use crate::{
     digest::{Digest, Digestible},
     PageId,
 };
-use anyhow::Result;
-use std::collections::HashMap;
+use anyhow::{Context, Result};
+use std::collections::{HashMap, HashSet};
 
 pub struct Apply {
     pub root_id: Option<NodeId>,
     pub nodes: HashMap<Digest, MerkleNode>,
+    // nodes of pages cut off by a truncation, to be deleted from the store
+    pub removed: HashSet<Digest>,
 }
 
 impl Apply {
@@ -36,6 +38,7 @@ impl<'a, L: MerkleNodeLoader> WriteContext<'a, L> {
             apply: Apply {
                 root_id,
                 nodes: HashMap::new(),
+                removed: HashSet::new(),
             },
         }
     }
@@ -58,14 +61,89 @@ impl<'a, L: MerkleNodeLoader> WriteContext<'a, L> {
     }
 
     fn get_node(&self, id: &NodeId) -> Result<Option<MerkleNode>> {
-        Ok(match self.apply.nodes.get(&id.to_digest()) {
+        let addr = id.to_digest();
+        if self.apply.removed.contains(&addr) {
+            return Ok(None);
+        }
+        Ok(match self.apply.nodes.get(&addr) {
             Some(n) => Some(n.clone()),
             None => self.node_loader.load_node(id)?,
         })
     }
 
     fn write_node(&mut self, id: NodeId, n: MerkleNode) {
-        self.apply.nodes.insert(id.to_digest(), n);
+        let addr = id.to_digest();
+        self.apply.removed.remove(&addr);
+        self.apply.nodes.insert(addr, n);
+    }
+
+    fn remove_node(&mut self, id: NodeId) {
+        let addr = id.to_digest();
+        self.apply.nodes.remove(&addr);
+        self.apply.removed.insert(addr);
+    }
+
+    // number of pages in the tree, the last leaf is found by following the right spine
+    pub fn page_cnt(&self) -> Result<u32> {
+        let Some(mut id) = self.apply.root_id else {
+            return Ok(0);
+        };
+        while id.get_height() > 0 {
+            let r_id = NodeId::new(id.get_height() - 1, id.get_width() * 2 + 1);
+            id = if self.get_node(&r_id)?.is_some() {
+                r_id
+            } else {
+                NodeId::new(id.get_height() - 1, id.get_width() * 2)
+            };
+        }
+        Ok(id.get_width() + 1)
+    }
+
+    // drop the leaves of pages from `page_cnt` on, rebuild the path of the new last page and
+    // lower the root to its height
+    pub fn truncate(&mut self, page_cnt: u32) -> Result<()> {
+        let old_cnt = self.page_cnt()?;
+        if page_cnt >= old_cnt {
+            return Ok(());
+        }
+        let height = self.get_height();
+        let new_height = match page_cnt.checked_sub(1) {
+            Some(last) => find_height(PageId(last)),
+            None => 0,
+        };
+        for h in 0..=height {
+            // nodes above the new root go as well as those covering only cut off pages
+            let first = match page_cnt.checked_sub(1) {
+                Some(last) if h <= new_height => (last >> h) + 1,
+                _ => 0,
+            };
+            for w in first..=((old_cnt - 1) >> h) {
+                self.remove_node(NodeId::new(h, w));
+            }
+        }
+        let Some(last) = page_cnt.checked_sub(1) else {
+            self.apply.root_id = None;
+            return Ok(());
+        };
+
+        let mut cur_id = NodeId::from_page_id(PageId(last));
+        let mut cur_hash = self
+            .get_node(&cur_id)?
+            .context("Cannot find the last leaf")?
+            .get_hash();
+        while cur_id.get_height() < new_height {
+            // the right sibling of a node on this path lies past the last page
+            cur_hash = if cur_id.is_even() {
+                nonleaf_hash(Some(cur_hash), None)
+            } else {
+                let sib_hash = self.get_node(&cur_id.get_sib_id())?.map(|n| n.get_hash());
+                nonleaf_hash(sib_hash, Some(cur_hash))
+            };
+            cur_id = cur_id.get_parent_id();
+            self.write_node(cur_id, MerkleNode::new(cur_hash));
+        }
+        self.set_root_id(cur_id);
+        Ok(())
     }
 
     pub fn update(&mut self, p_hash: Digest, p_id: PageId) -> Result<()> {
@@ -132,7 +210,7 @@ impl<'a, L: MerkleNodeLoader> WriteContext<'a, L> {
     }
 }
 
-fn find_height(p_id: PageId) -> u32 {
+pub(crate) fn find_height(p_id: PageId) -> u32 {
     let mut p_id_num = p_id.get_id();
     let mut height = 0;
     while p_id_num != 0 {
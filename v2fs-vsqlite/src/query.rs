@@ -1,29 +1,42 @@
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
 use rusqlite::{Connection, OpenFlags};
 use std::{
+    collections::HashMap,
     ffi::CString,
     io::{Read, Write},
     mem::ManuallyDrop,
     net::TcpStream,
+    path::Path,
 };
 
 use crate::{
-    merkle_cb_tree::proof::Proof,
-    utils::{end_request, get_height, hand_shake, ResInfo, Time},
-    verify::verify,
+    digest::{Digest, Digestible},
+    merkle_cb_tree::{proof::Proof, transition::Transition},
+    stats::{self, take_stats},
+    utils::{
+        end_request, get_height, get_height_at, get_page_size, get_page_size_at, get_root,
+        get_root_at, get_vbf_digest, hand_shake, ResInfo, Time,
+    },
+    vbf::VbfUpdate,
+    verify::{verify, verify_with_root},
     vfs::{
-        server_vfs::{server_vfs_state, update_merkle_db},
+        attached_holder_path, attached_merkle_path,
+        error::{take_last_error, Corrupt},
+        io::require_page,
+        is_valid_db_name, read_msg,
+        server_vfs::{bump_version, lock_snapshot, recover_merkle_db, update_merkle_db},
         user_vfs::user_vfs_state,
-        BOTH_CACHE, HOLDER_FILE_PATH, MAIN_PATH, NO_CACHE, PAGE_SIZE, SERVER_VFS, USER_VFS,
-        YES_FLAG,
+        BOTH_CACHE, FETCH_VBF, GLOBAL_TS, HOLDER_FILE_PATH, MAIN_DB, MAIN_PATH, MAX_MSG_LEN,
+        MSG_BUF_LEN, NO_CACHE, SERVER_VFS, STREAM_VERIFY, SUBMIT_WRITE, USER_VFS, YES_FLAG,
     },
-    Type,
+    AttachedDb, PageId, Type,
 };
 
 pub fn query(sql: &str, tp: Type, stream: &mut TcpStream) -> Result<ResInfo> {
     let signal = match tp {
         Type::None | Type::Intra => NO_CACHE,
         Type::Both | Type::BothBloom | Type::SimpleBloom => BOTH_CACHE,
+        Type::Stream => STREAM_VERIFY,
     };
 
     // let signal = if opt_level == 2 || opt_level == 3 {
@@ -32,11 +45,47 @@ pub fn query(sql: &str, tp: Type, stream: &mut TcpStream) -> Result<ResInfo> {
     //     NO_CACHE
     // };
 
+    let name = ManuallyDrop::new(CString::new(USER_VFS)?);
+    let attached = unsafe {
+        let p_vfs = libsqlite3_sys::sqlite3_vfs_find(name.as_ptr());
+        let state = user_vfs_state(p_vfs).expect("null pointer");
+        state.vfs.new_session();
+        state
+            .vfs
+            .attached
+            .iter()
+            .map(|db| db.name.clone())
+            .collect::<Vec<_>>()
+    };
+
+    // drop what was counted outside of a query, e.g. attaching databases
+    take_stats();
     let timer1 = howlong::ProcessCPUTimer::new();
     hand_shake(stream, signal)?;
-    query_from_vfs(sql, stream)?;
+    query_from_vfs(sql, &attached, stream)?;
+    if let Type::Stream = tp {
+        // every page has been verified in u_read, no proof is sent after the query
+        let q_time = Time::from(timer1.elapsed());
+        info!(
+            "query time (streaming verification): {}ms",
+            q_time.real / 1000
+        );
+        let p_size = unsafe {
+            let p_vfs = libsqlite3_sys::sqlite3_vfs_find(name.as_ptr());
+            let state = user_vfs_state(p_vfs).expect("null pointer");
+            state.vfs.stream_proof_s
+        };
+        return Ok(ResInfo::new(
+            q_time,
+            Time::default(),
+            p_size,
+            0,
+            take_stats(),
+        ));
+    }
     let buf = receive_proof(stream);
-    let proof = bincode::deserialize::<Proof>(&buf)?;
+    // one proof for every database the session touched, keyed by database index
+    let bundle = bincode::deserialize::<Vec<(u32, Proof)>>(&buf)?;
     let q_time = Time::from(timer1.elapsed());
     info!("query time: {}ms", q_time.real / 1000);
 
@@ -44,68 +93,106 @@ pub fn query(sql: &str, tp: Type, stream: &mut TcpStream) -> Result<ResInfo> {
     info!("verifying results...");
     let height = get_height()?;
     let timer2 = howlong::ProcessCPUTimer::new();
-    let name = ManuallyDrop::new(CString::new(USER_VFS)?);
-    let (cache_size, _cache_height, map) = unsafe {
+    let (cache_size, map, attached) = unsafe {
         let p_vfs = libsqlite3_sys::sqlite3_vfs_find(name.as_ptr());
         let state = user_vfs_state(p_vfs).expect("null pointer");
-        let u_vfs = &state.vfs;
-        match tp {
-            Type::None | Type::Intra | Type::Both => {
-                let cache = &u_vfs.cache;
-                let (cache_size, cache_height) = cache.cache_size_and_height();
-                (cache_size, cache_height, &u_vfs.map)
-            }
-            Type::BothBloom => {
-                let vcache = &u_vfs.vcache;
-                let (cache_size, cache_height) = vcache.cache_size_and_height();
-                (cache_size, cache_height, &u_vfs.map)
-            }
-            Type::SimpleBloom => {
-                let svcache = &u_vfs.svcache;
-                let (cache_size, cache_height) = svcache.cache_size_and_height();
-                (cache_size, cache_height, &u_vfs.map)
-            }
-        }
-        // match opt_level {
-        //     4 => {
-        //         let svcache = &u_vfs.svcache;
-        //         let (cache_size, cache_height) = svcache.cache_size_and_height();
-        //         (cache_size, cache_height, &u_vfs.map)
-        //     }
-        //     3 => {
-        //         let vcache = &u_vfs.vcache;
-        //         let (cache_size, cache_height) = vcache.cache_size_and_height();
-        //         (cache_size, cache_height, &u_vfs.map)
-        //     }
-        //     2 | 1 | 0 => {
-        //         let cache = &u_vfs.cache;
-        //         let (cache_size, cache_height) = cache.cache_size_and_height();
-        //         (cache_size, cache_height, &u_vfs.map)
-        //     }
-        //     _ => {
-        //         panic!("invalid opt_level");
-        //     }
-        // }
+        let u_vfs = &mut state.vfs;
+        let cache_size = match u_vfs.verified_cache() {
+            Some(cache) => cache.cache_size_and_height().0,
+            None => 0,
+        };
+        (cache_size, &*u_vfs.map, &u_vfs.attached)
     };
-    verify(height, &proof, map)?;
+    verify(height, proof_of(&bundle, MAIN_DB)?, map)?;
+    for (i, db) in attached.iter().enumerate() {
+        if db.map.is_empty() {
+            continue;
+        }
+        let merkle_path = attached_merkle_path(&db.name);
+        let merkle_path = Path::new(&merkle_path);
+        let root = (get_root_at(merkle_path)?, get_height_at(merkle_path)?);
+        verify_with_root(&root, proof_of(&bundle, i + 1)?, &db.map)?;
+    }
     let v_time = Time::from(timer2.elapsed());
-    let p_size = bincode::serialize(&proof)?.len();
+    let p_size = buf.len();
     info!("verification succeeds!");
     info!("verification time: {}ms", v_time.real / 1000);
-    // info!(
-    //     "Cache size: {} MB, height: {}",
-    //     cache_size as f64 / (1024.0 * 1024.0),
-    //     cache_height
-    // );
-    Ok(ResInfo::new(q_time, v_time, p_size, cache_size))
+    Ok(ResInfo::new(
+        q_time,
+        v_time,
+        p_size,
+        cache_size,
+        take_stats(),
+    ))
+}
+
+// fetch the pages of a recorded trace before the first query, they are covered by one
+// proof and only cached once it verifies
+pub fn warm_up(p_ids: &[PageId], tp: Type, stream: &mut TcpStream) -> Result<usize> {
+    if !matches!(tp, Type::Both | Type::BothBloom | Type::SimpleBloom) {
+        bail!("warm-up needs an inter-query cache (opt_level 2 or 3)");
+    }
+    hand_shake(stream, BOTH_CACHE)?;
+    let mut map = HashMap::new();
+    let mut pages = Vec::with_capacity(p_ids.len());
+    for p_id in p_ids {
+        let bytes = require_page(*p_id, stream)?;
+        map.insert(*p_id, bytes.to_digest());
+        pages.push((*p_id, bytes));
+    }
+    end_request(stream)?;
+    let buf = receive_proof(stream);
+    let bundle = bincode::deserialize::<Vec<(u32, Proof)>>(&buf)?;
+    verify(get_height()?, proof_of(&bundle, MAIN_DB)?, &map).context("warm-up pages")?;
+
+    let name = ManuallyDrop::new(CString::new(USER_VFS)?);
+    unsafe {
+        let p_vfs = libsqlite3_sys::sqlite3_vfs_find(name.as_ptr());
+        let state = user_vfs_state(p_vfs).expect("null pointer");
+        let u_vfs = &mut state.vfs;
+        let vbf = u_vfs.vbf.clone();
+        if let Some(cache) = u_vfs.verified_cache() {
+            for (p_id, bytes) in pages {
+                cache.insert(p_id, bytes, &vbf);
+            }
+        }
+    }
+    Ok(map.len())
+}
+
+fn proof_of(bundle: &[(u32, Proof)], db: usize) -> Result<&Proof> {
+    match bundle.iter().find(|(i, _)| *i as usize == db) {
+        Some((_, proof)) => Ok(proof),
+        None => bail!("no proof received for database {}", db),
+    }
+}
+
+fn query_from_vfs(sql: &str, attached: &[String], stream: &mut TcpStream) -> Result<()> {
+    // a failed vfs callback only hands sqlite a result code, the cause is kept aside
+    take_last_error();
+    if let Err(e) = exec_query(sql, attached) {
+        return Err(match take_last_error() {
+            Some((code, msg)) => e.context(format!("vfs error {}: {}", code, msg)),
+            None => e,
+        });
+    }
+    end_request(stream)?;
+    Ok(())
 }
 
-fn query_from_vfs(sql: &str, stream: &mut TcpStream) -> Result<()> {
+fn exec_query(sql: &str, attached: &[String]) -> Result<()> {
     let conn = Connection::open_with_flags_and_vfs(
         HOLDER_FILE_PATH,
         OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         USER_VFS,
     )?;
+    // attached files are opened through USER_VFS as well and served from their own trees
+    for name in attached {
+        conn.execute(
+            "ATTACH DATABASE ?1 AS ?2",
+            [attached_holder_path(name).as_str(), name.as_str()],
+        )?;
+    }
     let mut stmt = conn.prepare(sql)?;
     let mut res_rows = stmt.query([])?;
     let mut res_cnt = 0;
@@ -115,14 +202,13 @@ fn query_from_vfs(sql: &str, stream: &mut TcpStream) -> Result<()> {
     }
 
     info!("Query finished, the num of res records: {}", res_cnt);
-    end_request(stream)?;
     Ok(())
 }
 
 fn receive_proof(stream: &mut TcpStream) -> Vec<u8> {
     // Read the vector size from the client
-    let mut buffer = [0; PAGE_SIZE as usize];
-    let _bytes_read = stream.read(&mut buffer).expect("failed to read stream");
+    let mut buffer = [0; MSG_BUF_LEN];
+    let bytes_read = stream.read(&mut buffer).expect("failed to read stream");
     let vector_size = bincode::deserialize::<u32>(&buffer).expect("failed to deserialize bincode");
 
     // Write response
@@ -135,23 +221,73 @@ fn receive_proof(stream: &mut TcpStream) -> Vec<u8> {
     stream
         .read_exact(&mut vector)
         .expect("Failed to read vector data");
+    stats::received(bytes_read + vector.len());
+    stats::sent(4);
 
     vector
 }
 
 pub fn update_db(sql: &str) -> Result<()> {
-    let conn = Connection::open_with_flags_and_vfs(
+    update_db_batch(&[sql.to_string()])?;
+    Ok(())
+}
+
+// run the statements in one transaction, the tree follows once sqlite commits it
+pub fn update_db_batch(stmts: &[String]) -> Result<Vec<Transition>> {
+    let mut conn = Connection::open_with_flags_and_vfs(
         MAIN_PATH,
         OpenFlags::SQLITE_OPEN_READ_WRITE
             | OpenFlags::SQLITE_OPEN_CREATE
             | OpenFlags::SQLITE_OPEN_NO_MUTEX,
         SERVER_VFS,
     )?;
-    conn.execute(sql, [])?;
-    update_merkle_db()?;
+    bump_version()?;
+    let mode: String = conn.query_row("PRAGMA journal_mode", [], |row| row.get(0))?;
+    if mode == "wal" {
+        // appends only reach the wal, pages are authenticated once they are checkpointed
+        conn.execute_batch("PRAGMA wal_autocheckpoint=0")?;
+        execute_in_tx(&mut conn, stmts)?;
+        checkpoint(&conn)
+    } else {
+        let _snapshot = lock_snapshot()?;
+        execute_in_tx(&mut conn, stmts)?;
+        update_merkle_db()
+    }
+}
+
+fn execute_in_tx(conn: &mut Connection, stmts: &[String]) -> Result<()> {
+    let tx = conn.transaction()?;
+    for sql in stmts {
+        tx.execute_batch(sql)
+            .with_context(|| format!("failed to execute {}", sql))?;
+    }
+    tx.commit()?;
     Ok(())
 }
 
+// copy the wal back into the main file and update the merkle tree with the copied pages
+pub fn checkpoint_db() -> Result<()> {
+    let conn = Connection::open_with_flags_and_vfs(
+        MAIN_PATH,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        SERVER_VFS,
+    )?;
+    checkpoint(&conn)?;
+    Ok(())
+}
+
+fn checkpoint(conn: &Connection) -> Result<Vec<Transition>> {
+    let _snapshot = lock_snapshot()?;
+    let (busy, log, ckpt): (i32, i32, i32) =
+        conn.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| {
+            Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        })?;
+    if busy != 0 {
+        bail!("checkpoint blocked, {} of {} wal frames copied", ckpt, log);
+    }
+    update_merkle_db()
+}
+
 #[allow(dead_code)]
 pub fn dbg_query(sql: &str) -> Result<()> {
     let conn = Connection::open_with_flags_and_vfs(
@@ -172,22 +308,157 @@ pub fn dbg_query(sql: &str) -> Result<()> {
     Ok(())
 }
 
-// simulate to obtain the latest vbf from the sgx
+// obtain the latest vbf from the server
 pub fn update_user_bf() -> Result<()> {
-    let s_name = ManuallyDrop::new(CString::new(SERVER_VFS)?);
-    let vbf = unsafe {
-        let p_vfs = libsqlite3_sys::sqlite3_vfs_find(s_name.as_ptr());
-        let state = server_vfs_state(p_vfs).expect("null pointer");
-        let s_vfs = &mut state.vfs;
-        s_vfs.vbf.clone()
+    let u_name = ManuallyDrop::new(CString::new(USER_VFS)?);
+    let u_vfs = unsafe {
+        let p_vfs = libsqlite3_sys::sqlite3_vfs_find(u_name.as_ptr());
+        let state = user_vfs_state(p_vfs).expect("null pointer");
+        &mut state.vfs
+    };
+    let mut stream = TcpStream::connect("127.0.0.1:7878")?;
+    let update = fetch_vbf(&mut stream, u_vfs.vbf.seed(), u_vfs.vbf.counter())?;
+    let VbfUpdate::Delta { ref slots, .. } = update else {
+        return u_vfs.update_vbf(update, get_vbf_digest()?);
     };
+    debug!("vbf delta of {} slots", slots.len());
+    if let Err(e) = u_vfs.update_vbf(update, get_vbf_digest()?) {
+        // the root may have moved since the delta, a counter ahead of the server's gets the
+        // whole filter
+        warn!("vbf delta rejected, fetching the whole filter: {:?}", e);
+        let mut stream = TcpStream::connect("127.0.0.1:7878")?;
+        let update = fetch_vbf(&mut stream, u_vfs.vbf.seed(), u64::MAX)?;
+        u_vfs.update_vbf(update, get_vbf_digest()?)?;
+    }
+    // pages cached from now on reflect every batch in the filter
+    unsafe {
+        GLOBAL_TS = GLOBAL_TS.max(u_vfs.vbf.latest_version());
+    }
+    debug!(
+        "vbf at counter {} is {:.2} full",
+        u_vfs.vbf.counter(),
+        u_vfs.vbf.fill_ratio()
+    );
+    Ok(())
+}
+
+// changes since the user's copy of the vbf, or the whole filter
+pub fn fetch_vbf(stream: &mut TcpStream, seed: u64, counter: u64) -> Result<VbfUpdate> {
+    stream.write_all(&FETCH_VBF.to_le_bytes())?;
+    let mut flag = [0; 4];
+    stream.read_exact(&mut flag)?;
+    if u32::from_le_bytes(flag) != YES_FLAG {
+        bail!("the server has no vbf");
+    }
+    stream.write_all(&bincode::serialize(&(seed, counter))?)?;
+    let mut len_buf = [0; 8];
+    stream.read_exact(&mut len_buf)?;
+    let mut buf = vec![0; u64::from_le_bytes(len_buf) as usize];
+    stream.read_exact(&mut buf)?;
+    Ok(bincode::deserialize(&buf)?)
+}
+
+// submit statements the server applies in one transaction; the new root is only accepted if it
+// follows from the published one by the pages the server reports as written
+pub fn submit_writes(stmts: &[String]) -> Result<Option<(Digest, u32)>> {
+    let trusted = (get_root()?, get_height()?);
+    let mut stream = TcpStream::connect("127.0.0.1:7878")?;
+    stream.write_all(&SUBMIT_WRITE.to_le_bytes())?;
+    let mut flag = [0; 4];
+    stream.read_exact(&mut flag)?;
+    if u32::from_le_bytes(flag) != YES_FLAG {
+        bail!("the server does not accept writes");
+    }
+    let bytes = bincode::serialize(stmts)?;
+    if bytes.len() > MAX_MSG_LEN {
+        bail!(
+            "{} bytes of statements exceed the message limit",
+            bytes.len()
+        );
+    }
+    let mut msg = (bytes.len() as u64).to_le_bytes().to_vec();
+    msg.extend(bytes);
+    stream.write_all(&msg)?;
+
+    let buf = read_msg(&mut stream)?;
+    let transitions = match bincode::deserialize::<Result<Vec<Transition>, String>>(&buf)? {
+        Ok(t) => t,
+        Err(e) => bail!("the server failed to apply the writes: {}", e),
+    };
+    // every commit since the trusted root, in order
+    let mut new_root = Some(trusted);
+    for transition in &transitions {
+        new_root = transition
+            .verify(new_root)
+            .context(Corrupt("write transition not verified".to_string()))?;
+    }
+    info!(
+        "{} pages written, new root {:?}",
+        transitions.iter().map(|t| t.modif.len()).sum::<usize>(),
+        new_root
+    );
+    Ok(new_root)
+}
+
+// let sqlite roll back a hot journal left by a crash, then check the merkle tree against the
+// main file
+pub fn recover_db() -> Result<()> {
+    let conn = Connection::open_with_flags_and_vfs(
+        MAIN_PATH,
+        OpenFlags::SQLITE_OPEN_READ_WRITE | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        SERVER_VFS,
+    )?;
+    let _snapshot = lock_snapshot()?;
+    let _version: i64 = conn.query_row("PRAGMA schema_version", [], |row| row.get(0))?;
+    drop(conn);
+    recover_merkle_db()
+}
+
+// simulate to obtain the latest root from the blockchain
+pub fn update_user_root() -> Result<()> {
+    let root_hash = get_root()?;
+    let height = get_height()?;
 
     let u_name = ManuallyDrop::new(CString::new(USER_VFS)?);
     unsafe {
         let p_vfs = libsqlite3_sys::sqlite3_vfs_find(u_name.as_ptr());
         let state = user_vfs_state(p_vfs).expect("null pointer");
         let u_vfs = &mut state.vfs;
-        u_vfs.set_vbf(vbf);
+        u_vfs.set_root(root_hash, height);
+        for db in u_vfs.attached.iter_mut() {
+            let merkle_path = attached_merkle_path(&db.name);
+            let merkle_path = Path::new(&merkle_path);
+            db.root = (get_root_at(merkle_path)?, get_height_at(merkle_path)?);
+        }
+    }
+
+    Ok(())
+}
+
+// databases attached to every query, each one is verified against its own root
+pub fn attach_user_dbs(names: &[String]) -> Result<()> {
+    let page_size = get_page_size()?;
+    let mut attached = Vec::new();
+    for name in names {
+        if !is_valid_db_name(name) {
+            bail!("invalid database name {}", name);
+        }
+        let merkle_path = attached_merkle_path(name);
+        if get_page_size_at(Path::new(&merkle_path))? != page_size {
+            bail!(
+                "attached database {} must use page size {}",
+                name,
+                page_size
+            );
+        }
+        attached.push(AttachedDb::new(name.clone()));
+    }
+
+    let u_name = ManuallyDrop::new(CString::new(USER_VFS)?);
+    unsafe {
+        let p_vfs = libsqlite3_sys::sqlite3_vfs_find(u_name.as_ptr());
+        let state = user_vfs_state(p_vfs).expect("null pointer");
+        state.vfs.attached = attached;
     }
 
     Ok(())
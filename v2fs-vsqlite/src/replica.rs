@@ -0,0 +1,369 @@
+// primary/follower replication: a follower pulls the nodes where its tree differs from the
+// primary's, then the pages under the differing leaves, rebuilds the root from the pulled pages
+// and only installs them once the root matches the trusted one
+use crate::{
+    digest::{Digest, Digestible},
+    merkle_cb_tree::{write::WriteContext, NodeId, ReadInterface, WriteInterface},
+    utils::{get_height_at, get_root_at, get_vbf_digest_at},
+    vbf::VersionBloomFilter,
+    vfs::{
+        io::build_merkle_tree_at, read_msg, server_vfs::lock_snapshot, set_page_size, MAIN_PATH,
+        MERKLE_PATH, REPLICATE, SYNC_LOG_PATH, VBF_PATH, YES_FLAG,
+    },
+    MerkleDB, PageId,
+};
+use anyhow::{bail, Context, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    net::TcpStream,
+    path::Path,
+};
+
+// pages a follower asks for in one request
+const PAGES_PER_MSG: usize = 1024;
+
+// one request of a follower, each answered by a length-prefixed message
+#[derive(Debug, Serialize, Deserialize)]
+enum SyncRequest {
+    // (root id, page count, page size) of the primary
+    Head,
+    // hash of each node, none if the primary's tree does not have it
+    Nodes(Vec<NodeId>),
+    // content of each page
+    Pages(Vec<PageId>),
+    // the persisted vbf
+    Vbf,
+    End,
+}
+
+fn send_msg<T: Serialize>(stream: &mut TcpStream, msg: &T) -> Result<()> {
+    let bytes = bincode::serialize(msg)?;
+    let mut buf = (bytes.len() as u64).to_le_bytes().to_vec();
+    buf.extend(bytes);
+    stream.write_all(&buf)?;
+    Ok(())
+}
+
+fn recv_msg<T: DeserializeOwned>(stream: &mut TcpStream) -> Result<T> {
+    Ok(bincode::deserialize(&read_msg(stream)?)?)
+}
+
+/// Answer the requests of a follower, the caller holds the snapshot lock so every answer
+/// comes from the same root version.
+pub fn serve_follower(stream: &mut TcpStream) -> Result<()> {
+    stream.write_all(&YES_FLAG.to_le_bytes())?;
+    let merkle_db = MerkleDB::open_read_only(Path::new(MERKLE_PATH))?;
+    let page_size = merkle_db.get_page_size();
+    let mut pulled = 0;
+    loop {
+        match recv_msg::<SyncRequest>(stream)? {
+            SyncRequest::Head => {
+                let len = fs::metadata(MAIN_PATH)?.len();
+                let page_cnt = len.div_ceil(page_size as u64) as u32;
+                send_msg(stream, &(merkle_db.get_root_id(), page_cnt, page_size))?;
+            }
+            SyncRequest::Nodes(ids) => {
+                let mut hashes = Vec::with_capacity(ids.len());
+                for id in &ids {
+                    hashes.push(merkle_db.get_node(&id.to_digest())?.map(|n| n.get_hash()));
+                }
+                send_msg(stream, &hashes)?;
+            }
+            SyncRequest::Pages(p_ids) => {
+                let mut file = File::open(MAIN_PATH)?;
+                let mut pages = Vec::with_capacity(p_ids.len());
+                for p_id in &p_ids {
+                    pages.push(read_page(&mut file, *p_id, page_size)?);
+                }
+                pulled += pages.len();
+                send_msg(stream, &pages)?;
+            }
+            SyncRequest::Vbf => send_msg(stream, &fs::read(VBF_PATH)?)?,
+            SyncRequest::End => break,
+        }
+    }
+    info!("follower pulled {} pages", pulled);
+    Ok(())
+}
+
+fn read_page(file: &mut File, p_id: PageId, page_size: u32) -> Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(p_id.get_id() as u64 * page_size as u64))?;
+    let mut buf = vec![0; page_size as usize];
+    if let Err(err) = file.read_exact(&mut buf) {
+        if err.kind() != ErrorKind::UnexpectedEof {
+            return Err(err.into());
+        }
+    }
+    Ok(buf)
+}
+
+fn root_of(merkle_db: &MerkleDB, root_id: Option<NodeId>) -> Result<Option<(Digest, u32)>> {
+    let Some(id) = root_id else {
+        return Ok(None);
+    };
+    let root = merkle_db
+        .get_node(&id.to_digest())?
+        .context("Cannot find root in merkle tree")?;
+    Ok(Some((root.get_hash(), id.get_height())))
+}
+
+/// Bring the local database, tree and vbf to the root published at `trusted` by pulling from
+/// the primary at `addr`. Nothing is installed unless the pulled pages lead to that root, the
+/// follower keeps serving its last verified version otherwise. Returns the number of pages
+/// pulled, none if the follower already serves the trusted root.
+pub fn sync_from(addr: &str, trusted: &Path) -> Result<Option<usize>> {
+    {
+        let _snapshot = lock_snapshot()?;
+        recover_sync_at(
+            Path::new(MAIN_PATH),
+            Path::new(MERKLE_PATH),
+            Path::new(SYNC_LOG_PATH),
+        )?;
+    }
+    let trusted_root = (get_root_at(trusted)?, get_height_at(trusted)?);
+    let trusted_vbf = get_vbf_digest_at(trusted).ok();
+    if Path::new(MERKLE_PATH).exists() {
+        let merkle_db = MerkleDB::open_read_only(Path::new(MERKLE_PATH))?;
+        if root_of(&merkle_db, merkle_db.get_root_id())? == Some(trusted_root)
+            && merkle_db.get_vbf_digest() == trusted_vbf
+        {
+            return Ok(None);
+        }
+    }
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(&REPLICATE.to_le_bytes())?;
+    let mut flag = [0; 4];
+    stream.read_exact(&mut flag)?;
+    if u32::from_le_bytes(flag) != YES_FLAG {
+        bail!("the primary refused to replicate");
+    }
+    send_msg(&mut stream, &SyncRequest::Head)?;
+    let (primary_root_id, page_cnt, p_size) = recv_msg::<(Option<NodeId>, u32, u32)>(&mut stream)?;
+    if primary_root_id.map(|id| id.get_height()) != Some(trusted_root.1) {
+        bail!("the primary serves another version than the trusted root");
+    }
+    if !Path::new(MERKLE_PATH).exists() {
+        // a new follower takes the page size of the primary
+        set_page_size(p_size)?;
+    }
+    let mut merkle_db = MerkleDB::create_new(Path::new(MERKLE_PATH))?;
+    if merkle_db.get_page_size() != p_size {
+        bail!("page size of the primary differs from the local one");
+    }
+
+    // descend from the root where the hashes differ, equal nodes cover equal pages
+    let mut level = vec![NodeId::new(trusted_root.1, 0)];
+    let mut changed = Vec::new();
+    while !level.is_empty() {
+        send_msg(&mut stream, &SyncRequest::Nodes(level.clone()))?;
+        let hashes = recv_msg::<Vec<Option<Digest>>>(&mut stream)?;
+        if hashes.len() != level.len() {
+            bail!("the primary sent {} of {} nodes", hashes.len(), level.len());
+        }
+        let mut next = Vec::new();
+        for (id, hash) in level.iter().zip(hashes) {
+            let Some(hash) = hash else {
+                continue;
+            };
+            if merkle_db.get_node(&id.to_digest())?.map(|n| n.get_hash()) == Some(hash) {
+                continue;
+            }
+            if id.get_height() == 0 {
+                changed.push(PageId(id.get_width()));
+            } else {
+                let (h, w) = (id.get_height() - 1, id.get_width() * 2);
+                next.extend([NodeId::new(h, w), NodeId::new(h, w + 1)]);
+            }
+        }
+        level = next;
+    }
+    // pulled in batches, each reply stays well below the message limit
+    let mut pages = Vec::with_capacity(changed.len());
+    for batch in changed.chunks(PAGES_PER_MSG) {
+        send_msg(&mut stream, &SyncRequest::Pages(batch.to_vec()))?;
+        let batch_pages = recv_msg::<Vec<Vec<u8>>>(&mut stream)?;
+        if batch_pages.len() != batch.len()
+            || batch_pages.iter().any(|p| p.len() != p_size as usize)
+        {
+            bail!("the primary sent malformed pages");
+        }
+        pages.extend(batch_pages);
+    }
+    let vbf = match trusted_vbf {
+        Some(digest) => {
+            send_msg(&mut stream, &SyncRequest::Vbf)?;
+            let vbf: VersionBloomFilter = bincode::deserialize(&recv_msg::<Vec<u8>>(&mut stream)?)?;
+            if vbf.to_digest() != digest {
+                bail!("the vbf of the primary does not match the trusted digest");
+            }
+            Some(vbf)
+        }
+        None => None,
+    };
+    send_msg(&mut stream, &SyncRequest::End)?;
+
+    // the root is recomputed from the pulled pages, not taken from the primary
+    let mut ctx = WriteContext::new(&merkle_db, merkle_db.get_root_id());
+    for (p_id, bytes) in changed.iter().zip(&pages) {
+        ctx.update(bytes.to_digest(), *p_id)?;
+    }
+    ctx.truncate(page_cnt)?;
+    let changes = ctx.changes();
+    let new_root = match changes.root_id {
+        Some(id) => match changes.nodes.get(&id.to_digest()) {
+            Some(n) => Some((n.get_hash(), id.get_height())),
+            None => root_of(&merkle_db, Some(id))?,
+        },
+        None => None,
+    };
+    if new_root != Some(trusted_root) {
+        bail!("the pulled pages do not lead to the trusted root");
+    }
+
+    let _snapshot = lock_snapshot()?;
+    write_pages(
+        Path::new(MAIN_PATH),
+        Path::new(SYNC_LOG_PATH),
+        &changed,
+        &pages,
+        page_cnt,
+        p_size,
+    )?;
+    for (addr, node) in changes.nodes {
+        merkle_db.write_node(&addr, &node)?;
+    }
+    for addr in changes.removed {
+        merkle_db.remove_node(&addr)?;
+    }
+    if let Some(vbf) = vbf {
+        vbf.save(Path::new(VBF_PATH))?;
+        merkle_db.set_vbf_digest(vbf.to_digest());
+    }
+    merkle_db.update_param(changes.root_id)?;
+    merkle_db.close();
+    fs::remove_file(SYNC_LOG_PATH)?;
+    Ok(Some(changed.len()))
+}
+
+// pages go first, logged before the main file is touched; a crash before the tree follows leaves
+// the log for `recover_sync_at`
+fn write_pages(
+    main_path: &Path,
+    log_path: &Path,
+    p_ids: &[PageId],
+    pages: &[Vec<u8>],
+    page_cnt: u32,
+    p_size: u32,
+) -> Result<()> {
+    let mut log = File::create(log_path)?;
+    log.write_all(serde_json::to_string(p_ids)?.as_bytes())?;
+    log.sync_all()?;
+    if let Some(dir) = main_path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(main_path)?;
+    for (p_id, bytes) in p_ids.iter().zip(pages) {
+        file.seek(SeekFrom::Start(p_id.get_id() as u64 * p_size as u64))?;
+        file.write_all(bytes)?;
+    }
+    file.set_len(page_cnt as u64 * p_size as u64)?;
+    file.sync_all()?;
+    Ok(())
+}
+
+// after an interrupted sync the main file may be ahead of the tree, or partly written: the tree
+// is rebuilt from the file so the next sync pulls whatever still differs from the trusted root.
+// Returns whether a sync was interrupted
+fn recover_sync_at(main_path: &Path, merkle_path: &Path, log_path: &Path) -> Result<bool> {
+    if !log_path.exists() {
+        return Ok(false);
+    }
+    warn!(
+        "a sync was interrupted, rebuilding the merkle tree from {:?}",
+        main_path
+    );
+    // a new follower may have crashed before its first page
+    if main_path.exists() {
+        build_merkle_tree_at(main_path, merkle_path)?;
+    }
+    fs::remove_file(log_path)?;
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vfs::DEFAULT_PAGE_SIZE;
+    use std::path::PathBuf;
+
+    const P_SIZE: u32 = DEFAULT_PAGE_SIZE;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("replica_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // page 0 carries the sqlite header the tree build reads the page size from
+    fn pages(fills: &[u8]) -> Vec<Vec<u8>> {
+        let mut pages: Vec<_> = fills.iter().map(|f| vec![*f; P_SIZE as usize]).collect();
+        pages[0][..16].copy_from_slice(b"SQLite format 3\0");
+        pages[0][16..18].copy_from_slice(&(P_SIZE as u16).to_be_bytes());
+        pages
+    }
+
+    fn build(main_path: &Path, merkle_path: &Path, pages: &[Vec<u8>]) {
+        fs::write(main_path, pages.concat()).unwrap();
+        build_merkle_tree_at(main_path, merkle_path).unwrap();
+    }
+
+    fn root(merkle_path: &Path) -> Option<(Digest, u32)> {
+        let merkle_db = MerkleDB::open_read_only(merkle_path).unwrap();
+        root_of(&merkle_db, merkle_db.get_root_id()).unwrap()
+    }
+
+    #[test]
+    fn test_recover_interrupted_sync() {
+        let dir = test_dir("interrupted");
+        let (main_path, merkle_path) = (dir.join("test.db"), dir.join("merkle"));
+        let log_path = dir.join("sync.json");
+        build(&main_path, &merkle_path, &pages(&[0, 1, 2, 3, 4]));
+        let new = pages(&[0, 1, 9, 3, 4, 5]);
+        build(&dir.join("expected.db"), &dir.join("expected"), &new);
+        let expected = root(&dir.join("expected"));
+
+        // the sync stops once its pages are written, before the tree follows
+        let changed = [PageId(2), PageId(5)];
+        let changed_pages = [new[2].clone(), new[5].clone()];
+        write_pages(&main_path, &log_path, &changed, &changed_pages, 6, P_SIZE).unwrap();
+        assert_ne!(root(&merkle_path), expected);
+
+        assert!(recover_sync_at(&main_path, &merkle_path, &log_path).unwrap());
+        assert_eq!(root(&merkle_path), expected);
+        assert!(!log_path.exists());
+        assert!(!recover_sync_at(&main_path, &merkle_path, &log_path).unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_recover_sync_before_first_page() {
+        let dir = test_dir("first_page");
+        let (main_path, merkle_path) = (dir.join("test.db"), dir.join("merkle"));
+        let log_path = dir.join("sync.json");
+        // a new follower logged its sync and crashed before writing the main file
+        fs::write(&log_path, "[]").unwrap();
+        assert!(recover_sync_at(&main_path, &merkle_path, &log_path).unwrap());
+        assert!(!main_path.exists());
+        assert!(!log_path.exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
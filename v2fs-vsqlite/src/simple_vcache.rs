@@ -2,12 +2,17 @@ pub mod hash;
 pub mod leaf;
 pub mod non_leaf;
 
-use lru::LruCache;
-
 use crate::{
     digest::{Digest, Digestible},
+    eviction::Eviction,
     merkle_cb_tree::NodeId,
-    vfs::PAGE_SIZE,
+    vbf::VersionBloomFilter,
+    verified_cache::{
+        compression::{Compression, PageBytes},
+        store::{NodeStore, TreeNode},
+        VerifiedCache,
+    },
+    vfs::GLOBAL_TS,
     PageId,
 };
 
@@ -19,8 +24,15 @@ pub(crate) enum SVCacheNode {
     NonLeaf(SVCacheNonLeafNode),
 }
 
-impl SVCacheNode {
-    pub(crate) fn is_valid(&self) -> bool {
+impl TreeNode for SVCacheNode {
+    fn get_id(&self) -> NodeId {
+        match self {
+            SVCacheNode::Leaf(l) => l.get_id(),
+            SVCacheNode::NonLeaf(n) => n.get_id(),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
         match self {
             SVCacheNode::Leaf(l) => l.is_valid(),
             SVCacheNode::NonLeaf(n) => n.is_valid(),
@@ -34,10 +46,38 @@ impl SVCacheNode {
         }
     }
 
-    pub(crate) fn get_id(&self) -> NodeId {
+    // only leaves carry a version
+    fn validate_with_version(&mut self, version: u32) {
         match self {
-            SVCacheNode::Leaf(l) => l.get_id(),
-            SVCacheNode::NonLeaf(n) => n.get_id(),
+            SVCacheNode::Leaf(l) => l.validate_with_version(version),
+            SVCacheNode::NonLeaf(n) => n.validate(),
+        }
+    }
+
+    fn unconfirm(&mut self) {
+        match self {
+            SVCacheNode::Leaf(l) => l.unconfirm(),
+            SVCacheNode::NonLeaf(n) => n.unconfirm(),
+        }
+    }
+
+    fn get_bytes(&self) -> Option<Box<[u8]>> {
+        match self {
+            SVCacheNode::Leaf(l) => l.get_bytes(),
+            SVCacheNode::NonLeaf(_) => None,
+        }
+    }
+
+    fn merge(left: &Self, right: &Self) -> Self {
+        let id = left.get_id().get_parent_id();
+        let hash = merge_hash(&left.to_digest(), &right.to_digest());
+        SVCacheNode::NonLeaf(SVCacheNonLeafNode::new(id, hash))
+    }
+
+    fn heap_size(&self) -> usize {
+        match self {
+            SVCacheNode::Leaf(l) => l.heap_size(),
+            SVCacheNode::NonLeaf(_) => 0,
         }
     }
 }
@@ -51,179 +91,74 @@ impl Digestible for SVCacheNode {
     }
 }
 
+// leaves carry the version they were confirmed at, a page the vbf has no newer
+// write for is used without asking the server
 #[derive(Debug)]
 pub struct SVCache {
-    lru: LruCache<Digest, SVCacheNode>,
+    store: NodeStore<SVCacheNode>,
+    compression: Compression,
 }
 
 impl SVCache {
-    pub fn new(cap: usize) -> Self {
-        Self {
-            lru: LruCache::<Digest, SVCacheNode>::new(cap),
-        }
+    // `budget` in bytes
+    pub fn new(budget: usize) -> Self {
+        Self::with_eviction(budget, Eviction::default())
+    }
+
+    pub fn with_eviction(budget: usize, eviction: Eviction) -> Self {
+        Self::with_options(budget, eviction, Compression::default())
     }
 
-    pub(crate) fn get_node(&mut self, key: &Digest) -> Option<&SVCacheNode> {
-        self.lru.get(key)
-    }
-
-    pub(crate) fn get_node_mut(&mut self, key: &Digest) -> Option<&mut SVCacheNode> {
-        self.lru.get_mut(key)
-    }
-
-    // push node into cache, if some node is popped out, remove all its parents
-    pub(crate) fn push_node(&mut self, id: NodeId, node: SVCacheNode) {
-        let hash = id.to_digest();
-        if let Some((_, n)) = self.lru.push(hash, node) {
-            let mut cur_id = n.get_id();
-            let mut affected_pare_hashes = Vec::<Digest>::new();
-            while let Some(p) = self.find_parent(cur_id) {
-                match p {
-                    SVCacheNode::Leaf(_) => {
-                        panic!("impossible to be a leaf")
-                    }
-                    SVCacheNode::NonLeaf(n) => {
-                        affected_pare_hashes.push(n.get_id().to_digest());
-                        cur_id = n.get_id();
-                    }
-                }
-            }
-            for dig in affected_pare_hashes {
-                self.lru.pop(&dig);
-            }
+    // cached pages are compressed, the budget counts their compressed size
+    pub fn with_options(budget: usize, eviction: Eviction, compression: Compression) -> Self {
+        Self {
+            store: NodeStore::new(budget, eviction),
+            compression,
         }
     }
+}
 
-    pub fn clear(&mut self) {
-        self.lru.clear();
+impl VerifiedCache for SVCache {
+    fn lookup(&mut self, p_id: PageId) -> Option<(Box<[u8]>, bool)> {
+        self.store.lookup(NodeId::from_page_id(p_id))
     }
 
-    // change `is_valid` for all nodes to "false"
-    pub fn unconfirm(&mut self) {
-        for (_, cache_n) in self.lru.iter_mut() {
-            match cache_n {
-                SVCacheNode::Leaf(l) => {
-                    l.unconfirm();
-                }
-                SVCacheNode::NonLeaf(n) => n.unconfirm(),
-            }
-        }
+    fn confirm_path(&mut self, p_id: PageId) -> Vec<Digest> {
+        self.store.confirm_path(NodeId::from_page_id(p_id))
     }
 
-    // change `is_valid` for all covered nodes of a sub-root to "true"
-    pub(crate) fn confirm(&mut self, root_id: NodeId) {
-        let mut covered_ids = Vec::new();
-        let h = root_id.get_height();
-        let w = root_id.get_width();
-        for i in 0..h + 1 {
-            let a = 2_i32.pow(h - i) as u32;
-            for j in (w * a)..((w + 1) * a) {
-                covered_ids.push(NodeId::new(i, j as u32));
-            }
-        }
-        for id in covered_ids {
-            if let Some(n) = self.get_node_mut(&id.to_digest()) {
-                n.validate();
-            } else {
-                warn!("Cannot find a node during cache confirming");
-            }
+    fn fresh_subroot(&mut self, p_id: PageId, vbf: &VersionBloomFilter) -> Option<NodeId> {
+        let id = NodeId::from_page_id(p_id);
+        match self.store.get_node(&id)? {
+            SVCacheNode::Leaf(l) if !vbf.contains(p_id, l.get_version()) => Some(id),
+            _ => None,
         }
     }
 
-    // change `is_valid` for all covered nodes of a sub-root to "true" and set version
-    pub(crate) fn confirm_with_version(&mut self, root_id: NodeId, version: u32) {
-        let mut covered_ids = Vec::new();
-        let h = root_id.get_height();
-        let w = root_id.get_width();
-        for i in 0..h + 1 {
-            let a = 2_i32.pow(h - i) as u32;
-            for j in (w * a)..((w + 1) * a) {
-                covered_ids.push(NodeId::new(i, j as u32));
-            }
-        }
+    fn confirm(&mut self, root_id: NodeId) {
+        self.store.confirm(root_id);
+    }
 
-        for id in covered_ids {
-            if let Some(n) = self.get_node_mut(&id.to_digest()) {
-                match n {
-                    SVCacheNode::Leaf(l) => {
-                        l.validate_with_version(version);
-                    }
-                    SVCacheNode::NonLeaf(non) => {
-                        non.validate();
-                    }
-                }
-            } else {
-                warn!("Cannot find a node during cache confirming");
-            }
-        }
+    fn confirm_remote(&mut self, root_id: NodeId) {
+        let version = unsafe { GLOBAL_TS };
+        self.store.confirm_with_version(root_id, version);
     }
 
-    pub fn cache_size_and_height(&self) -> (u32, u32) {
-        let mut height = 0;
-        let mut size = 0;
-        for (_, n) in self.lru.iter() {
-            match n {
-                SVCacheNode::Leaf(_) => {
-                    size += 4100;
-                }
-                SVCacheNode::NonLeaf(n) => {
-                    size += 40;
-                    let id = n.get_id();
-                    let h = id.get_height();
-                    if height < h {
-                        height = h;
-                    }
-                }
-            }
-        }
+    fn insert(&mut self, p_id: PageId, bytes: Box<[u8]>, _vbf: &VersionBloomFilter) {
+        let version = unsafe { GLOBAL_TS };
+        let leaf = SVCacheLeafNode::new(p_id, PageBytes::new(bytes, self.compression), version);
+        self.store.insert(SVCacheNode::Leaf(leaf));
+    }
 
-        (size, height)
+    fn unconfirm(&mut self) {
+        self.store.unconfirm();
     }
 
-    pub(crate) fn find_parent(&mut self, n_id: NodeId) -> Option<&SVCacheNode> {
-        let height = n_id.get_height() + 1;
-        let width = n_id.get_width() / 2;
-        let parent_id = NodeId::new(height, width);
-        self.get_node(&parent_id.to_digest())
+    fn clear(&mut self) {
+        self.store.clear();
     }
 
-    pub(crate) fn find_sib(&mut self, id: NodeId) -> Option<&SVCacheNode> {
-        let sib_key = id.get_sib_id().to_digest();
-        if let Some(node) = self.get_node(&sib_key) {
-            if node.is_valid() {
-                return Some(node);
-            }
-        }
-        None
-    }
-
-    pub(crate) fn has_sib(&self, id: NodeId) -> bool {
-        let sib_key = id.get_sib_id().to_digest();
-        self.lru.contains(&sib_key)
-    }
-
-    pub(crate) fn insert(
-        &mut self,
-        p_id: PageId,
-        bytes: Box<[u8; PAGE_SIZE as usize]>,
-        version: u32,
-    ) {
-        let new_n = SVCacheLeafNode::new(p_id, bytes, version);
-        let mut cur_id = NodeId::from_page_id(p_id);
-        let mut cur_hash = new_n.to_digest();
-        self.push_node(cur_id, SVCacheNode::Leaf(new_n));
-
-        while let Some(sib) = self.find_sib(cur_id) {
-            let parent_id = cur_id.get_parent_id();
-            let parent_hash = if cur_id.is_even() {
-                merge_hash(&cur_hash, &sib.to_digest())
-            } else {
-                merge_hash(&sib.to_digest(), &cur_hash)
-            };
-            let parent = SVCacheNonLeafNode::new(parent_id, parent_hash);
-            cur_hash = parent_hash;
-            cur_id = parent_id;
-            self.push_node(parent_id, SVCacheNode::NonLeaf(parent));
-        }
+    fn cache_size_and_height(&self) -> (usize, u32) {
+        self.store.cache_size_and_height()
     }
 }
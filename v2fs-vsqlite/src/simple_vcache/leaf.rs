@@ -1,7 +1,7 @@
 use crate::{
     digest::{Digest, Digestible},
     merkle_cb_tree::NodeId,
-    vfs::PAGE_SIZE,
+    verified_cache::compression::PageBytes,
     PageId,
 };
 
@@ -10,13 +10,13 @@ use super::hash::leaf_hash;
 #[derive(Clone)]
 pub(crate) struct SVCacheLeafNode {
     id: NodeId,
-    bytes: Box<[u8; PAGE_SIZE as usize]>,
+    bytes: PageBytes,
     version: u32,
     is_valid: bool,
 }
 
 impl SVCacheLeafNode {
-    pub(crate) fn new(p_id: PageId, bytes: Box<[u8; PAGE_SIZE as usize]>, version: u32) -> Self {
+    pub(crate) fn new(p_id: PageId, bytes: PageBytes, version: u32) -> Self {
         Self {
             id: NodeId::new(0, p_id.get_id()),
             bytes,
@@ -33,8 +33,12 @@ impl SVCacheLeafNode {
         self.version
     }
 
-    pub(crate) fn get_bytes(&self) -> Box<[u8; PAGE_SIZE as usize]> {
-        self.bytes.clone()
+    pub(crate) fn get_bytes(&self) -> Option<Box<[u8]>> {
+        self.bytes.get()
+    }
+
+    pub(crate) fn heap_size(&self) -> usize {
+        self.bytes.heap_size()
     }
 
     pub(crate) fn is_valid(&self) -> bool {
@@ -57,6 +61,6 @@ impl SVCacheLeafNode {
 
 impl Digestible for SVCacheLeafNode {
     fn to_digest(&self) -> Digest {
-        leaf_hash(self.id.get_width(), &self.bytes.to_digest())
+        leaf_hash(self.id.get_width(), &self.bytes.digest())
     }
 }
@@ -0,0 +1,176 @@
+use crate::utils::ResInfo;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, fs, ops::AddAssign, path::Path};
+
+thread_local! {
+    // counters of the query running on this thread, sqlite calls `u_read` on it too
+    static STATS: RefCell<QueryStats> = RefCell::new(QueryStats::default());
+}
+
+// what one query cost the user, in cache lookups and round trips
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct QueryStats {
+    // pages sqlite read through `u_read`, repeats included
+    pub pages_requested: usize,
+    // cached pages that were already valid
+    pub valid_hits: usize,
+    // cached pages validated by the vbf without asking the server
+    pub vbf_hits: usize,
+    // CONFIRM round trips, by the height the server matched the path at
+    pub confirms: usize,
+    pub confirmed_at_leaf: usize,
+    pub confirmed_at_ancestor: usize,
+    // stale cached pages the server sent again in reply to CONFIRM
+    pub refetched: usize,
+    // pages that were not cached
+    pub fetched: usize,
+    pub bytes_sent: usize,
+    pub bytes_received: usize,
+}
+
+impl QueryStats {
+    // with the vbf, a CONFIRM is only sent for a page the filter shows as written since it was
+    // cached; the share of unchanged cached pages the filter still flagged, the vbf never misses
+    // a changed one
+    pub fn vbf_false_positive_rate(&self) -> f64 {
+        let flagged_unchanged = self.confirms - self.refetched;
+        match flagged_unchanged + self.vbf_hits {
+            0 => 0.0,
+            unchanged => flagged_unchanged as f64 / unchanged as f64,
+        }
+    }
+}
+
+impl AddAssign<&QueryStats> for QueryStats {
+    fn add_assign(&mut self, rhs: &QueryStats) {
+        self.pages_requested += rhs.pages_requested;
+        self.valid_hits += rhs.valid_hits;
+        self.vbf_hits += rhs.vbf_hits;
+        self.confirms += rhs.confirms;
+        self.confirmed_at_leaf += rhs.confirmed_at_leaf;
+        self.confirmed_at_ancestor += rhs.confirmed_at_ancestor;
+        self.refetched += rhs.refetched;
+        self.fetched += rhs.fetched;
+        self.bytes_sent += rhs.bytes_sent;
+        self.bytes_received += rhs.bytes_received;
+    }
+}
+
+pub(crate) fn count(f: impl FnOnce(&mut QueryStats)) {
+    STATS.with(|s| f(&mut s.borrow_mut()));
+}
+
+pub(crate) fn sent(len: usize) {
+    count(|s| s.bytes_sent += len);
+}
+
+pub(crate) fn received(len: usize) {
+    count(|s| s.bytes_received += len);
+}
+
+// counters since the last call, which start again from zero
+pub fn take_stats() -> QueryStats {
+    STATS.with(|s| s.take())
+}
+
+// one line of the per-query report, csv cannot write nested structs
+#[derive(Debug, Serialize)]
+struct StatsRow {
+    query: usize,
+    query_us: u64,
+    verify_us: u64,
+    proof_bytes: usize,
+    cache_bytes: usize,
+    pages_requested: usize,
+    valid_hits: usize,
+    vbf_hits: usize,
+    confirms: usize,
+    confirmed_at_leaf: usize,
+    confirmed_at_ancestor: usize,
+    refetched: usize,
+    fetched: usize,
+    bytes_sent: usize,
+    bytes_received: usize,
+}
+
+impl StatsRow {
+    fn new(query: usize, info: &ResInfo) -> Self {
+        let s = &info.stats;
+        Self {
+            query,
+            query_us: info.query_t.real,
+            verify_us: info.verify_t.real,
+            proof_bytes: info.proof_s,
+            cache_bytes: info.cache_size,
+            pages_requested: s.pages_requested,
+            valid_hits: s.valid_hits,
+            vbf_hits: s.vbf_hits,
+            confirms: s.confirms,
+            confirmed_at_leaf: s.confirmed_at_leaf,
+            confirmed_at_ancestor: s.confirmed_at_ancestor,
+            refetched: s.refetched,
+            fetched: s.fetched,
+            bytes_sent: s.bytes_sent,
+            bytes_received: s.bytes_received,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct StatsReport<'a> {
+    queries: Vec<StatsRow>,
+    total: &'a QueryStats,
+}
+
+// write the stats of every query as csv if the path ends with .csv, as json with the
+// totals otherwise
+pub fn write_stats(path: impl AsRef<Path>, infos: &[ResInfo], total: &QueryStats) -> Result<()> {
+    let path = path.as_ref();
+    let rows = infos
+        .iter()
+        .enumerate()
+        .map(|(i, info)| StatsRow::new(i, info));
+    let data = if path.extension().is_some_and(|e| e == "csv") {
+        let mut w = csv::Writer::from_writer(Vec::new());
+        for row in rows {
+            w.serialize(row)?;
+        }
+        w.into_inner()?
+    } else {
+        serde_json::to_vec_pretty(&StatsReport {
+            queries: rows.collect(),
+            total,
+        })?
+    };
+    fs::write(path, data).with_context(|| format!("failed to write stats {:?}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{count, received, sent, take_stats, QueryStats};
+
+    #[test]
+    fn test_stats() {
+        take_stats();
+        count(|s| s.valid_hits += 2);
+        sent(10);
+        received(4096);
+        let stats = take_stats();
+        assert_eq!((stats.valid_hits, stats.bytes_sent), (2, 10));
+        assert_eq!(take_stats(), QueryStats::default());
+
+        let mut total = QueryStats::default();
+        total += &stats;
+        total += &stats;
+        assert_eq!(total.bytes_received, 8192);
+
+        let stats = QueryStats {
+            vbf_hits: 9,
+            confirms: 4,
+            refetched: 1,
+            ..Default::default()
+        };
+        assert_eq!(stats.vbf_false_positive_rate(), 0.25);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{
+    cache::Cache,
+    digest::Digest,
+    eviction::Eviction,
+    merkle_cb_tree::NodeId,
+    simple_vcache::SVCache,
+    vbf::VersionBloomFilter,
+    verified_cache::{NoCache, VerifiedCache},
+    PageId,
+};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, collections::HashSet, fs, path::Path};
+
+thread_local! {
+    // page ids read from the main database by the current query, while recording
+    static RECORDER: RefCell<Option<Vec<u32>>> = const { RefCell::new(None) };
+}
+
+pub fn start_recording() {
+    RECORDER.with(|r| *r.borrow_mut() = Some(Vec::new()));
+}
+
+// page ids recorded since `start_recording`, recording stops
+pub fn stop_recording() -> Vec<u32> {
+    RECORDER.with(|r| r.borrow_mut().take()).unwrap_or_default()
+}
+
+pub(crate) fn record(p_ids: &[PageId]) {
+    RECORDER.with(|r| {
+        if let Some(trace) = r.borrow_mut().as_mut() {
+            trace.extend(p_ids.iter().map(|p| p.get_id()));
+        }
+    });
+}
+
+// pages requested by `u_read` during a workload, stored as json
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Trace {
+    // root hash and vbf version the workload was read at
+    pub root: String,
+    pub version: u32,
+    pub opt_level: u8,
+    pub page_size: u32,
+    // page ids of each query in request order, repeats included
+    pub queries: Vec<Vec<u32>>,
+}
+
+impl Trace {
+    pub fn new(root: Digest, version: u32, opt_level: u8, page_size: u32) -> Self {
+        Self {
+            root: root.to_string(),
+            version,
+            opt_level,
+            page_size,
+            queries: Vec::new(),
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path).with_context(|| format!("failed to read trace {:?}", path))?;
+        serde_json::from_slice(&data).with_context(|| format!("invalid trace {:?}", path))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        let data = serde_json::to_vec(self)?;
+        fs::write(path, data).with_context(|| format!("failed to write trace {:?}", path))
+    }
+
+    // distinct pages in order of first access
+    pub fn pages(&self) -> Vec<PageId> {
+        let mut seen = HashSet::new();
+        self.queries
+            .iter()
+            .flatten()
+            .filter(|p| seen.insert(**p))
+            .map(|p| PageId(*p))
+            .collect()
+    }
+
+    // replay the trace through a cache of `budget` bytes without a server, as if no
+    // page changed in between
+    pub fn simulate(&self, opt_level: u8, budget: usize, eviction: Eviction) -> SimStats {
+        let mut cache: Box<dyn VerifiedCache> = match opt_level {
+            0 => Box::new(NoCache),
+            1 | 2 => Box::new(Cache::with_eviction(budget, eviction)),
+            _ => Box::new(SVCache::with_eviction(budget, eviction)),
+        };
+        let vbf = VersionBloomFilter::new(1, 1);
+        let mut stats = SimStats::default();
+        for query in &self.queries {
+            if opt_level == 1 {
+                cache.clear();
+            } else {
+                cache.unconfirm();
+            }
+            for p_id in query.iter().map(|p| PageId(*p)) {
+                match cache.lookup(p_id) {
+                    Some((_, true)) => stats.hits += 1,
+                    Some(_) => {
+                        stats.hits += 1;
+                        // level 3 finds an unchanged page in the vbf, level 2 asks the server
+                        if opt_level == 2 {
+                            stats.confirms += 1;
+                        }
+                        cache.confirm(NodeId::from_page_id(p_id));
+                    }
+                    None => {
+                        stats.misses += 1;
+                        let bytes = vec![0; self.page_size as usize].into_boxed_slice();
+                        cache.insert(p_id, bytes, &vbf);
+                    }
+                }
+            }
+            stats.peak = stats.peak.max(cache.cache_size_and_height().0);
+        }
+        stats
+    }
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct SimStats {
+    pub hits: usize,
+    pub misses: usize,
+    // hits on cached pages the server had to confirm
+    pub confirms: usize,
+    // bytes held by the cache at most
+    pub peak: usize,
+}
+
+impl SimStats {
+    pub fn hit_ratio(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{record, start_recording, stop_recording, Trace};
+    use crate::{digest::Digest, eviction::Eviction, PageId};
+
+    #[test]
+    fn test_trace() {
+        record(&[PageId(9)]);
+        start_recording();
+        record(&[PageId(1), PageId(2)]);
+        record(&[PageId(1)]);
+        assert_eq!(stop_recording(), vec![1, 2, 1]);
+        assert!(stop_recording().is_empty());
+
+        let mut trace = Trace::new(Digest::zero(), 1, 2, 4096);
+        trace.queries = vec![vec![1, 2, 1], vec![2, 3]];
+        assert_eq!(trace.pages(), vec![PageId(1), PageId(2), PageId(3)]);
+
+        let big = trace.simulate(2, 1 << 20, Eviction::Lru);
+        assert_eq!((big.hits, big.misses, big.confirms), (2, 3, 1));
+        // intra-query caching forgets page 2 between queries
+        let intra = trace.simulate(1, 1 << 20, Eviction::Lru);
+        assert_eq!((intra.hits, intra.misses), (1, 4));
+        // room for a single page, every page evicts the previous one
+        let small = trace.simulate(2, 5000, Eviction::Lru);
+        assert_eq!((small.hits, small.misses), (0, 5));
+        assert!(small.peak <= 5000);
+    }
+}
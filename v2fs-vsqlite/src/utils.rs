@@ -15,16 +15,19 @@ use crate::{
     digest::{Digest, Digestible},
     merkle_cb_tree::ReadInterface,
     simple_vcache::SVCache,
+    stats::{self, QueryStats},
     vbf::VersionBloomFilter,
     version_cache::VCache,
     vfs::{
-        server_vfs::register_server, user_vfs::register_user, DEFAULT, END, MERKLE_PATH, NO_FLAG,
-        SERVER_VFS, USER_VFS, YES_FLAG,
+        server_vfs::{publish_vbf_digest, register_server},
+        set_page_size,
+        user_vfs::register_user,
+        DEFAULT, END, MERKLE_PATH, NO_FLAG, SERVER_VFS, USER_VFS, VBF_PATH, YES_FLAG,
     },
     MerkleDB, PageId, Parameter, ServerVfs, Type, UserVfs,
 };
 
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Default, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Time {
     pub real: u64,
     user: u64,
@@ -46,16 +49,25 @@ pub struct ResInfo {
     pub query_t: Time,
     pub verify_t: Time,
     pub proof_s: usize,
-    pub cache_size: u32,
+    // bytes held by the cache after the query
+    pub cache_size: usize,
+    pub stats: QueryStats,
 }
 
 impl ResInfo {
-    pub fn new(query_t: Time, verify_t: Time, proof_s: usize, cache_size: u32) -> Self {
+    pub fn new(
+        query_t: Time,
+        verify_t: Time,
+        proof_s: usize,
+        cache_size: usize,
+        stats: QueryStats,
+    ) -> Self {
         Self {
             query_t,
             verify_t,
             proof_s,
             cache_size,
+            stats,
         }
     }
 }
@@ -72,6 +84,7 @@ pub fn end_request(stream: &mut TcpStream) -> Result<()> {
     let end_info: (u32, PageId, Vec<Digest>) = (END, PageId(0), vec![]);
     let bytes = bincode::serialize(&end_info).expect("failed to serialize");
     let _w_amt = stream.write(&bytes)?;
+    stats::sent(bytes.len());
     Ok(())
 }
 
@@ -80,6 +93,8 @@ pub fn hand_shake(stream: &mut TcpStream, signal: u32) -> Result<()> {
     let mut reader = BufReader::new(stream);
     let mut buffer = [0; 4];
     reader.read_exact(&mut buffer)?;
+    stats::sent(4);
+    stats::received(4);
     let sig = bincode::deserialize::<u32>(&buffer)?;
 
     if sig == YES_FLAG {
@@ -93,19 +108,36 @@ pub fn hand_shake(stream: &mut TcpStream, signal: u32) -> Result<()> {
 
 // should get the height from blockchain
 pub fn get_height() -> Result<u32> {
-    let param = serde_json::from_str::<Parameter>(&fs::read_to_string(
-        Path::new(MERKLE_PATH).join("param.json"),
-    )?)?;
+    get_height_at(Path::new(MERKLE_PATH))
+}
+
+pub fn get_height_at(merkle_path: &Path) -> Result<u32> {
+    let param =
+        serde_json::from_str::<Parameter>(&fs::read_to_string(merkle_path.join("param.json"))?)?;
     Ok(param.get_height())
 }
 
+// page size of the database the tree is built on
+pub fn get_page_size() -> Result<u32> {
+    get_page_size_at(Path::new(MERKLE_PATH))
+}
+
+pub fn get_page_size_at(merkle_path: &Path) -> Result<u32> {
+    let param =
+        serde_json::from_str::<Parameter>(&fs::read_to_string(merkle_path.join("param.json"))?)?;
+    Ok(param.get_page_size())
+}
+
 // should get the root from blockchain
-fn get_root() -> Result<Digest> {
-    let param = serde_json::from_str::<Parameter>(&fs::read_to_string(
-        Path::new(MERKLE_PATH).join("param.json"),
-    )?)?;
+pub fn get_root() -> Result<Digest> {
+    get_root_at(Path::new(MERKLE_PATH))
+}
+
+pub fn get_root_at(merkle_path: &Path) -> Result<Digest> {
+    let param =
+        serde_json::from_str::<Parameter>(&fs::read_to_string(merkle_path.join("param.json"))?)?;
     let root_id = param.get_root_id().expect("Root digest not exists");
-    let merkle_db = MerkleDB::open_read_only(Path::new(MERKLE_PATH))?;
+    let merkle_db = MerkleDB::open_read_only(merkle_path)?;
     let root_n = merkle_db.get_node(&root_id.to_digest())?;
     if let Some(n) = root_n {
         Ok(n.get_hash())
@@ -113,6 +145,21 @@ fn get_root() -> Result<Digest> {
         bail!("Cannot find root in merkle tree");
     }
 }
+
+// should get the vbf digest from blockchain, along with the root
+pub fn get_vbf_digest() -> Result<Digest> {
+    get_vbf_digest_at(Path::new(MERKLE_PATH))
+}
+
+pub fn get_vbf_digest_at(merkle_path: &Path) -> Result<Digest> {
+    let param =
+        serde_json::from_str::<Parameter>(&fs::read_to_string(merkle_path.join("param.json"))?)?;
+    match param.get_vbf_digest() {
+        Some(d) => Ok(d),
+        None => bail!("no vbf digest is published with the root"),
+    }
+}
+
 pub fn compare_with_root(computed_hash: Digest) -> Result<()> {
     let root_hash = get_root()?;
     if computed_hash == root_hash {
@@ -122,14 +169,9 @@ pub fn compare_with_root(computed_hash: Digest) -> Result<()> {
     }
 }
 
-pub fn cal_cap(c_size_in_mb: usize, opt_level: u8) -> usize {
-    let total_byte_num = c_size_in_mb * 1024 * 1024;
-    let cap = total_byte_num / 4100;
-    if opt_level == 2 {
-        (cap as f64 * 1.4) as usize
-    } else {
-        cap
-    }
+// cache budget in bytes, shared by all opt levels so they compare at equal memory
+pub fn cal_budget(c_size_in_mb: usize) -> usize {
+    c_size_in_mb * 1024 * 1024
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -143,6 +185,7 @@ pub fn register_vfs(
     map_size: usize,
     hash_num: u32,
 ) -> Result<()> {
+    set_page_size(get_page_size()?)?;
     let u_vfs = UserVfs::new(
         tp,
         cache,
@@ -154,13 +197,18 @@ pub fn register_vfs(
     );
     register_user(USER_VFS, u_vfs)?;
 
-    let s_vfs = ServerVfs::new(
-        MERKLE_PATH.to_string(),
-        HashMap::new(),
-        VersionBloomFilter::new(map_size, hash_num),
-    );
-    register_server(SERVER_VFS, s_vfs)?;
-    Ok(())
+    // only the writer creates, saves and publishes the vbf, users get a copy from the server
+    let vbf = VersionBloomFilter::open_read_only(Path::new(VBF_PATH), map_size, hash_num)?;
+    let s_vfs = ServerVfs::new(MERKLE_PATH.to_string(), vbf);
+    register_server(SERVER_VFS, s_vfs)
+}
+
+// the writer keeps the vbf across runs and publishes its digest with the root
+pub fn register_server_vfs(map_size: usize, hash_num: u32) -> Result<()> {
+    let vbf = VersionBloomFilter::open_or_create(Path::new(VBF_PATH), map_size, hash_num)?;
+    publish_vbf_digest(&vbf)?;
+    let s_vfs = ServerVfs::new(MERKLE_PATH.to_string(), vbf);
+    register_server(SERVER_VFS, s_vfs)
 }
 
 pub fn default_connect() -> Result<TcpStream> {
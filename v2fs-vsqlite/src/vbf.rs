@@ -1,14 +1,28 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
 use std::{
-    collections::{
-        hash_map::{DefaultHasher, RandomState},
-        HashSet,
-    },
-    hash::{BuildHasher, Hash, Hasher},
+    collections::{BTreeMap, BTreeSet, HashSet, VecDeque},
+    fs,
+    path::Path,
+};
+
+use crate::{
+    digest::{blake2, Digest, Digestible},
+    PageId,
 };
 
-use crate::PageId;
+// slot changes kept for deltas, older ones are only available as a full filter
+const CHANGE_LOG_LEN: usize = 4096;
 
-#[derive(Clone, Debug, Default)]
+// share of non-zero slots past which the writer grows the filter, a stale cached page
+// passes `contains` with about this ratio to the power of `hash_num`
+pub const MAX_FILL_RATIO: f64 = 0.5;
+
+// share of unchanged cached pages flagged by the filter past which a client warns; a filter at
+// `MAX_FILL_RATIO` with 4 hashes flags about 6% of them
+pub const MAX_FP_RATE: f64 = 0.1;
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 struct VersionMap {
     map: Vec<u32>,
 }
@@ -27,27 +41,229 @@ impl VersionMap {
     }
 }
 
-#[derive(Clone, Debug)]
+// page positions only depend on the header, so a filter sent to another process marks
+// the same slots as the one it was copied from
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct VersionBloomFilter {
-    vmap: VersionMap,
+    // key of the slot hashes
+    seed: u64,
     map_size: u64,
     hash_num: u32,
-    hashers: [DefaultHasher; 2],
+    vmap: VersionMap,
+    // bumped by every insert
+    counter: u64,
+    // (counter, slot) of the latest slot changes, complete for every counter after `log_from`
+    log: VecDeque<(u64, u32)>,
+    log_from: u64,
+    // latest version of every written page, kept by the writer to rebuild the filter
+    versions: BTreeMap<PageId, u32>,
+}
+
+// what a user needs to catch up with the server's filter
+#[derive(Debug, Serialize, Deserialize)]
+pub enum VbfUpdate {
+    Full(VersionBloomFilter),
+    // new values of the slots changed between two counters
+    Delta {
+        from: u64,
+        to: u64,
+        slots: Vec<(u32, u32)>,
+    },
 }
 
 impl VersionBloomFilter {
     pub fn new(map_size: usize, hash_num: u32) -> Self {
-        let hashers = [
-            RandomState::new().build_hasher(),
-            RandomState::new().build_hasher(),
-        ];
+        Self::with_seed(map_size, hash_num, rand::random())
+    }
 
+    pub fn with_seed(map_size: usize, hash_num: u32, seed: u64) -> Self {
         Self {
-            vmap: VersionMap::new(map_size),
+            seed,
             map_size: map_size as u64,
             hash_num,
-            hashers,
+            vmap: VersionMap::new(map_size),
+            counter: 0,
+            log: VecDeque::new(),
+            log_from: 0,
+            versions: BTreeMap::new(),
+        }
+    }
+
+    // the filter persisted at `path`, rebuilt if asked for more slots or other hashes, or a
+    // new one saved there
+    pub fn open_or_create(path: &Path, map_size: usize, hash_num: u32) -> Result<Self> {
+        if path.exists() {
+            let mut vbf = Self::load(path)?;
+            if vbf.map_size < map_size as u64 || vbf.hash_num != hash_num {
+                info!(
+                    "rebuilding the persisted vbf with {} slots and {} hashes",
+                    map_size, hash_num
+                );
+                vbf = vbf.rebuild(map_size.max(vbf.map_size as usize), hash_num);
+                vbf.save(path)?;
+            }
+            Ok(vbf)
+        } else {
+            let vbf = Self::new(map_size, hash_num);
+            vbf.save(path)?;
+            Ok(vbf)
+        }
+    }
+
+    // the filter persisted at `path`, or a new one if there is none yet; nothing is written, for
+    // processes other than the writer
+    pub fn open_read_only(path: &Path, map_size: usize, hash_num: u32) -> Result<Self> {
+        if path.exists() {
+            Self::load(path)
+        } else {
+            Ok(Self::new(map_size, hash_num))
+        }
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = fs::read(path).with_context(|| format!("failed to read vbf {:?}", path))?;
+        bincode::deserialize(&data).with_context(|| format!("invalid vbf {:?}", path))
+    }
+
+    // written aside and renamed, readers never see a partial filter
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, bincode::serialize(self)?)
+            .with_context(|| format!("failed to write vbf {:?}", tmp))?;
+        fs::rename(&tmp, path).with_context(|| format!("failed to replace vbf {:?}", path))
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn counter(&self) -> u64 {
+        self.counter
+    }
+
+    pub fn map_size(&self) -> usize {
+        self.map_size as usize
+    }
+
+    // share of slots some page was written to
+    pub fn fill_ratio(&self) -> f64 {
+        let filled = self.vmap.map.iter().filter(|v| **v > 0).count();
+        filled as f64 / self.map_size as f64
+    }
+
+    // version of the last batch written, pages fetched after it are cached with it
+    pub fn latest_version(&self) -> u32 {
+        self.vmap.map.iter().max().copied().unwrap_or(0)
+    }
+
+    // chance that a page with no newer write still passes `contains`
+    pub fn false_positive_rate(&self) -> f64 {
+        self.fill_ratio().powi(self.hash_num as i32)
+    }
+
+    // a new filter with a new seed holding the latest version of every written page; users
+    // still on the old seed get it whole with their next update
+    pub fn rebuild(&self, map_size: usize, hash_num: u32) -> Self {
+        let mut vbf = Self::new(map_size, hash_num);
+        for (p_id, version) in &self.versions {
+            let (h1, h2) = vbf.hash_kernel(*p_id);
+            for i in 0..hash_num {
+                let idx = vbf.get_idx(h1, h2, i as u64);
+                vbf.vmap.set(idx, vbf.get_map_val(idx).max(*version));
+            }
+        }
+        vbf.counter = self.counter + 1;
+        vbf.log_from = vbf.counter;
+        vbf.versions = self.versions.clone();
+        vbf
+    }
+
+    // rebuilt at twice the size, or more, until it is half as full as allowed
+    pub fn grow_if_saturated(&mut self) -> bool {
+        if self.fill_ratio() <= MAX_FILL_RATIO {
+            return false;
+        }
+        let written = self.versions.values().filter(|v| **v > 0).count() as f64;
+        let mut map_size = self.map_size as usize * 2;
+        // expected fill of m slots after n pages with k hashes is 1 - e^(-kn/m)
+        while 1.0 - (-(self.hash_num as f64) * written / map_size as f64).exp()
+            > MAX_FILL_RATIO / 2.0
+        {
+            map_size *= 2;
+        }
+        info!(
+            "vbf is {:.2} full, growing it from {} to {} slots",
+            self.fill_ratio(),
+            self.map_size,
+            map_size
+        );
+        *self = self.rebuild(map_size, self.hash_num);
+        true
+    }
+
+    // changes since `counter` of a filter hashed with `seed`, the whole filter when the
+    // log does not reach back that far or the delta would not be smaller
+    pub fn update_since(&self, seed: u64, counter: u64) -> VbfUpdate {
+        if seed != self.seed || counter < self.log_from || counter > self.counter {
+            return VbfUpdate::Full(self.without_log());
+        }
+        let changed = self
+            .log
+            .iter()
+            .filter(|(c, _)| *c > counter)
+            .map(|(_, slot)| *slot)
+            .collect::<BTreeSet<_>>();
+        // a (slot, version) pair takes two slots of the full filter
+        if changed.len() * 2 >= self.map_size as usize {
+            return VbfUpdate::Full(self.without_log());
+        }
+        VbfUpdate::Delta {
+            from: counter,
+            to: self.counter,
+            slots: changed
+                .into_iter()
+                .map(|slot| (slot, self.get_map_val(slot as usize)))
+                .collect(),
+        }
+    }
+
+    // a copy for users, who never send deltas nor rebuild the filter
+    fn without_log(&self) -> Self {
+        Self {
+            seed: self.seed,
+            map_size: self.map_size,
+            hash_num: self.hash_num,
+            vmap: self.vmap.clone(),
+            counter: self.counter,
+            log: VecDeque::new(),
+            log_from: self.counter,
+            versions: BTreeMap::new(),
+        }
+    }
+
+    // catch up with the server, a full filter replaces this one
+    pub fn apply(&mut self, update: VbfUpdate) -> Result<()> {
+        match update {
+            VbfUpdate::Full(vbf) => *self = vbf,
+            VbfUpdate::Delta { from, to, slots } => {
+                if from != self.counter {
+                    bail!("vbf delta from {} applied at {}", from, self.counter);
+                }
+                for (slot, version) in slots {
+                    if slot as u64 >= self.map_size {
+                        bail!("vbf delta slot {} out of range", slot);
+                    }
+                    self.vmap.set(slot as usize, version);
+                }
+                self.counter = to;
+            }
         }
+        Ok(())
+    }
+
+    // both filters put every page in the same slots
+    pub fn same_hashing(&self, other: &Self) -> bool {
+        (self.seed, self.map_size, self.hash_num) == (other.seed, other.map_size, other.hash_num)
     }
 
     fn get_map_val(&self, idx: usize) -> u32 {
@@ -55,11 +271,21 @@ impl VersionBloomFilter {
         map.get(idx)
     }
 
+    // versions never go back, a page cached at a published version must keep looking stale
     pub fn insert(&mut self, p_id: PageId, version: u32) {
+        self.counter += 1;
+        let latest = self.versions.entry(p_id).or_default();
+        *latest = (*latest).max(version);
         let (h1, h2) = self.hash_kernel(p_id);
         for i in 0..self.hash_num {
             let idx = self.get_idx(h1, h2, i as u64);
-            self.vmap.set(idx, version);
+            self.vmap.set(idx, self.get_map_val(idx).max(version));
+            self.log.push_back((self.counter, idx as u32));
+        }
+        while self.log.len() > CHANGE_LOG_LEN {
+            if let Some((c, _)) = self.log.pop_front() {
+                self.log_from = self.log_from.max(c);
+            }
         }
     }
 
@@ -99,15 +325,13 @@ impl VersionBloomFilter {
     }
 
     fn hash_kernel(&self, p_id: PageId) -> (u64, u64) {
-        let hasher1 = &mut self.hashers[0].clone();
-        let hasher2 = &mut self.hashers[1].clone();
-        p_id.hash(hasher1);
-        p_id.hash(hasher2);
-
-        let hash1 = hasher1.finish();
-        let hash2 = hasher2.finish();
-
-        (hash1, hash2)
+        let hash = blake2b_simd::Params::new()
+            .hash_length(16)
+            .key(&self.seed.to_le_bytes())
+            .hash(&p_id.get_id().to_le_bytes());
+        let (h1, h2) = hash.as_bytes().split_at(8);
+        let to_u64 = |b: &[u8]| u64::from_le_bytes(b.try_into().expect("8 bytes"));
+        (to_u64(h1), to_u64(h2))
     }
 
     // g(x) = h1(x) + ih2(x)
@@ -116,6 +340,21 @@ impl VersionBloomFilter {
     }
 }
 
+// published with the root, the log and versions are left out as users never get them
+impl Digestible for VersionBloomFilter {
+    fn to_digest(&self) -> Digest {
+        let mut state = blake2().to_state();
+        state.update(&self.seed.to_le_bytes());
+        state.update(&self.map_size.to_le_bytes());
+        state.update(&self.hash_num.to_le_bytes());
+        state.update(&self.counter.to_le_bytes());
+        for v in &self.vmap.map {
+            state.update(&v.to_le_bytes());
+        }
+        Digest::from(state.finalize())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -135,4 +374,122 @@ mod tests {
         vbf.insert(PageId(4), 1);
         assert!(vbf.contains(PageId(4), 0));
     }
+
+    #[test]
+    fn test_vbf_version_never_lowered() {
+        let mut vbf = VersionBloomFilter::with_seed(100, 4, 7);
+        vbf.insert(PageId(4), 5);
+        vbf.insert(PageId(4), 2);
+        assert!(vbf.contains(PageId(4), 4));
+        assert_eq!(vbf.versions[&PageId(4)], 5);
+        assert_eq!(vbf.latest_version(), 5);
+    }
+
+    #[test]
+    fn test_vbf_deterministic() {
+        let mut vbf = VersionBloomFilter::with_seed(100, 4, 7);
+        vbf.insert(PageId(4), 3);
+        assert_eq!(vbf.latest_version(), 3);
+        let copy: VersionBloomFilter =
+            bincode::deserialize(&bincode::serialize(&vbf).unwrap()).unwrap();
+        assert!(copy.same_hashing(&vbf));
+        assert!(copy.contains(PageId(4), 2));
+        assert_eq!(
+            VersionBloomFilter::with_seed(100, 4, 7).get_bf_pos(PageId(4)),
+            vbf.get_bf_pos(PageId(4))
+        );
+    }
+
+    #[test]
+    fn test_vbf_delta() {
+        let mut server = VersionBloomFilter::with_seed(1000, 4, 7);
+        server.insert(PageId(1), 1);
+        let mut user = VersionBloomFilter::new(1000, 4);
+        // another seed gets the whole filter
+        let update = server.update_since(user.seed(), user.counter());
+        assert!(matches!(update, VbfUpdate::Full(_)));
+        user.apply(update).unwrap();
+        assert!(user.log.is_empty());
+
+        server.insert(PageId(2), 2);
+        let update = server.update_since(user.seed(), user.counter());
+        let VbfUpdate::Delta { ref slots, .. } = update else {
+            panic!("expect a delta");
+        };
+        assert!(slots.len() <= 4);
+        user.apply(update).unwrap();
+        assert!(user.contains(PageId(2), 1));
+        assert_eq!(user.vmap.map, server.vmap.map);
+        assert_eq!(user.to_digest(), server.to_digest());
+        assert_eq!(user.counter(), 2);
+
+        // a delta must start where the user is
+        let stale = VbfUpdate::Delta {
+            from: 1,
+            to: 2,
+            slots: vec![],
+        };
+        assert!(user.apply(stale).is_err());
+
+        // users behind the log get the whole filter
+        for p in 0..CHANGE_LOG_LEN as u32 {
+            server.insert(PageId(p), 3);
+        }
+        assert!(matches!(
+            server.update_since(user.seed(), user.counter()),
+            VbfUpdate::Full(_)
+        ));
+    }
+
+    #[test]
+    fn test_vbf_digest() {
+        let mut vbf = VersionBloomFilter::with_seed(100, 4, 7);
+        vbf.insert(PageId(4), 3);
+        let published = vbf.to_digest();
+        // zeroed slots would keep stale pages fresh
+        let mut forged = vbf.clone();
+        forged.vmap = VersionMap::new(100);
+        assert_ne!(forged.to_digest(), published);
+        assert_eq!(vbf.without_log().to_digest(), published);
+    }
+
+    // the enclave of v2fs-sgx advances the filter the server hands out, its host writes
+    // vbf.bin and publishes the digest; see `test_vbf_file_matches_vsqlite` there
+    #[test]
+    fn test_vbf_matches_sgx() {
+        let mut vbf = VersionBloomFilter::with_seed(16, 2, 7);
+        vbf.insert(PageId(4), 1);
+        vbf.insert(PageId(9), 1);
+        assert_eq!(
+            vbf.to_digest().to_string(),
+            "c6f9e04dc08075c2ff60a9cbc6a331a91b310726100a68fb1d9af5915ab0327d"
+        );
+        let bytes = bincode::serialize(&vbf.without_log()).unwrap();
+        assert_eq!(
+            bytes.to_digest().to_string(),
+            "c8224f9cdb59fb4b88f788069ace3a82c8348749fcb4f88cf7c1fdabf5937d71"
+        );
+    }
+
+    #[test]
+    fn test_vbf_grow() {
+        let mut vbf = VersionBloomFilter::with_seed(64, 4, 7);
+        assert!(!vbf.grow_if_saturated());
+        for p in 0..32 {
+            vbf.insert(PageId(p), p + 1);
+        }
+        assert!(vbf.fill_ratio() > MAX_FILL_RATIO);
+        let (seed, counter) = (vbf.seed(), vbf.counter());
+        assert!(vbf.grow_if_saturated());
+        assert!(vbf.map_size() > 64);
+        assert!(vbf.fill_ratio() <= MAX_FILL_RATIO / 2.0);
+        assert!(vbf.counter() > counter);
+        // every page is still newer than the version before its write
+        assert!((0..32).all(|p| vbf.contains(PageId(p), p)));
+        // users on the old seed switch to the new filter
+        assert!(matches!(
+            vbf.update_since(seed, counter),
+            VbfUpdate::Full(_)
+        ));
+    }
 }
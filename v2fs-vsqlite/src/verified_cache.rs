@@ -0,0 +1,63 @@
+pub mod compression;
+pub mod store;
+
+use crate::{digest::Digest, merkle_cb_tree::NodeId, vbf::VersionBloomFilter, PageId};
+
+// a client cache of merkle nodes whose pages are served only once they are confirmed,
+// either by the server or by the vbf; the page validation in `u_read` is written once
+// against this trait, so a new optimization level is a new implementation
+pub trait VerifiedCache {
+    // cached bytes of a page and whether they are confirmed for the current query
+    fn lookup(&mut self, p_id: PageId) -> Option<(Box<[u8]>, bool)>;
+
+    // digests from the page's leaf up through cached siblings, sent with CONFIRM
+    fn confirm_path(&mut self, p_id: PageId) -> Vec<Digest>;
+
+    // highest sub-root above a cached page that the vbf shows unchanged
+    fn fresh_subroot(&mut self, _p_id: PageId, _vbf: &VersionBloomFilter) -> Option<NodeId> {
+        None
+    }
+
+    // mark all cached nodes under `root_id` as confirmed
+    fn confirm(&mut self, root_id: NodeId);
+
+    // the server has confirmed `root_id` at the current version
+    fn confirm_remote(&mut self, root_id: NodeId) {
+        self.confirm(root_id);
+    }
+
+    // cache a page just received from the server
+    fn insert(&mut self, p_id: PageId, bytes: Box<[u8]>, vbf: &VersionBloomFilter);
+
+    // start a new query, every cached node has to be confirmed again
+    fn unconfirm(&mut self);
+
+    fn clear(&mut self);
+
+    fn cache_size_and_height(&self) -> (usize, u32);
+}
+
+// every page is fetched from the server
+pub struct NoCache;
+
+impl VerifiedCache for NoCache {
+    fn lookup(&mut self, _p_id: PageId) -> Option<(Box<[u8]>, bool)> {
+        None
+    }
+
+    fn confirm_path(&mut self, _p_id: PageId) -> Vec<Digest> {
+        Vec::new()
+    }
+
+    fn confirm(&mut self, _root_id: NodeId) {}
+
+    fn insert(&mut self, _p_id: PageId, _bytes: Box<[u8]>, _vbf: &VersionBloomFilter) {}
+
+    fn unconfirm(&mut self) {}
+
+    fn clear(&mut self) {}
+
+    fn cache_size_and_height(&self) -> (usize, u32) {
+        (0, 0)
+    }
+}
@@ -0,0 +1,125 @@
+use crate::digest::{Digest, Digestible};
+use anyhow::{bail, Error};
+use std::str::FromStr;
+
+// zstd level favouring speed, pages are decompressed on every cache hit
+const ZSTD_LEVEL: i32 = 1;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.to_lowercase().as_str() {
+            "none" => Compression::None,
+            "lz4" => Compression::Lz4,
+            "zstd" => Compression::Zstd,
+            _ => bail!("unknown compression {}, expect none, lz4 or zstd", s),
+        })
+    }
+}
+
+#[derive(Clone)]
+enum Stored {
+    Raw(Box<[u8]>),
+    Lz4(Box<[u8]>),
+    Zstd(Box<[u8]>),
+}
+
+// bytes of a cached page, compressed when that saves space, along with the page digest so
+// that merkle nodes are rebuilt without decompressing
+#[derive(Clone)]
+pub(crate) struct PageBytes {
+    stored: Stored,
+    len: usize,
+    digest: Digest,
+}
+
+impl PageBytes {
+    pub(crate) fn new(bytes: Box<[u8]>, compression: Compression) -> Self {
+        let len = bytes.len();
+        let digest = bytes.to_digest();
+        let compressed = match compression {
+            Compression::None => None,
+            Compression::Lz4 => Some(Stored::Lz4(lz4_flex::compress(&bytes).into())),
+            Compression::Zstd => zstd::bulk::compress(&bytes, ZSTD_LEVEL)
+                .ok()
+                .map(|c| Stored::Zstd(c.into())),
+        };
+        let stored = match compressed {
+            Some(c) if stored_len(&c) < len => c,
+            _ => Stored::Raw(bytes),
+        };
+        Self {
+            stored,
+            len,
+            digest,
+        }
+    }
+
+    // the page, None if it cannot be decompressed
+    pub(crate) fn get(&self) -> Option<Box<[u8]>> {
+        let res = match &self.stored {
+            Stored::Raw(b) => return Some(b.clone()),
+            Stored::Lz4(c) => lz4_flex::decompress(c, self.len).map_err(Error::from),
+            Stored::Zstd(c) => zstd::bulk::decompress(c, self.len).map_err(Error::from),
+        };
+        match res {
+            Ok(b) if b.len() == self.len => Some(b.into()),
+            Ok(_) => {
+                warn!("cached page decompressed to a wrong length");
+                None
+            }
+            Err(e) => {
+                warn!("failed to decompress a cached page: {:?}", e);
+                None
+            }
+        }
+    }
+
+    pub(crate) fn digest(&self) -> Digest {
+        self.digest
+    }
+
+    pub(crate) fn heap_size(&self) -> usize {
+        stored_len(&self.stored)
+    }
+}
+
+fn stored_len(stored: &Stored) -> usize {
+    match stored {
+        Stored::Raw(b) | Stored::Lz4(b) | Stored::Zstd(b) => b.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Compression, PageBytes};
+    use crate::digest::Digestible;
+
+    #[test]
+    fn test_page_bytes() {
+        let mut page = vec![0u8; 4096];
+        page[..11].copy_from_slice(b"SQLite page");
+        let page: Box<[u8]> = page.into();
+        for c in [Compression::Lz4, Compression::Zstd] {
+            let bytes = PageBytes::new(page.clone(), c);
+            assert!(bytes.heap_size() < 1024);
+            assert_eq!(bytes.get().unwrap(), page);
+            assert_eq!(bytes.digest(), page.to_digest());
+        }
+
+        // incompressible pages are kept as they are
+        let noise: Box<[u8]> = (0..4096).map(|_| rand::random::<u8>()).collect();
+        let bytes = PageBytes::new(noise.clone(), Compression::Lz4);
+        assert_eq!(bytes.heap_size(), 4096);
+        assert_eq!(bytes.get().unwrap(), noise);
+    }
+}
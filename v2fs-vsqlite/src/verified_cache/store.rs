@@ -0,0 +1,313 @@
+use crate::{
+    digest::{Digest, Digestible},
+    eviction::{Eviction, EvictionPolicy},
+    merkle_cb_tree::NodeId,
+};
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt, mem,
+};
+
+// a merkle node kept by a verified cache
+pub(crate) trait TreeNode: Digestible + Sized {
+    fn get_id(&self) -> NodeId;
+    fn is_valid(&self) -> bool;
+    fn validate(&mut self);
+    // confirmed by the server at `version`, caches without versions only validate
+    fn validate_with_version(&mut self, _version: u32) {
+        self.validate();
+    }
+    fn unconfirm(&mut self);
+    // bytes of a leaf page
+    fn get_bytes(&self) -> Option<Box<[u8]>>;
+    // parent of two siblings, `right` is the odd one
+    fn merge(left: &Self, right: &Self) -> Self;
+    // heap bytes owned by the node
+    fn heap_size(&self) -> usize;
+}
+
+// bytes a cached node takes, its map slot plus what it owns on the heap
+fn entry_size<N: TreeNode>(n: &N) -> usize {
+    mem::size_of::<(NodeId, N)>() + n.heap_size()
+}
+
+// nodes of a partial merkle tree, a parent only stays cached while the children it was
+// built from do, so evicting a node drops all of its cached ancestors
+pub(crate) struct NodeStore<N> {
+    nodes: HashMap<NodeId, N>,
+    // ids of the cached nodes, a subtree covers one range of widths per height
+    index: BTreeSet<NodeId>,
+    policy: Box<dyn EvictionPolicy>,
+    // bytes the cached nodes may take, and take now
+    budget: usize,
+    used: usize,
+}
+
+impl<N> fmt::Debug for NodeStore<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NodeStore")
+            .field("len", &self.nodes.len())
+            .field("budget", &self.budget)
+            .field("used", &self.used)
+            .field("policy", &self.policy)
+            .finish()
+    }
+}
+
+impl<N: TreeNode> NodeStore<N> {
+    pub(crate) fn new(budget: usize, eviction: Eviction) -> Self {
+        Self {
+            nodes: HashMap::new(),
+            index: BTreeSet::new(),
+            policy: eviction.build(),
+            budget,
+            used: 0,
+        }
+    }
+
+    pub(crate) fn get_node(&mut self, id: &NodeId) -> Option<&N> {
+        let n = self.nodes.get(id)?;
+        self.policy.access(id);
+        Some(n)
+    }
+
+    // push node into cache, evicting nodes until it fits the budget; a replaced or evicted
+    // node takes all its cached parents along
+    pub(crate) fn push_node(&mut self, node: N) {
+        let id = node.get_id();
+        let size = entry_size(&node);
+        let mut tracked = false;
+        if self.remove(&id).is_some() {
+            self.remove_parents(id);
+            tracked = true;
+        }
+        if size > self.budget {
+            if tracked {
+                self.policy.remove(&id);
+            }
+            return;
+        }
+        while self.used + size > self.budget {
+            let Some(victim) = self.policy.evict(&id) else {
+                break;
+            };
+            if victim == id {
+                tracked = false;
+            } else {
+                self.remove(&victim);
+            }
+            self.remove_parents(victim);
+        }
+        self.used += size;
+        self.nodes.insert(id, node);
+        self.index.insert(id);
+        if tracked {
+            self.policy.access(&id);
+        } else {
+            self.policy.insert(id);
+        }
+    }
+
+    fn remove(&mut self, id: &NodeId) -> Option<N> {
+        let n = self.nodes.remove(id)?;
+        self.index.remove(id);
+        self.used -= entry_size(&n);
+        Some(n)
+    }
+
+    fn remove_parents(&mut self, mut id: NodeId) {
+        while self.remove(&id.get_parent_id()).is_some() {
+            id = id.get_parent_id();
+            self.policy.remove(&id);
+        }
+    }
+
+    // push a leaf and every parent that can be built from confirmed siblings
+    pub(crate) fn insert(&mut self, leaf: N) {
+        let mut cur_id = leaf.get_id();
+        self.push_node(leaf);
+        while let Some(parent) = self.merge_with_sib(cur_id) {
+            cur_id = parent.get_id();
+            self.push_node(parent);
+        }
+    }
+
+    fn merge_with_sib(&mut self, id: NodeId) -> Option<N> {
+        let sib_id = id.get_sib_id();
+        let cur = self.nodes.get(&id)?;
+        let sib = self.nodes.get(&sib_id).filter(|n| n.is_valid())?;
+        let parent = if id.is_even() {
+            N::merge(cur, sib)
+        } else {
+            N::merge(sib, cur)
+        };
+        self.policy.access(&sib_id);
+        Some(parent)
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.nodes.clear();
+        self.index.clear();
+        self.policy.clear();
+        self.used = 0;
+    }
+
+    // change `is_valid` for all nodes to "false"
+    pub(crate) fn unconfirm(&mut self) {
+        for n in self.nodes.values_mut() {
+            n.unconfirm();
+        }
+    }
+
+    // change `is_valid` for all cached nodes under a sub-root to "true"; a confirm is not a use
+    // of the nodes, so the eviction policy does not see it
+    pub(crate) fn confirm(&mut self, root_id: NodeId) {
+        for id in self.covered_ids(root_id) {
+            if let Some(n) = self.nodes.get_mut(&id) {
+                n.validate();
+            }
+        }
+    }
+
+    // change `is_valid` for all cached nodes under a sub-root to "true" and set version
+    pub(crate) fn confirm_with_version(&mut self, root_id: NodeId, version: u32) {
+        for id in self.covered_ids(root_id) {
+            if let Some(n) = self.nodes.get_mut(&id) {
+                n.validate_with_version(version);
+            }
+        }
+    }
+
+    // cached nodes under a sub-root, one index range per height
+    fn covered_ids(&self, root_id: NodeId) -> Vec<NodeId> {
+        let h = root_id.get_height();
+        let w = root_id.get_width() as u64;
+        let mut ids = Vec::new();
+        for i in 0..=h {
+            let (lo, hi) = (w << (h - i), (w + 1) << (h - i));
+            if lo > u32::MAX as u64 {
+                continue;
+            }
+            let hi = NodeId::new(i, hi.min(u32::MAX as u64) as u32);
+            ids.extend(self.index.range(NodeId::new(i, lo as u32)..hi));
+        }
+        ids
+    }
+
+    // bytes in use and the height of the highest cached node
+    pub(crate) fn cache_size_and_height(&self) -> (usize, u32) {
+        let height = self.nodes.keys().map(|id| id.get_height()).max();
+        (self.used, height.unwrap_or(0))
+    }
+
+    pub(crate) fn find_parent(&mut self, id: NodeId) -> Option<&N> {
+        self.get_node(&id.get_parent_id())
+    }
+
+    pub(crate) fn has_sib(&self, id: NodeId) -> bool {
+        self.nodes.contains_key(&id.get_sib_id())
+    }
+
+    pub(crate) fn lookup(&mut self, id: NodeId) -> Option<(Box<[u8]>, bool)> {
+        let n = self.get_node(&id)?;
+        Some((n.get_bytes()?, n.is_valid()))
+    }
+
+    // digests from `id` up through cached siblings
+    pub(crate) fn confirm_path(&mut self, id: NodeId) -> Vec<Digest> {
+        let Some(n) = self.get_node(&id) else {
+            return Vec::new();
+        };
+        let mut path = vec![n.to_digest()];
+        let mut cur_id = id;
+        while self.has_sib(cur_id) {
+            let Some(parent) = self.find_parent(cur_id) else {
+                break;
+            };
+            path.push(parent.to_digest());
+            cur_id = parent.get_id();
+        }
+        path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entry_size, NodeStore, TreeNode};
+    use crate::{
+        cache::{leaf::CacheLeafNode, CacheNode},
+        eviction::Eviction,
+        merkle_cb_tree::NodeId,
+        verified_cache::compression::{Compression, PageBytes},
+        PageId,
+    };
+
+    fn leaf(w: u32) -> CacheNode {
+        let bytes = vec![w as u8; 100].into_boxed_slice();
+        CacheNode::Leaf(CacheLeafNode::new(
+            PageId(w),
+            PageBytes::new(bytes, Compression::None),
+        ))
+    }
+
+    #[test]
+    fn test_byte_budget() {
+        let size = entry_size(&leaf(0));
+        let mut store = NodeStore::new(3 * size, Eviction::Lru);
+        store.insert(leaf(0));
+        store.insert(leaf(1));
+        // confirmed siblings are merged into their parent
+        let parent = NodeId::new(1, 0);
+        let parent_size = entry_size(store.get_node(&parent).unwrap());
+        assert!(parent_size < size);
+        assert_eq!(store.cache_size_and_height(), (2 * size + parent_size, 1));
+
+        // 1 is the least recent, evicting it drops the parent built from it
+        store.insert(leaf(2));
+        assert!(store.get_node(&NodeId::new(0, 1)).is_none());
+        assert!(store.get_node(&parent).is_none());
+        assert_eq!(store.cache_size_and_height(), (2 * size, 0));
+
+        // a node larger than the budget is never cached
+        let mut store = NodeStore::new(size - 1, Eviction::Lru);
+        store.insert(leaf(0));
+        assert_eq!(store.cache_size_and_height(), (0, 0));
+    }
+
+    #[test]
+    fn test_confirm_subtree() {
+        let mut store = NodeStore::new(1 << 20, Eviction::Lru);
+        for w in [0, 1, 2, 5, 6, 7] {
+            store.insert(leaf(w));
+        }
+        store.unconfirm();
+        // widths 4..8 at height 0, 2..4 at height 1, 1 at height 2
+        store.confirm(NodeId::new(2, 1));
+        let valid = |store: &mut NodeStore<CacheNode>, h, w| {
+            store.get_node(&NodeId::new(h, w)).map(|n| n.is_valid())
+        };
+        assert_eq!(valid(&mut store, 0, 5), Some(true));
+        assert_eq!(valid(&mut store, 0, 7), Some(true));
+        assert_eq!(valid(&mut store, 1, 3), Some(true));
+        assert_eq!(valid(&mut store, 0, 2), Some(false));
+        assert_eq!(valid(&mut store, 1, 0), Some(false));
+        assert_eq!(store.covered_ids(NodeId::new(2, 1)).len(), 4);
+    }
+
+    #[test]
+    fn test_confirm_keeps_eviction_order() {
+        let size = entry_size(&leaf(0));
+        let mut store = NodeStore::new(2 * size, Eviction::Lru);
+        store.insert(leaf(0));
+        store.insert(leaf(2));
+        store.unconfirm();
+        store.confirm(NodeId::new(0, 0));
+        store.confirm_with_version(NodeId::new(0, 0), 1);
+
+        // 0 is still the least recent
+        store.insert(leaf(4));
+        assert!(!store.nodes.contains_key(&NodeId::new(0, 0)));
+        assert!(store.nodes.contains_key(&NodeId::new(0, 2)));
+        assert!(store.nodes.contains_key(&NodeId::new(0, 4)));
+    }
+}
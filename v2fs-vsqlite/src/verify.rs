@@ -1,10 +1,10 @@
 use crate::{
-    digest::Digest,
+    digest::{Digest, Digestible},
     merkle_cb_tree::{hash::leaf_hash, proof::Proof},
     utils::compare_with_root,
     PageId,
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
 use std::collections::HashMap;
 
 pub(crate) fn verify(height: u32, proof: &Proof, map: &HashMap<PageId, Digest>) -> Result<()> {
@@ -18,3 +18,37 @@ pub(crate) fn verify(height: u32, proof: &Proof, map: &HashMap<PageId, Digest>)
     }
     Ok(())
 }
+
+// verify the pages of an attached database against its own (root hash, height)
+pub(crate) fn verify_with_root(
+    root: &(Digest, u32),
+    proof: &Proof,
+    map: &HashMap<PageId, Digest>,
+) -> Result<()> {
+    if !map.is_empty() {
+        let (root_hash, height) = root;
+        if proof.root_hash()? != *root_hash {
+            bail!("Proof root hash not matched");
+        }
+        for (p_id, dig) in map.iter() {
+            let target_hash = leaf_hash(p_id, dig);
+            proof.verify_val(target_hash, *p_id, *height)?;
+        }
+    }
+    Ok(())
+}
+
+// verify a single page against the trusted root using its own authentication path
+pub(crate) fn verify_page(
+    root: &(Digest, u32),
+    p_id: PageId,
+    bytes: &[u8],
+    proof: &Proof,
+) -> Result<()> {
+    let (root_hash, height) = root;
+    if proof.root_hash()? != *root_hash {
+        bail!("Proof root hash not matched for page {}", p_id);
+    }
+    let target_hash = leaf_hash(&p_id, &bytes.to_digest());
+    proof.verify_val(target_hash, p_id, *height)
+}
@@ -2,16 +2,22 @@ pub mod hash;
 pub mod leaf;
 pub mod non_leaf;
 
-use std::collections::HashSet;
+use std::{collections::HashSet, mem};
 
 use self::{hash::merge_hash, leaf::VCacheLeafNode, non_leaf::VCacheNonLeafNode};
 use crate::{
     digest::{Digest, Digestible},
+    eviction::Eviction,
     merkle_cb_tree::NodeId,
-    vfs::PAGE_SIZE,
+    vbf::VersionBloomFilter,
+    verified_cache::{
+        compression::{Compression, PageBytes},
+        store::{NodeStore, TreeNode},
+        VerifiedCache,
+    },
+    vfs::GLOBAL_TS,
     PageId,
 };
-use lru::LruCache;
 
 #[derive(Clone)]
 pub(crate) enum VCacheNode {
@@ -20,7 +26,30 @@ pub(crate) enum VCacheNode {
 }
 
 impl VCacheNode {
-    pub(crate) fn is_valid(&self) -> bool {
+    pub(crate) fn get_set(&self) -> &HashSet<usize> {
+        match self {
+            VCacheNode::Leaf(l) => l.get_set(),
+            VCacheNode::NonLeaf(n) => n.get_set(),
+        }
+    }
+
+    pub(crate) fn get_version(&self) -> u32 {
+        match self {
+            VCacheNode::Leaf(l) => l.get_version(),
+            VCacheNode::NonLeaf(n) => n.get_version(),
+        }
+    }
+}
+
+impl TreeNode for VCacheNode {
+    fn get_id(&self) -> NodeId {
+        match self {
+            VCacheNode::Leaf(l) => l.get_id(),
+            VCacheNode::NonLeaf(n) => n.get_id(),
+        }
+    }
+
+    fn is_valid(&self) -> bool {
         match self {
             VCacheNode::Leaf(l) => l.is_valid(),
             VCacheNode::NonLeaf(n) => n.is_valid(),
@@ -41,28 +70,42 @@ impl VCacheNode {
         }
     }
 
-    pub(crate) fn get_id(&self) -> NodeId {
+    fn unconfirm(&mut self) {
         match self {
-            VCacheNode::Leaf(l) => l.get_id(),
-            VCacheNode::NonLeaf(n) => n.get_id(),
+            VCacheNode::Leaf(l) => l.unconfirm(),
+            VCacheNode::NonLeaf(n) => n.unconfirm(),
         }
     }
 
-    pub(crate) fn get_set(&self) -> &HashSet<usize> {
+    fn get_bytes(&self) -> Option<Box<[u8]>> {
         match self {
-            VCacheNode::Leaf(l) => l.get_set(),
-            VCacheNode::NonLeaf(n) => n.get_set(),
+            VCacheNode::Leaf(l) => l.get_bytes(),
+            VCacheNode::NonLeaf(_) => None,
         }
     }
 
-    pub(crate) fn get_version(&self) -> u32 {
+    // a parent is as old as its older child and covers the bloom filter positions of both
+    fn merge(left: &Self, right: &Self) -> Self {
+        let id = left.get_id().get_parent_id();
+        let hash = merge_hash(&left.to_digest(), &right.to_digest());
+        let version = left.get_version().min(right.get_version());
+        let idxes = left.get_set().union(right.get_set()).copied().collect();
+        VCacheNode::NonLeaf(VCacheNonLeafNode::new(id, hash, version, idxes))
+    }
+
+    fn heap_size(&self) -> usize {
         match self {
-            VCacheNode::Leaf(l) => l.get_version(),
-            VCacheNode::NonLeaf(n) => n.get_version(),
+            VCacheNode::Leaf(l) => l.heap_size(),
+            VCacheNode::NonLeaf(n) => n.heap_size(),
         }
     }
 }
 
+// a hash set allocates its capacity of slots plus one control byte each
+pub(crate) fn set_heap_size(set: &HashSet<usize>) -> usize {
+    set.capacity() * (mem::size_of::<usize>() + 1)
+}
+
 impl Digestible for VCacheNode {
     fn to_digest(&self) -> Digest {
         match self {
@@ -72,180 +115,90 @@ impl Digestible for VCacheNode {
     }
 }
 
+// every node carries a version and its bloom filter positions, so whole sub-trees
+// can be confirmed locally against the vbf
 #[derive(Debug)]
 pub struct VCache {
-    lru: LruCache<Digest, VCacheNode>,
+    store: NodeStore<VCacheNode>,
+    compression: Compression,
 }
 
 impl VCache {
-    pub fn new(cap: usize) -> Self {
-        Self {
-            lru: LruCache::<Digest, VCacheNode>::new(cap),
-        }
-    }
-
-    pub(crate) fn get_node(&mut self, key: &Digest) -> Option<&VCacheNode> {
-        self.lru.get(key)
+    // `budget` in bytes
+    pub fn new(budget: usize) -> Self {
+        Self::with_eviction(budget, Eviction::default())
     }
 
-    pub(crate) fn get_node_mut(&mut self, key: &Digest) -> Option<&mut VCacheNode> {
-        self.lru.get_mut(key)
+    pub fn with_eviction(budget: usize, eviction: Eviction) -> Self {
+        Self::with_options(budget, eviction, Compression::default())
     }
 
-    // push node into cache, if some node is popped out, remove all its parents
-    pub(crate) fn push_node(&mut self, id: NodeId, node: VCacheNode) {
-        let hash = id.to_digest();
-        if let Some((_, n)) = self.lru.push(hash, node) {
-            let mut cur_id = n.get_id();
-            let mut affected_pare_hashes = Vec::<Digest>::new();
-            while let Some(p) = self.find_parent(cur_id) {
-                match p {
-                    VCacheNode::Leaf(_) => {
-                        panic!("impossible to be a leaf")
-                    }
-                    VCacheNode::NonLeaf(n) => {
-                        affected_pare_hashes.push(n.get_id().to_digest());
-                        cur_id = n.get_id();
-                    }
-                }
-            }
-            for dig in affected_pare_hashes {
-                self.lru.pop(&dig);
-            }
+    // cached pages are compressed, the budget counts their compressed size
+    pub fn with_options(budget: usize, eviction: Eviction, compression: Compression) -> Self {
+        Self {
+            store: NodeStore::new(budget, eviction),
+            compression,
         }
     }
+}
 
-    pub fn clear(&mut self) {
-        self.lru.clear();
+impl VerifiedCache for VCache {
+    fn lookup(&mut self, p_id: PageId) -> Option<(Box<[u8]>, bool)> {
+        self.store.lookup(NodeId::from_page_id(p_id))
     }
 
-    // change `is_valid` for all nodes to "false"
-    pub fn unconfirm(&mut self) {
-        for (_, cache_n) in self.lru.iter_mut() {
-            match cache_n {
-                VCacheNode::Leaf(l) => {
-                    l.unconfirm();
-                }
-                VCacheNode::NonLeaf(n) => n.unconfirm(),
-            }
-        }
+    fn confirm_path(&mut self, p_id: PageId) -> Vec<Digest> {
+        self.store.confirm_path(NodeId::from_page_id(p_id))
     }
 
-    // change `is_valid` for all covered nodes of a sub-root to "true"
-    pub(crate) fn confirm(&mut self, root_id: NodeId) {
-        let mut covered_ids = Vec::new();
-        let h = root_id.get_height();
-        let w = root_id.get_width();
-        for i in 0..h + 1 {
-            let a = 2_i32.pow(h - i) as u32;
-            for j in (w * a)..((w + 1) * a) {
-                covered_ids.push(NodeId::new(i, j as u32));
-            }
+    // bottom-up while the vbf has no newer write under the node
+    fn fresh_subroot(&mut self, p_id: PageId, vbf: &VersionBloomFilter) -> Option<NodeId> {
+        let mut cur_id = NodeId::from_page_id(p_id);
+        let leaf = self.store.get_node(&cur_id)?;
+        if vbf.contains_subroot(leaf.get_set(), leaf.get_version()) {
+            return None;
         }
-        for id in covered_ids {
-            if let Some(n) = self.get_node_mut(&id.to_digest()) {
-                n.validate();
-            } else {
-                warn!("Cannot find a node during cache confirming");
+        while self.store.has_sib(cur_id) {
+            let Some(parent) = self.store.find_parent(cur_id) else {
+                break;
+            };
+            if vbf.contains_subroot(parent.get_set(), parent.get_version()) {
+                break;
             }
+            cur_id = parent.get_id();
         }
+        Some(cur_id)
     }
 
-    // change `is_valid` for all covered nodes of a sub-root to "true" and set version
-    pub(crate) fn confirm_with_version(&mut self, root_id: NodeId, version: u32) {
-        let mut covered_ids = Vec::new();
-        let h = root_id.get_height();
-        let w = root_id.get_width();
-        for i in 0..h + 1 {
-            let a = 2_i32.pow(h - i) as u32;
-            for j in (w * a)..((w + 1) * a) {
-                covered_ids.push(NodeId::new(i, j as u32));
-            }
-        }
-        for id in covered_ids {
-            if let Some(n) = self.get_node_mut(&id.to_digest()) {
-                n.validate_with_version(version);
-            } else {
-                warn!("Cannot find a node during cache confirming");
-            }
-        }
+    fn confirm(&mut self, root_id: NodeId) {
+        self.store.confirm(root_id);
     }
 
-    pub fn cache_size_and_height(&self) -> (u32, u32) {
-        let mut height = 0;
-        let mut size = 0;
-        for (_, n) in self.lru.iter() {
-            match n {
-                VCacheNode::Leaf(_) => {
-                    size += 4100;
-                }
-                VCacheNode::NonLeaf(n) => {
-                    size += 40;
-                    let id = n.get_id();
-                    let h = id.get_height();
-                    if height < h {
-                        height = h;
-                    }
-                }
-            }
-        }
-
-        (size, height)
+    fn confirm_remote(&mut self, root_id: NodeId) {
+        let version = unsafe { GLOBAL_TS };
+        self.store.confirm_with_version(root_id, version);
     }
 
-    pub(crate) fn find_parent(&mut self, n_id: NodeId) -> Option<&VCacheNode> {
-        let height = n_id.get_height() + 1;
-        let width = n_id.get_width() / 2;
-        let parent_id = NodeId::new(height, width);
-        self.get_node(&parent_id.to_digest())
+    fn insert(&mut self, p_id: PageId, bytes: Box<[u8]>, vbf: &VersionBloomFilter) {
+        let version = unsafe { GLOBAL_TS };
+        let leaf = VCacheLeafNode::new(
+            p_id,
+            PageBytes::new(bytes, self.compression),
+            version,
+            vbf.get_bf_pos(p_id),
+        );
+        self.store.insert(VCacheNode::Leaf(leaf));
     }
 
-    pub(crate) fn find_sib(&mut self, id: NodeId) -> Option<&VCacheNode> {
-        let sib_key = id.get_sib_id().to_digest();
-        if let Some(node) = self.get_node(&sib_key) {
-            if node.is_valid() {
-                return Some(node);
-            }
-        }
-        None
+    fn unconfirm(&mut self) {
+        self.store.unconfirm();
     }
 
-    pub(crate) fn has_sib(&self, id: NodeId) -> bool {
-        let sib_key = id.get_sib_id().to_digest();
-        self.lru.contains(&sib_key)
+    fn clear(&mut self) {
+        self.store.clear();
     }
 
-    pub(crate) fn insert(
-        &mut self,
-        p_id: PageId,
-        bytes: Box<[u8; PAGE_SIZE as usize]>,
-        version: u32,
-        idxes: HashSet<usize>,
-    ) {
-        let new_n = VCacheLeafNode::new(p_id, bytes, version, idxes.clone());
-        let mut cur_id = NodeId::from_page_id(p_id);
-        let mut cur_hash = new_n.to_digest();
-        self.push_node(cur_id, VCacheNode::Leaf(new_n));
-
-        let mut cur_idxes = idxes;
-        while let Some(sib) = self.find_sib(cur_id) {
-            let parent_id = cur_id.get_parent_id();
-            let parent_hash = if cur_id.is_even() {
-                merge_hash(&cur_hash, &sib.to_digest())
-            } else {
-                merge_hash(&sib.to_digest(), &cur_hash)
-            };
-            let sib_idxes = sib.get_set();
-            cur_idxes.extend(sib_idxes.iter().copied());
-
-            let sib_v = sib.get_version();
-            let parent_v = if version < sib_v { version } else { sib_v };
-
-            let parent =
-                VCacheNonLeafNode::new(parent_id, parent_hash, parent_v, cur_idxes.clone());
-            cur_hash = parent_hash;
-            cur_id = parent_id;
-            self.push_node(parent_id, VCacheNode::NonLeaf(parent));
-        }
+    fn cache_size_and_height(&self) -> (usize, u32) {
+        self.store.cache_size_and_height()
     }
 }
@@ -1,22 +1,18 @@
 use super::{hash::leaf_hash, *};
+use crate::verified_cache::compression::PageBytes;
 use std::collections::HashSet;
 
 #[derive(Clone)]
 pub(crate) struct VCacheLeafNode {
     id: NodeId,
-    bytes: Box<[u8; PAGE_SIZE as usize]>,
+    bytes: PageBytes,
     version: u32,
     idxes: HashSet<usize>,
     is_valid: bool,
 }
 
 impl VCacheLeafNode {
-    pub(crate) fn new(
-        p_id: PageId,
-        bytes: Box<[u8; PAGE_SIZE as usize]>,
-        version: u32,
-        idxes: HashSet<usize>,
-    ) -> Self {
+    pub(crate) fn new(p_id: PageId, bytes: PageBytes, version: u32, idxes: HashSet<usize>) -> Self {
         Self {
             id: NodeId::new(0, p_id.get_id()),
             bytes,
@@ -34,14 +30,18 @@ impl VCacheLeafNode {
         self.version
     }
 
-    pub(crate) fn get_bytes(&self) -> Box<[u8; PAGE_SIZE as usize]> {
-        self.bytes.clone()
+    pub(crate) fn get_bytes(&self) -> Option<Box<[u8]>> {
+        self.bytes.get()
     }
 
     pub(crate) fn get_set(&self) -> &HashSet<usize> {
         &self.idxes
     }
 
+    pub(crate) fn heap_size(&self) -> usize {
+        self.bytes.heap_size() + set_heap_size(&self.idxes)
+    }
+
     pub(crate) fn is_valid(&self) -> bool {
         self.is_valid
     }
@@ -62,6 +62,6 @@ impl VCacheLeafNode {
 
 impl Digestible for VCacheLeafNode {
     fn to_digest(&self) -> Digest {
-        leaf_hash(self.id.get_width(), &self.bytes.to_digest())
+        leaf_hash(self.id.get_width(), &self.bytes.digest())
     }
 }
@@ -34,6 +34,10 @@ impl VCacheNonLeafNode {
         self.version = version;
     }
 
+    pub(crate) fn heap_size(&self) -> usize {
+        set_heap_size(&self.idxes)
+    }
+
     pub(crate) fn is_valid(&self) -> bool {
         self.is_valid
     }
@@ -1,24 +1,124 @@
 // Part of the implementation of vfs is motivated by https://github.com/rkusa/sqlite-vfs
+pub mod error;
 pub mod io;
 pub mod server_vfs;
+pub mod shm;
 pub mod user_vfs;
 
 use crate::{
     digest::{Digest, Digestible},
     PageId,
 };
+use anyhow::{bail, Result};
 use libsqlite3_sys as ffi;
-use std::{cmp::Ordering, fs::File};
+use std::{
+    cmp::Ordering,
+    fs::File,
+    io::Read,
+    path::Path,
+    sync::atomic::{AtomicU32, Ordering as AtomicOrdering},
+};
 
 pub(crate) const MAX_PATH_LENGTH: usize = 512;
-pub const PAGE_SIZE: u32 = 4096;
+pub const DEFAULT_PAGE_SIZE: u32 = 4096;
+// receive buffer for control messages, independent of the page size
+pub const MSG_BUF_LEN: usize = 4096;
+// largest length-prefixed message (statements, transitions, sync replies) a peer may announce,
+// a longer one is refused before its buffer is allocated
+pub const MAX_MSG_LEN: usize = 1 << 28;
+
+// page size of the served database, taken from the tree parameters
+static CUR_PAGE_SIZE: AtomicU32 = AtomicU32::new(DEFAULT_PAGE_SIZE);
+
+// the body of a length-prefixed message, refused if announced longer than `MAX_MSG_LEN`
+pub fn read_msg(stream: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len_buf = [0; 8];
+    stream.read_exact(&mut len_buf)?;
+    let len = u64::from_le_bytes(len_buf);
+    if len > MAX_MSG_LEN as u64 {
+        bail!(
+            "message of {} bytes exceeds the limit of {}",
+            len,
+            MAX_MSG_LEN
+        );
+    }
+    let mut buf = vec![0; len as usize];
+    stream.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn is_valid_page_size(page_size: u32) -> bool {
+    (512..=65536).contains(&page_size) && page_size.is_power_of_two()
+}
+
+pub fn page_size() -> u32 {
+    CUR_PAGE_SIZE.load(AtomicOrdering::Relaxed)
+}
+
+pub fn set_page_size(page_size: u32) -> Result<()> {
+    if !is_valid_page_size(page_size) {
+        bail!("invalid page size {}", page_size);
+    }
+    CUR_PAGE_SIZE.store(page_size, AtomicOrdering::Relaxed);
+    Ok(())
+}
+
+// the page size is stored big-endian at offset 16 of the sqlite header, 1 stands for 65536
+pub fn page_size_from_header(header: &[u8]) -> Result<u32> {
+    if header.len() < 18 || !header.starts_with(b"SQLite format 3\0") {
+        bail!("not a sqlite database header");
+    }
+    let page_size = match u16::from_be_bytes([header[16], header[17]]) {
+        1 => 65536,
+        n => n as u32,
+    };
+    if !is_valid_page_size(page_size) {
+        bail!("invalid page size {} in sqlite header", page_size);
+    }
+    Ok(page_size)
+}
+
+pub fn read_page_size(path: &Path) -> Result<u32> {
+    let mut header = [0; 18];
+    File::open(path)?.read_exact(&mut header)?;
+    page_size_from_header(&header)
+}
 
 pub const MERKLE_PATH: &str = "./db/merkle_db/merkle_test";
+// the vbf of the main database, saved by the writer whenever the merkle tree changes
+pub const VBF_PATH: &str = "./db/merkle_db/merkle_test/vbf.bin";
+// pages of a tree update in progress, left behind if the writer crashes before it finishes
+pub const COMMIT_LOG_PATH: &str = "./db/merkle_db/merkle_test/commit.json";
+// pages of a follower's sync in progress, left behind if it crashes before the tree follows
+pub const SYNC_LOG_PATH: &str = "./db/merkle_db/merkle_test/sync.json";
+// flocked by every process that serves from or changes the tree, see `server_vfs::lock_snapshot`;
+// kept beside `MERKLE_PATH`, whose existence tells `MerkleDB::create_new` a tree was built
+pub const SNAPSHOT_LOCK_PATH: &str = "./db/merkle_db/snapshot.lock";
 
 pub const MAIN_PATH: &str = "./db/sqlite_db/test.db";
 
 pub const TMP_FILE_PATH: &str = "./db/tmp_file";
 pub const HOLDER_FILE_PATH: &str = "./db/holder_file";
+
+// index of the main database, attached databases are numbered from 1 in attach order
+pub const MAIN_DB: usize = 0;
+
+// an attached database `name` lives next to the main one and has its own merkle tree
+pub fn is_valid_db_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub fn attached_main_path(name: &str) -> String {
+    format!("./db/sqlite_db/{}.db", name)
+}
+
+pub fn attached_merkle_path(name: &str) -> String {
+    format!("./db/merkle_db/{}", name)
+}
+
+pub fn attached_holder_path(name: &str) -> String {
+    format!("{}_{}", HOLDER_FILE_PATH, name)
+}
 pub const SERVER_VFS: &str = "server_vfs";
 pub const USER_VFS: &str = "user_vfs";
 
@@ -30,9 +130,20 @@ pub const TMP_FLAG: usize = 100;
 pub const NO_CACHE: u32 = 10;
 pub const BOTH_CACHE: u32 = 11;
 pub const DEFAULT: u32 = 12;
+pub const STREAM_VERIFY: u32 = 13;
+// the server replies with the persisted vbf, length-prefixed, and ends the session
+pub const FETCH_VBF: u32 = 14;
+// length-prefixed sql statements follow, the server replies with the transition of its tree
+pub const SUBMIT_WRITE: u32 = 15;
+// a follower pulls nodes and pages of the current root through length-prefixed requests
+pub const REPLICATE: u32 = 16;
 pub const END: u32 = u32::MAX;
 pub const CONFIRM: u32 = u32::MAX - 1;
 pub const QUERY: u32 = u32::MAX - 2;
+// the page id carries the database index, the name follows as length-prefixed bytes
+pub const ATTACH: u32 = u32::MAX - 3;
+// the following requests target the database whose index is carried by the page id
+pub const SELECT_DB: u32 = u32::MAX - 4;
 
 pub static mut NAME_CNT: u8 = 0;
 
@@ -45,11 +156,11 @@ pub static mut GLOBAL_TS: u32 = 1;
 #[derive(Debug, PartialEq, Eq)]
 pub struct Page {
     p_id: PageId,
-    bytes: Box<[u8; PAGE_SIZE as usize]>,
+    bytes: Box<[u8]>,
 }
 
 impl Page {
-    pub fn new(p_id: PageId, bytes: Box<[u8; PAGE_SIZE as usize]>) -> Self {
+    pub fn new(p_id: PageId, bytes: Box<[u8]>) -> Self {
         Self { p_id, bytes }
     }
 }
@@ -172,3 +283,45 @@ impl OpenAccess {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{is_valid_db_name, page_size_from_header, read_msg, MAX_MSG_LEN};
+
+    fn header(raw: [u8; 2]) -> Vec<u8> {
+        let mut h = b"SQLite format 3\0".to_vec();
+        h.extend(raw);
+        h
+    }
+
+    #[test]
+    fn test_page_size_from_header() {
+        assert_eq!(page_size_from_header(&header([0x10, 0x00])).unwrap(), 4096);
+        assert_eq!(page_size_from_header(&header([0x20, 0x00])).unwrap(), 8192);
+        assert_eq!(page_size_from_header(&header([0x40, 0x00])).unwrap(), 16384);
+        assert_eq!(page_size_from_header(&header([0x00, 0x01])).unwrap(), 65536);
+        assert!(page_size_from_header(&header([0x10, 0x01])).is_err());
+        assert!(page_size_from_header(&header([0x01, 0x00])).is_err());
+        assert!(page_size_from_header(b"not a database..\0\x10\x00").is_err());
+    }
+
+    #[test]
+    fn test_db_name() {
+        assert!(is_valid_db_name("orders_2"));
+        assert!(!is_valid_db_name(""));
+        assert!(!is_valid_db_name("../test"));
+        assert!(!is_valid_db_name("a.db"));
+    }
+
+    #[test]
+    fn test_read_msg() {
+        let mut msg = 3u64.to_le_bytes().to_vec();
+        msg.extend([1, 2, 3]);
+        assert_eq!(read_msg(&mut msg.as_slice()).unwrap(), vec![1, 2, 3]);
+        // refused from the prefix alone, nothing follows it
+        let huge = (MAX_MSG_LEN as u64 + 1).to_le_bytes();
+        assert!(read_msg(&mut huge.as_slice()).is_err());
+        let short = 3u64.to_le_bytes();
+        assert!(read_msg(&mut short.as_slice()).is_err());
+    }
+}
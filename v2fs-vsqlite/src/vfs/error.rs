@@ -0,0 +1,93 @@
+use anyhow::Error;
+use libsqlite3_sys as ffi;
+use std::{
+    cell::RefCell,
+    fmt,
+    os::raw::{c_char, c_int},
+    slice,
+};
+
+thread_local! {
+    // last failure of a vfs callback on this thread, like errno sqlite asks for it on the same thread
+    static LAST_ERROR: RefCell<Option<(c_int, String)>> = const { RefCell::new(None) };
+}
+
+// data that failed verification or broke a cache invariant, reported as SQLITE_CORRUPT
+#[derive(Debug)]
+pub struct Corrupt(pub String);
+
+impl fmt::Display for Corrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Corrupt {}
+
+// record `err` for xGetLastError and return the code the callback reports to sqlite
+pub(crate) fn fail(code: c_int, err: Error) -> c_int {
+    let code = if err.is::<Corrupt>() {
+        ffi::SQLITE_CORRUPT
+    } else {
+        code
+    };
+    warn!("vfs callback failed with code {}: {:?}", code, err);
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some((code, format!("{:#}", err))));
+    code
+}
+
+// (code, message) of the last vfs failure on this thread
+pub fn last_error() -> Option<(c_int, String)> {
+    LAST_ERROR.with(|e| e.borrow().clone())
+}
+
+pub fn take_last_error() -> Option<(c_int, String)> {
+    LAST_ERROR.with(|e| e.borrow_mut().take())
+}
+
+// xGetLastError: copy the nul-terminated message into `z_err_msg`, return its code or 0 if none
+pub(crate) unsafe fn copy_last_error(n_byte: c_int, z_err_msg: *mut c_char) -> c_int {
+    let Some((code, msg)) = last_error() else {
+        return ffi::SQLITE_OK;
+    };
+    if n_byte > 0 && !z_err_msg.is_null() {
+        let out = slice::from_raw_parts_mut(z_err_msg as *mut u8, n_byte as usize);
+        let len = msg.len().min(out.len() - 1);
+        out[..len].copy_from_slice(&msg.as_bytes()[..len]);
+        out[len] = 0;
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{copy_last_error, fail, take_last_error, Corrupt};
+    use anyhow::{anyhow, Context};
+    use libsqlite3_sys as ffi;
+
+    #[test]
+    fn test_last_error() {
+        assert_eq!(
+            fail(ffi::SQLITE_IOERR_READ, anyhow!("connection reset")),
+            ffi::SQLITE_IOERR_READ
+        );
+        let mut buf = [1_i8; 8];
+        assert_eq!(
+            unsafe { copy_last_error(buf.len() as i32, buf.as_mut_ptr()) },
+            ffi::SQLITE_IOERR_READ
+        );
+        // truncated to the buffer and nul-terminated
+        assert_eq!(buf.map(|c| c as u8), *b"connect\0");
+
+        let err = Err::<(), _>(anyhow!("hash mismatch")).context(Corrupt("page 3".to_string()));
+        assert_eq!(
+            fail(ffi::SQLITE_IOERR_READ, err.unwrap_err()),
+            ffi::SQLITE_CORRUPT
+        );
+        assert_eq!(
+            take_last_error(),
+            Some((ffi::SQLITE_CORRUPT, "page 3: hash mismatch".to_string()))
+        );
+        assert_eq!(unsafe { copy_last_error(0, std::ptr::null_mut()) }, 0);
+    }
+}
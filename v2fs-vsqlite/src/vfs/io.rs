@@ -1,23 +1,24 @@
 use crate::{
-    cache::{leaf::CacheLeafNode, Cache, CacheNode},
     digest::{Digest, Digestible},
-    merkle_cb_tree::{write::WriteContext, NodeId, WriteInterface},
-    simple_vcache::{SVCache, SVCacheNode},
+    merkle_cb_tree::{proof::Proof, write::WriteContext, NodeId, WriteInterface},
+    stats,
     vbf::VersionBloomFilter,
-    version_cache::{VCache, VCacheNode},
+    verified_cache::{NoCache, VerifiedCache},
+    verify::verify_page,
     vfs::{
-        server_vfs::ServerFileState, user_vfs::UserFileState, GLOBAL_TS, MERKLE_PATH, QUERY,
-        REMOTE_FLAG, TMP_FILE_PATH, TMP_FLAG, YES_FLAG,
+        server_vfs::{commit, ServerFileState},
+        user_vfs::UserFileState,
+        OpenKind, MERKLE_PATH, QUERY, REMOTE_FLAG, TMP_FILE_PATH, TMP_FLAG, YES_FLAG,
     },
     MerkleDB, PageId,
 };
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use libsqlite3_sys as ffi;
 use std::{
-    collections::HashMap,
+    collections::{hash_map::Entry, HashMap},
     ffi::c_void,
     fs::File,
-    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
     mem::{self, MaybeUninit},
     net::TcpStream,
     os::raw::c_int,
@@ -25,7 +26,16 @@ use std::{
     slice,
 };
 
-use super::{FileData, Page, CONFIRM, MAIN_PATH, PAGE_SIZE};
+use super::{
+    attached_main_path,
+    error::{fail, Corrupt},
+    page_size, read_page_size, set_page_size, FileData, Page, CONFIRM, MAIN_DB, MAIN_PATH,
+    MSG_BUF_LEN, SELECT_DB,
+};
+
+// file format version numbers in the database header, 2 means wal
+const WRITE_VERSION_OFFSET: usize = 18;
+const READ_VERSION_OFFSET: usize = 19;
 
 unsafe fn s_get_file<'a>(ptr: *mut ffi::sqlite3_file) -> Result<&'a mut File> {
     let file_state = (ptr as *mut ServerFileState)
@@ -45,20 +55,12 @@ unsafe fn u_get_file<'a>(ptr: *mut ffi::sqlite3_file) -> Result<&'a mut FileData
     Ok(file)
 }
 
-unsafe fn s_get_map<'a>(ptr: *mut ffi::sqlite3_file) -> Result<&'a mut HashMap<PageId, Digest>> {
-    let file_state = (ptr as *mut ServerFileState)
-        .as_mut()
-        .context("null pointer")?;
-    let map = &mut file_state.map;
-    Ok(map)
-}
-
-unsafe fn s_get_vbf<'a>(ptr: *mut ffi::sqlite3_file) -> Result<&'a mut VersionBloomFilter> {
-    let file_state = (ptr as *mut ServerFileState)
-        .as_mut()
-        .context("null pointer")?;
-    let vbf = &mut file_state.vbf;
-    Ok(vbf)
+fn seek_to(file: &mut File, ofst: u64) -> Result<()> {
+    let pos = file.seek(SeekFrom::Start(ofst))?;
+    if pos != ofst {
+        bail!("seek to {} ended at {}", ofst, pos);
+    }
+    Ok(())
 }
 
 /// # Safety
@@ -73,29 +75,23 @@ pub unsafe extern "C" fn s_read(
     // starting from ofst, read i_amt length bytes to z_buf
     trace!("read offset={} len={}", i_ofst, i_amt);
 
-    let file = s_get_file(p_file).expect("failed to get file in ServerFileState");
+    let file = match s_get_file(p_file) {
+        Ok(file) => file,
+        Err(e) => return fail(ffi::SQLITE_IOERR_READ, e),
+    };
 
     // move the cursor to the offset
-    match file.seek(SeekFrom::Start(i_ofst as u64)) {
-        Ok(o) => {
-            if o != i_ofst as u64 {
-                return ffi::SQLITE_IOERR_READ;
-            }
-        }
-        Err(_) => {
-            return ffi::SQLITE_IOERR_READ;
-        }
+    if let Err(e) = seek_to(file, i_ofst as u64) {
+        return fail(ffi::SQLITE_IOERR_SEEK, e);
     }
     let out = slice::from_raw_parts_mut(z_buf as *mut u8, i_amt as usize);
 
     if let Err(err) = file.read_exact(out) {
-        let kind = err.kind();
-        if kind == ErrorKind::UnexpectedEof {
+        if err.kind() == ErrorKind::UnexpectedEof {
             // if len not enough, sqlite will fill with 0s
             return ffi::SQLITE_IOERR_SHORT_READ;
-        } else {
-            return ffi::SQLITE_IOERR_READ;
         }
+        return fail(ffi::SQLITE_IOERR_READ, err.into());
     }
 
     ffi::SQLITE_OK
@@ -113,40 +109,33 @@ pub unsafe extern "C" fn u_read(
     // starting from ofst, read i_amt length bytes to z_buf
     trace!("read offset={} len={}", i_ofst, i_amt);
 
-    let file_data = u_get_file(p_file).expect("failed to get file in ServerFileState");
+    let file_data = match u_get_file(p_file) {
+        Ok(file_data) => file_data,
+        Err(e) => return fail(ffi::SQLITE_IOERR_READ, e),
+    };
     let file_id = file_data.get_id();
 
     if file_id == TMP_FLAG {
         trace!("read tmp file");
         let file = &mut file_data.file;
         // move the cursor to the offset
-        match file.seek(SeekFrom::Start(i_ofst as u64)) {
-            Ok(o) => {
-                if o != i_ofst as u64 {
-                    return ffi::SQLITE_IOERR_READ;
-                }
-            }
-            Err(_) => {
-                return ffi::SQLITE_IOERR_READ;
-            }
+        if let Err(e) = seek_to(file, i_ofst as u64) {
+            return fail(ffi::SQLITE_IOERR_SEEK, e);
         }
         let out = slice::from_raw_parts_mut(z_buf as *mut u8, i_amt as usize);
 
         if let Err(err) = file.read_exact(out) {
-            let kind = err.kind();
-            if kind == ErrorKind::UnexpectedEof {
+            if err.kind() == ErrorKind::UnexpectedEof {
                 // if len not enough, sqlite will fill with 0s
                 return ffi::SQLITE_IOERR_SHORT_READ;
-            } else {
-                return ffi::SQLITE_IOERR_READ;
             }
+            return fail(ffi::SQLITE_IOERR_READ, err.into());
         }
     } else if file_id == REMOTE_FLAG {
         trace!("read remote content");
-        let file_state = (p_file as *mut UserFileState)
-            .as_mut()
-            .context("null pointer")
-            .expect("");
+        let Some(file_state) = (p_file as *mut UserFileState).as_mut() else {
+            return fail(ffi::SQLITE_IOERR_READ, anyhow!("null pointer"));
+        };
         let tp = &file_state.tp;
         let stream = &mut file_state.stream;
         let map = &mut file_state.map;
@@ -157,29 +146,46 @@ pub unsafe extern "C" fn u_read(
         debug!("i_amt: {}, i_ofst: {}", i_amt, i_ofst);
         debug!("start_p: {}, end_p: {}", start_p, end_p);
 
-        let mut pages = Vec::new();
-        match tp {
-            crate::Type::None => {
-                process_without_cache(stream, p_ids, &mut pages, map);
-            }
-            crate::Type::Intra => {
-                let cache = &mut file_state.cache;
-                process_intra_cache(cache, stream, p_ids, &mut pages, map);
-            }
-            crate::Type::Both => {
-                let cache = &mut file_state.cache;
-                process_both_cache(cache, stream, p_ids, &mut pages, map);
-            }
-            crate::Type::BothBloom => {
-                let vcache = &mut file_state.vcache;
-                let vbf = &file_state.vbf;
-                process_both_bloom(vcache, stream, p_ids, &mut pages, map, vbf);
-            }
-            crate::Type::SimpleBloom => {
-                let svcache = &mut file_state.svcache;
-                let vbf = &file_state.vbf;
-                process_simply_bloom(svcache, stream, p_ids, &mut pages, map, vbf);
+        let db = file_state.db;
+        if *file_state.cur_db != db {
+            if let Err(e) = select_db(stream, db) {
+                return fail(ffi::SQLITE_IOERR_READ, e);
             }
+            *file_state.cur_db = db;
+        }
+
+        if db == MAIN_DB {
+            crate::trace::record(&p_ids);
+        }
+        stats::count(|s| s.pages_requested += p_ids.len());
+
+        let mut pages = Vec::new();
+        let res = if let crate::Type::Stream = tp {
+            let root = if db == MAIN_DB {
+                file_state.root
+            } else {
+                &file_state.attached[db - 1].root
+            };
+            let proof_s = &mut file_state.stream_proof_s;
+            process_with_stream_verify(stream, p_ids, &mut pages, root, proof_s)
+        } else {
+            let mut no_cache = NoCache;
+            let (cache, map): (&mut dyn VerifiedCache, _) = if db != MAIN_DB {
+                // caches only hold pages of the main database
+                (&mut no_cache, &mut file_state.attached[db - 1].map)
+            } else {
+                let cache: &mut dyn VerifiedCache = match tp {
+                    crate::Type::None | crate::Type::Stream => &mut no_cache,
+                    crate::Type::Intra | crate::Type::Both => file_state.cache,
+                    crate::Type::BothBloom => file_state.vcache,
+                    crate::Type::SimpleBloom => file_state.svcache,
+                };
+                (cache, map)
+            };
+            process_pages(cache, stream, p_ids, &mut pages, map, file_state.vbf)
+        };
+        if let Err(e) = res {
+            return fail(ffi::SQLITE_IOERR_READ, e);
         }
 
         pages.sort();
@@ -191,16 +197,18 @@ pub unsafe extern "C" fn u_read(
         let out = slice::from_raw_parts_mut(z_buf as *mut u8, i_amt as usize);
 
         if let Err(err) = pages_cont_slice.read_exact(out) {
-            let kind = err.kind();
-            if kind == ErrorKind::UnexpectedEof {
+            if err.kind() == ErrorKind::UnexpectedEof {
                 // if len not enough, sqlite will fill with 0s
                 return ffi::SQLITE_IOERR_SHORT_READ;
-            } else {
-                return ffi::SQLITE_IOERR_READ;
             }
+            return fail(ffi::SQLITE_IOERR_READ, err.into());
         }
+        legacy_header(out, i_ofst as usize);
     } else {
-        panic!("should have an id");
+        return fail(
+            ffi::SQLITE_IOERR_READ,
+            anyhow!("unknown file id {}", file_id),
+        );
     }
 
     trace!("u_read finish");
@@ -208,490 +216,192 @@ pub unsafe extern "C" fn u_read(
     ffi::SQLITE_OK
 }
 
-fn process_without_cache(
-    stream: &mut TcpStream,
-    p_ids: Vec<PageId>,
-    pages: &mut Vec<Page>,
-    map: &mut HashMap<PageId, Digest>,
-) {
-    for p_id in p_ids {
-        let bytes = require_page(p_id, stream);
-        map.insert(p_id, bytes.to_digest());
-        let page = Page::new(p_id, Box::new(bytes));
-        pages.push(page);
+// the server only serves the checkpointed main file, so a wal database is read by the user
+// as a rollback-journal one; pages are verified before the file format bytes are rewritten
+fn legacy_header(out: &mut [u8], ofst: usize) {
+    for pos in [WRITE_VERSION_OFFSET, READ_VERSION_OFFSET] {
+        if (ofst..ofst + out.len()).contains(&pos) && out[pos - ofst] == 2 {
+            out[pos - ofst] = 1;
+        }
     }
 }
 
-fn pid_to_key(p_id: PageId) -> Digest {
-    let n_id = NodeId::from_page_id(p_id);
-    n_id.to_digest()
-}
-
-fn process_intra_cache(
-    cache: &mut Cache,
+// each page is verified against the trusted root before it is handed to sqlite
+fn process_with_stream_verify(
     stream: &mut TcpStream,
     p_ids: Vec<PageId>,
     pages: &mut Vec<Page>,
-    map: &mut HashMap<PageId, Digest>,
-) {
+    root: &(Digest, u32),
+    proof_s: &mut usize,
+) -> Result<()> {
     for p_id in p_ids {
-        let key = pid_to_key(p_id);
-        if let Some(n) = cache.get_node(&key) {
-            match n {
-                CacheNode::Leaf(l) => {
-                    pages.push(Page::new(p_id, l.get_bytes()));
-                }
-                CacheNode::NonLeaf(_) => {
-                    panic!("Impossible to get non-leaf node when only intra-cache is allowed")
-                }
-            }
-        } else {
-            let bytes = require_page(p_id, stream);
-            map.insert(p_id, bytes.to_digest());
-            let bytes_ptr = Box::new(bytes);
-            let page = Page::new(p_id, bytes_ptr.clone());
-            pages.push(page);
-            let new_n_id = NodeId::from_page_id(p_id);
-            cache.push_node(
-                new_n_id,
-                CacheNode::Leaf(CacheLeafNode::new(p_id, bytes_ptr)),
-            )
-        }
+        let (bytes, proof, p_len) = require_page_with_proof(p_id, stream)
+            .with_context(|| format!("failed to receive page {} with proof", p_id))?;
+        *proof_s += p_len;
+        stats::count(|s| s.fetched += 1);
+        verify_page(root, p_id, &bytes[..], &proof).context(Corrupt(format!(
+            "streaming verification failed for page {}",
+            p_id
+        )))?;
+        pages.push(Page::new(p_id, bytes));
     }
+    Ok(())
 }
 
-fn process_both_bloom(
-    vcache: &mut VCache,
+// serve cached pages once confirmed by the cache itself or by the server, fetch the rest
+fn process_pages(
+    cache: &mut dyn VerifiedCache,
     stream: &mut TcpStream,
     p_ids: Vec<PageId>,
     pages: &mut Vec<Page>,
     map: &mut HashMap<PageId, Digest>,
     vbf: &VersionBloomFilter,
-) {
+) -> Result<()> {
     for p_id in p_ids {
-        let key = pid_to_key(p_id);
-        let n_opt = vcache.get_node(&key).cloned();
-        if let Some(n) = n_opt {
-            if n.is_valid() {
+        let bytes = match cache.lookup(p_id) {
+            Some((bytes, true)) => {
                 trace!("the node is valid in cache");
-                match n {
-                    VCacheNode::Leaf(l) => {
-                        let bytes_ptr = l.get_bytes();
-                        let page = Page::new(p_id, bytes_ptr.clone());
-                        pages.push(page);
-                    }
-                    VCacheNode::NonLeaf(_) => {
-                        panic!("Impossible be a non-leaf node when check page")
-                    }
-                }
-            } else {
-                let (leaf_v, leaf_set) = match n.clone() {
-                    VCacheNode::Leaf(l) => (l.get_version(), l.get_set().clone()),
-                    VCacheNode::NonLeaf(_) => panic!("impossible to be a non-leaf"),
-                };
-                let mut path = vec![(leaf_v, leaf_set)];
-                let mut cur_id = n.get_id();
-
-                while vcache.has_sib(cur_id) {
-                    let parent_opt = vcache.find_parent(cur_id);
-                    if let Some(parent) = parent_opt {
-                        let parent_v = parent.get_version();
-                        let parent_set = parent.get_set().clone();
-                        path.push((parent_v, parent_set));
-                        cur_id = parent.get_id();
-                    } else {
-                        break;
-                    }
-                }
-
-                let mut valid_flag = false;
-                let mut cur_id = NodeId::from_page_id(p_id);
-                let mut target_n_id = NodeId::new(0, 0); // place-holder
-                for (v, set) in &path {
-                    // bottom-up
-                    if !vbf.contains_subroot(set, *v) {
-                        target_n_id = cur_id;
-                        valid_flag = true;
-                    } else {
-                        break;
-                    }
-                    cur_id = cur_id.get_parent_id();
-                }
-
-                if valid_flag {
-                    match n {
-                        VCacheNode::Leaf(l) => {
-                            let bytes_ptr = l.get_bytes();
-                            let page = Page::new(p_id, bytes_ptr.clone());
-                            pages.push(page);
-                        }
-                        VCacheNode::NonLeaf(_) => {
-                            panic!("Impossible be a non-leaf node when check page")
-                        }
-                    }
-                    vcache.confirm(target_n_id);
+                stats::count(|s| s.valid_hits += 1);
+                bytes
+            }
+            Some((bytes, false)) => {
+                if let Some(root_id) = cache.fresh_subroot(p_id, vbf) {
+                    stats::count(|s| s.vbf_hits += 1);
+                    cache.confirm(root_id);
+                    bytes
                 } else {
-                    // page validate algo
-                    match &n {
-                        VCacheNode::Leaf(l) => {
-                            // find path in cache
-                            let leaf_dig = l.to_digest();
-                            let leaf_bytes_dig = l.get_bytes().to_digest();
-                            let mut path = vec![leaf_dig];
-                            let bytes_ptr = l.get_bytes();
-                            let mut cur_id = n.get_id();
-                            while vcache.has_sib(cur_id) {
-                                let parent_opt = vcache.find_parent(cur_id);
-                                if let Some(parent) = parent_opt {
-                                    path.push(parent.to_digest());
-                                    cur_id = parent.get_id();
-                                } else {
-                                    break;
-                                }
-                            }
-
-                            // send path to server
-                            trace!("send confirm request to server: {}", p_id);
-                            let transfer_data = (CONFIRM, p_id, path);
-                            let bytes =
-                                bincode::serialize(&transfer_data).expect("failed to serialize");
-                            let _w_amt = stream.write(&bytes).expect("failed to write");
-
-                            // confirm or require page after receiving info from server
-                            let mut buffer = [0; PAGE_SIZE as usize];
-                            let _bytes_read =
-                                stream.read(&mut buffer).expect("failed to read stream");
-                            let resp = bincode::deserialize::<u32>(&buffer)
-                                .expect("failed to deserialize bincode");
-
-                            if resp == YES_FLAG {
-                                // will receive (NodeId)
-                                let _w_amt = stream
-                                    .write(&YES_FLAG.to_le_bytes())
-                                    .expect("failed to write");
-                                let mut buff = [0; PAGE_SIZE as usize];
-                                let _bytes_read =
-                                    stream.read(&mut buff).expect("failed to read stream");
-                                let (h, w) = bincode::deserialize::<(u32, u32)>(&buff)
-                                    .expect("failed to deserialize bincde");
-                                let cache_n_id = NodeId::new(h, w);
-                                map.insert(p_id, leaf_bytes_dig);
-
-                                unsafe {
-                                    let cur_v = GLOBAL_TS;
-                                    vcache.confirm_with_version(cache_n_id, cur_v);
-                                }
-
-                                let page = Page::new(p_id, bytes_ptr.clone());
-                                pages.push(page);
-                            } else {
-                                let _w_amt = stream
-                                    .write(&YES_FLAG.to_le_bytes())
-                                    .expect("failed to write");
-                                // will receive page
-                                let mut buff = [0; PAGE_SIZE as usize];
-                                let _bytes_read =
-                                    stream.read(&mut buff).expect("failed to read stream");
-                                let bytes: [u8; PAGE_SIZE as usize] = buff;
-                                map.insert(p_id, bytes.to_digest());
-                                let bytes_ptr = Box::new(bytes);
-                                let page = Page::new(p_id, bytes_ptr.clone());
-                                pages.push(page);
-
-                                unsafe {
-                                    let cur_v = GLOBAL_TS;
-                                    let idxes = vbf.get_bf_pos(p_id);
-                                    vcache.insert(p_id, bytes_ptr, cur_v, idxes);
-                                }
-                            }
+                    let path = cache.confirm_path(p_id);
+                    stats::count(|s| s.confirms += 1);
+                    match confirm_page(p_id, path, stream)? {
+                        Confirmed::Root(root_id) => {
+                            stats::count(|s| match root_id.get_height() {
+                                0 => s.confirmed_at_leaf += 1,
+                                _ => s.confirmed_at_ancestor += 1,
+                            });
+                            map.insert(p_id, bytes.to_digest());
+                            cache.confirm_remote(root_id);
+                            bytes
                         }
-                        VCacheNode::NonLeaf(_) => {
-                            panic!("Impossible be a non-leaf node when confirm page")
+                        Confirmed::Page(bytes) => {
+                            stats::count(|s| s.refetched += 1);
+                            map.insert(p_id, bytes.to_digest());
+                            cache.insert(p_id, bytes.clone(), vbf);
+                            bytes
                         }
                     }
                 }
             }
-        } else {
-            debug!("Page not exist in cache, require from remote");
-            let bytes = require_page(p_id, stream);
-            map.insert(p_id, bytes.to_digest());
-            let bytes_ptr = Box::new(bytes);
-            let page = Page::new(p_id, bytes_ptr.clone());
-            pages.push(page);
-            unsafe {
-                let version = GLOBAL_TS;
-                let idxes = vbf.get_bf_pos(p_id);
-                vcache.insert(p_id, bytes_ptr, version, idxes);
+            None => {
+                debug!("Page not exist in cache, require from remote");
+                let bytes = require_page(p_id, stream)?;
+                stats::count(|s| s.fetched += 1);
+                map.insert(p_id, bytes.to_digest());
+                cache.insert(p_id, bytes.clone(), vbf);
+                bytes
             }
-        }
+        };
+        pages.push(Page::new(p_id, bytes));
     }
+    Ok(())
 }
 
-fn process_simply_bloom(
-    svcache: &mut SVCache,
-    stream: &mut TcpStream,
-    p_ids: Vec<PageId>,
-    pages: &mut Vec<Page>,
-    map: &mut HashMap<PageId, Digest>,
-    vbf: &VersionBloomFilter,
-) {
-    for p_id in p_ids {
-        let key = pid_to_key(p_id);
-        let n_opt = svcache.get_node(&key).cloned();
-        if let Some(n) = n_opt {
-            if n.is_valid() {
-                trace!("the node is valid in cache");
-                match n {
-                    SVCacheNode::Leaf(l) => {
-                        let bytes_ptr = l.get_bytes();
-                        let page = Page::new(p_id, bytes_ptr.clone());
-                        pages.push(page);
-                    }
-                    SVCacheNode::NonLeaf(_) => {
-                        panic!("Impossible be a non-leaf node when check page")
-                    }
-                }
-            } else {
-                let leaf_v = match n.clone() {
-                    SVCacheNode::Leaf(l) => l.get_version(),
-                    SVCacheNode::NonLeaf(_) => panic!("impossible to be a non-leaf"),
-                };
-                let cur_id = n.get_id();
-
-                if !vbf.contains(p_id, leaf_v) {
-                    // directly use
-                    match n {
-                        SVCacheNode::Leaf(l) => {
-                            let bytes_ptr = l.get_bytes();
-                            let page = Page::new(p_id, bytes_ptr.clone());
-                            pages.push(page);
-                        }
-                        SVCacheNode::NonLeaf(_) => {
-                            panic!("Impossible be a non-leaf node when check page")
-                        }
-                    }
-                    svcache.confirm(cur_id);
-                } else {
-                    // page validate
-                    match &n {
-                        SVCacheNode::Leaf(l) => {
-                            // find path in cache
-                            let leaf_dig = l.to_digest();
-                            let leaf_bytes_dig = l.get_bytes().to_digest();
-                            let mut path = vec![leaf_dig];
-                            let bytes_ptr = l.get_bytes();
-                            let mut cur_id = n.get_id();
-                            while svcache.has_sib(cur_id) {
-                                let parent_opt = svcache.find_parent(cur_id);
-                                if let Some(parent) = parent_opt {
-                                    path.push(parent.to_digest());
-                                    cur_id = parent.get_id();
-                                } else {
-                                    break;
-                                }
-                            }
-
-                            // send path to server
-                            trace!("send confirm request to server: {}", p_id);
-                            let transfer_data = (CONFIRM, p_id, path);
-                            let bytes =
-                                bincode::serialize(&transfer_data).expect("failed to serialize");
-                            let _w_amt = stream.write(&bytes).expect("failed to write");
-
-                            // confirm or require page after receiving info from server
-                            let mut buffer = [0; PAGE_SIZE as usize];
-                            let _bytes_read =
-                                stream.read(&mut buffer).expect("failed to read stream");
-                            let resp = bincode::deserialize::<u32>(&buffer)
-                                .expect("failed to deserialize bincode");
-
-                            if resp == YES_FLAG {
-                                // will receive (NodeId)
-                                let _w_amt = stream
-                                    .write(&YES_FLAG.to_le_bytes())
-                                    .expect("failed to write");
-                                let mut buff = [0; PAGE_SIZE as usize];
-                                let _bytes_read =
-                                    stream.read(&mut buff).expect("failed to read stream");
-                                let (h, w) = bincode::deserialize::<(u32, u32)>(&buff)
-                                    .expect("failed to deserialize bincde");
-                                let cache_n_id = NodeId::new(h, w);
-                                map.insert(p_id, leaf_bytes_dig);
-
-                                unsafe {
-                                    let cur_v = GLOBAL_TS;
-                                    svcache.confirm_with_version(cache_n_id, cur_v);
-                                }
-
-                                let page = Page::new(p_id, bytes_ptr.clone());
-                                pages.push(page);
-                            } else {
-                                let _w_amt = stream
-                                    .write(&YES_FLAG.to_le_bytes())
-                                    .expect("failed to write");
-                                // will receive page
-                                let mut buff = [0; PAGE_SIZE as usize];
-                                let _bytes_read =
-                                    stream.read(&mut buff).expect("failed to read stream");
-                                let bytes: [u8; PAGE_SIZE as usize] = buff;
-                                map.insert(p_id, bytes.to_digest());
-                                let bytes_ptr = Box::new(bytes);
-                                let page = Page::new(p_id, bytes_ptr.clone());
-                                pages.push(page);
-
-                                unsafe {
-                                    let cur_v = GLOBAL_TS;
-                                    svcache.insert(p_id, bytes_ptr, cur_v);
-                                }
-                            }
-                        }
-                        SVCacheNode::NonLeaf(_) => {
-                            panic!("Impossible be a non-leaf node when confirm page")
-                        }
-                    }
-                }
-            }
-        } else {
-            debug!("Page not exist in cache, require from remote");
-            let bytes = require_page(p_id, stream);
-            map.insert(p_id, bytes.to_digest());
-            let bytes_ptr = Box::new(bytes);
-            let page = Page::new(p_id, bytes_ptr.clone());
-            pages.push(page);
-            unsafe {
-                let version = GLOBAL_TS;
-                svcache.insert(p_id, bytes_ptr, version);
-            }
-        }
-    }
+enum Confirmed {
+    // the cached page is current, all cached nodes under this sub-root are
+    Root(NodeId),
+    // the cached page is stale, here is the current one
+    Page(Box<[u8]>),
 }
 
-fn process_both_cache(
-    cache: &mut Cache,
-    stream: &mut TcpStream,
-    p_ids: Vec<PageId>,
-    pages: &mut Vec<Page>,
-    map: &mut HashMap<PageId, Digest>,
-) {
-    trace!("process both cache");
-    for p_id in p_ids {
-        let key = pid_to_key(p_id);
-        if let Some(n) = cache.get_node(&key) {
-            if n.is_valid() {
-                trace!("the node is valid in cache");
-                match n {
-                    CacheNode::Leaf(l) => {
-                        let bytes_ptr = l.get_bytes();
-                        let page = Page::new(p_id, bytes_ptr.clone());
-                        pages.push(page);
-                    }
-                    CacheNode::NonLeaf(_) => {
-                        panic!("Impossible be a non-leaf node when check page")
-                    }
-                }
-            } else {
-                trace!("the node is unknown in cache");
-                match n {
-                    CacheNode::Leaf(l) => {
-                        // find path in cache
-                        let leaf_dig = l.to_digest();
-                        let leaf_bytes_dig = l.get_bytes().to_digest();
-                        let mut path = vec![leaf_dig];
-                        let bytes_ptr = l.get_bytes();
-
-                        let mut cur_n = n.clone();
-                        while cache.has_sib(cur_n.get_id()) {
-                            let parent_opt = cache.find_parent(cur_n.get_id());
-                            if let Some(parent) = parent_opt {
-                                path.push(parent.to_digest());
-                                cur_n = parent.clone();
-                            } else {
-                                break;
-                            }
-                        }
+fn confirm_page(p_id: PageId, path: Vec<Digest>, stream: &mut TcpStream) -> Result<Confirmed> {
+    // send path to server
+    trace!("send confirm request to server: {}", p_id);
+    let transfer_data = (CONFIRM, p_id, path);
+    let bytes = bincode::serialize(&transfer_data)?;
+    let _w_amt = stream.write(&bytes)?;
+    stats::sent(bytes.len());
+
+    // confirm or require page after receiving info from server
+    let mut buffer = [0; MSG_BUF_LEN];
+    let bytes_read = stream.read(&mut buffer)?;
+    let resp = bincode::deserialize::<u32>(&buffer)?;
+    let _w_amt = stream.write(&YES_FLAG.to_le_bytes())?;
+    stats::received(bytes_read);
+    stats::sent(4);
+
+    if resp == YES_FLAG {
+        // will receive (NodeId)
+        let mut buff = [0; MSG_BUF_LEN];
+        let bytes_read = stream.read(&mut buff)?;
+        stats::received(bytes_read);
+        let (h, w) = bincode::deserialize::<(u32, u32)>(&buff)?;
+        Ok(Confirmed::Root(NodeId::new(h, w)))
+    } else {
+        // will receive page
+        let mut bytes = vec![0; page_size() as usize].into_boxed_slice();
+        stream.read_exact(&mut bytes)?;
+        stats::received(bytes.len());
+        Ok(Confirmed::Page(bytes))
+    }
+}
 
-                        // send path to server
-                        trace!("send confirm request to server: {}", p_id);
-                        let transfer_data = (CONFIRM, p_id, path);
-                        let bytes =
-                            bincode::serialize(&transfer_data).expect("failed to serialize");
-                        let _w_amt = stream.write(&bytes).expect("failed to write");
-
-                        // confirm or require page after receiving info from server
-                        let mut buffer = [0; PAGE_SIZE as usize];
-                        let _bytes_read = stream.read(&mut buffer).expect("failed to read stream");
-                        let resp = bincode::deserialize::<u32>(&buffer)
-                            .expect("failed to deserialize bincode");
-
-                        if resp == YES_FLAG {
-                            // will receive (NodeId)
-                            let _w_amt = stream
-                                .write(&YES_FLAG.to_le_bytes())
-                                .expect("failed to write");
-                            let mut buff = [0; PAGE_SIZE as usize];
-                            let _bytes_read =
-                                stream.read(&mut buff).expect("failed to read stream");
-                            let (h, w) = bincode::deserialize::<(u32, u32)>(&buff)
-                                .expect("failed to deserialize bincde");
-                            let cache_n_id = NodeId::new(h, w);
-                            map.insert(p_id, leaf_bytes_dig);
-
-                            cache.confirm(cache_n_id);
-
-                            let page = Page::new(p_id, bytes_ptr.clone());
-                            pages.push(page);
-                        } else {
-                            let _w_amt = stream
-                                .write(&YES_FLAG.to_le_bytes())
-                                .expect("failed to write");
-                            // will receive page
-                            let mut buff = [0; PAGE_SIZE as usize];
-                            let _bytes_read =
-                                stream.read(&mut buff).expect("failed to read stream");
-                            let bytes: [u8; PAGE_SIZE as usize] = buff;
-                            map.insert(p_id, bytes.to_digest());
-                            let bytes_ptr = Box::new(bytes);
-                            let page = Page::new(p_id, bytes_ptr.clone());
-                            pages.push(page);
-                            cache.insert(p_id, bytes_ptr);
-                        }
-                    }
-                    CacheNode::NonLeaf(_) => {
-                        panic!("Impossible be a non-leaf node when confirm page")
-                    }
-                }
-            }
-        } else {
-            debug!("Page not exist in cache, require from remote");
-            let bytes = require_page(p_id, stream);
-            map.insert(p_id, bytes.to_digest());
-            let bytes_ptr = Box::new(bytes);
-            let page = Page::new(p_id, bytes_ptr.clone());
-            pages.push(page);
-            cache.insert(p_id, bytes_ptr);
-        }
+// the server acknowledges before the next request, so both never share one read
+fn select_db(stream: &mut TcpStream, db: usize) -> Result<()> {
+    let msg: (u32, PageId, Vec<Digest>) = (SELECT_DB, PageId(db as u32), vec![]);
+    let bytes = bincode::serialize(&msg)?;
+    stream.write_all(&bytes)?;
+    let mut flag = [0; 4];
+    stream.read_exact(&mut flag)?;
+    stats::sent(bytes.len());
+    stats::received(flag.len());
+    if u32::from_le_bytes(flag) != YES_FLAG {
+        bail!("server refused to select database {}", db);
     }
+    Ok(())
 }
 
-fn require_page(pid: PageId, stream: &mut TcpStream) -> [u8; PAGE_SIZE as usize] {
+pub(crate) fn require_page(pid: PageId, stream: &mut TcpStream) -> Result<Box<[u8]>> {
     debug!("required page id: {:?}", pid);
     let transfer_data: (u32, PageId, Vec<Digest>) = (QUERY, pid, vec![]);
-    let bytes = bincode::serialize(&transfer_data).expect("failed to serialize");
-    let _w_amt = stream.write(&bytes).expect("failed to write");
-    let mut reader = BufReader::new(stream);
-    let mut buffer = [0; PAGE_SIZE as usize];
-    let read_len = reader.read(&mut buffer).expect("failed to read buffer");
-    debug!("read length: {}", read_len);
-    let p_cont: [u8; PAGE_SIZE as usize] = buffer;
+    let bytes = bincode::serialize(&transfer_data)?;
+    let _w_amt = stream.write(&bytes)?;
+    // large pages arrive in several segments
+    let mut p_cont = vec![0; page_size() as usize].into_boxed_slice();
+    stream.read_exact(&mut p_cont)?;
+    stats::sent(bytes.len());
+    stats::received(p_cont.len());
     debug!("user has received page bytes");
-    p_cont
+    Ok(p_cont)
+}
+
+// (page, proof) is length-prefixed since the proof size varies
+fn require_page_with_proof(
+    pid: PageId,
+    stream: &mut TcpStream,
+) -> Result<(Box<[u8]>, Proof, usize)> {
+    debug!("required page id with proof: {:?}", pid);
+    let transfer_data: (u32, PageId, Vec<Digest>) = (QUERY, pid, vec![]);
+    let bytes = bincode::serialize(&transfer_data)?;
+    stream.write_all(&bytes)?;
+    let mut len_buf = [0; 8];
+    stream.read_exact(&mut len_buf)?;
+    let len = u64::from_le_bytes(len_buf) as usize;
+    let mut buf = vec![0; len];
+    stream.read_exact(&mut buf)?;
+    stats::sent(bytes.len());
+    stats::received(len_buf.len() + len);
+    let (p_cont, proof) = bincode::deserialize::<(Vec<u8>, Proof)>(&buf)?;
+    if p_cont.len() != page_size() as usize {
+        bail!("invalid page length received for page {}", pid);
+    }
+    Ok((p_cont.into_boxed_slice(), proof, len))
 }
 
 fn compute_page_ids(ofst: u64, len: u64) -> (u64, Vec<PageId>) {
-    let start_page = ofst / PAGE_SIZE as u64;
-    let start_point = start_page * PAGE_SIZE as u64;
-    // let end_page = (ofst + len) / PAGE_SIZE as u64;
-    let end_page = (ofst + len - 1) / PAGE_SIZE as u64; // ATTENTION PLEASE
+    let page_size = page_size() as u64;
+    let start_page = ofst / page_size;
+    let start_point = start_page * page_size;
+    // let end_page = (ofst + len) / page_size;
+    let end_page = (ofst + len - 1) / page_size; // ATTENTION PLEASE
     let mut res = Vec::new();
     for i in start_page..(end_page + 1) {
         res.push(PageId(i as u32));
@@ -701,11 +411,18 @@ fn compute_page_ids(ofst: u64, len: u64) -> (u64, Vec<PageId>) {
 
 // build the merkle tree from scratch
 pub fn build_merkle_tree() -> Result<()> {
-    info!("building merkle tree...");
-    let mut file = File::open(Path::new(MAIN_PATH)).expect("failed to open file");
-    let file_len = file.metadata().expect("Failed to get metadata").len();
-    let mut merkle_db =
-        MerkleDB::create_new(Path::new(MERKLE_PATH)).expect("failed to open or create merkle db");
+    build_merkle_tree_at(Path::new(MAIN_PATH), Path::new(MERKLE_PATH))
+}
+
+pub fn build_merkle_tree_at(main_path: &Path, merkle_path: &Path) -> Result<()> {
+    info!("building merkle tree for {:?}...", main_path);
+    let page_size = read_page_size(main_path)?;
+    set_page_size(page_size)?;
+    info!("page size: {}", page_size);
+    let mut file = File::open(main_path)?;
+    let file_len = file.metadata()?.len();
+    let mut merkle_db = MerkleDB::create_new(merkle_path)?;
+    merkle_db.set_page_size(page_size);
     let root_id = merkle_db.get_root_id();
     let mut ctx = WriteContext::new(&merkle_db, root_id);
     let mut ofset: u64 = 0;
@@ -713,7 +430,7 @@ pub fn build_merkle_tree() -> Result<()> {
 
     loop {
         trace!("updating for page: {}", p_id_num);
-        let mut buf: [u8; PAGE_SIZE as usize] = [0; PAGE_SIZE as usize];
+        let mut buf = vec![0; page_size as usize];
         match file.seek(SeekFrom::Start(ofset)) {
             Ok(o) => {
                 if o != ofset {
@@ -734,21 +451,25 @@ pub fn build_merkle_tree() -> Result<()> {
                 bail!("sqlite io error");
             }
         }
-        ctx.update(buf.to_digest(), PageId(p_id_num))
-            .expect("Failed to update merkle tree");
+        ctx.update(buf.to_digest(), PageId(p_id_num))?;
 
-        ofset += PAGE_SIZE as u64;
+        ofset += page_size as u64;
         p_id_num += 1;
         if ofset >= file_len {
             break;
         }
     }
+    // leaves of a larger file the tree was built for before
+    ctx.truncate(p_id_num)?;
 
     let changes = ctx.changes();
     let new_root_id = changes.root_id;
     for (addr, node) in changes.nodes {
         merkle_db.write_node(&addr, &node)?;
     }
+    for addr in changes.removed {
+        merkle_db.remove_node(&addr)?;
+    }
 
     merkle_db.update_param(new_root_id)?;
     merkle_db.close();
@@ -756,49 +477,94 @@ pub fn build_merkle_tree() -> Result<()> {
     Ok(())
 }
 
-fn check_dig(dig: &Digest) -> bool {
-    let buf = [0_u8; PAGE_SIZE as usize];
-    *dig == buf.to_digest()
+// read a page of a file, the part past the end of the file is left as zeros
+fn read_page(file: &mut File, ofst: u64, buf: &mut [u8]) -> Result<()> {
+    seek_to(file, ofst)?;
+    let mut read = 0;
+    while read < buf.len() {
+        match file.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(n) => read += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
 }
 
-unsafe fn update_merkle_tree(
-    ofset: u64,
-    page_ids: Vec<PageId>,
-    p_file: *mut ffi::sqlite3_file,
-) -> c_int {
-    trace!("updating merkle tree");
-    let mut file = File::open(Path::new(MAIN_PATH)).expect("failed to open file");
-    let map = s_get_map(p_file).expect("Cannot get the map");
-    let mut ofset = ofset;
-    for p_id in page_ids {
-        let mut buf: [u8; PAGE_SIZE as usize] = [0; PAGE_SIZE as usize];
-        match file.seek(SeekFrom::Start(ofset)) {
-            Ok(o) => {
-                if o != ofset {
-                    return ffi::SQLITE_IOERR_WRITE;
+unsafe fn buffer_write(p_file: *mut ffi::sqlite3_file, ofst: u64, data: &[u8]) -> Result<()> {
+    let file_state = (p_file as *mut ServerFileState)
+        .as_mut()
+        .context("null pointer")?;
+    buffer_pages(
+        file_state.dirty,
+        file_state.file.assume_init_mut(),
+        page_size() as u64,
+        ofst,
+        data,
+    )
+}
+
+// copy a write of the main file into the images of the pages it touches, a page seen for the
+// first time starts from its content in the file unless the write covers all of it
+fn buffer_pages(
+    dirty: &mut HashMap<PageId, Box<[u8]>>,
+    file: &mut File,
+    page_size: u64,
+    ofst: u64,
+    data: &[u8],
+) -> Result<()> {
+    let end = ofst + data.len() as u64;
+    let mut page_start = ofst - ofst % page_size;
+    while page_start < end {
+        let (from, to) = (ofst.max(page_start), end.min(page_start + page_size));
+        let p_id = PageId((page_start / page_size) as u32);
+        let image = match dirty.entry(p_id) {
+            Entry::Occupied(e) => e.into_mut(),
+            Entry::Vacant(e) => {
+                let mut buf = vec![0; page_size as usize].into_boxed_slice();
+                if to - from < page_size {
+                    read_page(file, page_start, &mut buf)?;
                 }
+                e.insert(buf)
             }
-            Err(_) => {
-                return ffi::SQLITE_IOERR_WRITE;
-            }
-        }
+        };
+        image[(from - page_start) as usize..(to - page_start) as usize]
+            .copy_from_slice(&data[(from - ofst) as usize..(to - ofst) as usize]);
+        page_start += page_size;
+    }
+    Ok(())
+}
 
-        if let Err(err) = file.read_exact(&mut buf) {
-            let kind = err.kind();
-            if kind == ErrorKind::UnexpectedEof {
-                trace!("file length not enough");
-            } else {
-                return ffi::SQLITE_IOERR_READ;
-            }
-        }
-        let dig = buf.to_digest();
-        if !check_dig(&dig) {
-            map.insert(p_id, buf.to_digest());
-        }
-        ofset += PAGE_SIZE as u64;
+// digests of all pages of a main file
+pub(crate) fn page_digests(path: &Path) -> Result<Vec<(PageId, Digest)>> {
+    let mut file = File::open(path)?;
+    let page_size = page_size() as usize;
+    let page_cnt = file.metadata()?.len().div_ceil(page_size as u64);
+    let mut res = Vec::new();
+    for p_id_num in 0..page_cnt {
+        let mut buf = vec![0; page_size];
+        read_page(&mut file, p_id_num * page_size as u64, &mut buf)?;
+        res.push((PageId(p_id_num as u32), buf.to_digest()));
     }
-    drop(file);
-    ffi::SQLITE_OK
+    Ok(res)
+}
+
+// the main file of this journal still holds a transaction that can be rolled back
+fn journal_is_live(main_path: &str) -> bool {
+    std::fs::metadata(format!("{}-journal", main_path)).is_ok_and(|meta| meta.len() > 0)
+}
+
+unsafe fn s_commit(p_file: *mut ffi::sqlite3_file) -> Result<()> {
+    let file_state = (p_file as *mut ServerFileState)
+        .as_mut()
+        .context("null pointer")?;
+    commit(
+        file_state.dirty,
+        file_state.truncated,
+        file_state.vbf,
+        file_state.committed,
+    )
 }
 
 /// # Safety
@@ -812,32 +578,42 @@ pub unsafe extern "C" fn s_write(
 ) -> c_int {
     trace!("server write offset={} len={}", i_ofst, i_amt);
 
-    let file = s_get_file(p_file).expect("failed to get file in ServerFileState");
+    let file = match s_get_file(p_file) {
+        Ok(file) => file,
+        Err(e) => return fail(ffi::SQLITE_IOERR_WRITE, e),
+    };
 
     // move the cursor to the offset
-    match file.seek(SeekFrom::Start(i_ofst as u64)) {
-        Ok(o) => {
-            if o != i_ofst as u64 {
-                return ffi::SQLITE_IOERR_READ;
-            }
-        }
-        Err(_) => {
-            return ffi::SQLITE_IOERR_READ;
-        }
+    if let Err(e) = seek_to(file, i_ofst as u64) {
+        return fail(ffi::SQLITE_IOERR_SEEK, e);
     }
     let data = slice::from_raw_parts(z as *const u8, i_amt as usize);
-    if let Err(_err) = file.write_all(data) {
-        return ffi::SQLITE_IOERR_WRITE;
+    if let Err(err) = file.write_all(data) {
+        return fail(ffi::SQLITE_IOERR_WRITE, err.into());
     }
 
-    let (ofset, page_ids) = compute_page_ids(i_ofst as u64, i_amt as u64);
-    let version = GLOBAL_TS;
-    let vbf = s_get_vbf(p_file).expect("failed to get file in ServerFileState");
-    for p_id in &page_ids {
-        vbf.insert(*p_id, version);
+    // journals and wal frames are not part of the authenticated snapshot, in wal mode
+    // the main file (and hence the merkle tree) only changes when a checkpoint copies pages back
+    let kind = match (p_file as *mut ServerFileState).as_ref() {
+        Some(file_state) => file_state.kind,
+        None => return fail(ffi::SQLITE_IOERR_WRITE, anyhow!("null pointer")),
+    };
+    if kind == OpenKind::MainJournal && i_ofst == 0 && data.iter().all(|b| *b == 0) {
+        // a zeroed header commits in persist journal mode
+        return match s_commit(p_file) {
+            Ok(()) => ffi::SQLITE_OK,
+            Err(e) => fail(ffi::SQLITE_IOERR_WRITE, e),
+        };
+    }
+    if kind != OpenKind::MainDb {
+        return ffi::SQLITE_OK;
     }
 
-    update_merkle_tree(ofset, page_ids, p_file)
+    // kept pending until sqlite commits, the pages are hashed and the tree and the vbf updated then
+    match buffer_write(p_file, i_ofst as u64, data) {
+        Ok(()) => ffi::SQLITE_OK,
+        Err(e) => fail(ffi::SQLITE_IOERR_WRITE, e),
+    }
 }
 
 /// # Safety
@@ -850,24 +626,20 @@ pub unsafe extern "C" fn u_write(
     i_ofst: ffi::sqlite3_int64,
 ) -> c_int {
     trace!("user write offset={} len={}", i_ofst, i_amt);
-    let file_data = u_get_file(p_file).expect("failed to get file in ServerFileState");
+    let file_data = match u_get_file(p_file) {
+        Ok(file_data) => file_data,
+        Err(e) => return fail(ffi::SQLITE_IOERR_WRITE, e),
+    };
 
     let file = &mut file_data.file;
     trace!("u_write file: {:?}", file);
     // move the cursor to the offset
-    match file.seek(SeekFrom::Start(i_ofst as u64)) {
-        Ok(o) => {
-            if o != i_ofst as u64 {
-                return ffi::SQLITE_IOERR_READ;
-            }
-        }
-        Err(_) => {
-            return ffi::SQLITE_IOERR_READ;
-        }
+    if let Err(e) = seek_to(file, i_ofst as u64) {
+        return fail(ffi::SQLITE_IOERR_SEEK, e);
     }
     let data = slice::from_raw_parts(z as *mut u8, i_amt as usize);
-    if let Err(_err) = file.write_all(data) {
-        return ffi::SQLITE_IOERR_WRITE;
+    if let Err(err) = file.write_all(data) {
+        return fail(ffi::SQLITE_IOERR_WRITE, err.into());
     }
     trace!("write succeeds");
     ffi::SQLITE_OK
@@ -881,10 +653,29 @@ pub unsafe extern "C" fn s_truncate(
     size: ffi::sqlite3_int64,
 ) -> c_int {
     trace!("truncate");
-    let file = s_get_file(p_file).expect("failed to get file in ServerFileState");
+    let file = match s_get_file(p_file) {
+        Ok(file) => file,
+        Err(e) => return fail(ffi::SQLITE_IOERR_TRUNCATE, e),
+    };
 
-    if file.set_len(size as u64).is_err() {
-        return ffi::SQLITE_IOERR_TRUNCATE;
+    if let Err(err) = file.set_len(size as u64) {
+        return fail(ffi::SQLITE_IOERR_TRUNCATE, err.into());
+    }
+
+    let Some(file_state) = (p_file as *mut ServerFileState).as_mut() else {
+        return fail(ffi::SQLITE_IOERR_TRUNCATE, anyhow!("null pointer"));
+    };
+    if file_state.kind == OpenKind::MainDb {
+        // vacuum shrank the file, the tree drops the cut off pages once sqlite commits
+        let page_cnt = (size as u64).div_ceil(page_size() as u64) as u32;
+        file_state.dirty.retain(|p_id, _| p_id.get_id() < page_cnt);
+        *file_state.truncated = Some(file_state.truncated.map_or(page_cnt, |n| n.min(page_cnt)));
+    }
+    // an emptied journal commits in truncate journal mode
+    if file_state.kind == OpenKind::MainJournal && size == 0 {
+        if let Err(e) = s_commit(p_file) {
+            return fail(ffi::SQLITE_IOERR_TRUNCATE, e);
+        }
     }
 
     ffi::SQLITE_OK
@@ -892,12 +683,15 @@ pub unsafe extern "C" fn s_truncate(
 
 /// # Safety
 ///
-/// User truncates the file, should never reached here.
+/// User truncates the file, remote files are read-only so this should never be reached.
 pub unsafe extern "C" fn u_truncate(
     _p_file: *mut ffi::sqlite3_file,
-    _size: ffi::sqlite3_int64,
+    size: ffi::sqlite3_int64,
 ) -> c_int {
-    panic!("Should never reach user i/o truncate");
+    fail(
+        ffi::SQLITE_IOERR_TRUNCATE,
+        anyhow!("user files cannot be truncated to {}", size),
+    )
 }
 
 /// # Safety
@@ -905,9 +699,23 @@ pub unsafe extern "C" fn u_truncate(
 /// Server persists changes to the file.
 pub unsafe extern "C" fn s_sync(p_file: *mut ffi::sqlite3_file, _flags: c_int) -> c_int {
     trace!("s_sync");
-    let file = s_get_file(p_file).expect("failed to get file in ServerFileState");
-    if file.flush().is_err() {
-        return ffi::SQLITE_IOERR_FSYNC;
+    let file = match s_get_file(p_file) {
+        Ok(file) => file,
+        Err(e) => return fail(ffi::SQLITE_IOERR_FSYNC, e),
+    };
+    if let Err(err) = file.flush() {
+        return fail(ffi::SQLITE_IOERR_FSYNC, err.into());
+    }
+
+    // with a rollback journal the commit point is the end of the journal, otherwise (wal
+    // checkpoints, journal_mode off or memory) the main file is synced once the pages are written
+    let Some(file_state) = (p_file as *mut ServerFileState).as_ref() else {
+        return fail(ffi::SQLITE_IOERR_FSYNC, anyhow!("null pointer"));
+    };
+    if file_state.kind == OpenKind::MainDb && !journal_is_live(&file_state.path) {
+        if let Err(e) = s_commit(p_file) {
+            return fail(ffi::SQLITE_IOERR_FSYNC, e);
+        }
     }
 
     ffi::SQLITE_OK
@@ -929,9 +737,13 @@ pub unsafe extern "C" fn s_file_size(
     p_size: *mut ffi::sqlite3_int64,
 ) -> c_int {
     trace!("file_size");
-    let file = s_get_file(p_file).expect("failed to get file in ServerFileState");
-    let len = file.metadata().expect("failed to query metadata").len();
-    let p_size: &mut ffi::sqlite3_int64 = p_size.as_mut().expect("null pointer");
+    let len = match s_get_file(p_file).and_then(|file| Ok(file.metadata()?.len())) {
+        Ok(len) => len,
+        Err(e) => return fail(ffi::SQLITE_IOERR_FSTAT, e),
+    };
+    let Some(p_size) = p_size.as_mut() else {
+        return fail(ffi::SQLITE_IOERR_FSTAT, anyhow!("null pointer"));
+    };
     *p_size = len as ffi::sqlite3_int64;
 
     ffi::SQLITE_OK
@@ -941,15 +753,25 @@ pub unsafe extern "C" fn s_file_size(
 ///
 /// User returns the current file-size of the file.
 pub unsafe extern "C" fn u_file_size(
-    _p_file: *mut ffi::sqlite3_file,
+    p_file: *mut ffi::sqlite3_file,
     p_size: *mut ffi::sqlite3_int64,
 ) -> c_int {
     trace!("require user file size");
     // todo: should be implemented by remote request
     // file size should be written to chain, or a merkle proof should be returned
-    let file = File::open(Path::new(MAIN_PATH)).expect("Failed to open file");
-    let len = file.metadata().expect("failed to query metadata").len();
-    let p_size: &mut ffi::sqlite3_int64 = p_size.as_mut().expect("null pointer");
+    let path = match (p_file as *mut UserFileState).as_ref() {
+        Some(file_state) if file_state.db != MAIN_DB => {
+            attached_main_path(&file_state.attached[file_state.db - 1].name)
+        }
+        _ => MAIN_PATH.to_string(),
+    };
+    let len = match File::open(Path::new(&path)).and_then(|file| file.metadata()) {
+        Ok(meta) => meta.len(),
+        Err(err) => return fail(ffi::SQLITE_IOERR_FSTAT, err.into()),
+    };
+    let Some(p_size) = p_size.as_mut() else {
+        return fail(ffi::SQLITE_IOERR_FSTAT, anyhow!("null pointer"));
+    };
     *p_size = len as ffi::sqlite3_int64;
 
     // file.sync_all().expect("err happened while closing the db file"); // time consuming since db file is large
@@ -964,6 +786,7 @@ pub unsafe extern "C" fn s_close(p_file: *mut ffi::sqlite3_file) -> c_int {
     if let Some(file_state) = (p_file as *mut ServerFileState).as_mut() {
         let file = file_state.file.assume_init_mut();
         trace!("close file {:?}", file);
+        drop(mem::take(&mut file_state.path));
 
         let old_file_opt = mem::replace(&mut file_state.file, MaybeUninit::uninit());
         // file_state.file = None;
@@ -973,7 +796,9 @@ pub unsafe extern "C" fn s_close(p_file: *mut ffi::sqlite3_file) -> c_int {
     }
     let path = Path::new(TMP_FILE_PATH);
     if path.exists() {
-        std::fs::remove_file(path).expect("cannot remove tmp file");
+        if let Err(err) = std::fs::remove_file(path) {
+            return fail(ffi::SQLITE_IOERR_CLOSE, err.into());
+        }
     }
     ffi::SQLITE_OK
 }
@@ -988,13 +813,18 @@ pub unsafe extern "C" fn u_close(p_file: *mut ffi::sqlite3_file) -> c_int {
         debug!("close: {:?}", file_data);
         let file_path = &file_data.name;
         let path = Path::new(file_path);
-        if path.exists() {
-            std::fs::remove_file(path).expect("cannot remove tmp file");
-        }
+        let res = if path.exists() {
+            std::fs::remove_file(path)
+        } else {
+            Ok(())
+        };
         let old_file_opt = mem::replace(&mut file_state.tmp_file, MaybeUninit::uninit());
         let old_file_data = old_file_opt.assume_init();
         let old_file = old_file_data.file;
         drop(old_file);
+        if let Err(err) = res {
+            return fail(ffi::SQLITE_IOERR_CLOSE, err.into());
+        }
     }
 
     trace!("u_close succeeds");
@@ -1010,6 +840,25 @@ pub unsafe extern "C" fn lock(_p_file: *mut ffi::sqlite3_file, _e_lock: c_int) -
     ffi::SQLITE_OK
 }
 
+/// # Safety
+///
+/// Server locks a file. Only the exclusive lock on a wal database is checked, sqlite takes it
+/// on close to checkpoint and delete the wal, which must not happen while other connections use it.
+pub unsafe extern "C" fn s_lock(p_file: *mut ffi::sqlite3_file, e_lock: c_int) -> c_int {
+    trace!("s_lock");
+    let Some(file_state) = (p_file as *mut ServerFileState).as_mut() else {
+        return fail(ffi::SQLITE_IOERR_LOCK, anyhow!("null pointer"));
+    };
+    if e_lock == ffi::SQLITE_LOCK_EXCLUSIVE && file_state.kind == OpenKind::MainDb {
+        if let Some(shm) = file_state.shm.get(&file_state.path) {
+            if shm.in_use_by_others(p_file as usize) {
+                return ffi::SQLITE_BUSY;
+            }
+        }
+    }
+    ffi::SQLITE_OK
+}
+
 /// # Safety
 ///
 /// Unlock a file.
@@ -1082,6 +931,72 @@ pub unsafe extern "C" fn device_characteristics(_p_file: *mut ffi::sqlite3_file)
         ffi::SQLITE_IOCAP_SEQUENTIAL
 }
 
+/// # Safety
+///
+/// Server maps region `i_pg` of the in-process wal-index.
+pub unsafe extern "C" fn s_shm_map(
+    p_file: *mut ffi::sqlite3_file,
+    i_pg: i32,
+    pgsz: i32,
+    b_extend: i32,
+    pp: *mut *mut c_void,
+) -> i32 {
+    trace!("s_shm_map pg={} sz={} extend={}", i_pg, pgsz, b_extend);
+    let Some(file_state) = (p_file as *mut ServerFileState).as_mut() else {
+        return fail(ffi::SQLITE_IOERR_SHMMAP, anyhow!("null pointer"));
+    };
+    let shm = file_state.shm.entry(file_state.path.clone()).or_default();
+    let region = shm.map(p_file as usize, i_pg as usize, pgsz as usize, b_extend != 0);
+    *pp = region.map_or(std::ptr::null_mut(), |p| p as *mut c_void);
+
+    ffi::SQLITE_OK
+}
+
+/// # Safety
+///
+/// Server locks or unlocks slots of the wal-index.
+pub unsafe extern "C" fn s_shm_lock(
+    p_file: *mut ffi::sqlite3_file,
+    offset: i32,
+    n: i32,
+    flags: i32,
+) -> i32 {
+    trace!("s_shm_lock offset={} n={} flags={}", offset, n, flags);
+    let Some(file_state) = (p_file as *mut ServerFileState).as_mut() else {
+        return fail(ffi::SQLITE_IOERR_SHMLOCK, anyhow!("null pointer"));
+    };
+    match file_state.shm.get_mut(&file_state.path) {
+        Some(shm) => shm.lock(p_file as usize, offset as usize, n as usize, flags),
+        None => ffi::SQLITE_IOERR_SHMLOCK,
+    }
+}
+
+/// # Safety
+///
+/// Server memory barrier on the wal-index.
+pub unsafe extern "C" fn s_shm_barrier(_p_file: *mut ffi::sqlite3_file) {
+    trace!("s_shm_barrier");
+    std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// # Safety
+///
+/// Server releases the wal-index of this connection, dropped with the last one.
+pub unsafe extern "C" fn s_shm_unmap(p_file: *mut ffi::sqlite3_file, _delete_flags: i32) -> i32 {
+    trace!("s_shm_unmap");
+    let Some(file_state) = (p_file as *mut ServerFileState).as_mut() else {
+        return fail(ffi::SQLITE_IOERR_SHMMAP, anyhow!("null pointer"));
+    };
+    let path = &file_state.path;
+    if let Some(shm) = file_state.shm.get_mut(path) {
+        if shm.unmap(p_file as usize) {
+            file_state.shm.remove(path);
+        }
+    }
+
+    ffi::SQLITE_OK
+}
+
 /// # Safety
 ///
 /// Create a shared memory file mapping.
@@ -1153,3 +1068,119 @@ pub unsafe extern "C" fn mem_unfetch(
 
     ffi::SQLITE_OK
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        fs::{self, OpenOptions},
+        path::PathBuf,
+    };
+
+    const P_SIZE: u64 = 512;
+
+    struct MainFile {
+        path: PathBuf,
+        file: File,
+        dirty: HashMap<PageId, Box<[u8]>>,
+    }
+
+    impl MainFile {
+        // a file of one page per fill byte
+        fn new(name: &str, fills: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("io_{}_{}", name, std::process::id()));
+            let bytes: Vec<u8> = fills
+                .iter()
+                .flat_map(|f| vec![*f; P_SIZE as usize])
+                .collect();
+            fs::write(&path, bytes).unwrap();
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&path)
+                .unwrap();
+            Self {
+                path,
+                file,
+                dirty: HashMap::new(),
+            }
+        }
+
+        // as `s_write`: through to the file, then into the page images
+        fn write(&mut self, ofst: u64, data: &[u8]) {
+            seek_to(&mut self.file, ofst).unwrap();
+            self.file.write_all(data).unwrap();
+            buffer_pages(&mut self.dirty, &mut self.file, P_SIZE, ofst, data).unwrap();
+        }
+
+        fn page(&mut self, p_id: u32) -> Vec<u8> {
+            let mut buf = vec![0; P_SIZE as usize];
+            read_page(&mut self.file, p_id as u64 * P_SIZE, &mut buf).unwrap();
+            buf
+        }
+
+        // every buffered page is the page the file holds, as hashed at commit
+        fn assert_images_match(&mut self) {
+            let images: Vec<_> = self.dirty.iter().map(|(p, i)| (*p, i.clone())).collect();
+            for (p_id, image) in images {
+                assert_eq!(&self.page(p_id.get_id())[..], &image[..], "page {}", p_id);
+            }
+        }
+    }
+
+    impl Drop for MainFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn test_buffer_sub_page_write() {
+        let mut main = MainFile::new("sub_page", &[1, 2]);
+        main.write(P_SIZE + 10, &[9; 100]);
+        assert_eq!(main.dirty.keys().collect::<Vec<_>>(), vec![&PageId(1)]);
+        let image = &main.dirty[&PageId(1)];
+        assert!(image[..10].iter().all(|b| *b == 2));
+        assert!(image[10..110].iter().all(|b| *b == 9));
+        assert!(image[110..].iter().all(|b| *b == 2));
+        main.assert_images_match();
+
+        // a partial page past the end of the file starts from zeros
+        main.write(2 * P_SIZE + 8, &[5; 8]);
+        let image = &main.dirty[&PageId(2)];
+        assert!(image[..8].iter().all(|b| *b == 0));
+        assert!(image[8..16].iter().all(|b| *b == 5));
+        main.assert_images_match();
+    }
+
+    #[test]
+    fn test_buffer_write_across_pages() {
+        let mut main = MainFile::new("across", &[1, 2, 3]);
+        main.write(P_SIZE - 100, &[7; 200]);
+        let mut touched: Vec<_> = main.dirty.keys().copied().collect();
+        touched.sort();
+        assert_eq!(touched, vec![PageId(0), PageId(1)]);
+        assert!(main.dirty[&PageId(0)][..P_SIZE as usize - 100]
+            .iter()
+            .all(|b| *b == 1));
+        assert!(main.dirty[&PageId(1)][100..].iter().all(|b| *b == 2));
+        main.assert_images_match();
+    }
+
+    #[test]
+    fn test_read_dirty_page_before_commit() {
+        let mut main = MainFile::new("dirty_read", &[1, 2]);
+        main.write(20, &[4; 10]);
+        // sqlite reads the page back before the transaction commits and sees its own write
+        let page = main.page(0);
+        assert!(page[20..30].iter().all(|b| *b == 4));
+        assert_eq!(&page[..], &main.dirty[&PageId(0)][..]);
+
+        // a later write to the buffered page keeps the first one
+        main.write(100, &[6; 10]);
+        let image = &main.dirty[&PageId(0)];
+        assert!(image[20..30].iter().all(|b| *b == 4));
+        assert!(image[100..110].iter().all(|b| *b == 6));
+        main.assert_images_match();
+    }
+}
@@ -1,21 +1,33 @@
-use super::{io, MAX_PATH_LENGTH, MERKLE_PATH, SERVER_VFS};
-use crate::digest::Digest;
+use super::{
+    io, COMMIT_LOG_PATH, GLOBAL_TS, MAIN_PATH, MAX_PATH_LENGTH, MERKLE_PATH, SERVER_VFS,
+    SNAPSHOT_LOCK_PATH, VBF_PATH,
+};
+use crate::digest::{Digest, Digestible};
+use crate::merkle_cb_tree::hash::leaf_hash;
+use crate::merkle_cb_tree::transition::{Transition, WriteProof};
 use crate::merkle_cb_tree::write::WriteContext;
-use crate::merkle_cb_tree::WriteInterface;
+use crate::merkle_cb_tree::{MerkleNodeLoader, NodeId, ReadInterface, WriteInterface};
 use crate::vbf::VersionBloomFilter;
-use crate::vfs::{OpenOptions, TMP_FILE_PATH};
+use crate::vfs::{
+    error::{copy_last_error, fail},
+    io::{build_merkle_tree, page_digests},
+    shm::Shm,
+    OpenKind, OpenOptions, TMP_FILE_PATH,
+};
 use crate::{MerkleDB, PageId, ServerVfs};
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use libsqlite3_sys as ffi;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::ffi::{c_void, CString};
 use std::mem::{size_of, ManuallyDrop, MaybeUninit};
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::ptr::null_mut;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::time::{Duration, Instant};
 use std::{
     ffi::CStr,
-    fs::File,
+    fs::{self, File},
     io::ErrorKind,
     os::raw::{c_char, c_int},
 };
@@ -34,8 +46,58 @@ pub(crate) struct ServerFileState<'a> {
     pub(crate) ctx: ffi::sqlite3_file,
     pub(crate) file: MaybeUninit<File>, // todo: use Option here
     pub(crate) merkle_db_path: String,
-    pub(crate) map: &'a mut HashMap<PageId, Digest>,
+    pub(crate) path: String,
+    pub(crate) kind: OpenKind,
+    pub(crate) dirty: &'a mut HashMap<PageId, Box<[u8]>>,
+    pub(crate) truncated: &'a mut Option<u32>,
     pub(crate) vbf: &'a mut VersionBloomFilter,
+    pub(crate) committed: &'a mut Vec<Transition>,
+    pub(crate) shm: &'a mut HashMap<String, Shm>,
+}
+
+// held by readers that serve pages and proofs, and by writers that change the main file;
+// in wal mode writers only take it for the checkpoint, so readers keep the last checkpoint as snapshot
+static SNAPSHOT_LOCK: RwLock<()> = RwLock::new(());
+
+// the snapshot lock of this process along with an flock on `SNAPSHOT_LOCK_PATH`, so a writer in
+// another process, such as the updater, waits for the server's readers and the other way round
+pub struct Snapshot<G> {
+    _file: File,
+    _guard: G,
+}
+
+pub fn lock_snapshot_shared() -> Result<Snapshot<RwLockReadGuard<'static, ()>>> {
+    let guard = SNAPSHOT_LOCK.read().expect("snapshot lock poisoned");
+    Ok(Snapshot {
+        _file: flock_snapshot(libc::LOCK_SH)?,
+        _guard: guard,
+    })
+}
+
+pub fn lock_snapshot() -> Result<Snapshot<RwLockWriteGuard<'static, ()>>> {
+    let guard = SNAPSHOT_LOCK.write().expect("snapshot lock poisoned");
+    Ok(Snapshot {
+        _file: flock_snapshot(libc::LOCK_EX)?,
+        _guard: guard,
+    })
+}
+
+// the flock is released when the file is closed
+fn flock_snapshot(op: c_int) -> Result<File> {
+    let path = Path::new(SNAPSHOT_LOCK_PATH);
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("failed to open {:?}", path))?;
+    if unsafe { libc::flock(file.as_raw_fd(), op) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("failed to lock {:?}", path));
+    }
+    Ok(file)
 }
 
 /// # Safety
@@ -69,27 +131,45 @@ pub unsafe extern "C" fn s_open(
         debug!("opening {}", path);
     }
 
-    let state = server_vfs_state(p_vfs).expect("null pointer");
+    let state = match server_vfs_state(p_vfs) {
+        Ok(state) => state,
+        Err(e) => return fail(ffi::SQLITE_CANTOPEN, e),
+    };
 
     let opts = match OpenOptions::from_flags(flags) {
         Some(opts) => opts,
         None => {
-            return ffi::SQLITE_CANTOPEN;
+            return fail(
+                ffi::SQLITE_CANTOPEN,
+                anyhow!("unsupported open flags {:#x} for {}", flags, path),
+            );
         }
     };
 
+    let kind = opts.kind;
     let s_vfs = &mut state.vfs;
-    let s_file = s_vfs
-        .open(path.as_ref(), opts)
-        .expect("failed to open path");
-    let file_state = (p_file as *mut ServerFileState)
-        .as_mut()
-        .expect("null pointer");
+    let s_file = match s_vfs.open(path.as_ref(), opts) {
+        Ok(file) => file,
+        Err(e) => {
+            return fail(
+                ffi::SQLITE_CANTOPEN,
+                e.context(format!("failed to open {}", path)),
+            )
+        }
+    };
+    let Some(file_state) = (p_file as *mut ServerFileState).as_mut() else {
+        return fail(ffi::SQLITE_CANTOPEN, anyhow!("null pointer"));
+    };
     file_state.ctx.pMethods = &state.io_methods;
     file_state.merkle_db_path = s_vfs.merkle_db_path.clone();
     file_state.file.write(s_file);
-    file_state.map = &mut s_vfs.map;
+    std::ptr::addr_of_mut!(file_state.path).write(path);
+    file_state.kind = kind;
+    file_state.dirty = &mut s_vfs.dirty;
+    file_state.truncated = &mut s_vfs.truncated;
     file_state.vbf = &mut s_vfs.vbf;
+    file_state.committed = &mut s_vfs.committed;
+    file_state.shm = &mut s_vfs.shm;
 
     // todo: use option here will cause error due to unsuccessful assignment
     // debug!("{:?}", s_file);
@@ -108,20 +188,31 @@ pub unsafe extern "C" fn s_delete(
     _sync_dir: c_int,
 ) -> c_int {
     trace!("s_delete");
-    let state = server_vfs_state(p_vfs).expect("null pointer");
+    let state = match server_vfs_state(p_vfs) {
+        Ok(state) => state,
+        Err(e) => return fail(ffi::SQLITE_IOERR_DELETE, e),
+    };
     let path = CStr::from_ptr(z_path);
     let path = path.to_string_lossy().to_string();
 
     match state.vfs.delete(path.as_ref()) {
-        Ok(_) => ffi::SQLITE_OK,
-        Err(err) => {
-            if err.kind() == ErrorKind::NotFound {
-                ffi::SQLITE_OK
-            } else {
-                ffi::SQLITE_DELETE
-            }
+        Ok(_) => {}
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return fail(ffi::SQLITE_IOERR_DELETE, err.into()),
+    }
+    // deleting the rollback journal is the commit point in the default journal mode
+    if path.ends_with("-journal") {
+        let s_vfs = &mut state.vfs;
+        if let Err(e) = commit(
+            &mut s_vfs.dirty,
+            &mut s_vfs.truncated,
+            &mut s_vfs.vbf,
+            &mut s_vfs.committed,
+        ) {
+            return fail(ffi::SQLITE_IOERR_DELETE, e);
         }
     }
+    ffi::SQLITE_OK
 }
 
 /// # Safety
@@ -134,7 +225,10 @@ pub unsafe extern "C" fn s_access(
     p_res_out: *mut c_int,
 ) -> c_int {
     trace!("server access");
-    let state = server_vfs_state(p_vfs).expect("null pointer");
+    let state = match server_vfs_state(p_vfs) {
+        Ok(state) => state,
+        Err(e) => return fail(ffi::SQLITE_IOERR_ACCESS, e),
+    };
     let path = CStr::from_ptr(z_path);
     let path = path.to_string_lossy().to_string();
 
@@ -147,11 +241,13 @@ pub unsafe extern "C" fn s_access(
 
     match result {
         Ok(ok) => {
-            let p_res_out: &mut c_int = p_res_out.as_mut().expect("null pointer");
+            let Some(p_res_out) = p_res_out.as_mut() else {
+                return fail(ffi::SQLITE_IOERR_ACCESS, anyhow!("null pointer"));
+            };
             *p_res_out = ok as i32;
         }
-        Err(_) => {
-            return ffi::SQLITE_IOERR_ACCESS;
+        Err(err) => {
+            return fail(ffi::SQLITE_IOERR_ACCESS, err.into());
         }
     }
     ffi::SQLITE_OK
@@ -256,13 +352,16 @@ pub unsafe extern "C" fn s_current_time(
     ffi::SQLITE_OK
 }
 
-pub extern "C" fn s_get_last_error(
+/// # Safety
+///
+/// Copy the message of the last failed callback on this thread into `z_err_msg` and return its code.
+pub unsafe extern "C" fn s_get_last_error(
     _p_vfs: *mut ffi::sqlite3_vfs,
-    _n_byte: c_int,
-    _z_err_msg: *mut c_char,
+    n_byte: c_int,
+    z_err_msg: *mut c_char,
 ) -> c_int {
     trace!("s_get_last_error");
-    ffi::SQLITE_OK
+    copy_last_error(n_byte, z_err_msg)
 }
 
 /// # Safety
@@ -286,16 +385,16 @@ pub fn register_server(name: &str, s_vfs: ServerVfs) -> Result<()> {
         xTruncate: Some(io::s_truncate),
         xSync: Some(io::s_sync),
         xFileSize: Some(io::s_file_size),
-        xLock: Some(io::lock),
+        xLock: Some(io::s_lock),
         xUnlock: Some(io::unlock),
         xCheckReservedLock: Some(io::check_reserved_lock),
         xFileControl: Some(io::file_control),
         xSectorSize: Some(io::sector_size),
         xDeviceCharacteristics: Some(io::device_characteristics),
-        xShmMap: Some(io::shm_map),
-        xShmLock: Some(io::shm_lock),
-        xShmBarrier: Some(io::shm_barrier),
-        xShmUnmap: Some(io::shm_unmap),
+        xShmMap: Some(io::s_shm_map),
+        xShmLock: Some(io::s_shm_lock),
+        xShmBarrier: Some(io::s_shm_barrier),
+        xShmUnmap: Some(io::s_shm_unmap),
         xFetch: Some(io::mem_fetch),
         xUnfetch: Some(io::mem_unfetch),
     };
@@ -338,39 +437,216 @@ pub fn register_server(name: &str, s_vfs: ServerVfs) -> Result<()> {
     Ok(())
 }
 
-pub fn update_merkle_db() -> Result<()> {
+// commit the pages a writer left pending, e.g. a checkpoint run without syncs, and hand out the
+// transitions of the tree since the last call, to be verified in order by users who submitted writes
+pub fn update_merkle_db() -> Result<Vec<Transition>> {
     let name = ManuallyDrop::new(CString::new(SERVER_VFS)?);
-    let map = unsafe {
+    let s_vfs = unsafe {
         let p_vfs = libsqlite3_sys::sqlite3_vfs_find(name.as_ptr());
-        let state = server_vfs_state(p_vfs).expect("null pointer");
-        let s_vfs = &mut state.vfs;
-        &mut s_vfs.map
+        &mut server_vfs_state(p_vfs)?.vfs
+    };
+    commit(
+        &mut s_vfs.dirty,
+        &mut s_vfs.truncated,
+        &mut s_vfs.vbf,
+        &mut s_vfs.committed,
+    )?;
+    Ok(s_vfs.committed.drain(..).collect())
+}
+
+// every batch of writes gets a version above those already in the vbf
+pub fn bump_version() -> Result<u32> {
+    let name = ManuallyDrop::new(CString::new(SERVER_VFS)?);
+    let s_vfs = unsafe {
+        let p_vfs = libsqlite3_sys::sqlite3_vfs_find(name.as_ptr());
+        &mut server_vfs_state(p_vfs)?.vfs
     };
+    unsafe {
+        GLOBAL_TS = GLOBAL_TS.max(s_vfs.vbf.latest_version()) + 1;
+        Ok(GLOBAL_TS)
+    }
+}
+
+// hash the pages of a transaction sqlite has committed and apply them
+pub(crate) fn commit(
+    dirty: &mut HashMap<PageId, Box<[u8]>>,
+    truncated: &mut Option<u32>,
+    vbf: &mut VersionBloomFilter,
+    committed: &mut Vec<Transition>,
+) -> Result<()> {
+    let digests = dirty
+        .drain()
+        .map(|(p_id, bytes)| (p_id, bytes.to_digest()))
+        .collect();
+    apply(digests, truncated.take(), vbf, committed)
+}
+
+// update the merkle tree and the vbf with new page digests and cut the tree to `page_cnt`
+// pages; pages whose content is already in the tree, e.g. restored by a rollback, are dropped
+fn apply(
+    digests: Vec<(PageId, Digest)>,
+    page_cnt: Option<u32>,
+    vbf: &mut VersionBloomFilter,
+    committed: &mut Vec<Transition>,
+) -> Result<()> {
+    if digests.is_empty() && page_cnt.is_none() {
+        return Ok(());
+    }
+    let mut merkle_db = MerkleDB::create_new(Path::new(MERKLE_PATH))
+        .context("failed to open or create merkle db")?;
+    let root_id = merkle_db.get_root_id();
+    let tree_cnt = WriteContext::new(&merkle_db, root_id).page_cnt()?;
+    // pages a file grew by again after the truncation are kept
+    let max_written = digests.iter().map(|(p_id, _)| p_id.get_id() + 1).max();
+    let page_cnt = page_cnt
+        .map(|n| n.max(max_written.unwrap_or(0)))
+        .filter(|n| *n < tree_cnt);
     let mut modif = Vec::new();
-    for (p_id, dig) in map.drain() {
-        modif.push((p_id, dig));
+    for (p_id, dig) in digests {
+        let leaf = merkle_db.load_node(&NodeId::from_page_id(p_id))?;
+        if leaf.map(|n| n.get_hash()) != Some(leaf_hash(&p_id, &dig)) {
+            modif.push((p_id, dig));
+        }
+    }
+    if modif.is_empty() && page_cnt.is_none() {
+        merkle_db.close();
+        return Ok(());
     }
     modif.sort_by(|(p1, _), (p2, _)| p1.cmp(p2));
 
-    let mut merkle_db =
-        MerkleDB::create_new(Path::new(MERKLE_PATH)).expect("failed to open or create merkle db");
-    let root_id = merkle_db.get_root_id();
+    // a crash from here on leaves the tree half updated, it is rebuilt on restart
+    let mut logged = read_commit_log()?.unwrap_or_default();
+    logged.extend(modif.iter().map(|(p_id, _)| *p_id));
+    fs::write(COMMIT_LOG_PATH, serde_json::to_string(&logged)?)?;
+
+    let old_root = root_of(&merkle_db, root_id)?;
+    let last = page_cnt.and_then(|n| n.checked_sub(1)).map(PageId);
+    let proof = WriteProof::new(
+        &merkle_db,
+        root_id,
+        modif.iter().map(|(p_id, _)| *p_id).chain(last),
+    )?;
+    let version = unsafe { GLOBAL_TS };
     let mut ctx = WriteContext::new(&merkle_db, root_id);
-    for (p_id, dig) in modif {
-        ctx.update(dig, p_id).expect("Failed to update merkle tree");
+    for (p_id, dig) in &modif {
+        vbf.insert(*p_id, version);
+        ctx.update(*dig, *p_id)
+            .context("failed to update merkle tree")?;
+    }
+    if let Some(n) = page_cnt {
+        ctx.truncate(n).context("failed to truncate merkle tree")?;
     }
     let changes = ctx.changes();
     let new_root_id = changes.root_id;
     for (addr, node) in changes.nodes {
         merkle_db
             .write_node(&addr, &node)
-            .expect("Failed to write node to merkle db");
+            .context("failed to write node to merkle db")?;
     }
+    for addr in changes.removed {
+        merkle_db
+            .remove_node(&addr)
+            .context("failed to remove node from merkle db")?;
+    }
+    let new_root = root_of(&merkle_db, new_root_id)?;
+    // users fetch the vbf from the server and check it against the digest published with the root
+    vbf.grow_if_saturated();
+    vbf.save(Path::new(VBF_PATH))?;
+    merkle_db.set_vbf_digest(vbf.to_digest());
     merkle_db
         .update_param(new_root_id)
-        .expect("Failed to update merkle root id in merkle db");
+        .context("failed to update merkle root id in merkle db")?;
     merkle_db.close();
+    fs::remove_file(COMMIT_LOG_PATH)?;
+
+    debug!("commit of {} pages applied to the merkle tree", modif.len());
+    committed.push(Transition {
+        old_root,
+        new_root,
+        modif,
+        page_cnt,
+        proof,
+    });
+    Ok(())
+}
+
+// pages of an interrupted tree update, empty if it only shrank the tree
+fn read_commit_log() -> Result<Option<BTreeSet<PageId>>> {
+    match fs::read_to_string(COMMIT_LOG_PATH) {
+        Ok(s) => Ok(Some(serde_json::from_str(&s)?)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
 
+fn root_of(merkle_db: &MerkleDB, root_id: Option<NodeId>) -> Result<Option<(Digest, u32)>> {
+    let Some(id) = root_id else {
+        return Ok(None);
+    };
+    match merkle_db.get_node(&id.to_digest())? {
+        Some(n) => Ok(Some((n.get_hash(), id.get_height()))),
+        None => bail!("Cannot find root in merkle tree"),
+    }
+}
+
+// bring the tree and the vbf in line with the main file after a crash, once sqlite has rolled
+// back any hot journal: a tree update that was cut short is redone from the whole file, pages
+// committed before the tree followed are applied like a new commit, at a version above every
+// published one
+pub fn recover_merkle_db() -> Result<()> {
+    bump_version()?;
+    let name = ManuallyDrop::new(CString::new(SERVER_VFS)?);
+    let s_vfs = unsafe {
+        let p_vfs = libsqlite3_sys::sqlite3_vfs_find(name.as_ptr());
+        &mut server_vfs_state(p_vfs)?.vfs
+    };
+    if let Some(logged) = read_commit_log()? {
+        warn!(
+            "tree update of {} pages was interrupted, rebuilding the merkle tree",
+            logged.len()
+        );
+        build_merkle_tree()?;
+        let version = unsafe { GLOBAL_TS };
+        for p_id in &logged {
+            s_vfs.vbf.insert(*p_id, version);
+        }
+        s_vfs.vbf.save(Path::new(VBF_PATH))?;
+        publish_vbf_digest(&s_vfs.vbf)?;
+        fs::remove_file(COMMIT_LOG_PATH)?;
+        return Ok(());
+    }
+
+    let cnt = s_vfs.committed.len();
+    let digests = page_digests(Path::new(MAIN_PATH))?;
+    let page_cnt = digests.len() as u32;
+    apply(
+        digests,
+        Some(page_cnt),
+        &mut s_vfs.vbf,
+        &mut s_vfs.committed,
+    )?;
+    if let Some(t) = s_vfs.committed.get(cnt) {
+        warn!(
+            "{} pages were ahead of the merkle tree and have been applied",
+            t.modif.len()
+        );
+    }
+    Ok(())
+}
+
+// publish the digest of a vbf the writer created or that changed without a new root
+pub fn publish_vbf_digest(vbf: &VersionBloomFilter) -> Result<()> {
+    let digest = vbf.to_digest();
+    let mut merkle_db = MerkleDB::create_new(Path::new(MERKLE_PATH))
+        .context("failed to open or create merkle db")?;
+    if merkle_db.get_vbf_digest() != Some(digest) {
+        merkle_db.set_vbf_digest(digest);
+        let root_id = merkle_db.get_root_id();
+        merkle_db
+            .update_param(root_id)
+            .context("failed to publish the vbf digest")?;
+    }
+    merkle_db.close();
     Ok(())
 }
 
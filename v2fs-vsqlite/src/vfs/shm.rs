@@ -0,0 +1,144 @@
+use libsqlite3_sys as ffi;
+use std::collections::HashSet;
+use std::os::raw::c_int;
+
+const SHM_NLOCK: usize = ffi::SQLITE_SHM_NLOCK as usize;
+
+// wal-index of one database, kept in process memory and shared by all of its connections
+#[derive(Debug, Default)]
+pub struct Shm {
+    // u64 words keep every region 8-byte aligned as sqlite expects
+    regions: Vec<Box<[u64]>>,
+    // connections that currently map the wal-index
+    refs: HashSet<usize>,
+    // connections holding a shared lock on each slot
+    shared: [HashSet<usize>; SHM_NLOCK],
+    // connection holding the exclusive lock on each slot
+    exclusive: [Option<usize>; SHM_NLOCK],
+}
+
+impl Shm {
+    // return region `i_pg`, allocating the missing regions when `extend` is set
+    pub fn map(&mut self, owner: usize, i_pg: usize, pgsz: usize, extend: bool) -> Option<*mut u8> {
+        self.refs.insert(owner);
+        if self.regions.len() <= i_pg {
+            if !extend {
+                return None;
+            }
+            let words = pgsz.div_ceil(8);
+            self.regions
+                .resize_with(i_pg + 1, || vec![0_u64; words].into_boxed_slice());
+        }
+        Some(self.regions[i_pg].as_mut_ptr() as *mut u8)
+    }
+
+    // acquire or release the slots [offset, offset + n) for `owner`, all or nothing
+    pub fn lock(&mut self, owner: usize, offset: usize, n: usize, flags: c_int) -> c_int {
+        if offset + n > SHM_NLOCK {
+            return ffi::SQLITE_IOERR_SHMLOCK;
+        }
+        let slots = offset..offset + n;
+        let exclusive = flags & ffi::SQLITE_SHM_EXCLUSIVE != 0;
+
+        if flags & ffi::SQLITE_SHM_UNLOCK != 0 {
+            for i in slots {
+                if exclusive {
+                    if self.exclusive[i] == Some(owner) {
+                        self.exclusive[i] = None;
+                    }
+                } else {
+                    self.shared[i].remove(&owner);
+                }
+            }
+            return ffi::SQLITE_OK;
+        }
+
+        for i in slots.clone() {
+            if matches!(self.exclusive[i], Some(o) if o != owner) {
+                return ffi::SQLITE_BUSY;
+            }
+            if exclusive && self.shared[i].iter().any(|o| *o != owner) {
+                return ffi::SQLITE_BUSY;
+            }
+        }
+        for i in slots {
+            if exclusive {
+                self.exclusive[i] = Some(owner);
+            } else {
+                self.shared[i].insert(owner);
+            }
+        }
+        ffi::SQLITE_OK
+    }
+
+    // whether a connection other than `owner` still uses the wal-index
+    pub fn in_use_by_others(&self, owner: usize) -> bool {
+        self.refs.iter().any(|o| *o != owner)
+    }
+
+    // drop every lock of `owner`, returns true once no connection maps the wal-index any more
+    pub fn unmap(&mut self, owner: usize) -> bool {
+        for i in 0..SHM_NLOCK {
+            self.shared[i].remove(&owner);
+            if self.exclusive[i] == Some(owner) {
+                self.exclusive[i] = None;
+            }
+        }
+        self.refs.remove(&owner);
+        if self.refs.is_empty() {
+            // like a fresh shm file, the next opener rebuilds the wal-index from the wal
+            self.regions.clear();
+            return true;
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shm;
+    use libsqlite3_sys as ffi;
+
+    const LOCK_SHARED: i32 = ffi::SQLITE_SHM_LOCK | ffi::SQLITE_SHM_SHARED;
+    const LOCK_EXCL: i32 = ffi::SQLITE_SHM_LOCK | ffi::SQLITE_SHM_EXCLUSIVE;
+    const UNLOCK_SHARED: i32 = ffi::SQLITE_SHM_UNLOCK | ffi::SQLITE_SHM_SHARED;
+    const UNLOCK_EXCL: i32 = ffi::SQLITE_SHM_UNLOCK | ffi::SQLITE_SHM_EXCLUSIVE;
+
+    #[test]
+    fn test_shm_map() {
+        let mut shm = Shm::default();
+        assert!(shm.map(1, 0, 32768, false).is_none());
+        let p0 = shm.map(1, 0, 32768, true).unwrap();
+        unsafe { *p0 = 7 };
+        let p1 = shm.map(2, 1, 32768, true).unwrap();
+        assert_eq!(p0 as usize % 8, 0);
+        assert_eq!(p1 as usize % 8, 0);
+        // earlier regions stay in place when the wal-index grows
+        assert_eq!(shm.map(2, 0, 32768, false).unwrap(), p0);
+        assert_eq!(unsafe { *p0 }, 7);
+        assert!(shm.in_use_by_others(1));
+        assert!(!shm.unmap(1));
+        assert!(!shm.in_use_by_others(2));
+        assert!(shm.unmap(2));
+    }
+
+    #[test]
+    fn test_shm_lock() {
+        let mut shm = Shm::default();
+        assert_eq!(shm.lock(1, 3, 1, LOCK_SHARED), ffi::SQLITE_OK);
+        assert_eq!(shm.lock(2, 3, 1, LOCK_SHARED), ffi::SQLITE_OK);
+        assert_eq!(shm.lock(2, 3, 1, LOCK_EXCL), ffi::SQLITE_BUSY);
+        assert_eq!(shm.lock(1, 3, 1, UNLOCK_SHARED), ffi::SQLITE_OK);
+        // upgrading its own shared lock is allowed
+        assert_eq!(shm.lock(2, 3, 1, LOCK_EXCL), ffi::SQLITE_OK);
+        assert_eq!(shm.lock(1, 3, 1, LOCK_SHARED), ffi::SQLITE_BUSY);
+        // a failed range lock takes no slot
+        assert_eq!(shm.lock(1, 2, 2, LOCK_EXCL), ffi::SQLITE_BUSY);
+        assert_eq!(shm.lock(3, 2, 1, LOCK_EXCL), ffi::SQLITE_OK);
+        assert_eq!(shm.lock(2, 3, 1, UNLOCK_EXCL), ffi::SQLITE_OK);
+        assert_eq!(shm.lock(2, 3, 1, UNLOCK_SHARED), ffi::SQLITE_OK);
+        assert!(shm.unmap(3));
+        assert_eq!(shm.lock(1, 2, 2, LOCK_EXCL), ffi::SQLITE_OK);
+        assert_eq!(shm.lock(1, 7, 2, LOCK_EXCL), ffi::SQLITE_IOERR_SHMLOCK);
+    }
+}
@@ -4,15 +4,19 @@ use crate::{
     simple_vcache::SVCache,
     vbf::VersionBloomFilter,
     version_cache::VCache,
-    vfs::{NAME_CNT, REMOTE_FLAG, TMP_FLAG},
-    PageId, Type, UserVfs,
+    vfs::{
+        attached_holder_path, ATTACH, HOLDER_FILE_PATH, MAIN_DB, NAME_CNT, NO_FLAG, REMOTE_FLAG,
+        TMP_FLAG, YES_FLAG,
+    },
+    AttachedDb, PageId, Type, UserVfs,
 };
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use libsqlite3_sys as ffi;
 use std::{
     collections::HashMap,
     ffi::{c_void, CStr, CString},
     fs::OpenOptions,
+    io::{Read, Write},
     mem::{size_of, ManuallyDrop, MaybeUninit},
     net::TcpStream,
     os::raw::{c_char, c_int},
@@ -21,7 +25,10 @@ use std::{
     time::{Duration, Instant},
 };
 
-use super::{io, FileData, MAX_PATH_LENGTH};
+use super::{
+    error::{copy_last_error, fail},
+    io, FileData, MAX_PATH_LENGTH,
+};
 
 pub struct UserState<'a, 'b> {
     pub vfs: UserVfs<'a, 'b>,
@@ -40,6 +47,12 @@ pub(crate) struct UserFileState<'a, 'b> {
     pub(crate) stream: &'b mut TcpStream,
     pub(crate) tmp_file: MaybeUninit<FileData>,
     pub(crate) vbf: &'a VersionBloomFilter,
+    pub(crate) root: &'a (Digest, u32),
+    pub(crate) stream_proof_s: &'a mut usize,
+    // MAIN_DB, or i + 1 for attached[i]
+    pub(crate) db: usize,
+    pub(crate) attached: &'a mut Vec<AttachedDb>,
+    pub(crate) cur_db: &'a mut usize,
 }
 
 /// # Safety
@@ -65,42 +78,69 @@ pub unsafe extern "C" fn u_open(
     _flags: c_int,
     _p_out_flag: *mut c_int,
 ) -> c_int {
-    let state = user_vfs_state(p_vfs).expect("null pointer");
+    let state = match user_vfs_state(p_vfs) {
+        Ok(state) => state,
+        Err(e) => return fail(ffi::SQLITE_CANTOPEN, e),
+    };
     let u_vfs = &mut state.vfs;
 
-    let u_file_data = if z_name.is_null() {
+    let mut db = MAIN_DB;
+    if !z_name.is_null() {
+        let path = CStr::from_ptr(z_name).to_string_lossy();
+        if path != HOLDER_FILE_PATH {
+            let pos = u_vfs
+                .attached
+                .iter()
+                .position(|a| attached_holder_path(&a.name) == path);
+            let Some(i) = pos else {
+                return fail(
+                    ffi::SQLITE_CANTOPEN,
+                    anyhow!("{} is not a configured attached database", path),
+                );
+            };
+            db = i + 1;
+            let name = &u_vfs.attached[i].name;
+            if let Err(e) = attach_remote(u_vfs.stream, db, name) {
+                return fail(
+                    ffi::SQLITE_CANTOPEN,
+                    e.context(format!("failed to attach {}", name)),
+                );
+            }
+        }
+    }
+
+    let (id, path) = if z_name.is_null() {
         let surfix = NAME_CNT.to_string();
         let path = "./db/tmp_file".to_string() + &surfix;
         debug!("z_name is null, open {}", path);
         NAME_CNT += 1;
-        FileData {
-            id: TMP_FLAG,
-            name: path.clone(),
-            file: OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(path)
-                .expect("failed to open tmp file"),
-        }
+        (TMP_FLAG, path)
     } else {
         debug!("z_name is not null, open remote file");
         let path = CStr::from_ptr(z_name).to_string_lossy().to_string();
         debug!("create holder file: {}", path);
-        FileData {
-            id: REMOTE_FLAG,
-            name: path.clone(),
-            file: OpenOptions::new()
-                .read(true)
-                .write(true)
-                .create(true)
-                .open(path)
-                .expect("failed to open holder file"),
+        (REMOTE_FLAG, path)
+    };
+    let file = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(&path)
+    {
+        Ok(file) => file,
+        Err(err) => {
+            let err = anyhow::Error::from(err).context(format!("failed to open {}", path));
+            return fail(ffi::SQLITE_CANTOPEN, err);
         }
     };
-    let file_state = (p_file as *mut UserFileState)
-        .as_mut()
-        .expect("null pointer");
+    let u_file_data = FileData {
+        id,
+        name: path,
+        file,
+    };
+    let Some(file_state) = (p_file as *mut UserFileState).as_mut() else {
+        return fail(ffi::SQLITE_CANTOPEN, anyhow!("null pointer"));
+    };
     file_state.ctx.pMethods = &state.io_methods;
     file_state.tp = u_vfs.tp;
     file_state.cache = &mut u_vfs.cache;
@@ -110,21 +150,54 @@ pub unsafe extern "C" fn u_open(
     file_state.stream = &mut u_vfs.stream;
     file_state.tmp_file.write(u_file_data);
     file_state.vbf = &u_vfs.vbf;
+    file_state.root = &u_vfs.root;
+    file_state.stream_proof_s = &mut u_vfs.stream_proof_s;
+    file_state.db = db;
+    file_state.attached = &mut u_vfs.attached;
+    file_state.cur_db = &mut u_vfs.cur_db;
 
     trace!("open succeeds");
     ffi::SQLITE_OK
 }
 
+// ask the server to serve database `name` under index `db` for the rest of the session
+fn attach_remote(stream: &mut TcpStream, db: usize, name: &str) -> Result<()> {
+    let msg: (u32, PageId, Vec<Digest>) = (ATTACH, PageId(db as u32), vec![]);
+    stream.write_all(&bincode::serialize(&msg)?)?;
+    let mut flag = [0; 4];
+    stream.read_exact(&mut flag)?;
+    if u32::from_le_bytes(flag) != YES_FLAG {
+        bail!("invalid signal for attach");
+    }
+    let mut bytes = (name.len() as u64).to_le_bytes().to_vec();
+    bytes.extend(name.as_bytes());
+    stream.write_all(&bytes)?;
+    stream.read_exact(&mut flag)?;
+    match u32::from_le_bytes(flag) {
+        YES_FLAG => Ok(()),
+        NO_FLAG => bail!("server refused to attach {}", name),
+        _ => bail!("transmission error, unknown signal"),
+    }
+}
+
 /// # Safety
 ///
 /// Delete file for user. This function should never be called
 pub unsafe extern "C" fn u_delete(
     _p_vfs: *mut ffi::sqlite3_vfs,
-    _z_path: *const c_char,
+    z_path: *const c_char,
     _sync_dir: c_int,
 ) -> c_int {
     trace!("user delete");
-    panic!("Should not reach user delete");
+    let path = if z_path.is_null() {
+        "".into()
+    } else {
+        CStr::from_ptr(z_path).to_string_lossy()
+    };
+    fail(
+        ffi::SQLITE_IOERR_DELETE,
+        anyhow!("user files cannot be deleted: {}", path),
+    )
 }
 
 /// # Safety
@@ -145,7 +218,9 @@ pub unsafe extern "C" fn u_access(
         _ => return ffi::SQLITE_IOERR_ACCESS,
     };
 
-    let p_res_out: &mut c_int = p_res_out.as_mut().expect("null pointer");
+    let Some(p_res_out) = p_res_out.as_mut() else {
+        return fail(ffi::SQLITE_IOERR_ACCESS, anyhow!("null pointer"));
+    };
     *p_res_out = result;
 
     ffi::SQLITE_OK
@@ -256,13 +331,14 @@ pub unsafe extern "C" fn u_current_time(
 
 /// # Safety
 ///
+/// Copy the message of the last failed callback on this thread into `z_err_msg` and return its code.
 pub unsafe extern "C" fn u_get_last_error(
     _p_vfs: *mut ffi::sqlite3_vfs,
-    _n_byte: c_int,
-    _z_err_msg: *mut c_char,
+    n_byte: c_int,
+    z_err_msg: *mut c_char,
 ) -> c_int {
     trace!("user u_get_last_error");
-    ffi::SQLITE_OK
+    copy_last_error(n_byte, z_err_msg)
 }
 
 /// # Safety